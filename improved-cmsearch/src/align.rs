@@ -0,0 +1,768 @@
+use serde::{Deserialize, Serialize};
+use crate::cm::Consensus;
+use crate::digital;
+use crate::hmm::{self, ProfileHmm};
+use crate::pool;
+
+const MATCH: usize = 0;
+const INSERT: usize = 1;
+const DELETE: usize = 2;
+
+/// One step of an alignment path through the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignStep {
+    Match(usize, usize),  // Consensus position k aligned to residue i (0-based)
+    Insert(usize, usize), // Residue i inserted after consensus position k
+    Delete(usize),        // Consensus position k aligned to no residue
+}
+
+/// Glocal alignment of the whole model to a hit's residues, or with
+/// truncation the part of the model between its first and last residue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlignPath {
+    pub steps: Vec<AlignStep>,
+    pub score: f64,                   // Log-odds bits of the path
+    pub posteriors: Option<Vec<f64>>, // Per step (0 for deletes); None if over the memory cap
+}
+
+impl AlignPath {
+    /// Mean posterior probability of the aligned residues.
+    pub fn accuracy(&self) -> Option<f64> {
+        let posteriors = self.posteriors.as_ref()?;
+        let (sum, n) = self.steps.iter()
+            .zip(posteriors)
+            .filter(|(step, _)| !matches!(step, AlignStep::Delete(_)))
+            .fold((0.0, 0usize), |(sum, n), (_, pp)| (sum + pp, n + 1));
+        (n > 0).then(|| sum / n as f64)
+    }
+
+    /// Display form of the alignment, trimmed to the model positions
+    /// between the first and last aligned residue; `sequence` is the text of
+    /// the aligned residues.
+    pub fn alignment(&self, hmm: &ProfileHmm, consensus: &Consensus, sequence: &[u8]) -> Alignment {
+        let aligned = |step: &AlignStep| !matches!(step, AlignStep::Delete(_));
+        let first = self.steps.iter().position(aligned).unwrap_or(0);
+        let last = self.steps.iter().rposition(aligned).map_or(0, |j| j + 1);
+        let steps = &self.steps[first..last];
+
+        let cons = consensus.sequence.as_bytes();
+        let cs = consensus.structure.as_bytes();
+        let partners = wuss_pairs(&consensus.structure);
+        let residue_at = |k: usize| steps.iter().find_map(|step| match *step {
+            AlignStep::Match(k2, i) if k2 == k => Some(sequence[i]),
+            _ => None,
+        });
+
+        let mut alignment = Alignment {
+            model_from: 0,
+            model_to: 0,
+            structure: String::with_capacity(steps.len()),
+            model: String::with_capacity(steps.len()),
+            matches: String::with_capacity(steps.len()),
+            target: String::with_capacity(steps.len()),
+            posteriors: None,
+            score: self.score,
+            accuracy: self.accuracy(),
+        };
+        for step in steps {
+            let (cs_char, cons_char, match_char, residue) = match *step {
+                AlignStep::Match(k, i) => {
+                    let residue = sequence[i].to_ascii_uppercase();
+                    let match_char = if same_base(residue, cons[k]) {
+                        '|'
+                    } else if partners[k].and_then(residue_at).is_some_and(|partner| pairs(residue, partner)) {
+                        ':'
+                    } else if hmm.emission(k, digital::code(residue)) > 0.0 {
+                        '+'
+                    } else {
+                        ' '
+                    };
+                    (cs[k] as char, cons[k] as char, match_char, residue as char)
+                }
+                AlignStep::Insert(_, i) => ('.', '.', ' ', sequence[i].to_ascii_lowercase() as char),
+                AlignStep::Delete(k) => (cs[k] as char, cons[k] as char, ' ', '-'),
+            };
+            alignment.structure.push(cs_char);
+            alignment.model.push(cons_char);
+            alignment.matches.push(match_char);
+            alignment.target.push(residue);
+        }
+
+        let positions: Vec<usize> = steps.iter()
+            .filter_map(|step| match *step {
+                AlignStep::Match(k, _) | AlignStep::Delete(k) => Some(k + 1),
+                AlignStep::Insert(..) => None,
+            })
+            .collect();
+        alignment.model_from = positions.first().copied().unwrap_or(0);
+        alignment.model_to = positions.last().copied().unwrap_or(0);
+
+        alignment.posteriors = self.posteriors.as_ref().map(|posteriors| {
+            steps.iter()
+                .zip(&posteriors[first..last])
+                .map(|(step, &p)| match step {
+                    AlignStep::Delete(_) => '.',
+                    _ => pp_char(p),
+                })
+                .collect()
+        });
+        alignment
+    }
+}
+
+/// Hit alignment to the model, as displayed under each hit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alignment {
+    pub model_from: usize,          // First consensus position shown (1-based)
+    pub model_to: usize,            // Last consensus position shown
+    pub structure: String,          // Consensus structure (CS) in WUSS, '.' over inserts
+    pub model: String,              // Consensus residues, '.' over inserts
+    pub matches: String,            // '|' identity, ':' kept base pair, '+' positive score
+    pub target: String,             // Residues: '-' for deletions, lower case for inserts
+    pub posteriors: Option<String>, // PP line: '0'-'9', '*', '.' for deletions
+    pub score: f64,                 // Bits
+    pub accuracy: Option<f64>,      // Mean residue posterior probability
+}
+
+impl Alignment {
+    /// NC line of the alignment display: 'v' under both residues of each
+    /// consensus base pair that is not Watson-Crick or G-U; None if there
+    /// are none. Pairs with a deleted half are not marked.
+    pub fn noncanonical(&self) -> Option<String> {
+        let target = self.target.as_bytes();
+        let mut line = vec![b' '; target.len()];
+        for (i, partner) in wuss_pairs(&self.structure).into_iter().enumerate() {
+            if let Some(j) = partner {
+                if target[i] != b'-' && target[j] != b'-' && !pairs(target[i], target[j]) {
+                    line[i] = b'v';
+                }
+            }
+        }
+        line.contains(&b'v').then(|| String::from_utf8(line).unwrap())
+    }
+}
+
+/// DP memory, in bytes, to align a model of `model_length` positions to
+/// `residues` residues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignMemory {
+    pub full: usize,    // Full matrices: single-pass traceback and posteriors
+    pub minimum: usize, // Divide and conquer, a few rows at a time
+}
+
+impl AlignMemory {
+    pub fn new(model_length: usize, residues: usize) -> Self {
+        let cells = (residues + 1).saturating_mul(model_length + 1);
+        Self {
+            full: cells.saturating_mul(3 * CELL_BYTES),
+            minimum: 4 * (model_length + 1) * CELL_BYTES,
+        }
+    }
+}
+
+/// Best alignment of the whole profile HMM to all of the digitized
+/// `sequence`: the highest scoring (Viterbi) path, or with `accurate` the
+/// optimal accuracy path, which maximizes the summed posterior probabilities
+/// of its residues. This is an HMM alignment: it does not use the CM's base
+/// pairs, and is not Infernal's CM divide and conquer (the generalized
+/// Hirschberg over the SCFG), which this crate does not implement.
+///
+/// Viterbi DP matrices that would exceed `max_bytes` are avoided by divide
+/// and conquer: the sequence is split at its middle row, the optimal path's
+/// cell in that row is found from a forward and a backward pass in linear
+/// memory, and each half is aligned recursively. Posteriors need the full
+/// Forward and Backward matrices, so over the cap they are left out and the
+/// optimal accuracy mode falls back to the Viterbi path. Returns None if
+/// even divide and conquer would exceed `max_bytes`.
+///
+/// With `truncated`, the sequence may be a fragment missing either end of
+/// the model: the consensus positions deleted before its first residue and
+/// after its last cost a truncation penalty, one per end, instead of their
+/// delete transitions.
+pub fn hmm_align(hmm: &ProfileHmm, sequence: &[u8], max_bytes: usize, accurate: bool, truncated: bool) -> Option<AlignPath> {
+    let memory = AlignMemory::new(hmm.length, sequence.len());
+    if hmm.length == 0 || memory.minimum > max_bytes {
+        return None;
+    }
+    let viterbi = Aligner {
+        hmm,
+        sequence,
+        trans: hmm.transitions(),
+        max_cells: max_bytes / CELL_BYTES,
+        sum: false,
+        posteriors: None,
+        // Entry or exit at any of the K positions, as likely as each other
+        trunc_penalty: truncated.then(|| -(hmm.length as f64).ln()),
+    };
+
+    let posteriors = if memory.full <= max_bytes {
+        Aligner { sum: true, ..viterbi.clone() }.posteriors()
+    } else {
+        None
+    };
+
+    let (steps, score) = match &posteriors {
+        Some(pp) if accurate => {
+            // Any allowed transition is free; only the residue posteriors count
+            let trans = viterbi.trans.map(|row| row.map(|t| if t.is_finite() { 0.0 } else { f64::NEG_INFINITY }));
+            let trunc_penalty = viterbi.trunc_penalty.map(|_| 0.0);
+            let oa = Aligner { trans, posteriors: Some(pp), trunc_penalty, ..viterbi.clone() };
+            let steps = oa.global_path()?.0;
+            let score = viterbi.path_score(&steps);
+            (steps, score)
+        }
+        _ => {
+            let (steps, nats) = viterbi.global_path()?;
+            (steps, nats / std::f64::consts::LN_2)
+        }
+    };
+
+    let posteriors = posteriors.map(|pp| {
+        steps.iter()
+            .map(|step| match *step {
+                AlignStep::Match(k, i) => pp.get(i + 1, k + 1, MATCH),
+                AlignStep::Insert(k, i) => pp.get(i + 1, k + 1, INSERT),
+                AlignStep::Delete(_) => 0.0,
+            })
+            .collect()
+    });
+    Some(AlignPath { steps, score, posteriors })
+}
+
+/// Posterior probability as a PP annotation character: '0'-'9' for the
+/// nearest tenth, '*' for 0.95 and above.
+pub fn pp_char(p: f64) -> char {
+    if p + 0.05 >= 1.0 {
+        '*'
+    } else {
+        (b'0' + ((p + 0.05) * 10.0).max(0.0) as u8) as char
+    }
+}
+
+// Partner of each position in a WUSS structure, from matched brackets
+fn wuss_pairs(structure: &str) -> Vec<Option<usize>> {
+    let mut partners = vec![None; structure.len()];
+    let mut open: [Vec<usize>; 4] = Default::default();
+    for (k, c) in structure.bytes().enumerate() {
+        if let Some(b) = b"<([{".iter().position(|&o| o == c) {
+            open[b].push(k);
+        } else if let Some(b) = b">)]}".iter().position(|&o| o == c) {
+            if let Some(j) = open[b].pop() {
+                partners[j] = Some(k);
+                partners[k] = Some(j);
+            }
+        }
+    }
+    partners
+}
+
+fn same_base(a: u8, b: u8) -> bool {
+    matches!(hmm::residue_index(a), Some(r) if hmm::residue_index(b) == Some(r))
+}
+
+// Watson-Crick or G-U wobble pair
+fn pairs(a: u8, b: u8) -> bool {
+    matches!(
+        (hmm::residue_index(a), hmm::residue_index(b)),
+        (Some(0), Some(3)) | (Some(3), Some(0)) | (Some(1), Some(2)) | (Some(2), Some(1)) | (Some(2), Some(3)) | (Some(3), Some(2))
+    )
+}
+
+// Bytes per DP cell: M, I and D scores for one (residue, position) pair
+const CELL_BYTES: usize = 3 * std::mem::size_of::<f64>();
+
+// A path node: state `s` of consensus position k after i residues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    i: usize,
+    k: usize,
+    s: usize,
+}
+
+// Posterior probabilities that M_k and I_k emit residue i, for i, k >= 1
+struct Posteriors {
+    width: usize,
+    pp: Vec<[f64; 2]>,
+}
+
+impl Posteriors {
+    fn get(&self, i: usize, k: usize, s: usize) -> f64 {
+        self.pp[(i - 1) * self.width + k][s]
+    }
+}
+
+#[derive(Clone)]
+struct Aligner<'a> {
+    hmm: &'a ProfileHmm,
+    sequence: &'a [u8],
+    trans: [[f64; 3]; 3],
+    max_cells: usize,
+    sum: bool,                          // Forward/Backward sums instead of best paths
+    posteriors: Option<&'a Posteriors>, // Score residues by posterior, for optimal accuracy
+    trunc_penalty: Option<f64>,         // Truncated alignment: cost of skipping either end of the model
+}
+
+impl Aligner<'_> {
+    // Score for entering state `s` of position k on row i
+    fn emit(&self, i: usize, k: usize, s: usize) -> f64 {
+        match (self.posteriors, s) {
+            (_, DELETE) => 0.0,
+            (Some(pp), _) => pp.get(i, k, s),
+            (None, MATCH) => self.hmm.emission(k - 1, self.sequence[i - 1]),
+            (None, _) => 0.0, // Inserts emit with background probabilities
+        }
+    }
+
+    fn combine(&self, a: f64, b: f64) -> f64 {
+        if self.sum { hmm::log_sum(a, b) } else { a.max(b) }
+    }
+
+    fn combine3(&self, a: f64, b: f64, c: f64) -> f64 {
+        self.combine(self.combine(a, b), c)
+    }
+
+    // Score of the transition from state `from` to `to` leaving row i: with
+    // truncation, deletes on the first and last rows skip the model's ends
+    // for the penalty, paid on entering the first of them
+    fn transition(&self, i: usize, from: usize, to: usize) -> f64 {
+        let t = self.trans[from][to];
+        match self.trunc_penalty {
+            Some(penalty) if t.is_finite() && (i == 0 || i == self.sequence.len()) => match (from, to) {
+                (MATCH, DELETE) => penalty,
+                (DELETE, DELETE) => 0.0,
+                (DELETE, MATCH) if i == 0 => 0.0,
+                _ => t,
+            },
+            _ => t,
+        }
+    }
+
+    // Log-odds score of a path in bits, recomputed step by step
+    fn path_score(&self, steps: &[AlignStep]) -> f64 {
+        let (mut prev, mut i) = (MATCH, 0);
+        let mut nats = 0.0;
+        for step in steps {
+            let (s, emit) = match *step {
+                AlignStep::Match(k, r) => (MATCH, self.hmm.emission(k, self.sequence[r])),
+                AlignStep::Insert(_, _) => (INSERT, 0.0),
+                AlignStep::Delete(_) => (DELETE, 0.0),
+            };
+            nats += self.transition(i, prev, s) + emit;
+            if s != DELETE {
+                i += 1;
+            }
+            prev = s;
+        }
+        nats / std::f64::consts::LN_2
+    }
+
+    // There is no I_0 or I_K state
+    fn has_insert(&self, k: usize) -> bool {
+        k >= 1 && k < self.hmm.length
+    }
+
+    // Best path from the begin state (M_0) through all residues, leaving
+    // through M_K or D_K, with its score
+    fn global_path(&self) -> Option<(Vec<AlignStep>, f64)> {
+        let (l, k_len) = (self.sequence.len(), self.hmm.length);
+        let begin = Cell { i: 0, k: 0, s: MATCH };
+        let last = self.forward_row(begin, l, k_len);
+        let end_state = if last[k_len][MATCH] >= last[k_len][DELETE] { MATCH } else { DELETE };
+        let score = last[k_len][end_state];
+        if !score.is_finite() {
+            return None;
+        }
+
+        let mut cells = Vec::new();
+        self.best_path(begin, Cell { i: l, k: k_len, s: end_state }, &mut cells);
+        let steps = cells.into_iter()
+            .map(|c| match c.s {
+                MATCH => AlignStep::Match(c.k - 1, c.i - 1),
+                INSERT => AlignStep::Insert(c.k - 1, c.i - 1),
+                _ => AlignStep::Delete(c.k - 1),
+            })
+            .collect();
+        Some((steps, score))
+    }
+
+    // Posterior decoding from full Forward and Backward matrices
+    fn posteriors(&self) -> Option<Posteriors> {
+        let (l, k_len) = (self.sequence.len(), self.hmm.length);
+        let width = k_len + 1;
+        let begin = Cell { i: 0, k: 0, s: MATCH };
+
+        let mut fwd = pool::take(0, [0.0; 3]);
+        fwd.reserve((l + 1) * width);
+        self.forward(begin, l, k_len, |cells| fwd.extend_from_slice(cells));
+        let end = fwd[l * width + k_len];
+        let total = hmm::log_sum(end[MATCH], end[DELETE]);
+        if !total.is_finite() {
+            return None;
+        }
+
+        // Backward rows arrive last to first; either glocal exit ends a path
+        let mut bwd = pool::take((l + 1) * width, [f64::NEG_INFINITY; 3]);
+        let mut row = l + 1;
+        let exits = [0.0, f64::NEG_INFINITY, 0.0];
+        self.backward(l, k_len, exits, 0, 0, |cells| {
+            row -= 1;
+            bwd[row * width..(row + 1) * width].copy_from_slice(cells);
+        });
+
+        let pp = (width..(l + 1) * width)
+            .map(|cell| {
+                let p = |s: usize| (fwd[cell][s] + bwd[cell][s] - total).exp();
+                [p(MATCH), p(INSERT)]
+            })
+            .collect();
+        Some(Posteriors { width, pp })
+    }
+
+    // Appends the cells of the best path from `a` (exclusive) to `b`
+    // (inclusive)
+    fn best_path(&self, a: Cell, b: Cell, path: &mut Vec<Cell>) {
+        let cells = (b.i - a.i + 1) * (b.k - a.k + 1);
+        if b.i - a.i < 2 || cells <= self.max_cells {
+            self.traceback(a, b, path);
+            return;
+        }
+
+        let mid = (a.i + b.i) / 2;
+        let fwd = self.forward_row(a, mid, b.k);
+        let bwd = self.backward_row(b, mid, a.k);
+        let mut best = (f64::NEG_INFINITY, a);
+        for (col, (f, w)) in fwd.iter().zip(&bwd).enumerate() {
+            for s in [MATCH, INSERT, DELETE] {
+                if f[s] + w[s] > best.0 {
+                    best = (f[s] + w[s], Cell { i: mid, k: a.k + col, s });
+                }
+            }
+        }
+
+        let split = best.1;
+        self.best_path(a, split, path);
+        self.best_path(split, b, path);
+    }
+
+    // Scores of the best paths from `a` to each cell of row `last`, for
+    // positions a.k..=k_end
+    fn forward_row(&self, a: Cell, last: usize, k_end: usize) -> Vec<[f64; 3]> {
+        let mut row = Vec::new();
+        self.forward(a, last, k_end, |cells| {
+            row.clear();
+            row.extend_from_slice(cells);
+        });
+        row
+    }
+
+    // Forward pass from `a` over rows a.i..=last; `on_row` sees each row
+    fn forward(&self, a: Cell, last: usize, k_end: usize, mut on_row: impl FnMut(&[[f64; 3]])) {
+        let t = |i: usize, from: usize, to: usize| self.transition(i, from, to);
+        let width = k_end - a.k + 1;
+        let mut prev = pool::take(width, [f64::NEG_INFINITY; 3]);
+        let mut cur = pool::take(width, [f64::NEG_INFINITY; 3]);
+
+        for i in a.i..=last {
+            for col in 0..width {
+                let k = a.k + col;
+                cur[col] = [f64::NEG_INFINITY; 3];
+                if i == a.i {
+                    if col == 0 {
+                        cur[col][a.s] = 0.0;
+                    }
+                } else {
+                    if col > 0 {
+                        let p = &prev[col - 1];
+                        cur[col][MATCH] = self.emit(i, k, MATCH) + self.combine3(
+                            p[MATCH] + t(i - 1, MATCH, MATCH),
+                            p[INSERT] + t(i - 1, INSERT, MATCH),
+                            p[DELETE] + t(i - 1, DELETE, MATCH),
+                        );
+                    }
+                    if self.has_insert(k) {
+                        let p = &prev[col];
+                        cur[col][INSERT] = self.emit(i, k, INSERT)
+                            + self.combine(p[MATCH] + t(i - 1, MATCH, INSERT), p[INSERT] + t(i - 1, INSERT, INSERT));
+                    }
+                }
+                if col > 0 {
+                    let p = cur[col - 1];
+                    cur[col][DELETE] = self.combine(p[MATCH] + t(i, MATCH, DELETE), p[DELETE] + t(i, DELETE, DELETE));
+                }
+            }
+            on_row(&cur);
+            std::mem::swap(&mut prev, &mut cur);
+        }
+    }
+
+    // Scores of the best paths from each cell of row `first` to `b`,
+    // excluding the cell's own emission, for positions k_start..=b.k
+    fn backward_row(&self, b: Cell, first: usize, k_start: usize) -> Vec<[f64; 3]> {
+        let mut exits = [f64::NEG_INFINITY; 3];
+        exits[b.s] = 0.0;
+        let mut row = Vec::new();
+        self.backward(b.i, b.k, exits, first, k_start, |cells| {
+            row.clear();
+            row.extend_from_slice(cells);
+        });
+        row
+    }
+
+    // Backward pass over rows last..=first (descending) to the states of
+    // cell (last, k_end) scored by `exits`; `on_row` sees each row
+    fn backward(
+        &self,
+        last: usize,
+        k_end: usize,
+        exits: [f64; 3],
+        first: usize,
+        k_start: usize,
+        mut on_row: impl FnMut(&[[f64; 3]]),
+    ) {
+        let t = |i: usize, from: usize, to: usize| self.transition(i, from, to);
+        let width = k_end - k_start + 1;
+        let mut next = pool::take(width, [f64::NEG_INFINITY; 3]);
+        let mut cur = pool::take(width, [f64::NEG_INFINITY; 3]);
+
+        for i in (first..=last).rev() {
+            for col in (0..width).rev() {
+                let k = k_start + col;
+                if i == last && col == width - 1 {
+                    cur[col] = exits;
+                    continue;
+                }
+
+                // Continuation through each successor state
+                let via_match = if i < last && col + 1 < width {
+                    self.emit(i + 1, k + 1, MATCH) + next[col + 1][MATCH]
+                } else {
+                    f64::NEG_INFINITY
+                };
+                let via_insert = if i < last && self.has_insert(k) {
+                    self.emit(i + 1, k, INSERT) + next[col][INSERT]
+                } else {
+                    f64::NEG_INFINITY
+                };
+                let via_delete = if col + 1 < width { cur[col + 1][DELETE] } else { f64::NEG_INFINITY };
+
+                for s in [MATCH, INSERT, DELETE] {
+                    cur[col][s] = self.combine3(
+                        t(i, s, MATCH) + via_match,
+                        t(i, s, INSERT) + via_insert,
+                        t(i, s, DELETE) + via_delete,
+                    );
+                }
+            }
+            on_row(&cur);
+            std::mem::swap(&mut next, &mut cur);
+        }
+    }
+
+    // Full-matrix best path from `a` to `b` with traceback
+    fn traceback(&self, a: Cell, b: Cell, path: &mut Vec<Cell>) {
+        let width = b.k - a.k + 1;
+        let mut matrix = pool::take(0, [0.0; 3]);
+        matrix.reserve((b.i - a.i + 1) * width);
+        self.forward(a, b.i, b.k, |cells| matrix.extend_from_slice(cells));
+        let at = |c: Cell| matrix[(c.i - a.i) * width + (c.k - a.k)][c.s];
+
+        let start = path.len();
+        let mut c = b;
+        while c != a {
+            path.push(c);
+            let (i, k) = match c.s {
+                MATCH => (c.i - 1, c.k - 1),
+                INSERT => (c.i - 1, c.k),
+                _ => (c.i, c.k - 1),
+            };
+            let score = at(c) - self.emit(c.i, c.k, c.s);
+            c = [MATCH, INSERT, DELETE]
+                .into_iter()
+                .map(|s| Cell { i, k, s })
+                .filter(|p| p.i >= a.i && p.k >= a.k)
+                .max_by(|p, q| {
+                    let dp = (at(*p) + self.transition(p.i, p.s, c.s) - score).abs();
+                    let dq = (at(*q) + self.transition(q.i, q.s, c.s) - score).abs();
+                    dq.partial_cmp(&dp).unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .expect("traceback left the DP rectangle");
+        }
+        path[start..].reverse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cm::{Alphabet, Cm};
+    use crate::digital::DigitizedSeq;
+
+    fn test_consensus(sequence: &str, structure: &str) -> Consensus {
+        Consensus {
+            sequence: sequence.to_string(),
+            structure: structure.to_string(),
+            length: sequence.len(),
+        }
+    }
+
+    fn test_hmm(consensus: &str) -> ProfileHmm {
+        let mut cm = Cm::new("test".to_string(), Alphabet::RNA);
+        cm.length = consensus.len();
+        cm.consensus.sequence = consensus.to_string();
+        ProfileHmm::from_cm(&cm)
+    }
+
+    fn aligner<'a>(hmm: &'a ProfileHmm, sequence: &'a [u8], trunc_penalty: Option<f64>) -> Aligner<'a> {
+        Aligner {
+            hmm,
+            sequence,
+            trans: hmm.transitions(),
+            max_cells: usize::MAX,
+            sum: false,
+            posteriors: None,
+            trunc_penalty,
+        }
+    }
+
+    // Smallest cap that still allows alignment, forcing divide and conquer
+    fn min_bytes(hmm: &ProfileHmm) -> usize {
+        AlignMemory::new(hmm.length, 0).minimum
+    }
+
+    #[test]
+    fn test_divide_and_conquer_matches_full_traceback() {
+        let hmm = test_hmm("GCAUUCGAUACGGAUCCAGUAGCAUGCAUUAG");
+        let targets: [&[u8]; 4] = [
+            b"GCAUUCGAUACGGAUCCAGUAGCAUGCAUUAG",
+            b"GCAUUCGAUUUUACGGAUCCAGUAGCAUGCAUUAG",
+            b"GCAUUCGAUACGGCAGUAGCAUGCAG",
+            b"AAAGCAUUCGGGAUACGGAUCCAGUACAUGCAUUAGAA",
+        ];
+
+        for target in targets.map(DigitizedSeq::from_text) {
+            let full = hmm_align(&hmm, &target, usize::MAX, false, false).unwrap();
+            let split = hmm_align(&hmm, &target, min_bytes(&hmm), false, false).unwrap();
+
+            assert!((full.score - split.score).abs() < 1e-9);
+            assert!((aligner(&hmm, &target, None).path_score(&split.steps) - split.score).abs() < 1e-9);
+
+            // Every residue and every consensus position appears exactly once
+            let residues = split.steps.iter().filter(|s| !matches!(s, AlignStep::Delete(_))).count();
+            let positions = split.steps.iter().filter(|s| !matches!(s, AlignStep::Insert(..))).count();
+            assert_eq!(residues, target.len());
+            assert_eq!(positions, hmm.length);
+        }
+    }
+
+    #[test]
+    fn test_align_places_insertions() {
+        let hmm = test_hmm("GCAUUCGAUACGGAUCCAGU");
+        let target = DigitizedSeq::from_text(b"GCAUUCGAUAAAAACGGAUCCAGU");
+        let path = hmm_align(&hmm, &target, min_bytes(&hmm), false, false).unwrap();
+        let inserts = path.steps.iter().filter(|s| matches!(s, AlignStep::Insert(..))).count();
+        assert_eq!(inserts, 4);
+        assert_eq!(path.steps.first(), Some(&AlignStep::Match(0, 0)));
+        assert_eq!(path.steps.last(), Some(&AlignStep::Match(19, 23)));
+    }
+
+    #[test]
+    fn test_truncated_alignment() {
+        let consensus = "GCAUUCGAUACGGAUCCAGUAGCAUGCAUUAG";
+        let hmm = test_hmm(consensus);
+        let target = DigitizedSeq::from_text(&consensus.as_bytes()[8..24]);
+        let full = hmm_align(&hmm, &target, usize::MAX, false, true).unwrap();
+        let split = hmm_align(&hmm, &target, min_bytes(&hmm), false, true).unwrap();
+        assert_eq!(full.steps, split.steps);
+
+        // The fragment's residues match their own positions, the ends are skipped
+        let matches: Vec<_> = full.steps.iter().filter(|s| !matches!(s, AlignStep::Delete(_))).collect();
+        assert_eq!(matches.len(), 16);
+        assert!(matches.iter().enumerate().all(|(i, step)| **step == AlignStep::Match(i + 8, i)));
+        let glocal = hmm_align(&hmm, &target, usize::MAX, false, false).unwrap();
+        assert!(full.score > glocal.score);
+
+        let penalty = Some(-(hmm.length as f64).ln());
+        assert!((aligner(&hmm, &target, penalty).path_score(&full.steps) - full.score).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_optimal_accuracy_alignment() {
+        let hmm = test_hmm("GCAUUCGAUACGGAUCCAGUAGCAUGCAUUAG");
+        let target = DigitizedSeq::from_text(b"GCAUUCGAUUUUACGGAUCCAGUAGCAUGCAUUAG");
+        let viterbi = hmm_align(&hmm, &target, usize::MAX, false, false).unwrap();
+        let accurate = hmm_align(&hmm, &target, usize::MAX, true, false).unwrap();
+
+        // Posteriors of each residue sum to one over the states emitting it
+        let pp = Aligner { sum: true, ..aligner(&hmm, &target, None) }.posteriors().unwrap();
+        for i in 1..=target.len() {
+            let total: f64 = (1..=hmm.length).map(|k| pp.get(i, k, MATCH) + pp.get(i, k, INSERT)).sum();
+            assert!((total - 1.0).abs() < 1e-6, "residue {} posteriors sum to {}", i, total);
+        }
+
+        let (acc_viterbi, acc_accurate) = (viterbi.accuracy().unwrap(), accurate.accuracy().unwrap());
+        assert!(acc_accurate >= acc_viterbi - 1e-9);
+        assert!(acc_accurate > 0.0 && acc_accurate <= 1.0);
+        assert!(accurate.score <= viterbi.score + 1e-9);
+
+        // Without room for the posterior matrices both modes give a Viterbi path
+        let capped = hmm_align(&hmm, &target, min_bytes(&hmm), true, false).unwrap();
+        assert!((capped.score - viterbi.score).abs() < 1e-9);
+        assert_eq!(capped.accuracy(), None);
+    }
+
+    #[test]
+    fn test_pp_line() {
+        assert_eq!(pp_char(0.0), '0');
+        assert_eq!(pp_char(0.04), '0');
+        assert_eq!(pp_char(0.05), '1');
+        assert_eq!(pp_char(0.51), '5');
+        assert_eq!(pp_char(0.949), '9');
+        assert_eq!(pp_char(0.95), '*');
+        assert_eq!(pp_char(1.0), '*');
+
+        let hmm = test_hmm("GCAUUCGAUACGGAUCCAGU");
+        let target = b"GCAUUCGAUACGAUCCAGU";
+        let alignment = hmm_align(&hmm, &DigitizedSeq::from_text(target), usize::MAX, false, false).unwrap()
+            .alignment(&hmm, &test_consensus("GCAUUCGAUACGGAUCCAGU", "::::::::::::::::::::"), target);
+        let pp = alignment.posteriors.unwrap();
+        assert_eq!(pp.len(), alignment.target.len());
+        // The deleted position has no PP; aligned residues all have one
+        for (residue, pp) in alignment.target.chars().zip(pp.chars()) {
+            assert_eq!(residue == '-', pp == '.');
+        }
+    }
+
+    #[test]
+    fn test_alignment_lines() {
+        let consensus = test_consensus("GGGAAACCCAUCG", "<<<___>>>::::");
+        let hmm = test_hmm(&consensus.sequence);
+        // Compensatory G-C -> C-G change in the first pair, T for U
+        let target = b"CGGAAACCGAGTCG";
+        let alignment = hmm_align(&hmm, &DigitizedSeq::from_text(target), usize::MAX, false, false).unwrap()
+            .alignment(&hmm, &consensus, target);
+
+        assert_eq!(alignment.model_from, 1);
+        assert_eq!(alignment.model_to, 13);
+        assert_eq!(alignment.structure.len(), alignment.target.len());
+        assert_eq!(alignment.structure, "<<<___>>>:.:::");
+        assert_eq!(alignment.model, "GGGAAACCCA.UCG");
+        assert_eq!(alignment.target, "CGGAAACCGAgTCG");
+        assert_eq!(alignment.matches, ":|||||||:| |||");
+        assert_eq!(alignment.noncanonical(), None);
+
+        let mispaired = Alignment { target: "CAGAAACCGAgTCG".to_string(), ..alignment };
+        assert_eq!(mispaired.noncanonical().as_deref(), Some(" v     v      "));
+    }
+
+    #[test]
+    fn test_align_respects_memory_cap() {
+        let hmm = test_hmm("GCAUUCGAUACGGAUCCAGU");
+        let target = DigitizedSeq::from_text(b"GCAUUCGAUACGGAUCCAGU");
+        let memory = AlignMemory::new(hmm.length, target.len());
+        assert!(memory.minimum < memory.full);
+
+        assert!(hmm_align(&hmm, &target, memory.minimum - 1, false, false).is_none());
+        assert!(hmm_align(&hmm, &target, memory.minimum, false, false).unwrap().posteriors.is_none());
+        assert!(hmm_align(&hmm, &target, memory.full, false, false).unwrap().posteriors.is_some());
+    }
+}
@@ -0,0 +1,371 @@
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::Args;
+use std::str::FromStr;
+use crate::config::{DupNames, MsaFormat, SortOrder, StageKind};
+
+/// Filter presets, as --preset names them.
+pub const PRESETS: [&str; 5] = ["max", "nohmm", "mid", "default", "rfam"];
+
+/// Parser of an option taking one of `names`, or of the `hidden` aliases,
+/// so that help and shell completion can list the choices.
+pub fn one_of<T>(names: &'static [&'static str], hidden: &'static [&'static str]) -> impl TypedValueParser<Value = T>
+where
+    T: FromStr<Err = String> + Clone + Send + Sync + 'static,
+{
+    let choices = names.iter()
+        .map(|&name| PossibleValue::new(name))
+        .chain(hidden.iter().map(|&name| PossibleValue::new(name).hide(true)));
+    PossibleValuesParser::new(choices).try_map(|name| name.parse::<T>())
+}
+
+/// Options of the `search` command, which [`Config::from_args`] turns into
+/// the search's configuration.
+///
+/// [`Config::from_args`]: crate::config::Config::from_args
+#[derive(Args)]
+pub struct SearchArgs {
+    /// CM file path ('-' reads stdin)
+    #[arg(required = true)]
+    pub cmfile: String,
+    
+    /// Sequence database file path ('-' reads stdin, e.g. from zcat)
+    #[arg(required = true)]
+    pub seqdb: String,
+    
+    /// Output file (default: stdout)
+    #[arg(short, long)]
+    pub output: Option<String>,
+    
+    /// E-value threshold
+    #[arg(short = 'E', long, default_value = "10.0")]
+    pub evalue: f64,
+    
+    /// Score threshold
+    #[arg(short = 'T', long)]
+    pub score: Option<f64>,
+    
+    /// E-value threshold for including a hit ('!'); reported hits beyond it are marginal ('?')
+    #[arg(long = "incE", value_name = "X", default_value = "0.01")]
+    pub inc_evalue: f64,
+    
+    /// Score threshold for including a hit, in place of --incE
+    #[arg(long = "incT", value_name = "X")]
+    pub inc_score: Option<f64>,
+    
+    /// Compute E-values as if the database were <Mb> megabases (both strands)
+    #[arg(short = 'Z', value_name = "Mb")]
+    pub z: Option<f64>,
+    
+    /// Omit hit alignments from the report and --json, keeping the hit table
+    #[arg(long)]
+    pub noali: bool,
+    
+    /// Wrap alignment blocks, and cut descriptions in the hit table, to lines of <N> characters
+    #[arg(long, value_name = "N", default_value = "120", value_parser = clap::value_parser!(u64).range(120..))]
+    pub textw: u64,
+    
+    /// Do not wrap or cut report lines
+    #[arg(long, conflicts_with = "textw")]
+    pub notextw: bool,
+    
+    /// Align hits for optimal accuracy (summed posteriors) instead of the best score
+    #[arg(long)]
+    pub acc: bool,
+    
+    /// Also write hits to <FILE> as an Infernal tabular hit table
+    #[arg(long, value_name = "FILE")]
+    pub tblout: Option<String>,
+    
+    /// --tblout format: 1, or 2 adding the olp/anyidx/afrct/winidx/wfrct overlap
+    /// annotation, clan, and model and target length columns
+    #[arg(long, default_value = "1", requires = "tblout", value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub fmt: u8,
+    
+    /// Also write results to <FILE> as JSON Lines: a run metadata object, then
+    /// one object per hit (with its alignment unless --noali)
+    #[arg(long, value_name = "FILE")]
+    pub json: Option<String>,
+    
+    /// Also write hit alignments to <FILE> as SAM against the targets (e.g. for IGV):
+    /// the model consensus is the read, so target inserts are D and deletions I
+    #[arg(long, value_name = "FILE")]
+    pub sam: Option<String>,
+    
+    /// Save a multiple alignment of all reported hits to <FILE>
+    #[arg(short = 'A', value_name = "FILE")]
+    pub msa: Option<String>,
+    
+    /// Format of the -A alignment: stockholm, a2m, or afa (aligned FASTA); the A2M
+    /// and aligned FASTA files start with the model consensus as a reference row
+    #[arg(long, value_name = "FORMAT", default_value = "stockholm", ignore_case = true,
+          value_parser = one_of::<MsaFormat>(&["stockholm", "a2m", "afa"], &["pfam"]))]
+    pub outformat: MsaFormat,
+    
+    /// Save the residues of each reported hit to <FILE> as FASTA, reverse complemented
+    /// for minus strand hits and named <target>/<from>-<to>
+    #[arg(long, value_name = "FILE")]
+    pub hitfasta: Option<String>,
+    
+    /// Order of the hits in every output: evalue, score, position (by target, then
+    /// start coordinate), or target (by target, then E-value)
+    #[arg(long, value_name = "ORDER", default_value = "evalue", ignore_case = true,
+          value_parser = one_of::<SortOrder>(&["evalue", "score", "position", "target"], &[]))]
+    pub sort: SortOrder,
+    
+    /// Also write the pipeline statistics summary to <FILE> as a JSON object
+    #[arg(long, value_name = "FILE")]
+    pub stats_json: Option<String>,
+    
+    /// Write hits to <FILE> as JSON Lines as soon as they are found, so an interrupted
+    /// run keeps them; streamed hits are unsorted and may repeat overlapping loci.
+    /// A run answered from --cache writes its final hits
+    #[arg(long, value_name = "FILE")]
+    pub stream: Option<String>,
+    
+    /// Also write hits to <FILE> as GFF3 features
+    #[arg(long, value_name = "FILE")]
+    pub gff: Option<String>,
+    
+    /// Write histograms of the scores of every filter stage, including windows below
+    /// its threshold, and of the final hit scores to <FILE>, for choosing -T/-E cutoffs
+    #[arg(long, value_name = "FILE")]
+    pub histogram: Option<String>,
+    
+    /// Run the HMM filter stages (SSV, Viterbi, Forward, gFwd, envelope) before the
+    /// consensus filter. With `--hmm-filter=false` every window goes straight to the
+    /// consensus filter and final scoring, as with --nohmm: much slower, and hits
+    /// are not narrowed to an HMM envelope
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, num_args = 0..=1,
+          require_equals = true, default_missing_value = "true", value_name = "BOOL")]
+    pub hmm_filter: bool,
+    
+    /// Run the HMM filter DP in single precision: less memory traffic, scores
+    /// within about 1e-3 bits; final scores stay double precision
+    #[arg(long)]
+    pub f32_filters: bool,
+    
+    /// Maximum DP matrix memory per alignment in MB; larger alignments use divide and conquer
+    #[arg(long, visible_alias = "mx-size", default_value = "1024")]
+    pub max_mx_size: f64,
+    
+    /// Keep peak memory near <MB> megabytes for huge targets: measure the database in
+    /// a first pass instead of holding it, run single precision filters, give the DP
+    /// matrices of each thread a share of the budget, and queue fewer chunks. Each
+    /// target record is still held whole while it is searched
+    #[arg(long, value_name = "MB")]
+    pub low_mem: Option<f64>,
+    
+    /// Stop the search before resident memory goes over <MB> megabytes, writing the
+    /// hits found so far and exiting with an error; with --checkpoint the search
+    /// can then be resumed
+    #[arg(long, value_name = "MB")]
+    pub max_rss: Option<f64>,
+    
+    /// Skip the truncated passes for hits running off sequence ends. These passes are a
+    /// heuristic, not Infernal's TrCYK: model fragments are placed ungapped, scored by
+    /// consensus log-odds without their base pairs
+    #[arg(long, conflicts_with = "anytrunc")]
+    pub notrunc: bool,
+    
+    /// Allow truncated hits anywhere, not just at sequence ends (with the same ungapped
+    /// fragment placement as the truncated passes)
+    #[arg(long)]
+    pub anytrunc: bool,
+    
+    /// Search only the top (given) strand of each target
+    #[arg(long, conflicts_with = "bottomonly")]
+    pub toponly: bool,
+    
+    /// Search only the bottom (reverse complement) strand of each target
+    #[arg(long)]
+    pub bottomonly: bool,
+    
+    /// Search passes: 1 standard only, 2 adds 5'- and 3'-truncated passes at sequence ends, 3 also 5'&3'-truncated;
+    /// the truncated passes place model fragments ungapped (see --notrunc)
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(u8).range(1..=3))]
+    pub passes: u8,
+    
+    /// P-value threshold for the SSV stage (default: set by preset and database size)
+    #[arg(long = "F1")]
+    pub f1: Option<f64>,
+    
+    /// P-value threshold for the Viterbi stage
+    #[arg(long = "F2")]
+    pub f2: Option<f64>,
+    
+    /// P-value threshold for the local HMM Forward stage
+    #[arg(long = "F3")]
+    pub f3: Option<f64>,
+    
+    /// P-value threshold for the glocal HMM Forward (gFwd) stage
+    #[arg(long = "F4")]
+    pub f4: Option<f64>,
+    
+    /// P-value threshold for the gFwd stage after bias correction
+    #[arg(long = "F4b")]
+    pub f4b: Option<f64>,
+    
+    /// P-value threshold for the glocal envelope stage
+    #[arg(long = "F5")]
+    pub f5: Option<f64>,
+    
+    /// P-value threshold for the consensus filter stage
+    #[arg(long = "F6")]
+    pub f6: Option<f64>,
+    
+    /// Turn off the null3 low-complexity score correction
+    #[arg(long)]
+    pub nonull3: bool,
+    
+    /// FASTQ input: mask residues with Phred quality below <Q> as N
+    #[arg(long, value_name = "Q")]
+    pub mask_quality: Option<u8>,
+    
+    /// Memory-map the sequence database instead of reading it through a buffer;
+    /// faster for repeated searches of a large file that stays in the page cache
+    #[arg(long)]
+    pub mmap: bool,
+    
+    /// Treat soft-masked (lowercase) residues as masked: they score as background
+    /// in every stage, and no hit starts in them
+    #[arg(long)]
+    pub skip_masked: bool,
+    
+    /// Also treat residues written as <C> (e.g. N or X for hard masking) as masked
+    #[arg(long, value_name = "C")]
+    pub mask_char: Option<char>,
+    
+    /// Jump over runs of at least <LEN> N (or other ambiguous) residues, such as
+    /// assembly gaps; runs shorter than the model's maximum hit length are searched
+    #[arg(long, value_name = "LEN", default_value = "1000")]
+    pub min_n_run: usize,
+    
+    /// Continue an interrupted search: search only the records after <NAME>.
+    /// E-values still cover the whole database. Fast with an index (see `index`)
+    #[arg(long, value_name = "NAME")]
+    pub resume: Option<String>,
+    
+    /// Save progress to <FILE> (the targets searched and their hits) while searching;
+    /// if <FILE> exists, resume from it, skipping those targets. Removed on success
+    #[arg(long, value_name = "FILE")]
+    pub checkpoint: Option<String>,
+    
+    /// Seconds between checkpoint saves
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    pub checkpoint_interval: u64,
+    
+    /// Listen on <ADDR> (host:port) for `worker` processes, which search chunks of the
+    /// targets alongside the local threads. Stage statistics count local chunks only
+    #[arg(long, value_name = "ADDR")]
+    pub coordinator: Option<String>,
+    
+    /// Reuse the results of an identical earlier search (same CM file, targets, and
+    /// options) cached in <DIR> instead of searching, and cache this search's results there
+    #[arg(long, value_name = "DIR")]
+    pub cache: Option<String>,
+    
+    /// Search only the targets named in <FILE> (one name or accession per line),
+    /// or with names matching re:<REGEX>. Uses the index when there is one
+    #[arg(long, value_name = "FILE")]
+    pub seqlist: Option<String>,
+    
+    /// Skip the targets named in <FILE>, or with names matching re:<REGEX>
+    #[arg(long, value_name = "FILE")]
+    pub seqexclude: Option<String>,
+    
+    /// Fail on the first target residue that is not an IUPAC nucleotide code,
+    /// reporting the sequence and position, instead of scoring it as N
+    #[arg(long)]
+    pub strict_seqs: bool,
+    
+    /// Targets sharing a name: error, suffix (rename later copies name.1, name.2, ...),
+    /// or dedup (drop later copies with identical residues)
+    #[arg(long, value_name = "MODE", default_value = "error", ignore_case = true,
+          value_parser = one_of::<DupNames>(&["error", "suffix", "dedup"], &[]))]
+    pub dup_names: DupNames,
+    
+    /// Write extracted/emitted sequences with T (DNA alphabet)
+    #[arg(long, conflicts_with = "rna_out")]
+    pub dna_out: bool,
+    
+    /// Write extracted/emitted sequences with U (RNA alphabet)
+    #[arg(long)]
+    pub rna_out: bool,
+    
+    /// Filter stages to run, in order (final scoring always runs last)
+    #[arg(long, value_delimiter = ',', default_value = "ssv,vit,fwd,gfwd,env,cons", ignore_case = true,
+          value_parser = one_of::<StageKind>(&["ssv", "vit", "fwd", "gfwd", "env", "cons"],
+                                             &["viterbi", "forward", "envelope", "consensus", "score"]))]
+    pub stages: Vec<StageKind>,
+    
+    /// Maximum sensitivity: turn off all filter stages and score every window with the CM
+    #[arg(long, group = "preset", conflicts_with = "stages")]
+    pub max: bool,
+    
+    /// Skip the HMM filter stages but keep the consensus filter
+    #[arg(long, group = "preset")]
+    pub nohmm: bool,
+    
+    /// Skip the SSV and Viterbi stages and loosen the remaining HMM thresholds
+    #[arg(long, group = "preset")]
+    pub mid: bool,
+    
+    /// Default filter stages and thresholds
+    #[arg(long, group = "preset")]
+    pub default: bool,
+    
+    /// Strict thresholds for very large databases: fastest, least sensitive
+    #[arg(long, group = "preset")]
+    pub rfam: bool,
+    
+    /// Report hits overlapping a better hit (olp '=') instead of removing them
+    #[arg(long)]
+    pub keep_overlaps: bool,
+    
+    /// Debug: write the Forward DP matrix of each hit envelope to DIR/<hit id>.npy
+    #[arg(long, value_name = "DIR")]
+    pub dump_dp: Option<String>,
+    
+    /// Print the resolved pipeline (filter stages and thresholds, W, expected memory
+    /// per thread, E-value parameters, outputs) and exit without searching
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::config::{Config, Preset, Strands, TruncMode};
+
+    #[derive(Parser)]
+    struct Search {
+        #[command(flatten)]
+        args: SearchArgs,
+    }
+
+    #[test]
+    fn test_config_from_args() {
+        let config = |args: &[&str]| {
+            let search = Search::try_parse_from(["search"].iter().chain(args).chain(&["tRNA.cm", "chr1.fa"])).unwrap();
+            Config::from_args(search.args, 2)
+        };
+        let config_default = config(&[]);
+        assert_eq!((config_default.preset, config_default.trunc, config_default.strands),
+                   (Preset::Default, TruncMode::Termini, Strands::Both));
+        assert_eq!(config_default.textw, Some(120));
+
+        let set = config(&["--rfam", "--notrunc", "--toponly", "--noali", "--notextw", "--low-mem", "100"]);
+        assert_eq!((set.preset, set.trunc, set.strands), (Preset::Rfam, TruncMode::Off, Strands::Top));
+        assert!(!set.alignments && set.textw.is_none());
+        // --low-mem gives the DP matrices of the 2 threads half its budget
+        assert!(set.f32_filters);
+        assert_eq!(set.max_mx_size, 25.0);
+        assert_eq!(set.threads, 2);
+        
+        // --hmm-filter alone turns the filter on; its value needs the `=`, so
+        // the positionals that follow are not taken for one
+        assert!(config_default.hmm_filter && config(&["--hmm-filter"]).hmm_filter);
+        assert!(!config(&["--hmm-filter=false"]).hmm_filter);
+    }
+}
@@ -0,0 +1,196 @@
+use log::{error, info};
+use rayon::ThreadPoolBuilder;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use crate::config::Config;
+use crate::error::{self, BatchError, Error, FileError, PipelineError};
+use crate::pipeline::SearchModel;
+use crate::search::CmSearch;
+use crate::utils;
+
+/// One search of a batch manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub line: usize, // 1-based line of the manifest
+    pub cmfile: String,
+    pub seqdb: String,
+    pub options: Vec<String>, // search options, split at whitespace
+    pub prefix: String,
+}
+
+impl Job {
+    /// The job as the arguments of a `search` command: its own options,
+    /// with the report written to `<prefix>.out` and the hit table to
+    /// `<prefix>.tbl` unless they name other files.
+    pub fn search_args(&self) -> Vec<String> {
+        let mut args = vec!["search".to_string()];
+        args.extend(self.options.iter().cloned());
+        for (names, suffix) in [(&["-o", "--output"][..], "out"), (&["--tblout"][..], "tbl")] {
+            if !self.sets(names) {
+                args.extend([names[0].to_string(), format!("{}.{}", self.prefix, suffix)]);
+            }
+        }
+        args.extend(["--".to_string(), self.cmfile.clone(), self.seqdb.clone()]);
+        args
+    }
+    
+    // Whether the job's options give the option of one of `names`
+    fn sets(&self, names: &[&str]) -> bool {
+        self.options.iter().any(|option| {
+            names.iter().any(|name| option == name || option.strip_prefix(name).is_some_and(|rest| rest.starts_with('=')))
+        })
+    }
+}
+
+/// Jobs of a batch manifest (`batch --manifest`): one per line, four
+/// tab-separated fields, the CM file, the sequence file, the search options
+/// (separated by spaces; empty or `-` for none), and the output prefix.
+/// Blank lines and `#` comments are ignored.
+pub fn read_manifest(path: &str) -> Result<Vec<Job>, FileError> {
+    let text = fs::read_to_string(path).map_err(FileError::io(|| format!("Failed to read manifest {}", path)))?;
+    parse_manifest(&text).map_err(|message| FileError::invalid(format!("Invalid manifest {}: {}", path, message)))
+}
+
+pub fn parse_manifest(text: &str) -> Result<Vec<Job>, String> {
+    let mut jobs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [cmfile, seqdb, options, prefix] = fields[..] else {
+            return Err(format!("line {}: expected 4 tab-separated fields (cmfile, seqdb, options, prefix), found {}",
+                               i + 1, fields.len()));
+        };
+        if cmfile.is_empty() || seqdb.is_empty() || prefix.is_empty() {
+            return Err(format!("line {}: the CM file, sequence file, and output prefix must be given", i + 1));
+        }
+        let options = if options == "-" { Vec::new() } else { options.split_whitespace().map(String::from).collect() };
+        jobs.push(Job {
+            line: i + 1,
+            cmfile: cmfile.to_string(),
+            seqdb: seqdb.to_string(),
+            options,
+            prefix: prefix.to_string(),
+        });
+    }
+    Ok(jobs)
+}
+
+/// Outcome of a batch: jobs run, those that failed, and the hits reported.
+#[derive(Debug, Clone, Default)]
+pub struct BatchSummary {
+    pub jobs: usize,
+    pub failed: usize,
+    pub hits: usize,
+}
+
+/// Runs the searches of `jobs`, each with its config, in order on one pool
+/// of `threads` threads (0: every available CPU). The models of each CM
+/// file are built once and shared by the jobs that search it. A failed job
+/// stops the batch unless `keep_going`, which logs it and goes on.
+pub fn run(jobs: Vec<(Job, Config)>, threads: usize, keep_going: bool) -> Result<BatchSummary, BatchError> {
+    let threads = utils::resolve_threads(threads);
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build().map_err(PipelineError::other)?;
+    let mut cache = ModelCache::default();
+    let mut summary = BatchSummary { jobs: jobs.len(), ..Default::default() };
+    info!("Running {} jobs on {} threads", jobs.len(), threads);
+
+    for (job, config) in jobs {
+        // <prefix>.out, unless the job's options give -o
+        let output = config.output.clone().unwrap_or_else(|| "standard output".to_string());
+        let search = || -> Result<usize, Error> {
+            let config = Config { threads, ..config };
+            config.validate().map_err(PipelineError::Config)?;
+            let models = cache.models(&config)?;
+            CmSearch::with_models(config, models)?.run_on(&pool)
+        };
+        match search().map_err(|source| BatchError::Job { line: job.line, prefix: job.prefix.clone(), source }) {
+            Ok(hits) => {
+                info!("Job on manifest line {}: {} hits, written to {}", job.line, hits, output);
+                summary.hits += hits;
+            }
+            Err(err) if keep_going => {
+                error!("{}", error::report(&err));
+                summary.failed += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(summary)
+}
+
+// Search models of the CM files read so far, by path and the options that
+// shape them (--f32-filters)
+#[derive(Default)]
+struct ModelCache {
+    models: HashMap<(String, bool), Vec<Arc<SearchModel>>>,
+}
+
+impl ModelCache {
+    fn models(&mut self, config: &Config) -> Result<Vec<Arc<SearchModel>>, Error> {
+        let key = (config.cmfile.clone(), config.f32_filters);
+        if let Some(models) = self.models.get(&key) {
+            return Ok(models.clone());
+        }
+        let models = CmSearch::load_models(config)?;
+        self.models.insert(key, models.clone());
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::fixtures::{planted, write_models, CONSENSUS};
+
+    #[test]
+    fn test_parse_manifest() {
+        let jobs = parse_manifest("# cmfile\tseqdb\toptions\tprefix\ntRNA.cm\tchr1.fa\t-E 1e-5 --noali\tout/chr1\n\n\
+                                   tRNA.cm\tchr2.fa\t-\tout/chr2\n").unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].line, 2);
+        assert_eq!(jobs[0].options, vec!["-E", "1e-5", "--noali"]);
+        assert!(jobs[1].options.is_empty());
+        assert_eq!(jobs[1].search_args(),
+                   vec!["search", "-o", "out/chr2.out", "--tblout", "out/chr2.tbl", "--", "tRNA.cm", "chr2.fa"]);
+        let job = Job { options: vec!["--tblout=hits.tbl".to_string()], ..jobs[1].clone() };
+        assert_eq!(job.search_args(), vec!["search", "--tblout=hits.tbl", "-o", "out/chr2.out", "--", "tRNA.cm", "chr2.fa"]);
+
+        assert!(parse_manifest("tRNA.cm\tchr1.fa\tout/chr1\n").is_err());
+        assert!(parse_manifest("tRNA.cm\t\t-\tout/chr1\n").is_err());
+    }
+
+    #[test]
+    fn test_run_keeps_going_past_the_memory_limit() {
+        let dir = std::env::temp_dir().join(format!("cmsearch-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cm, targets) = (dir.join("model.cm"), dir.join("targets.fa"));
+        // A model emitting its consensus, and a target holding it once
+        write_models(&cm, &[("m", CONSENSUS)]);
+        fs::write(&targets, format!(">chr1\n{}\n", planted(CONSENSUS))).unwrap();
+
+        let job = |prefix: &str, max_rss: Option<f64>| {
+            let job = Job {
+                line: 1,
+                cmfile: cm.display().to_string(),
+                seqdb: targets.display().to_string(),
+                options: Vec::new(),
+                prefix: dir.join(prefix).display().to_string(),
+            };
+            let config = Config {
+                cmfile: job.cmfile.clone(),
+                seqdb: job.seqdb.clone(),
+                output: Some(format!("{}.out", job.prefix)),
+                max_rss,
+                ..Config::new()
+            };
+            (job, config)
+        };
+        // The first job stops at its --max-rss; the second has no limit
+        let summary = run(vec![job("over", Some(1e-3)), job("within", None)], 2, true).unwrap();
+        assert_eq!((summary.jobs, summary.failed, summary.hits), (2, 1, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use log::info;
+use rayon::ThreadPoolBuilder;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::cm::Cm;
+use crate::config::{Config, Preset};
+use crate::digital;
+use crate::error::PipelineError;
+use crate::pipeline::{Pipeline, SearchModel, StageSummary};
+use crate::search::{Hit, Sequence};
+use crate::stage::CmScores;
+
+/// Targets of a synthetic `bench` workload: random decoy sequences with
+/// sequences emitted by the model planted in them, half of them reverse
+/// complemented.
+pub struct Workload {
+    pub targets: Vec<Sequence>,
+    pub planted: Vec<(usize, Range<usize>)>, // Target index and residues of each planted sequence
+    pub seed: u64,
+}
+
+impl Workload {
+    /// `residues` residues in `targets` targets, with `planted` model
+    /// sequences of the given divergence from the consensus spread over
+    /// them; the same seed gives the same workload.
+    pub fn generate(
+        scores: &CmScores,
+        residues: usize,
+        targets: usize,
+        planted: usize,
+        divergence: f64,
+        seed: u64,
+    ) -> Result<Self, PipelineError> {
+        let targets = targets.max(1);
+        let mut random = Lcg(seed);
+        let mut workload = Workload { targets: Vec::with_capacity(targets), planted: Vec::new(), seed };
+        for t in 0..targets {
+            let length = residues / targets + usize::from(t < residues % targets);
+            let mut sequence: Vec<u8> = (0..length).map(|_| b"ACGU"[(random.next() * 4.0) as usize % 4]).collect();
+
+            // Each planted sequence at a random offset in its own slot
+            let count = planted / targets + usize::from(t < planted % targets);
+            let slot = length / count.max(1);
+            for i in 0..count {
+                let mut emitted = scores.emit(divergence, || random.next());
+                if random.next() < 0.5 {
+                    emitted = digital::reverse_complement_text(&emitted);
+                }
+                if emitted.len() > slot {
+                    return Err(PipelineError::Config(format!(
+                        "{} residues are too few to plant {} sequences of up to {} residues in {} targets",
+                        residues, planted, emitted.len(), targets)));
+                }
+                let start = i * slot + (random.next() * (slot - emitted.len()) as f64) as usize;
+                sequence[start..start + emitted.len()].copy_from_slice(emitted.as_bytes());
+                workload.planted.push((t, start..start + emitted.len()));
+            }
+
+            let sequence = String::from_utf8(sequence).expect("residues are ASCII");
+            workload.targets.push(Sequence::new(format!("bench{}", t + 1), String::new(), sequence));
+        }
+        Ok(workload)
+    }
+
+    /// Residues of the targets, one strand.
+    pub fn residues(&self) -> u64 {
+        self.targets.iter().map(|target| target.length as u64).sum()
+    }
+
+    /// Planted sequences overlapped by a hit, on either strand.
+    pub fn found(&self, hits: &[Hit]) -> usize {
+        self.planted
+            .iter()
+            .filter(|(t, planted)| {
+                let name = &self.targets[*t].name;
+                hits.iter().any(|hit| hit.sequence_name == *name && hit.start < planted.end && planted.start < hit.end)
+            })
+            .count()
+    }
+}
+
+// Deterministic pseudo-random numbers in [0, 1) from a linear
+// congruential generator
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// One timed search of a workload.
+pub struct BenchRun {
+    pub preset: Preset,
+    pub threads: usize,
+    pub seconds: f64,
+    pub hits: usize,
+    pub found: usize, // Planted sequences found
+    pub stages: Vec<StageSummary>,
+    pub trunc_stages: Vec<StageSummary>,
+}
+
+/// Search `workload` with the options of `config` but `preset`, on a pool
+/// of `threads` threads.
+pub fn run(model: &Arc<SearchModel>, config: &Config, workload: &Workload, preset: Preset, threads: usize) -> Result<BenchRun, PipelineError> {
+    let config = Arc::new(Config { preset, threads, ..config.clone() });
+    let db_residues = config.searched_residues(workload.residues());
+    let pipeline = Pipeline::new(Arc::clone(model), config, db_residues)?;
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build().map_err(PipelineError::other)?;
+
+    let start = Instant::now();
+    let hits = pool.install(|| pipeline.search(workload.targets.iter().cloned().map(Ok)))?;
+    let seconds = start.elapsed().as_secs_f64();
+    info!("{:?} preset, {} threads: {:.2}s, {} hits", preset, threads, seconds, hits.len());
+
+    let summary = pipeline.summary();
+    Ok(BenchRun {
+        preset,
+        threads,
+        seconds,
+        hits: hits.len(),
+        found: workload.found(&hits),
+        stages: summary.stages,
+        trunc_stages: summary.trunc_stages,
+    })
+}
+
+// Least scaling efficiency at which --speedtest counts more threads as
+// worth using
+const GOOD_EFFICIENCY: f64 = 0.75;
+
+/// Thread counts of --speedtest: 1, 2, 4, ... up to `max`, then `max`.
+pub fn speedtest_threads(max: usize) -> Vec<usize> {
+    let max = max.max(1);
+    let mut counts: Vec<usize> = std::iter::successors(Some(1usize), |n| n.checked_mul(2))
+        .take_while(|&n| n < max)
+        .collect();
+    counts.push(max);
+    counts
+}
+
+/// --speedtest report: the speedup of each run over the run on the fewest
+/// threads, and its scaling efficiency (the speedup per thread added).
+pub fn write_scaling(out: &mut dyn Write, workload: &Workload, runs: &[BenchRun]) -> io::Result<()> {
+    let Some(baseline) = runs.iter().min_by_key(|run| run.threads) else {
+        return Ok(());
+    };
+    let efficiency = |run: &BenchRun| baseline.seconds / run.seconds * baseline.threads as f64 / run.threads as f64;
+    writeln!(out, "# improved-cmsearch speedtest")?;
+    writeln!(out, "# workload: {} residues in {} targets (seed {})", workload.residues(), workload.targets.len(), workload.seed)?;
+    writeln!(out, "#")?;
+    writeln!(out, "# {:>7} {:>9} {:>9} {:>8} {:>10}", "threads", "seconds", "Mb/s", "speedup", "efficiency")?;
+    writeln!(out, "# {:->7} {:->9} {:->9} {:->8} {:->10}", "", "", "", "", "")?;
+    for run in runs {
+        writeln!(out, "  {:>7} {:>9.2} {:>9.3} {:>8.2} {:>9.0}%",
+                 run.threads, run.seconds, workload.residues() as f64 / 1e6 / run.seconds,
+                 baseline.seconds / run.seconds, 100.0 * efficiency(run))?;
+    }
+    let threads = runs.iter()
+        .filter(|run| efficiency(run) >= GOOD_EFFICIENCY)
+        .map(|run| run.threads)
+        .max()
+        .unwrap_or(baseline.threads);
+    writeln!(out, "#")?;
+    writeln!(out, "# Scaling stays at {:.0}% efficiency or better up to -t {}", 100.0 * GOOD_EFFICIENCY, threads)?;
+    Ok(())
+}
+
+/// Throughput and speedup of each run, then the time and survival of each
+/// stage. Speedups are against the same preset's run on the fewest threads.
+pub fn write_report(out: &mut dyn Write, cm: &Cm, workload: &Workload, runs: &[BenchRun]) -> io::Result<()> {
+    let megabases = workload.residues() as f64 / 1e6;
+    writeln!(out, "# improved-cmsearch bench")?;
+    writeln!(out, "# model:    {} ({} consensus positions)", cm.name, cm.consensus.length)?;
+    writeln!(out, "# workload: {} residues in {} targets, {} planted model sequences (seed {})",
+             workload.residues(), workload.targets.len(), workload.planted.len(), workload.seed)?;
+    writeln!(out, "#")?;
+    writeln!(out, "# {:<8} {:>7} {:>9} {:>9} {:>8} {:>6} {:>9}", "preset", "threads", "seconds", "Mb/s", "speedup", "hits", "found")?;
+    writeln!(out, "# {:-<8} {:->7} {:->9} {:->9} {:->8} {:->6} {:->9}", "", "", "", "", "", "", "")?;
+    for run in runs {
+        let baseline = runs.iter()
+            .filter(|other| other.preset == run.preset)
+            .min_by_key(|other| other.threads)
+            .map_or(run.seconds, |other| other.seconds);
+        writeln!(out, "  {:<8} {:>7} {:>9.2} {:>9.3} {:>8.2} {:>6} {:>9}",
+                 preset_name(run.preset), run.threads, run.seconds, megabases / run.seconds,
+                 baseline / run.seconds, run.hits, format!("{}/{}", run.found, workload.planted.len()))?;
+    }
+
+    writeln!(out, "#")?;
+    writeln!(out, "# Stage time is summed over threads; truncated passes are marked with 't'")?;
+    writeln!(out, "# {:<8} {:>7} {:<7} {:>10} {:>10} {:>9}", "preset", "threads", "stage", "entered", "passed", "seconds")?;
+    writeln!(out, "# {:-<8} {:->7} {:-<7} {:->10} {:->10} {:->9}", "", "", "", "", "", "")?;
+    for run in runs {
+        let standard = run.stages.iter().map(|stage| (stage, ""));
+        for (stage, mark) in standard.chain(run.trunc_stages.iter().map(|stage| (stage, "t"))) {
+            let name = format!("{}{}", stage.kind.name(), mark);
+            writeln!(out, "  {:<8} {:>7} {:<7} {:>10} {:>10} {:>9.3}",
+                     preset_name(run.preset), run.threads, name, stage.entered, stage.passed, stage.seconds)?;
+        }
+    }
+    Ok(())
+}
+
+fn preset_name(preset: Preset) -> String {
+    format!("{:?}", preset).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cm::Alphabet;
+
+    #[test]
+    fn test_workload_is_reproducible() {
+        let mut cm = Cm::new("test".to_string(), Alphabet::RNA);
+        cm.consensus.sequence = "GCGGAUUUAGCUCAGUUGGGAGAGCGCCAGACUGAAGAUCUGGAGG".to_string();
+        cm.consensus.length = cm.consensus.sequence.len();
+        let scores = CmScores::new(&cm);
+
+        let workload = Workload::generate(&scores, 10_000, 3, 4, 0.2, 7).unwrap();
+        assert_eq!(workload.residues(), 10_000);
+        assert_eq!(workload.targets.len(), 3);
+        assert_eq!(workload.planted.len(), 4);
+        let again = Workload::generate(&scores, 10_000, 3, 4, 0.2, 7).unwrap();
+        assert!(workload.targets.iter().zip(&again.targets).all(|(a, b)| a.sequence == b.sequence));
+        assert!(Workload::generate(&scores, 100, 1, 4, 0.2, 7).is_err());
+    }
+
+    #[test]
+    fn test_speedtest_threads() {
+        assert_eq!(speedtest_threads(1), vec![1]);
+        assert_eq!(speedtest_threads(8), vec![1, 2, 4, 8]);
+        assert_eq!(speedtest_threads(6), vec![1, 2, 4, 6]);
+    }
+}
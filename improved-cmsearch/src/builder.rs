@@ -0,0 +1,252 @@
+use rayon::ThreadPoolBuilder;
+use std::sync::Arc;
+use crate::cm::Cm;
+use crate::config::{Config, Preset, Strands};
+use crate::error::{Error, PipelineError, SeqFormatError};
+use crate::output::QueryResult;
+use crate::pipeline::{self, Pipeline, SearchModel};
+use crate::search::Sequence;
+use crate::seqfile::SequenceReader;
+use crate::utils;
+
+/// A search of models and targets already in memory, set up option by
+/// option rather than through the file paths of a command line [`Config`]:
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use improved_cmsearch::cm::Cm;
+/// # use improved_cmsearch::config::Config;
+/// # use improved_cmsearch::search::CmSearch;
+/// # use improved_cmsearch::seqfile;
+/// # fn main() -> Result<(), improved_cmsearch::error::Error> {
+/// let cm = Cm::from_file(Path::new("tRNA.cm"))?;
+/// let targets = seqfile::open(Path::new("genome.fa"), &Config::new())?;
+/// let results = CmSearch::builder().cm(cm).targets(targets).evalue(1e-5).threads(8).build()?.run()?;
+/// for hit in &results[0].hits {
+///     println!("{} {:.1} bits", hit.sequence_name, hit.score);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct SearchBuilder {
+    cms: Vec<Cm>,
+    targets: Option<SequenceReader>,
+    config: Config,
+}
+
+impl SearchBuilder {
+    pub fn new() -> Self {
+        Self::with_config(Config::new())
+    }
+
+    /// A builder starting from the options of `config`, which the setters
+    /// then change. The search reads and writes no files: the input paths,
+    /// the output files, --stream, --checkpoint, --cache, --dump-dp and
+    /// --coordinator are not used, and [`SearchBuilder::build`] fails on the
+    /// options that read targets or clans from files.
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            cms: Vec::new(),
+            targets: None,
+            config,
+        }
+    }
+
+    /// Adds a model to search for; the targets are read once for all of them.
+    pub fn cm(mut self, cm: Cm) -> Self {
+        self.cms.push(cm);
+        self
+    }
+
+    pub fn cms(mut self, cms: impl IntoIterator<Item = Cm>) -> Self {
+        self.cms.extend(cms);
+        self
+    }
+
+    /// The target sequences, such as a [`crate::seqfile::open`] reader.
+    pub fn targets<I>(mut self, targets: I) -> Self
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send + 'static,
+    {
+        self.targets = Some(Box::new(targets));
+        self
+    }
+
+    /// Reporting E-value threshold (-E).
+    pub fn evalue(mut self, evalue: f64) -> Self {
+        self.config.evalue = evalue;
+        self
+    }
+
+    /// Reporting bit score threshold (-T), in place of the E-value.
+    pub fn score(mut self, score: f64) -> Self {
+        self.config.score = Some(score);
+        self
+    }
+
+    /// Database size in Mb (-Z); without it the targets are read in first to
+    /// measure them.
+    pub fn z(mut self, mb: f64) -> Self {
+        self.config.z = Some(mb);
+        self
+    }
+
+    /// Threads of the search (0: every available CPU).
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.config.preset = preset;
+        self
+    }
+
+    pub fn strands(mut self, strands: Strands) -> Self {
+        self.config.strands = strands;
+        self
+    }
+
+    /// Whether hits are aligned to their model (on by default).
+    pub fn alignments(mut self, alignments: bool) -> Self {
+        self.config.alignments = alignments;
+        self
+    }
+
+    /// The search, once its options are valid and it has models and targets.
+    pub fn build(self) -> Result<Search, Error> {
+        if self.cms.is_empty() {
+            return Err(PipelineError::Config("A search needs at least one CM".to_string()).into());
+        }
+        let targets = self.targets.ok_or_else(|| PipelineError::Config("A search needs target sequences".to_string()))?;
+        let files = [("--clanin", &self.config.clanin), ("--seqlist", &self.config.seqlist),
+                     ("--seqexclude", &self.config.seqexclude), ("--resume", &self.config.resume)];
+        if let Some((option, _)) = files.iter().find(|(_, value)| value.is_some()) {
+            let message = format!("{} is not supported when the targets are passed in; select them before the search", option);
+            return Err(PipelineError::Config(message).into());
+        }
+
+        // The models and targets are in memory, not at the paths the
+        // command line options name, and the hits are returned rather than
+        // written anywhere
+        let names: Vec<&str> = self.cms.iter().map(|cm| cm.name.as_str()).collect();
+        let config = Config {
+            cmfile: names.join(","),
+            seqdb: "(targets)".to_string(),
+            threads: utils::resolve_threads(self.config.threads),
+            stream: None,
+            checkpoint: None,
+            cache: None,
+            dump_dp: None,
+            coordinator: None,
+            ..self.config
+        };
+        config.validate_options().map_err(PipelineError::Config)?;
+        for cm in &self.cms {
+            cm.validate()?;
+        }
+        let models = self.cms.into_iter().map(|cm| Arc::new(SearchModel::new(cm, &config))).collect();
+        Ok(Search {
+            config: Arc::new(config),
+            models,
+            targets,
+        })
+    }
+}
+
+impl Default for SearchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A search built by [`SearchBuilder`], ready to run.
+pub struct Search {
+    config: Arc<Config>,
+    models: Vec<Arc<SearchModel>>,
+    targets: SequenceReader,
+}
+
+impl Search {
+    /// Searches the targets for every model, and returns the reported hits
+    /// of each model, in the order the models were added. A search that
+    /// stops at --max-rss fails with [`PipelineError::ResourceLimit`].
+    pub fn run(self) -> Result<Vec<QueryResult>, PipelineError> {
+        let pool = ThreadPoolBuilder::new().num_threads(self.config.threads).build()
+            .map_err(|err| PipelineError::Other(err.into()))?;
+
+        // E-values scale with the database size: -Z, or else the targets
+        // are read in first to measure them
+        let (targets, residues) = match self.config.z {
+            Some(_) => (self.targets, 0),
+            None => {
+                let targets = self.targets.collect::<Result<Vec<_>, _>>()?;
+                let residues = targets.iter().map(|s| s.length as u64).sum();
+                (Box::new(targets.into_iter().map(Ok)) as SequenceReader, residues)
+            }
+        };
+        let db_residues = self.config.effective_db_residues(self.config.searched_residues(residues));
+        let pipelines = self.models.iter()
+            .map(|model| Pipeline::new(Arc::clone(model), Arc::clone(&self.config), db_residues))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let hits = pool.install(|| pipeline::search_batch(&pipelines, targets))?;
+        pipelines.iter().map(Pipeline::check_limit).fold(Ok(()), Result::and)?;
+        Ok(self.models.iter()
+            .zip(&pipelines)
+            .zip(hits)
+            .map(|((model, pipeline), hits)| QueryResult::new(&model.cm, hits, pipeline.summary()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+    use crate::pipeline::fixtures::{flank, planted, write_models, CONSENSUS};
+    use crate::search::{CmSearch, Strand};
+
+    #[test]
+    fn test_build_needs_a_model() {
+        let err = SearchBuilder::new().targets(std::iter::empty()).build().err().unwrap();
+        assert_eq!(err.to_string(), "A search needs at least one CM");
+    }
+
+    #[test]
+    fn test_run_reports_hits() {
+        // A model emitting its consensus, and a target holding it once
+        let path = std::env::temp_dir().join(format!("builder-test-{}.cm", std::process::id()));
+        write_models(&path, &[("tRNA", CONSENSUS)]);
+        let cm = Cm::from_file(Path::new(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let target = |name: &str, sequence: String| {
+            Sequence { name: name.to_string(), description: String::new(), length: sequence.len(), sequence }
+        };
+        let targets = vec![target("chr1", planted(CONSENSUS)), target("chr2", flank())];
+
+        let results = CmSearch::builder().cm(cm.clone()).targets(targets.clone().into_iter().map(Ok)).threads(2).build().unwrap().run().unwrap();
+        assert_eq!(results.len(), 1);
+        let hits = &results[0].hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].sequence_name.as_str(), hits[0].start, hits[0].end), ("chr1", 240, 286));
+        assert_eq!(hits[0].strand, Strand::Plus);
+        let alignment = hits[0].alignment.as_ref().unwrap();
+        assert_eq!((alignment.model_from, alignment.model_to), (1, 46));
+        assert_eq!(results[0].summary.targets, 2);
+
+        // Stopped at --max-rss, the search fails rather than return part of
+        // the hits
+        let config = Config { max_rss: Some(1e-3), ..Config::new() };
+        let search = SearchBuilder::with_config(config).cm(cm.clone()).targets(targets.clone().into_iter().map(Ok)).build().unwrap();
+        assert!(matches!(search.run(), Err(PipelineError::ResourceLimit { .. })));
+
+        // Options that read from files are refused rather than ignored
+        let config = Config { seqlist: Some("targets.txt".to_string()), ..Config::new() };
+        let err = SearchBuilder::with_config(config).cm(cm).targets(targets.into_iter().map(Ok)).build().err().unwrap();
+        assert_eq!(err.to_string(), "--seqlist is not supported when the targets are passed in; select them before the search");
+    }
+}
@@ -0,0 +1,216 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::config::Config;
+use crate::error::FileError;
+use crate::output::QueryResult;
+use crate::pipeline::{PipelineSummary, SearchModel};
+use crate::search::Hit;
+use crate::stage::HistogramBin;
+use crate::utils::{self, StableHasher};
+
+/// Results of completed searches (--cache): one file per search in a
+/// directory, named by a digest of the CM file, the target file, and the
+/// options that decide the hits. A search already in the cache is not run
+/// again; its outputs are written from the cached hits and statistics.
+pub struct ResultCache {
+    path: PathBuf, // Of this search's entry
+}
+
+// What a search's digest covers
+#[derive(Serialize)]
+struct CacheKey {
+    version: &'static str,
+    cm: u64,         // Digest of the CM file
+    targets: u64,    // Digest of the target file
+    lists: Vec<u64>, // Digests of the --seqlist and --seqexclude files
+    options: Config,
+    alignments: bool, // Whether the hits were aligned
+    residues: bool,   // Whether the hits kept their residues (--hitfasta)
+}
+
+// A cached search: the hits and statistics of each query model, in order
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    db_residues: u64,
+    queries: Vec<CachedQuery>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedQuery {
+    name: String,
+    hits: Vec<Hit>,
+    summary: PipelineSummary,
+    histograms: Vec<Vec<CachedBin>>, // Of each stage, then each truncated stage
+}
+
+// A histogram bin; the open end bins have no bound (JSON has no infinity)
+#[derive(Serialize, Deserialize)]
+struct CachedBin {
+    low: Option<f64>,
+    high: Option<f64>,
+    scored: usize,
+    passed: usize,
+}
+
+impl ResultCache {
+    /// The cache entry in `dir` of the search `config` describes; reads the
+    /// CM file, the target file, and any target lists to digest them.
+    pub fn open(dir: &str, config: &Config) -> Result<Self, FileError> {
+        let lists = [&config.seqlist, &config.seqexclude]
+            .into_iter()
+            .flatten()
+            .filter(|list| !list.starts_with("re:"))
+            .map(|list| digest_file(Path::new(list)))
+            .collect::<Result<_, _>>()?;
+        let key = CacheKey {
+            version: crate::VERSION,
+            cm: digest_file(Path::new(&config.cmfile))?,
+            targets: digest_file(&config.get_seqdb_path())?,
+            lists,
+            options: config.cache_options(),
+            alignments: config.needs_alignments(),
+            residues: config.hitfasta.is_some(),
+        };
+        let key = serde_json::to_vec(&key)
+            .map_err(io::Error::from)
+            .map_err(FileError::io(|| "Failed to digest the search for --cache".to_string()))?;
+        let digest = utils::stable_hash(&key);
+        Ok(Self { path: Path::new(dir).join(format!("{:016x}.json", digest)) })
+    }
+    
+    /// The results of the search of `models`, and the database size, if it
+    /// is in the cache. An unreadable entry is searched again and replaced.
+    pub fn load(&self, models: &[Arc<SearchModel>]) -> Result<Option<(Vec<QueryResult>, u64)>, FileError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let read = || -> io::Result<CacheEntry> {
+            let file = File::open(&self.path)?;
+            Ok(serde_json::from_reader(BufReader::new(file))?)
+        };
+        let entry = match read() {
+            Ok(entry) if entry.queries.iter().map(|q| &q.name).eq(models.iter().map(|model| &model.cm.name)) => entry,
+            Ok(_) => {
+                warn!("Cached results {} are of other models; searching again", self.path.display());
+                return Ok(None);
+            }
+            Err(err) => {
+                warn!("Failed to read cached results {}: {:#}; searching again", self.path.display(), err);
+                return Ok(None);
+            }
+        };
+        
+        info!("Reusing the results of an identical search from {}", self.path.display());
+        let queries = models.iter().zip(entry.queries).map(|(model, query)| {
+            let mut summary = query.summary;
+            let stages = summary.stages.iter_mut().chain(&mut summary.trunc_stages);
+            for (stage, bins) in stages.zip(query.histograms) {
+                stage.histogram = bins.into_iter().map(CachedBin::into_bin).collect();
+            }
+            QueryResult::new(&model.cm, query.hits, summary)
+        }).collect();
+        Ok(Some((queries, entry.db_residues)))
+    }
+    
+    /// Cache the results of the search. Written to a temporary file and
+    /// renamed, so that concurrent runs never read a partial entry.
+    pub fn store(&self, queries: &[QueryResult], db_residues: u64) -> Result<(), FileError> {
+        let entry = CacheEntry {
+            db_residues,
+            queries: queries.iter().map(|query| {
+                let summary = &query.summary;
+                CachedQuery {
+                    name: query.name.clone(),
+                    hits: query.hits.clone(),
+                    summary: summary.clone(),
+                    histograms: summary.stages.iter().chain(&summary.trunc_stages)
+                        .map(|stage| stage.histogram.iter().map(CachedBin::from_bin).collect())
+                        .collect(),
+                }
+            }).collect(),
+        };
+        
+        let temp = self.path.with_extension(format!("tmp{}", std::process::id()));
+        let write = || -> io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut out = BufWriter::new(File::create(&temp)?);
+            serde_json::to_writer(&mut out, &entry)?;
+            out.flush()?;
+            fs::rename(&temp, &self.path)?;
+            Ok(())
+        };
+        write().map_err(FileError::io(|| format!("Failed to cache results in {}", self.path.display())))?;
+        info!("Cached the results in {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl CachedBin {
+    fn from_bin(bin: &HistogramBin) -> Self {
+        Self {
+            low: Some(bin.low).filter(|low| low.is_finite()),
+            high: Some(bin.high).filter(|high| high.is_finite()),
+            scored: bin.scored,
+            passed: bin.passed,
+        }
+    }
+    
+    fn into_bin(self) -> HistogramBin {
+        HistogramBin {
+            low: self.low.unwrap_or(f64::NEG_INFINITY),
+            high: self.high.unwrap_or(f64::INFINITY),
+            scored: self.scored,
+            passed: self.passed,
+        }
+    }
+}
+
+// Digest of a file's bytes
+fn digest_file(path: &Path) -> Result<u64, FileError> {
+    let mut file = File::open(path).map_err(FileError::io(|| format!("Failed to open {} for --cache", path.display())))?;
+    let mut hasher = StableHasher::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buffer).map_err(FileError::io(|| format!("Failed to read {} for --cache", path.display())))?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_key_follows_files_and_options() {
+        let dir = std::env::temp_dir().join(format!("cmsearch-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cm, targets) = (dir.join("model.cm"), dir.join("targets.fa"));
+        fs::write(&cm, "INFERNAL1/a\nNAME  m\n//\n").unwrap();
+        fs::write(&targets, ">a\nACGU\n").unwrap();
+        let config = Config {
+            cmfile: cm.display().to_string(),
+            seqdb: targets.display().to_string(),
+            ..Config::new()
+        };
+        let key = |config: &Config| ResultCache::open("cache", config).unwrap().path;
+        
+        // Output files and threads leave the search the same
+        let same = Config { tblout: Some("hits.tbl".to_string()), threads: 4, ..config.clone() };
+        assert_eq!(key(&config), key(&same));
+        let stricter = Config { evalue: 0.01, ..config.clone() };
+        assert_ne!(key(&config), key(&stricter));
+        let before = key(&config);
+        fs::write(&targets, ">a\nACGG\n").unwrap();
+        assert_ne!(key(&config), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
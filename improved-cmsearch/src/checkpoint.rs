@@ -0,0 +1,224 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::error::FileError;
+use crate::retry;
+use crate::search::Hit;
+
+/// Progress of a search saved by --checkpoint: the targets searched to the
+/// end and their hits, before overlap resolution.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub cm_name: String,
+    pub seqdb: String,
+    pub db_residues: u64, // Z of the search, on the strands searched
+    #[serde(default)]
+    pub options: u64, // Config::options_digest of the search
+    pub targets: Vec<String>, // Names of the finished targets
+    pub residues: u64,        // Their residues, one strand
+    pub hits: Vec<Hit>,
+}
+
+/// Tracks which targets are finished as their chunks are searched, and
+/// saves a checkpoint at most every `interval`. Targets finish out of
+/// order, since their chunks are searched in parallel.
+pub struct CheckpointWriter {
+    path: String,
+    interval: Duration,
+    resumed: HashSet<String>, // Targets finished by earlier runs
+    progress: Mutex<Progress>,
+}
+
+#[derive(Default)]
+struct Progress {
+    checkpoint: Checkpoint,
+    pending: HashMap<String, PendingTarget>,
+    saved: Option<Instant>,
+}
+
+// A target whose chunks are still being read or searched
+#[derive(Default)]
+struct PendingTarget {
+    queued: usize,   // Chunks sent to the workers
+    searched: usize, // Chunks searched
+    read: bool,      // All of its chunks are queued
+    length: usize,
+    hits: Vec<Hit>,
+}
+
+impl CheckpointWriter {
+    /// Checkpoints to `path`, resuming from it if it exists. A checkpoint of
+    /// a different model, database, database size, or search options (by
+    /// their digest `options`) is an error rather than being overwritten.
+    pub fn open(path: &str, interval: Duration, cm_name: &str, seqdb: &str, db_residues: u64, options: u64) -> Result<Self, FileError> {
+        let checkpoint = if Path::new(path).exists() {
+            let file = File::open(path).map_err(FileError::io(|| format!("Failed to open checkpoint {}", path)))?;
+            let checkpoint: Checkpoint = serde_json::from_reader(BufReader::new(file))
+                .map_err(io::Error::from)
+                .map_err(FileError::io(|| format!("Failed to read checkpoint {}", path)))?;
+            if checkpoint.cm_name != cm_name || checkpoint.seqdb != seqdb || checkpoint.db_residues != db_residues {
+                return Err(FileError::invalid(format!("Checkpoint {} is of a search of {} against {} (Z = {}); remove it to start over",
+                                                      path, checkpoint.cm_name, checkpoint.seqdb, checkpoint.db_residues)));
+            }
+            if checkpoint.options != options {
+                return Err(FileError::invalid(format!("Checkpoint {} is of a search with other options; rerun with those or remove it to start over", path)));
+            }
+            info!("Resuming from checkpoint {}: {} targets ({} residues) already searched, {} hits",
+                  path, checkpoint.targets.len(), checkpoint.residues, checkpoint.hits.len());
+            checkpoint
+        } else {
+            Checkpoint {
+                cm_name: cm_name.to_string(),
+                seqdb: seqdb.to_string(),
+                db_residues,
+                options,
+                ..Checkpoint::default()
+            }
+        };
+
+        Ok(Self {
+            path: path.to_string(),
+            interval,
+            resumed: checkpoint.targets.iter().cloned().collect(),
+            progress: Mutex::new(Progress { checkpoint, ..Progress::default() }),
+        })
+    }
+
+    /// Whether an earlier run finished searching `target`.
+    pub fn is_resumed(&self, target: &str) -> bool {
+        self.resumed.contains(target)
+    }
+
+    /// Targets and residues (one strand) searched by earlier runs.
+    pub fn resumed_counts(&self) -> (usize, u64) {
+        let progress = self.lock();
+        (self.resumed.len(), progress.checkpoint.residues)
+    }
+
+    /// Hits of the targets searched by earlier runs.
+    pub fn resumed_hits(&self) -> Vec<Hit> {
+        let progress = self.lock();
+        progress.checkpoint.hits.iter()
+            .filter(|hit| self.resumed.contains(&hit.sequence_name))
+            .cloned()
+            .collect()
+    }
+
+    /// A chunk of `target` was queued for the workers.
+    pub fn chunk_queued(&self, target: &str) {
+        self.lock().pending.entry(target.to_string()).or_default().queued += 1;
+    }
+
+    /// Every chunk of `target`, of `length` residues, has been queued.
+    pub fn target_read(&self, target: &str, length: usize) -> Result<(), FileError> {
+        let mut progress = self.lock();
+        let pending = progress.pending.entry(target.to_string()).or_default();
+        pending.read = true;
+        pending.length = length;
+        self.update(&mut progress, target)
+    }
+
+    /// A chunk of `target` was searched, finding `hits`.
+    pub fn chunk_searched(&self, target: &str, hits: &[Hit]) -> Result<(), FileError> {
+        let mut progress = self.lock();
+        let pending = progress.pending.entry(target.to_string()).or_default();
+        pending.searched += 1;
+        pending.hits.extend_from_slice(hits);
+        self.update(&mut progress, target)
+    }
+
+    /// Save the targets finished so far, when the search stops early.
+    pub fn flush(&self) -> Result<(), FileError> {
+        let mut progress = self.lock();
+        progress.saved = Some(Instant::now());
+        self.save(&progress.checkpoint)
+    }
+
+    /// Remove the checkpoint once the search is complete.
+    pub fn finish(&self) -> Result<(), FileError> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).map_err(FileError::io(|| format!("Failed to remove checkpoint {}", self.path)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Moves `target` to the checkpoint if it is finished, and saves the
+    // checkpoint when it is due
+    fn update(&self, progress: &mut Progress, target: &str) -> Result<(), FileError> {
+        let finished = progress.pending.get(target).is_some_and(|p| p.read && p.searched == p.queued);
+        if !finished {
+            return Ok(());
+        }
+        if let Some(pending) = progress.pending.remove(target) {
+            progress.checkpoint.targets.push(target.to_string());
+            progress.checkpoint.residues += pending.length as u64;
+            progress.checkpoint.hits.extend(pending.hits);
+        }
+
+        if progress.saved.is_some_and(|saved| saved.elapsed() < self.interval) {
+            return Ok(());
+        }
+        progress.saved = Some(Instant::now());
+        self.save(&progress.checkpoint)
+    }
+
+    // Written to a temporary file and renamed, so a crash while saving
+    // leaves the previous checkpoint intact; transient failures are retried
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), FileError> {
+        let temp = format!("{}.tmp", self.path);
+        let write = || -> io::Result<()> {
+            let mut out = BufWriter::new(File::create(&temp)?);
+            serde_json::to_writer(&mut out, checkpoint)?;
+            out.flush()?;
+            out.into_inner()?.sync_all()?;
+            fs::rename(&temp, &self.path)?;
+            Ok(())
+        };
+        retry::with_retry(&format!("Writing checkpoint {}", self.path), write)
+            .map_err(FileError::io(|| format!("Failed to write checkpoint {}", self.path)))?;
+        info!("Checkpoint: {} targets searched", checkpoint.targets.len());
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Progress> {
+        self.progress.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_finish_when_all_chunks_are_searched() {
+        let path = std::env::temp_dir().join(format!("cmsearch-checkpoint-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let writer = CheckpointWriter::open(path, Duration::ZERO, "model", "db.fa", 100, 7).unwrap();
+
+        writer.chunk_queued("a");
+        writer.chunk_queued("a");
+        writer.chunk_searched("a", &[]).unwrap();
+        writer.target_read("a", 40).unwrap();
+        assert!(!Path::new(path).exists());
+        writer.chunk_searched("a", &[]).unwrap();
+        writer.target_read("b", 10).unwrap();
+
+        // A new run resumes after both
+        let resumed = CheckpointWriter::open(path, Duration::ZERO, "model", "db.fa", 100, 7).unwrap();
+        assert!(resumed.is_resumed("a") && resumed.is_resumed("b"));
+        assert_eq!(resumed.resumed_counts(), (2, 50));
+        assert!(CheckpointWriter::open(path, Duration::ZERO, "other", "db.fa", 100, 7).is_err());
+        let err = CheckpointWriter::open(path, Duration::ZERO, "model", "db.fa", 100, 8).err().unwrap();
+        assert!(err.to_string().contains("is of a search with other options"), "{}", err);
+
+        resumed.finish().unwrap();
+        assert!(!Path::new(path).exists());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use crate::error::FileError;
+use crate::output::QueryResult;
+use crate::search::Strand;
+
+// Sequence, strand, and clan of competing hits
+type ClanGroup<'a> = (&'a str, Strand, &'a str);
+
+// A hit, by the index of its query and its index among the query's hits
+type HitIndex = (usize, usize);
+
+/// Clan membership of the models, from an Rfam clanin file (--clanin): one
+/// clan per line, its name then the names of its member models, separated
+/// by whitespace.
+#[derive(Debug, Clone, Default)]
+pub struct Clans {
+    by_model: HashMap<String, String>, // Model name to clan name
+}
+
+impl Clans {
+    pub fn from_file(path: &str) -> Result<Self, FileError> {
+        let text = fs::read_to_string(path).map_err(FileError::io(|| format!("Failed to read clan file {}", path)))?;
+        Self::parse(&text).map_err(|message| FileError::invalid(format!("Invalid clan file {}: {}", path, message)))
+    }
+
+    /// Clans one per line; blank lines and `#` comments are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut by_model = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let Some(clan) = fields.next().filter(|field| !field.starts_with('#')) else {
+                continue;
+            };
+            for model in fields {
+                if let Some(other) = by_model.insert(model.to_string(), clan.to_string()) {
+                    return Err(format!("line {}: model {} is in clans {} and {}", i + 1, model, other, clan));
+                }
+            }
+        }
+        Ok(Self { by_model })
+    }
+
+    pub fn clan_of(&self, model: &str) -> Option<&str> {
+        self.by_model.get(model).map(String::as_str)
+    }
+
+    /// Clan competition: of the overlapping hits on one sequence and strand
+    /// from models of the same clan, only the most significant is kept.
+    /// Returns the number of hits removed.
+    pub fn compete(&self, queries: &mut [QueryResult]) -> usize {
+        // Hits of clan members by sequence, strand, and clan, best first
+        let mut groups: HashMap<ClanGroup, Vec<HitIndex>> = HashMap::new();
+        for (q, query) in queries.iter().enumerate() {
+            let Some(clan) = self.clan_of(&query.name) else {
+                continue;
+            };
+            for (h, hit) in query.hits.iter().enumerate() {
+                groups.entry((hit.sequence_name.as_str(), hit.strand, clan)).or_default().push((q, h));
+            }
+        }
+
+        let mut removed = HashSet::new();
+        for group in groups.values_mut() {
+            group.sort_by(|&(qa, ha), &(qb, hb)| {
+                let (a, b) = (&queries[qa].hits[ha], &queries[qb].hits[hb]);
+                a.evalue.total_cmp(&b.evalue).then(b.score.total_cmp(&a.score))
+            });
+            let mut kept: Vec<HitIndex> = Vec::new();
+            for &(q, h) in group.iter() {
+                let hit = &queries[q].hits[h];
+                let overlapped = kept.iter().any(|&(kq, kh)| {
+                    let better = &queries[kq].hits[kh];
+                    better.start < hit.end && hit.start < better.end
+                });
+                if overlapped {
+                    removed.insert((q, h));
+                } else {
+                    kept.push((q, h));
+                }
+            }
+        }
+
+        for (q, query) in queries.iter_mut().enumerate() {
+            let hits = std::mem::take(&mut query.hits);
+            query.hits = hits.into_iter()
+                .enumerate()
+                .filter(|(h, _)| !removed.contains(&(q, *h)))
+                .map(|(_, hit)| hit)
+                .collect();
+        }
+        removed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cm::{Alphabet, Cm};
+    use crate::overlap::Overlap;
+    use crate::pipeline::PipelineSummary;
+    use crate::search::{Hit, Truncation};
+
+    fn query(model: &str, hits: &[(usize, f64)]) -> QueryResult {
+        let hits = hits.iter().map(|&(start, evalue)| Hit {
+            id: format!("{}:{}", model, start),
+            sequence_name: "chr1".to_string(),
+            sequence_description: String::new(),
+            sequence_length: 1000,
+            start,
+            end: start + 80,
+            strand: Strand::Plus,
+            trunc: Truncation::None,
+            overlap: Overlap::None,
+            score: -evalue.log10(),
+            bias: 0.0,
+            gc: 0.5,
+            pvalue: evalue,
+            evalue,
+            alignment: None,
+            residues: None,
+        }).collect();
+        let summary = PipelineSummary { targets: 1, residues: 1000, ..PipelineSummary::default() };
+        QueryResult::new(&Cm::new(model.to_string(), Alphabet::RNA), hits, summary)
+    }
+
+    #[test]
+    fn test_compete_within_clans() {
+        let clans = Clans::parse("CL00001 tRNA tRNA-Sec\n").unwrap();
+        let mut queries = vec![
+            query("tRNA", &[(100, 1e-10), (500, 1e-3)]),
+            query("tRNA-Sec", &[(120, 1e-5), (520, 1e-8)]),
+            query("5S_rRNA", &[(110, 1e-2)]),
+        ];
+        assert_eq!(clans.compete(&mut queries), 2);
+        let starts = |query: &QueryResult| query.hits.iter().map(|hit| hit.start).collect::<Vec<_>>();
+        assert_eq!(starts(&queries[0]), vec![100]);
+        assert_eq!(starts(&queries[1]), vec![520]);
+        assert_eq!(starts(&queries[2]), vec![110]);
+    }
+
+    #[test]
+    fn test_parse_clans() {
+        let clans = Clans::parse("# Rfam clans\nCL00001\ttRNA\ttRNA-Sec\n\nCL00002 SRP_bact SRP_euk\n").unwrap();
+        assert_eq!(clans.clan_of("tRNA-Sec"), Some("CL00001"));
+        assert_eq!(clans.clan_of("SRP_euk"), Some("CL00002"));
+        assert_eq!(clans.clan_of("5S_rRNA"), None);
+        assert!(Clans::parse("CL00001 tRNA\nCL00002 tRNA\n").is_err());
+    }
+}
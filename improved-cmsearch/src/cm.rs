@@ -1,8 +1,10 @@
-use anyhow::{Result, Context};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::io::{self, Read};
 use std::path::Path;
-use log::{debug, info, warn};
+use log::info;
+use crate::config::STDIN_PATH;
+use crate::error::{CmParseError, CmParseErrorKind};
+use crate::retry::RetryingFile;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Alphabet {
@@ -79,14 +81,18 @@ pub struct Cm {
     pub description: Option<String>,
     pub alphabet: Alphabet,
     pub length: usize,
+    pub window_length: Option<usize>, // W: maximum expected hit length
     pub nodes: Vec<Node>,
     pub states: Vec<State>,
     pub consensus: Consensus,
     pub null_model: NullModel,
-    pub calibration_params: Option<CalibrationParams>,
+    pub calibration: Calibration,
     pub hmm_filter: Option<HmmFilter>,
 }
 
+/// Prior probability of the null2 and null3 bias models, Infernal's 1/2^16.
+pub const NULL_OMEGA: f64 = 1.0 / 65536.0;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NullModel {
     pub background_freqs: Vec<f64>,
@@ -95,12 +101,59 @@ pub struct NullModel {
     pub null3_omega: f64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Exponential tail fit for one search mode, from an `ECM*` line written by
+/// cmcalibrate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CalibrationParams {
     pub lambda: f64,
-    pub mu: f64,
-    pub eff_seqlen: f64,
-    pub nseqs: usize,
+    pub mu_extrap: f64,
+    pub mu_orig: f64,
+    pub dbsize: f64, // Residues in the calibration database
+    pub nhits: f64,  // Random hits observed in the calibration database
+    pub tailp: f64,  // Fraction of the score tail fitted
+}
+
+impl CalibrationParams {
+    fn parse(fields: &[&str]) -> Option<Self> {
+        let values = fields.iter()
+            .map(|f| f.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match values[..] {
+            [lambda, mu_extrap, mu_orig, dbsize, nhits, tailp] if lambda > 0.0 && dbsize > 0.0 => Some(Self {
+                lambda,
+                mu_extrap,
+                mu_orig,
+                dbsize,
+                nhits,
+                tailp,
+            }),
+            _ => None,
+        }
+    }
+    
+    /// P-value of a bit score under the fitted exponential tail.
+    pub fn pvalue(&self, score: f64) -> f64 {
+        (-self.lambda * (score - self.mu_extrap)).exp().min(1.0)
+    }
+    
+    /// Expected number of random hits when searching `db_residues` residues
+    /// (both strands), which scales P-values to E-values.
+    pub fn expected_hits(&self, db_residues: f64) -> f64 {
+        self.nhits * db_residues / self.dbsize
+    }
+
+}
+
+/// E-value calibrations of the model file (its ECM lines), which Infernal
+/// fits to its CYK and Inside scores. Only the Inside ones are used, for the
+/// final consensus score that stands in for Inside; the CYK ones are kept as
+/// read.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Calibration {
+    pub local_cyk: Option<CalibrationParams>,     // ECMLC
+    pub glocal_cyk: Option<CalibrationParams>,    // ECMGC
+    pub local_inside: Option<CalibrationParams>,  // ECMLI
+    pub glocal_inside: Option<CalibrationParams>, // ECMGI
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -117,6 +170,7 @@ impl Cm {
             description: None,
             alphabet,
             length: 0,
+            window_length: None,
             nodes: Vec::new(),
             states: Vec::new(),
             consensus: Consensus {
@@ -127,17 +181,31 @@ impl Cm {
             null_model: NullModel {
                 background_freqs: vec![0.25, 0.25, 0.25, 0.25], // Default for RNA
                 loop_prob: 0.5,
-                null2_omega: 0.000015258791, // 1/(2^16)
-                null3_omega: 0.000015258791,
+                null2_omega: NULL_OMEGA,
+                null3_omega: NULL_OMEGA,
             },
-            calibration_params: None,
+            calibration: Calibration::default(),
             hmm_filter: None,
         }
     }
     
-    pub fn from_file(path: &Path) -> Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let lines: Vec<&str> = content.lines().collect();
+    /// The first model of a CM file.
+    pub fn from_file(path: &Path) -> Result<Self, CmParseError> {
+        let content = read_cm_file(path)?;
+        let (first_line, text) = split_models(&content)[0];
+        Self::parse(text, path, first_line)
+    }
+    
+    /// Every model of a CM file, in file order; a file such as Rfam.cm
+    /// holds one per family.
+    pub fn all_from_file(path: &Path) -> Result<Vec<Self>, CmParseError> {
+        let content = read_cm_file(path)?;
+        split_models(&content).into_iter().map(|(first_line, text)| Self::parse(text, path, first_line)).collect()
+    }
+    
+    // One model's records, starting at line `first_line` of the file at
+    // `path`; both are for error messages
+    fn parse(content: &str, path: &Path, first_line: usize) -> Result<Self, CmParseError> {
         let mut cm = Self::new("".to_string(), Alphabet::RNA);
         let mut consensus_sequence = String::new();
         let mut consensus_structure = String::new();
@@ -146,13 +214,15 @@ impl Cm {
         let mut emission_params = Vec::new();
         let mut transition_params = Vec::new();
         
-        for line in lines {
+        for (i, line) in content.lines().enumerate() {
             if line.starts_with("NAME") {
                 cm.name = line.split_whitespace().nth(1).unwrap_or("unknown").to_string();
             } else if line.starts_with("ACC") {
                 cm.accession = Some(line.split_whitespace().nth(1).unwrap_or("").to_string());
             } else if line.starts_with("CLEN") {
                 cm.length = line.split_whitespace().nth(1).unwrap_or("0").parse().unwrap_or(0);
+            } else if line.starts_with("W ") {
+                cm.window_length = line.split_whitespace().nth(1).and_then(|w| w.parse().ok());
             } else if line.starts_with("ALPH") {
                 let alph = line.split_whitespace().nth(1).unwrap_or("RNA");
                 cm.alphabet = match alph {
@@ -161,9 +231,22 @@ impl Cm {
                     "Protein" => Alphabet::Protein,
                     _ => Alphabet::RNA,
                 };
+            } else if line.starts_with("ECM") {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                let params = CalibrationParams::parse(&parts[1..]).ok_or_else(|| {
+                    CmParseError::new(path.display().to_string(), Some(first_line + i), CmParseErrorKind::Calibration(line.to_string()))
+                })?;
+                let mode = match parts[0] {
+                    "ECMLC" => &mut cm.calibration.local_cyk,
+                    "ECMGC" => &mut cm.calibration.glocal_cyk,
+                    "ECMLI" => &mut cm.calibration.local_inside,
+                    "ECMGI" => &mut cm.calibration.glocal_inside,
+                    _ => continue,
+                };
+                *mode = Some(params);
             } else if line.starts_with("HMM") {
                 in_hmm_section = true;
-            } else if in_hmm_section && line.len() > 0 && line.chars().nth(0).unwrap_or(' ').is_ascii_digit() {
+            } else if in_hmm_section && line.as_bytes().first().is_some_and(u8::is_ascii_digit) {
                 // This is an HMM state line, extract consensus nucleotide and parameters
                 let parts: Vec<&str> = line.split_whitespace().collect();
                 if parts.len() >= 6 {
@@ -182,40 +265,41 @@ impl Cm {
                     
                     // If we didn't find a consensus nucleotide in the expected position,
                     // try to extract it from the emission scores by finding the maximum
-                    if consensus_sequence.len() <= state_count - 1 {
-                        if parts.len() >= 5 {
-                            let mut max_score = f64::NEG_INFINITY;
-                            let mut max_index = 0;
-                            
-                            for i in 1..=4 {
-                                if let Ok(score) = parts[i].parse::<f64>() {
-                                    if score > max_score {
-                                        max_score = score;
-                                        max_index = i;
-                                    }
+                    if consensus_sequence.len() < state_count && parts.len() >= 5 {
+                        let mut max_score = f64::NEG_INFINITY;
+                        let mut max_index = 0;
+                        
+                        for (i, part) in parts.iter().enumerate().take(5).skip(1) {
+                            if let Ok(score) = part.parse::<f64>() {
+                                if score > max_score {
+                                    max_score = score;
+                                    max_index = i;
                                 }
                             }
-                            
-                            // Convert index to nucleotide
-                            let nucleotide = match max_index {
-                                1 => 'A',
-                                2 => 'C', 
-                                3 => 'G',
-                                4 => 'U',
-                                _ => 'N',
-                            };
-                            
-                            if consensus_sequence.len() <= state_count - 1 {
-                                consensus_sequence.push(nucleotide);
-                            }
                         }
+                        
+                        // Convert index to nucleotide
+                        let nucleotide = match max_index {
+                            1 => 'A',
+                            2 => 'C', 
+                            3 => 'G',
+                            4 => 'U',
+                            _ => 'N',
+                        };
+                        
+                        consensus_sequence.push(nucleotide);
+                    }
+                    
+                    // HMMER3-style lines end with the consensus structure (CS) in WUSS
+                    if let Some(cs) = parts.last().filter(|p| p.len() == 1 && is_wuss(p.as_bytes()[0])) {
+                        consensus_structure.push_str(cs);
                     }
                     
                     // Extract emission parameters (positions 1-4 are usually emission scores)
                     if parts.len() >= 5 {
                         let mut emissions = Vec::new();
-                        for i in 1..=4 {
-                            if let Ok(score) = parts[i].parse::<f64>() {
+                        for part in &parts[1..=4] {
+                            if let Ok(score) = part.parse::<f64>() {
                                 emissions.push(score);
                             } else {
                                 emissions.push(0.0);
@@ -227,8 +311,8 @@ impl Cm {
                     // Extract transition parameters (positions after consensus are usually transitions)
                     if parts.len() >= 10 {
                         let mut transitions = Vec::new();
-                        for i in 6..parts.len() {
-                            if let Ok(score) = parts[i].parse::<f64>() {
+                        for part in &parts[6..] {
+                            if let Ok(score) = part.parse::<f64>() {
                                 transitions.push(score);
                             } else {
                                 transitions.push(0.0);
@@ -251,6 +335,11 @@ impl Cm {
             consensus_sequence = consensus_sequence[..cm.length].to_string();
         }
         
+        // Without a full CS annotation, treat every position as unpaired
+        if consensus_structure.len() != consensus_sequence.len() {
+            consensus_structure = ":".repeat(consensus_sequence.len());
+        }
+        
         cm.consensus = Consensus {
             sequence: consensus_sequence,
             structure: consensus_structure,
@@ -264,8 +353,8 @@ impl Cm {
         cm.null_model = NullModel {
             background_freqs: cm.calculate_background_frequencies(),
             loop_prob: 0.5,
-            null2_omega: 1e-5,
-            null3_omega: 1e-5,
+            null2_omega: NULL_OMEGA,
+            null3_omega: NULL_OMEGA,
         };
         
         info!("Loaded CM: {} (length: {}, consensus: {} bases, states: {})", 
@@ -276,8 +365,6 @@ impl Cm {
     
     fn create_nodes_from_parameters(&mut self, emission_params: &[Vec<f64>], _transition_params: &[Vec<f64>]) {
         // Create a simplified node structure for validation
-        let consensus_len = self.consensus.sequence.len();
-        
         // Add START node
         self.add_node(Node {
             id: 0,
@@ -292,13 +379,13 @@ impl Cm {
         // Add a few MATCH nodes to satisfy validation
         let num_nodes_to_create = std::cmp::min(emission_params.len(), 10); // Limit to first 10 for simplicity
         
-        for i in 0..num_nodes_to_create {
+        for (i, emissions) in emission_params.iter().enumerate().take(num_nodes_to_create) {
             let node_id = i + 1;
             let parent_id = if i == 0 { 0 } else { i };
             let left_child = if i < num_nodes_to_create - 1 { Some(node_id + 1) } else { Some(num_nodes_to_create + 1) };
             
             // Convert emission scores to probabilities
-            let match_emissions = self.convert_scores_to_probabilities(&emission_params[i]);
+            let match_emissions = self.convert_scores_to_probabilities(emissions);
             
             self.add_node(Node {
                 id: node_id,
@@ -355,7 +442,7 @@ impl Cm {
     
     fn calculate_background_frequencies(&self) -> Vec<f64> {
         // Calculate background frequencies from consensus sequence
-        let mut counts = vec![0; 4]; // A, C, G, U
+        let mut counts = [0; 4]; // A, C, G, U
         let mut total = 0;
         
         for c in self.consensus.sequence.chars() {
@@ -375,6 +462,12 @@ impl Cm {
         }
     }
     
+    /// Maximum hit length W: from the model file if given, otherwise the
+    /// consensus length plus room for insertions.
+    pub fn max_hit_length(&self) -> usize {
+        self.window_length.unwrap_or(self.length + self.length / 4).max(1)
+    }
+    
     pub fn add_node(&mut self, node: Node) {
         self.nodes.push(node);
     }
@@ -391,32 +484,23 @@ impl Cm {
         self.states.get(id)
     }
     
-    pub fn validate(&self) -> Result<()> {
+    /// Checks the model's structure; the error names the model.
+    pub fn validate(&self) -> Result<(), CmParseError> {
+        let invalid = |kind| Err(CmParseError::new(self.name.clone(), None, kind));
         if self.nodes.is_empty() {
-            return Err(anyhow::anyhow!("CM has no nodes"));
+            return invalid(CmParseErrorKind::NoNodes);
         }
         
         if self.consensus.length == 0 {
-            return Err(anyhow::anyhow!("CM has no consensus sequence"));
+            return invalid(CmParseErrorKind::NoConsensus);
         }
         
         // Check that all nodes have valid parent/child relationships
         for node in &self.nodes {
-            if let Some(parent_id) = node.parent {
-                if parent_id >= self.nodes.len() {
-                    return Err(anyhow::anyhow!("Node {} has invalid parent {}", node.id, parent_id));
-                }
-            }
-            
-            if let Some(left_child) = node.left_child {
-                if left_child >= self.nodes.len() {
-                    return Err(anyhow::anyhow!("Node {} has invalid left child {}", node.id, left_child));
-                }
-            }
-            
-            if let Some(right_child) = node.right_child {
-                if right_child >= self.nodes.len() {
-                    return Err(anyhow::anyhow!("Node {} has invalid right child {}", node.id, right_child));
+            let links = [("parent", node.parent), ("left child", node.left_child), ("right child", node.right_child)];
+            for (link, target) in links {
+                if let Some(target) = target.filter(|&target| target >= self.nodes.len()) {
+                    return invalid(CmParseErrorKind::InvalidLink { node: node.id, link, target });
                 }
             }
         }
@@ -460,8 +544,79 @@ impl Cm {
     }
 }
 
+// The text of a CM file, or of stdin for "-"
+fn read_cm_file(path: &Path) -> Result<String, CmParseError> {
+    let error = |kind| CmParseError::new(path.display().to_string(), None, kind);
+    let mut content = String::new();
+    if path == Path::new(STDIN_PATH) {
+        io::stdin().read_to_string(&mut content)
+            .map_err(|source| CmParseError::new("stdin", None, CmParseErrorKind::Read { offset: content.len() as u64, source }))?;
+    } else {
+        let mut file = RetryingFile::open(path).map_err(|err| error(CmParseErrorKind::Open(err)))?;
+        if let Err(source) = file.read_to_string(&mut content) {
+            return Err(error(CmParseErrorKind::Read { offset: file.offset(), source }));
+        }
+    }
+    Ok(content)
+}
+
+// The text of each model in a CM file, with the 1-based line it starts at.
+// A model starts at its INFERNAL header, and its HMM filter record follows
+// it; anything before the first header goes with the first model
+fn split_models(content: &str) -> Vec<(usize, &str)> {
+    let mut models = Vec::new();
+    let (mut start, mut start_line, mut offset, mut header) = (0, 1, 0, false);
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        if line.starts_with("INFERNAL") {
+            if header {
+                models.push((start_line, &content[start..offset]));
+                (start, start_line) = (offset, i + 1);
+            }
+            header = true;
+        }
+        offset += line.len();
+    }
+    models.push((start_line, &content[start..]));
+    models
+}
+
+fn is_wuss(c: u8) -> bool {
+    b"<>()[]{}:,_-~.".contains(&c)
+}
+
 impl Default for Cm {
     fn default() -> Self {
         Self::new("default_cm".to_string(), Alphabet::RNA)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_pvalue() {
+        let fields = ["0.5", "-5.0", "-10.0", "1000000", "100", "0.01"];
+        let params = CalibrationParams::parse(&fields).unwrap();
+        assert_eq!(params.mu_extrap, -5.0);
+        assert_eq!(params.pvalue(-6.0), 1.0);
+
+        assert_eq!(params.pvalue(-5.0), 1.0);
+        assert!((params.pvalue(5.0) - (-5.0f64).exp()).abs() < 1e-12);
+        assert!((params.expected_hits(1e6) - 100.0).abs() < 1e-9);
+        assert!((params.expected_hits(2e6) - 200.0).abs() < 1e-9);
+
+        assert!(CalibrationParams::parse(&fields[..5]).is_none());
+        assert!(CalibrationParams::parse(&["x"; 6]).is_none());
+    }
+
+    #[test]
+    fn test_split_models() {
+        let content = "INFERNAL1/a\nNAME  a\n//\nHMMER3/f\nNAME  a\n//\nINFERNAL1/a\nNAME  b\n//\n";
+        let models = split_models(content);
+        assert_eq!(models.len(), 2);
+        assert!(models[0].1.ends_with("HMMER3/f\nNAME  a\n//\n"));
+        assert_eq!(models[1], (7, "INFERNAL1/a\nNAME  b\n//\n"));
+        assert_eq!(split_models("NAME  a\n"), vec![(1, "NAME  a\n")]);
+    }
+}
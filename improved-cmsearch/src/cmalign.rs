@@ -0,0 +1,109 @@
+use log::{info, warn};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::path::Path;
+use std::sync::Arc;
+use crate::cm::Cm;
+use crate::config::Config;
+use crate::digital::{self, DigitizedSeq};
+use crate::error::{Error, PipelineError, SeqFormatError};
+use crate::memory::SearchMemory;
+use crate::output::{OutputWriter, QueryResult};
+use crate::overlap::Overlap;
+use crate::pipeline::{PipelineSummary, SearchModel};
+use crate::search::{Hit, Sequence, Strand, Truncation};
+use crate::seqfile;
+use crate::utils;
+
+/// The `align` command, as Infernal's cmalign: every sequence of the file
+/// aligned to the model, written as one multiple alignment.
+pub struct CmAlign {
+    config: Config,
+    model: SearchModel,
+    output_writer: OutputWriter,
+}
+
+impl CmAlign {
+    pub fn new(mut config: Config) -> Result<Self, Error> {
+        config.threads = utils::resolve_threads(config.threads);
+        config.validate().map_err(PipelineError::Config)?;
+        let mut cms = Cm::all_from_file(Path::new(&config.cmfile))?;
+        if cms.len() != 1 {
+            return Err(PipelineError::Config(format!("align takes a single model, but {} holds {}",
+                                                     config.cmfile, cms.len())).into());
+        }
+        let cm = cms.remove(0);
+        cm.validate()?;
+
+        let output_writer = OutputWriter::new(&config)?;
+        Ok(Self {
+            model: SearchModel::new(cm, &config),
+            config,
+            output_writer,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let sequences = seqfile::open(Path::new(&self.config.seqdb), &self.config)?
+            .collect::<Result<Vec<Sequence>, SeqFormatError>>()?;
+        info!("Aligning {} sequences to {}", sequences.len(), self.model.cm.name);
+
+        let (model, config) = (&self.model, &self.config);
+        let pool = ThreadPoolBuilder::new().num_threads(config.threads).build().map_err(PipelineError::other)?;
+        let memory = Arc::new(SearchMemory::new(None, (config.max_mx_size * 1024.0 * 1024.0) as usize));
+        let hits: Vec<Hit> = pool.install(|| sequences.par_iter().filter_map(|sequence| {
+            let _align = memory.enter();
+            align(model, config, sequence)
+        }).collect());
+        if hits.len() < sequences.len() {
+            warn!("{} of {} sequences could not be aligned", sequences.len() - hits.len(), sequences.len());
+        }
+        let summary = PipelineSummary {
+            targets: sequences.len(),
+            residues: sequences.iter().map(|sequence| sequence.length as u64).sum(),
+            stages: Vec::new(),
+            trunc_stages: Vec::new(),
+            memory_peak: memory.peak() as u64,
+        };
+        let query = QueryResult::new(&model.cm, hits, summary);
+        Ok(self.output_writer.write_aligned(&query)?)
+    }
+}
+
+// Truncated alignment of the whole sequence, as a plus strand hit whose
+// `trunc` is the ends of the model it does not reach
+fn align(model: &SearchModel, config: &Config, sequence: &Sequence) -> Option<Hit> {
+    if sequence.length == 0 {
+        warn!("Skipping {}: it has no residues", sequence.name);
+        return None;
+    }
+    let text = sequence.sequence.as_bytes();
+    let codes = DigitizedSeq::from_text(text);
+    let alignment = model.hmm_align(config, &sequence.name, &codes, text, true)?;
+    let trunc = match (alignment.model_from > 1, alignment.model_to < model.cm.length) {
+        (false, false) => Truncation::None,
+        (true, false) => Truncation::FivePrime,
+        (false, true) => Truncation::ThreePrime,
+        (true, true) => Truncation::Both,
+    };
+
+    Some(Hit {
+        id: Hit::stable_id(&model.cm.name, &sequence.name, 0, sequence.length, Strand::Plus),
+        sequence_name: sequence.name.clone(),
+        sequence_description: sequence.description.clone(),
+        sequence_length: sequence.length,
+        start: 0,
+        end: sequence.length,
+        strand: Strand::Plus,
+        trunc,
+        overlap: Overlap::None,
+        score: alignment.score,
+        bias: 0.0,
+        gc: digital::gc_fraction(text),
+        // Aligned sequences are given, not found, so have no significance
+        pvalue: f64::NAN,
+        evalue: f64::NAN,
+        alignment: Some(alignment),
+        residues: None,
+    })
+}
@@ -1,5 +1,281 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use crate::args::SearchArgs;
+use crate::search::{Hit, Strand};
+use crate::utils;
+
+/// Path argument that reads the CM or sequence database from standard input.
+pub const STDIN_PATH: &str = "-";
+
+/// Alphabet for sequences written by extraction/emission outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeqAlphabet {
+    Dna,
+    Rna,
+}
+
+/// Pipeline stages, in their canonical order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StageKind {
+    Ssv,
+    Viterbi,
+    Forward,
+    GlocalForward,
+    Envelope,
+    Consensus,
+    Score,
+}
+
+impl StageKind {
+    /// Filter stages run by default; final scoring always runs last.
+    pub const FILTERS: [StageKind; 6] = [
+        StageKind::Ssv,
+        StageKind::Viterbi,
+        StageKind::Forward,
+        StageKind::GlocalForward,
+        StageKind::Envelope,
+        StageKind::Consensus,
+    ];
+    
+    /// Whether this is one of the profile HMM filter stages run before the
+    /// consensus filter.
+    pub fn is_hmm(&self) -> bool {
+        !matches!(self, StageKind::Consensus | StageKind::Score)
+    }
+    
+    /// Short name, as accepted by --stages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StageKind::Ssv => "ssv",
+            StageKind::Viterbi => "vit",
+            StageKind::Forward => "fwd",
+            StageKind::GlocalForward => "gfwd",
+            StageKind::Envelope => "env",
+            StageKind::Consensus => "cons",
+            StageKind::Score => "score",
+        }
+    }
+}
+
+impl std::str::FromStr for StageKind {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ssv" => Ok(StageKind::Ssv),
+            "vit" | "viterbi" => Ok(StageKind::Viterbi),
+            "fwd" | "forward" => Ok(StageKind::Forward),
+            "gfwd" => Ok(StageKind::GlocalForward),
+            "env" | "envelope" => Ok(StageKind::Envelope),
+            "cons" | "consensus" => Ok(StageKind::Consensus),
+            "score" => Ok(StageKind::Score),
+            _ => Err(format!("unknown stage '{}' (expected ssv, vit, fwd, gfwd, env, cons)", s)),
+        }
+    }
+}
+
+/// Where truncated hits (running off the end of a sequence) are searched for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TruncMode {
+    Off,      // --notrunc
+    Termini,  // Default: only at sequence ends
+    Anywhere, // --anytrunc
+}
+
+/// Strands of the targets searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Strands {
+    Both,   // Default
+    Top,    // --toponly
+    Bottom, // --bottomonly
+}
+
+impl Strands {
+    pub fn includes(self, strand: Strand) -> bool {
+        match self {
+            Strands::Both => true,
+            Strands::Top => strand == Strand::Plus,
+            Strands::Bottom => strand == Strand::Minus,
+        }
+    }
+    
+    /// Number of strands searched, by which the residues of the targets
+    /// count toward the database size.
+    pub fn count(self) -> u64 {
+        match self {
+            Strands::Both => 2,
+            Strands::Top | Strands::Bottom => 1,
+        }
+    }
+    
+    /// How the database size describes the strands searched.
+    pub fn label(self) -> &'static str {
+        match self {
+            Strands::Both => "both strands",
+            Strands::Top => "top strand only",
+            Strands::Bottom => "bottom strand only",
+        }
+    }
+}
+
+/// Order of the reported hits in every output format (--sort).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortOrder {
+    Evalue,   // Default: most significant first (the canonical ordering)
+    Score,    // Best score first
+    Position, // By target name, then start coordinate
+    Target,   // By target name, then most significant first
+}
+
+impl SortOrder {
+    /// Whether hits are ordered best first, so that those within the
+    /// inclusion threshold come before the rest.
+    pub fn by_significance(self) -> bool {
+        matches!(self, SortOrder::Evalue | SortOrder::Score)
+    }
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "evalue" => Ok(SortOrder::Evalue),
+            "score" => Ok(SortOrder::Score),
+            "position" => Ok(SortOrder::Position),
+            "target" => Ok(SortOrder::Target),
+            _ => Err(format!("unknown sort order '{}' (expected evalue, score, position, target)", s)),
+        }
+    }
+}
+
+/// Format of the -A alignment of the hits (--outformat).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MsaFormat {
+    Stockholm, // Default: Pfam-style, one block, with PP, SS_cons and RF
+    A2m,       // Match columns uppercase or '-', inserts lowercase and unpadded
+    Afa,       // Aligned FASTA: every row padded to the same width
+}
+
+impl std::str::FromStr for MsaFormat {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stockholm" | "pfam" => Ok(MsaFormat::Stockholm),
+            "a2m" => Ok(MsaFormat::A2m),
+            "afa" => Ok(MsaFormat::Afa),
+            _ => Err(format!("unknown alignment format '{}' (expected stockholm, a2m, afa)", s)),
+        }
+    }
+}
+
+/// What to do with target sequences that share a name, which would make
+/// their hits ambiguous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DupNames {
+    Error,  // Default: stop at the first duplicate
+    Suffix, // Rename later copies name.1, name.2, ...
+    Dedup,  // Drop later copies with identical residues
+}
+
+impl std::str::FromStr for DupNames {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(DupNames::Error),
+            "suffix" => Ok(DupNames::Suffix),
+            "dedup" => Ok(DupNames::Dedup),
+            _ => Err(format!("unknown duplicate name mode '{}' (expected error, suffix, dedup)", s)),
+        }
+    }
+}
+
+/// One-flag sensitivity presets, from most sensitive (Max) to fastest (Rfam).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Preset {
+    Max,
+    NoHmm,
+    Mid,
+    Default,
+    Rfam,
+}
+
+impl std::str::FromStr for Preset {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "max" => Ok(Preset::Max),
+            "nohmm" => Ok(Preset::NoHmm),
+            "mid" => Ok(Preset::Mid),
+            "default" => Ok(Preset::Default),
+            "rfam" => Ok(Preset::Rfam),
+            _ => Err(format!("unknown preset '{}' (expected max, nohmm, mid, default, rfam)", s)),
+        }
+    }
+}
+
+/// Filter survival thresholds (P-values) for the six filter stages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterThresholds {
+    pub f1: f64,  // SSV
+    pub f2: f64,  // Viterbi
+    pub f3: f64,  // local Forward
+    pub f4: f64,  // glocal Forward
+    pub f4b: f64, // glocal Forward after bias correction
+    pub f5: f64,  // envelope
+    pub f6: f64,  // consensus filter
+}
+
+// Default thresholds by database size (residues, both strands): smaller
+// databases can afford looser filters. The values are Infernal's (F6 its
+// CYK threshold), applied here to the 2^-s bound on each stage's P-value,
+// so the pass rates are not expected to match Infernal's
+const SIZE_TIERS: [(f64, FilterThresholds); 5] = [
+    (2e6, FilterThresholds { f1: 0.35, f2: 0.15, f3: 0.02, f4: 0.02, f4b: 0.02, f5: 0.02, f6: 1e-4 }),
+    (2e7, FilterThresholds { f1: 0.35, f2: 0.15, f3: 0.003, f4: 0.003, f4b: 0.003, f5: 0.003, f6: 1e-4 }),
+    (2e8, FilterThresholds { f1: 0.30, f2: 0.15, f3: 0.002, f4: 0.002, f4b: 0.002, f5: 0.002, f6: 1e-4 }),
+    (2e9, FilterThresholds { f1: 0.15, f2: 0.15, f3: 2e-4, f4: 2e-4, f4b: 2e-4, f5: 2e-4, f6: 1e-4 }),
+    (f64::INFINITY, FilterThresholds { f1: 0.06, f2: 0.02, f3: 2e-4, f4: 2e-4, f4b: 2e-4, f5: 2e-4, f6: 1e-4 }),
+];
+
+impl Preset {
+    /// Default thresholds of the preset for a database of `db_residues`
+    /// residues (both strands).
+    pub fn thresholds(&self, db_residues: u64) -> FilterThresholds {
+        let sized = SIZE_TIERS.iter()
+            .find(|(limit, _)| (db_residues as f64) < *limit)
+            .map_or(SIZE_TIERS[SIZE_TIERS.len() - 1].1, |(_, t)| *t);
+        
+        match self {
+            // Looser HMM thresholds make up for skipping SSV and Viterbi
+            Preset::Mid => FilterThresholds { f3: 0.02, f4: 0.02, f4b: 0.02, f5: 0.002, ..sized },
+            // Fixed strict thresholds, tuned for very large databases
+            Preset::Rfam => FilterThresholds {
+                f1: 0.02,
+                f2: 0.001,
+                f3: 1e-5,
+                f4: 0.0005,
+                f4b: 0.0005,
+                f5: 0.00005,
+                f6: 1e-4,
+            },
+            Preset::Max | Preset::NoHmm | Preset::Default => sized,
+        }
+    }
+    
+    /// Whether a configured filter stage runs under this preset.
+    pub fn keeps(&self, stage: StageKind) -> bool {
+        match self {
+            Preset::Max => false,
+            Preset::NoHmm => !stage.is_hmm(),
+            Preset::Mid => !matches!(stage, StageKind::Ssv | StageKind::Viterbi),
+            Preset::Default | Preset::Rfam => true,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -8,13 +284,65 @@ pub struct Config {
     pub output: Option<String>,
     pub evalue: f64,
     pub score: Option<f64>,
-    pub alignments: bool,
-    pub tabular: bool,
-    pub hmm_filter: bool,
+    pub inc_evalue: f64, // --incE: E-value threshold for including a reported hit
+    pub inc_score: Option<f64>, // --incT: score threshold for inclusion, in place of --incE
+    pub z: Option<f64>, // Effective database size in Mb (strands searched), overriding the actual size
+    pub alignments: bool, // Hit alignments in the report and --json; off with --noali
+    pub textw: Option<usize>, // Report line width; None with --notextw
+    pub acc: bool, // Optimal accuracy alignments instead of Viterbi
+    pub tblout: Option<String>, // Infernal tabular hit table file
+    pub fmt: u8, // --tblout format: 1, or 2 with overlap annotation
+    pub json: Option<String>, // JSON Lines results file
+    pub sam: Option<String>, // SAM file of hit alignments
+    pub msa: Option<String>, // -A: multiple alignment of the hits
+    pub msa_format: MsaFormat, // Format of the -A alignment
+    pub hitfasta: Option<String>, // FASTA file of the hit residues
+    pub sort: SortOrder, // Order of the reported hits
+    pub stats_json: Option<String>, // JSON file of the pipeline statistics
+    pub stream: Option<String>, // JSON Lines file of hits as they are found
+    pub gff: Option<String>, // GFF3 file of the hits
+    pub histogram: Option<String>, // Score histograms of the stages and hits
+    pub hmm_filter: bool, // Run the HMM filter stages
+    pub f32_filters: bool, // Single precision DP in the HMM filter stages
     pub max_mx_size: f64,
-    pub trunc: bool,
-    pub passes: usize,
-    pub threads: usize,
+    pub low_mem: Option<f64>, // Peak memory budget in MB
+    pub max_rss: Option<f64>, // Resident memory at which the search stops, in MB
+    pub trunc: TruncMode,
+    pub strands: Strands, // --toponly or --bottomonly search one strand
+    pub passes: usize, // 1: standard; 2: + 5'/3' truncated; 3: + 5'&3' truncated
+    pub threads: usize, // 0: every available CPU
+    // Filter P-value thresholds; None uses the preset default for the
+    // database size
+    pub f1: Option<f64>,
+    pub f2: Option<f64>,
+    pub f3: Option<f64>,
+    pub f4: Option<f64>,
+    pub f4b: Option<f64>,
+    pub f5: Option<f64>,
+    pub f6: Option<f64>,
+    pub nonull3: bool,
+    pub mask_quality: Option<u8>, // Mask FASTQ residues below this Phred quality
+    pub mmap: bool, // Memory-map the sequence database instead of reading it
+    pub resume: Option<String>, // Search only the records after this one
+    pub checkpoint: Option<String>, // Progress file to save to and resume from
+    pub checkpoint_interval: u64, // Seconds between checkpoint saves
+    pub coordinator: Option<String>, // Address to serve chunks to remote workers on
+    pub seqlist: Option<String>, // Search only targets named in this file, or matching re:<REGEX>
+    pub seqexclude: Option<String>, // Skip targets named in this file, or matching re:<REGEX>
+    pub strict_seqs: bool, // Fail on residues that are not IUPAC nucleotide codes
+    pub dup_names: DupNames,
+    pub skip_masked: bool, // Exclude soft-masked (lowercase) residues
+    pub mask_char: Option<char>, // Exclude residues written as this character
+    pub min_n_run: usize, // Skip runs of at least this many ambiguous residues
+    pub out_alphabet: Option<SeqAlphabet>, // None keeps residues as read
+    pub stages: Vec<StageKind>, // Filter stages in run order
+    pub preset: Preset,
+    pub keep_overlaps: bool, // Report overlapped hits (marked) instead of removing them
+    pub dump_dp: Option<String>, // Directory for debug DP matrix dumps
+    pub cache: Option<String>, // Directory of the results of completed searches
+    pub scan: bool, // cmscan mode: each target sequence reported with the hits of every model
+    pub clanin: Option<String>, // Rfam clan membership file, for clan competition in scans
+    pub keep_clan_overlaps: bool, // Skip clan competition, keeping overlapping hits of a clan
 }
 
 impl Config {
@@ -25,17 +353,249 @@ impl Config {
             output: None,
             evalue: 10.0,
             score: None,
-            alignments: false,
-            tabular: false,
-            hmm_filter: false,
+            inc_evalue: 0.01,
+            inc_score: None,
+            z: None,
+            alignments: true,
+            textw: Some(120),
+            acc: false,
+            tblout: None,
+            fmt: 1,
+            json: None,
+            sam: None,
+            msa: None,
+            msa_format: MsaFormat::Stockholm,
+            hitfasta: None,
+            sort: SortOrder::Evalue,
+            stats_json: None,
+            stream: None,
+            gff: None,
+            histogram: None,
+            hmm_filter: true,
+            f32_filters: false,
             max_mx_size: 1024.0,
-            trunc: false,
+            low_mem: None,
+            max_rss: None,
+            trunc: TruncMode::Termini,
+            strands: Strands::Both,
             passes: 3,
             threads: 1,
+            f1: None,
+            f2: None,
+            f3: None,
+            f4: None,
+            f4b: None,
+            f5: None,
+            f6: None,
+            nonull3: false,
+            mask_quality: None,
+            mmap: false,
+            resume: None,
+            checkpoint: None,
+            checkpoint_interval: 300,
+            coordinator: None,
+            seqlist: None,
+            seqexclude: None,
+            strict_seqs: false,
+            dup_names: DupNames::Error,
+            skip_masked: false,
+            mask_char: None,
+            min_n_run: 1000,
+            out_alphabet: None,
+            stages: StageKind::FILTERS.to_vec(),
+            preset: Preset::Default,
+            keep_overlaps: false,
+            dump_dp: None,
+            cache: None,
+            scan: false,
+            clanin: None,
+            keep_clan_overlaps: false,
         }
     }
     
+    /// The search of a `search` command line run on `threads` threads; the
+    /// caller acts on its --dry-run.
+    pub fn from_args(args: SearchArgs, threads: usize) -> Self {
+        let SearchArgs {
+            cmfile,
+            seqdb,
+            output,
+            evalue,
+            score,
+            inc_evalue,
+            inc_score,
+            z,
+            noali,
+            textw,
+            notextw,
+            acc,
+            tblout,
+            fmt,
+            json,
+            sam,
+            msa,
+            outformat,
+            hitfasta,
+            sort,
+            stats_json,
+            stream,
+            gff,
+            histogram,
+            hmm_filter,
+            f32_filters,
+            max_mx_size,
+            low_mem,
+            max_rss,
+            notrunc,
+            anytrunc,
+            toponly,
+            bottomonly,
+            passes,
+            f1,
+            f2,
+            f3,
+            f4,
+            f4b,
+            f5,
+            f6,
+            nonull3,
+            mask_quality,
+            mmap,
+            resume,
+            checkpoint,
+            checkpoint_interval,
+            coordinator,
+            cache,
+            seqlist,
+            seqexclude,
+            strict_seqs,
+            dup_names,
+            skip_masked,
+            mask_char,
+            min_n_run,
+            dna_out,
+            rna_out,
+            stages,
+            max,
+            nohmm,
+            mid,
+            default: _,
+            rfam,
+            keep_overlaps,
+            dump_dp,
+            dry_run: _,
+        } = args;
+        let out_alphabet = if dna_out {
+            Some(SeqAlphabet::Dna)
+        } else if rna_out {
+            Some(SeqAlphabet::Rna)
+        } else {
+            None
+        };
+        
+        let preset = if max {
+            Preset::Max
+        } else if nohmm {
+            Preset::NoHmm
+        } else if mid {
+            Preset::Mid
+        } else if rfam {
+            Preset::Rfam
+        } else {
+            Preset::Default
+        };
+        
+        // --low-mem: half the budget for the DP matrices of the threads
+        let max_mx_size = low_mem.map_or(max_mx_size, |budget| max_mx_size.min(budget / (2 * utils::resolve_threads(threads)) as f64));
+        let f32_filters = f32_filters || low_mem.is_some();
+        
+        let trunc = if notrunc {
+            TruncMode::Off
+        } else if anytrunc {
+            TruncMode::Anywhere
+        } else {
+            TruncMode::Termini
+        };
+        let strands = match (toponly, bottomonly) {
+            (true, _) => Strands::Top,
+            (_, true) => Strands::Bottom,
+            _ => Strands::Both,
+        };
+        
+        Config {
+            cmfile,
+            seqdb,
+            output,
+            evalue,
+            score,
+            inc_evalue,
+            inc_score,
+            z,
+            alignments: !noali,
+            textw: (!notextw).then_some(textw as usize),
+            acc,
+            tblout,
+            fmt,
+            json,
+            sam,
+            msa,
+            msa_format: outformat,
+            hitfasta,
+            sort,
+            stats_json,
+            stream,
+            gff,
+            histogram,
+            hmm_filter,
+            f32_filters,
+            max_mx_size,
+            low_mem,
+            max_rss,
+            trunc,
+            strands,
+            passes: passes as usize,
+            threads,
+            f1,
+            f2,
+            f3,
+            f4,
+            f4b,
+            f5,
+            f6,
+            nonull3,
+            mask_quality,
+            mmap,
+            resume,
+            checkpoint,
+            checkpoint_interval,
+            coordinator,
+            cache,
+            seqlist,
+            seqexclude,
+            strict_seqs,
+            dup_names,
+            skip_masked,
+            mask_char,
+            min_n_run,
+            out_alphabet,
+            stages,
+            preset,
+            keep_overlaps,
+            dump_dp,
+            scan: false,
+            clanin: None,
+            keep_clan_overlaps: false,
+        }
+    }
+    
+    /// Checks a command line search: its input files, then its options.
     pub fn validate(&self) -> Result<(), String> {
+        self.validate_paths()?;
+        self.validate_options()
+    }
+    
+    // The CM file and sequence database, and the options that read them
+    fn validate_paths(&self) -> Result<(), String> {
         if self.cmfile.is_empty() {
             return Err("CM file path is required".to_string());
         }
@@ -44,27 +604,225 @@ impl Config {
             return Err("Sequence database path is required".to_string());
         }
         
-        if self.evalue <= 0.0 {
+        if self.cmfile == STDIN_PATH && self.seqdb == STDIN_PATH {
+            return Err("Only one of the CM file and the sequence database can be read from stdin".to_string());
+        }
+        
+        if self.cache.is_some() && (self.cmfile == STDIN_PATH || self.seqdb == STDIN_PATH) {
+            return Err("--cache identifies a search by its CM and target files, so neither can be read from stdin".to_string());
+        }
+        
+        if self.mmap && self.seqdb == STDIN_PATH {
+            return Err("--mmap needs a sequence database file, not stdin".to_string());
+        }
+        
+        // Checked before stdin is read, which would wait for all of it
+        if self.low_mem.is_some() && self.z.is_none() && self.seqdb == STDIN_PATH {
+            return Err("--low-mem reads the targets twice, to measure them and then to search them; give -Z to search stdin".to_string());
+        }
+        
+        Ok(())
+    }
+    
+    /// Checks the search options alone, for models and targets that are
+    /// not read from the file paths.
+    pub fn validate_options(&self) -> Result<(), String> {
+        if !positive(self.evalue) {
             return Err("E-value must be positive".to_string());
         }
         
-        if self.max_mx_size <= 0.0 {
+        if !positive(self.inc_evalue) {
+            return Err("Inclusion E-value (--incE) must be positive".to_string());
+        }
+        
+        if self.inc_score.is_some_and(|score| !score.is_finite()) {
+            return Err("Inclusion score (--incT) must be a finite number".to_string());
+        }
+        
+        if self.z.is_some_and(|z| !positive(z)) {
+            return Err("Database size (-Z) must be positive".to_string());
+        }
+        
+        if !positive(self.max_mx_size) {
             return Err("Maximum matrix size must be positive".to_string());
         }
         
-        if self.passes == 0 {
-            return Err("Number of passes must be at least 1".to_string());
+        if self.low_mem.is_some_and(|budget| !positive(budget)) {
+            return Err("Memory budget (--low-mem) must be positive".to_string());
+        }
+        
+        if self.max_rss.is_some_and(|budget| !positive(budget)) {
+            return Err("Memory limit (--max-rss) must be positive".to_string());
+        }
+        
+        if self.fmt == 0 || self.fmt > 2 {
+            return Err("Hit table format (--fmt) must be 1 or 2".to_string());
+        }
+        
+        if self.passes == 0 || self.passes > 3 {
+            return Err("Number of passes must be between 1 and 3".to_string());
+        }
+        
+        if self.min_n_run == 0 {
+            return Err("Minimum N run length must be at least 1".to_string());
+        }
+        
+        if self.mask_char.is_some_and(|c| !c.is_ascii_graphic()) {
+            return Err("Mask character must be a printable ASCII character".to_string());
+        }
+        
+        for (i, stage) in self.stages.iter().enumerate() {
+            if *stage == StageKind::Score {
+                return Err("Final scoring always runs last and cannot be listed as a filter stage".to_string());
+            }
+            if self.stages[..i].contains(stage) {
+                return Err(format!("Stage {:?} is listed more than once", stage));
+            }
         }
         
-        if self.threads == 0 {
-            return Err("Number of threads must be at least 1".to_string());
+        let thresholds = [
+            ("F1", self.f1),
+            ("F2", self.f2),
+            ("F3", self.f3),
+            ("F4", self.f4),
+            ("F4b", self.f4b),
+            ("F5", self.f5),
+            ("F6", self.f6),
+        ];
+        for (name, value) in thresholds.into_iter().filter_map(|(n, v)| Some((n, v?))) {
+            if !(value > 0.0 && value <= 1.0) {
+                return Err(format!("--{} P-value threshold must be in (0, 1]", name));
+            }
         }
         
         Ok(())
     }
     
+    /// Whether a reported hit is included ('!'), within --incT if set and
+    /// otherwise --incE, rather than marginal ('?').
+    pub fn includes(&self, hit: &Hit) -> bool {
+        match self.inc_score {
+            Some(threshold) => hit.score >= threshold,
+            None => hit.evalue <= self.inc_evalue,
+        }
+    }
+    
+    /// Whether hits are aligned: for the report (unless --noali), for the
+    /// model coordinates of the tabular hit table and GFF, and for SAM and
+    /// Stockholm output.
+    pub fn needs_alignments(&self) -> bool {
+        self.alignments || self.tblout.is_some() || self.gff.is_some() || self.sam.is_some() || self.msa.is_some()
+    }
+    
+    /// The options a --coordinator sends its workers: the same search, with
+    /// the files only the coordinator writes left out.
+    pub fn for_worker(&self) -> Self {
+        Self {
+            stream: None,
+            checkpoint: None,
+            coordinator: None,
+            dump_dp: None,
+            ..self.clone()
+        }
+    }
+    
+    /// The options that decide the hits of a search, for --cache: without the
+    /// input and output paths, and the settings that only change how the
+    /// search runs.
+    pub fn cache_options(&self) -> Self {
+        Self {
+            cmfile: String::new(),
+            seqdb: String::new(),
+            output: None,
+            tblout: None,
+            json: None,
+            sam: None,
+            msa: None,
+            hitfasta: None,
+            stats_json: None,
+            stream: None,
+            gff: None,
+            histogram: None,
+            low_mem: None,
+            max_rss: None,
+            threads: 1,
+            mmap: false,
+            checkpoint: None,
+            checkpoint_interval: 0,
+            coordinator: None,
+            dump_dp: None,
+            cache: None,
+            ..self.clone()
+        }
+    }
+    
+    /// Digest of the options that decide the hits, with whether they are
+    /// aligned and keep their residues, for --checkpoint: a search is only
+    /// resumed with the options it was started with.
+    pub fn options_digest(&self) -> u64 {
+        let options = (self.cache_options(), self.needs_alignments(), self.hitfasta.is_some());
+        utils::stable_hash(&serde_json::to_vec(&options).expect("options serialize to JSON"))
+    }
+    
+    /// Whether residue masking (--skip-masked or --mask-char) is on.
+    pub fn masks_residues(&self) -> bool {
+        self.skip_masked || self.mask_char.is_some()
+    }
+    
+    /// Whether `residue` is masked: lowercase with --skip-masked, or the
+    /// --mask-char character in either case.
+    pub fn is_masked(&self, residue: u8) -> bool {
+        (self.skip_masked && residue.is_ascii_lowercase())
+            || self.mask_char.is_some_and(|c| residue.eq_ignore_ascii_case(&(c as u8)))
+    }
+    
+    /// Whether truncated passes run at all: they need truncation on and at
+    /// least two passes.
+    pub fn truncated_passes(&self) -> bool {
+        self.trunc != TruncMode::Off && self.passes >= 2
+    }
+    
+    /// Configured filter stages that the sensitivity preset and the HMM
+    /// filter switch leave on.
+    pub fn active_stages(&self) -> Vec<StageKind> {
+        self.stages
+            .iter()
+            .copied()
+            .filter(|&stage| self.preset.keeps(stage))
+            .filter(|stage| self.hmm_filter || !stage.is_hmm())
+            .collect()
+    }
+    
+    /// Filter thresholds for a database of `db_residues` residues (both
+    /// strands): explicit options override the preset defaults.
+    pub fn filter_thresholds(&self, db_residues: u64) -> FilterThresholds {
+        let defaults = self.preset.thresholds(db_residues);
+        FilterThresholds {
+            f1: self.f1.unwrap_or(defaults.f1),
+            f2: self.f2.unwrap_or(defaults.f2),
+            f3: self.f3.unwrap_or(defaults.f3),
+            f4: self.f4.unwrap_or(defaults.f4),
+            f4b: self.f4b.unwrap_or(defaults.f4b),
+            f5: self.f5.unwrap_or(defaults.f5),
+            f6: self.f6.unwrap_or(defaults.f6),
+        }
+    }
+    
+    /// Residues searched in `residues` of targets (one strand): on each
+    /// strand searched.
+    pub fn searched_residues(&self, residues: u64) -> u64 {
+        self.strands.count() * residues
+    }
+    
+    /// Residues (on the strands searched) that E-values and filter
+    /// thresholds assume: the -Z size if given, otherwise the residues
+    /// actually searched.
+    pub fn effective_db_residues(&self, searched: u64) -> u64 {
+        self.z.map_or(searched, |mb| (mb * 1e6).round() as u64)
+    }
+    
     pub fn get_output_path(&self) -> Option<PathBuf> {
-        self.output.as_ref().map(|s| PathBuf::from(s))
+        self.output.as_ref().map(PathBuf::from)
     }
     
     pub fn get_cm_path(&self) -> PathBuf {
@@ -76,8 +834,14 @@ impl Config {
     }
 }
 
+// Whether `x` is a positive number; false for NaN, which a `<= 0.0` check
+// would let through
+fn positive(x: f64) -> bool {
+    x > 0.0
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+} 
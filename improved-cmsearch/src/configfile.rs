@@ -0,0 +1,218 @@
+use anyhow::{bail, Context, Result};
+use clap::Command;
+use std::fs;
+
+/// Environment variable naming the configuration file when --config is not
+/// given.
+pub const CONFIG_ENV: &str = "IMPROVED_CMSEARCH_CONFIG";
+
+// Table of the named presets of a configuration file
+const PRESETS: &str = "presets";
+
+/// Option defaults from a TOML configuration file (--config). Keys are the
+/// long names of options, with `-` or `_`: those at the top level apply to
+/// every subcommand that has the option, those in a table named for a
+/// subcommand (`[search]`, `[scan]`, ...) to that subcommand only, and
+/// those in a `[presets.<name>]` table, laid out the same way, only with
+/// `--config-preset <name>`. Flags given on the command line override the
+/// file.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    path: String,
+    table: toml::Table,
+}
+
+impl ConfigFile {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+        Self::parse(&text, path)
+    }
+
+    pub fn parse(text: &str, path: &str) -> Result<Self> {
+        let table = text.parse().with_context(|| format!("Invalid config file {}", path))?;
+        Ok(Self { path: path.to_string(), table })
+    }
+
+    /// The configuration file named by --config in `args`, else by
+    /// IMPROVED_CMSEARCH_CONFIG; None if neither is set.
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        match option_value(args, "--config").or_else(|| std::env::var(CONFIG_ENV).ok()) {
+            Some(path) => Self::from_file(&path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Make the file's values, and those of the named `preset`, the default
+    /// values of the options of `command` and its subcommands.
+    pub fn apply(&self, mut command: Command, preset: Option<&str>) -> Result<Command> {
+        let mut layers = vec![&self.table];
+        if let Some(name) = preset {
+            match self.table.get(PRESETS).and_then(|presets| presets.get(name)).and_then(toml::Value::as_table) {
+                Some(table) => layers.push(table),
+                None => bail!("--config-preset {}: {} has no [{}.{}] table", name, self.path, PRESETS, name),
+            }
+        }
+
+        // Later layers override earlier ones, and subcommand tables the top level
+        for layer in layers {
+            let (tables, options): (Vec<_>, Vec<_>) = layer.iter()
+                .filter(|(key, _)| *key != PRESETS)
+                .partition(|(key, value)| value.is_table() && command.find_subcommand(key).is_some());
+            command = self.set_defaults(command, &options, false)?;
+            for (name, table) in tables {
+                let options: Vec<_> = table.as_table().expect("partitioned on tables").iter().collect();
+                let subcommand = command.find_subcommand(name).expect("partitioned on subcommands").clone();
+                let subcommand = self.set_defaults(subcommand, &options, true)?;
+                command = command.mut_subcommand(name, |_| subcommand);
+            }
+        }
+        Ok(command)
+    }
+
+    // Sets each value of `options` as the default of the option of `command`
+    // with its name; with `strict` every key must name one, otherwise a key
+    // may instead name an option of any subcommand
+    fn set_defaults(&self, mut command: Command, options: &[(&String, &toml::Value)], strict: bool) -> Result<Command> {
+        for &(key, value) in options {
+            let long = key.replace('_', "-");
+            let values = default_values(value).with_context(|| format!("{}: invalid value for {}", self.path, key))?;
+            let mut found = false;
+            if let Some(id) = find_option(&command, &long)? {
+                command = command.mut_arg(id, |arg| arg.default_values(values.clone()));
+                found = true;
+            }
+            if !strict {
+                let names: Vec<String> = command.get_subcommands().map(|sub| sub.get_name().to_string()).collect();
+                for name in names {
+                    let sub = command.find_subcommand(&name).expect("listed above");
+                    if let Some(id) = find_option(sub, &long)? {
+                        command = command.mut_subcommand(&name, |sub| sub.mut_arg(id, |arg| arg.default_values(values.clone())));
+                        found = true;
+                    }
+                }
+            }
+            if !found {
+                bail!("{}: unknown option {} in [{}]", self.path, key, if strict { command.get_name() } else { "top level" });
+            }
+        }
+        Ok(command)
+    }
+}
+
+// Id of the option of `command` whose long name is `long`
+fn find_option(command: &Command, long: &str) -> Result<Option<String>> {
+    let Some(arg) = command.get_arguments().find(|arg| arg.get_long() == Some(long)) else {
+        return Ok(None);
+    };
+    if arg.is_positional() || arg.get_id() == "config" || arg.get_id() == "config_preset" {
+        bail!("--{} cannot be set in a config file", long);
+    }
+    Ok(Some(arg.get_id().to_string()))
+}
+
+// Command line form of a TOML value; the strings are leaked, as clap
+// keeps default values for the life of the command, which is the run
+fn default_values(value: &toml::Value) -> Result<Vec<&'static str>> {
+    let text = |value: &toml::Value| -> Result<String> {
+        Ok(match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(n) => n.to_string(),
+            toml::Value::Float(x) => x.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            other => bail!("expected a string, number, or boolean, found {}", other.type_str()),
+        })
+    };
+    let values = match value {
+        toml::Value::Array(items) => items.iter().map(text).collect::<Result<Vec<_>>>()?,
+        value => vec![text(value)?],
+    };
+    Ok(values.into_iter().map(|value| &*Box::leak(value.into_boxed_str())).collect())
+}
+
+// Value of a `--name value` or `--name=value` option in `args`, before any `--`
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// The --config-preset named in `args`.
+pub fn preset_from_args(args: &[String]) -> Option<String> {
+    option_value(args, "--config-preset")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("threads").long("threads").default_value("1"))
+            .subcommand(Command::new("search")
+                .arg(Arg::new("cmfile").required(true))
+                .arg(Arg::new("evalue").long("evalue").default_value("10.0"))
+                .arg(Arg::new("preset").long("preset").default_value("default"))
+                .arg(Arg::new("noali").long("noali").action(ArgAction::SetTrue)))
+            .subcommand(Command::new("scan")
+                .arg(Arg::new("evalue").long("evalue").default_value("10.0")))
+    }
+
+    fn search_value(command: Command, args: &[&str], id: &str) -> String {
+        let matches = command.try_get_matches_from(args).unwrap();
+        let search = matches.subcommand_matches("search").unwrap();
+        match id {
+            "noali" => search.get_flag(id).to_string(),
+            _ => search.get_one::<String>(id).unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn test_config_file_sets_defaults() {
+        let file = ConfigFile::parse(
+            "threads = 8\nevalue = 0.01\n\n[search]\nevalue = 1e-5\nnoali = true\n\n[presets.rfam.search]\npreset = \"rfam\"\n",
+            "test.toml",
+        ).unwrap();
+        let command = file.apply(command(), None).unwrap();
+        let matches = command.clone().try_get_matches_from(["test", "scan"]).unwrap();
+        assert_eq!(matches.get_one::<String>("threads").unwrap(), "8");
+        assert_eq!(matches.subcommand_matches("scan").unwrap().get_one::<String>("evalue").unwrap(), "0.01");
+        assert_eq!(search_value(command.clone(), &["test", "search", "a.cm"], "evalue"), "0.00001");
+        assert_eq!(search_value(command.clone(), &["test", "search", "a.cm"], "noali"), "true");
+        assert_eq!(search_value(command.clone(), &["test", "search", "a.cm"], "preset"), "default");
+
+        // The command line wins
+        assert_eq!(search_value(command, &["test", "search", "a.cm", "--evalue", "2"], "evalue"), "2");
+
+        let preset = file.apply(self::command(), Some("rfam")).unwrap();
+        assert_eq!(search_value(preset, &["test", "search", "a.cm"], "preset"), "rfam");
+        assert!(file.apply(self::command(), Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_config_file_errors() {
+        let apply = |text: &str| ConfigFile::parse(text, "test.toml").and_then(|file| file.apply(command(), None));
+        assert!(apply("evalue = ").is_err());
+        assert!(apply("evalu = 1.0").is_err());
+        assert!(apply("[scan]\nnoali = true").is_err());
+        assert!(apply("cmfile = \"a.cm\"").is_err());
+        assert!(apply("evalue = { a = 1 }").is_err());
+    }
+
+    #[test]
+    fn test_option_value() {
+        let args: Vec<String> = ["prog", "--config=a.toml", "search", "--config-preset", "rfam", "--", "--config", "b"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(option_value(&args, "--config").as_deref(), Some("a.toml"));
+        assert_eq!(preset_from_args(&args).as_deref(), Some("rfam"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::ops::{Deref, Range};
+
+/// Code of residues other than A, C, G, U/T; they score as background.
+pub const AMBIGUOUS: u8 = 4;
+
+// Code of each byte: A=0, C=1, G=2, U/T=3, either case; AMBIGUOUS otherwise
+static CODES: [u8; 256] = {
+    let mut codes = [AMBIGUOUS; 256];
+    let bases: [(u8, u8); 5] = [(b'A', 0), (b'C', 1), (b'G', 2), (b'U', 3), (b'T', 3)];
+    let mut b = 0;
+    while b < bases.len() {
+        let (c, code) = bases[b];
+        codes[c as usize] = code;
+        codes[c.to_ascii_lowercase() as usize] = code;
+        b += 1;
+    }
+    codes
+};
+
+/// Code of a residue character.
+pub fn code(c: u8) -> u8 {
+    CODES[c as usize]
+}
+
+/// Code of the complementary residue; ambiguous residues stay ambiguous.
+pub fn complement(code: u8) -> u8 {
+    if code < AMBIGUOUS { 3 - code } else { AMBIGUOUS }
+}
+
+/// Fraction of G and C among the A, C, G, U/T residues of `text`; other
+/// residues are not counted.
+pub fn gc_fraction(text: &[u8]) -> f64 {
+    let (mut gc, mut bases) = (0, 0);
+    for &c in text {
+        match code(c) {
+            1 | 2 => { gc += 1; bases += 1; }
+            AMBIGUOUS => {}
+            _ => bases += 1,
+        }
+    }
+    if bases == 0 { 0.0 } else { gc as f64 / bases as f64 }
+}
+
+/// Whether `c` is an IUPAC nucleotide code, either case: a base, or an
+/// ambiguity code such as N or R.
+pub fn is_iupac(c: u8) -> bool {
+    b"ACGTURYSWKMBDHVN".contains(&c.to_ascii_uppercase())
+}
+
+/// Complement of a residue character in the alphabet of its sequence: A
+/// pairs with U in RNA and with T in DNA. Case is kept, and IUPAC ambiguity
+/// codes map to their complementary codes.
+pub fn complement_char(c: u8, rna: bool) -> u8 {
+    let upper = match c.to_ascii_uppercase() {
+        b'A' if rna => b'U',
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'G' => b'C',
+        b'C' => b'G',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
+    };
+    if c.is_ascii_lowercase() { upper.to_ascii_lowercase() } else { upper }
+}
+
+/// Whether residue text is RNA rather than DNA: it has at least as many U
+/// as T, so text with neither is taken as RNA, like the models.
+pub fn is_rna(text: &[u8]) -> bool {
+    let count = |base: u8| text.iter().filter(|c| c.to_ascii_uppercase() == base).count();
+    count(b'U') >= count(b'T')
+}
+
+/// Reverse complement of residue text, in the text's own alphabet.
+pub fn reverse_complement_text(text: &str) -> String {
+    let rna = is_rna(text.as_bytes());
+    text.bytes().rev().map(|c| complement_char(c, rna) as char).collect()
+}
+
+/// Runs of at least `min_length` ambiguous residues (assembly gaps of N)
+/// in the residue text `text`, in order.
+pub fn ambiguous_runs(text: &[u8], min_length: usize) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, &c) in text.iter().chain(std::iter::once(&b'A')).enumerate() {
+        match (code(c) == AMBIGUOUS, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                if i - s >= min_length.max(1) {
+                    runs.push(s..i);
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    runs
+}
+
+/// A target sequence digitized to one code per residue, so that scoring
+/// loops index emission tables directly instead of matching characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DigitizedSeq {
+    codes: Vec<u8>,
+}
+
+impl DigitizedSeq {
+    pub fn from_text(text: &[u8]) -> Self {
+        Self { codes: text.iter().map(|&c| code(c)).collect() }
+    }
+
+    /// Recodes the residues at positions where `masked` holds as ambiguous,
+    /// so they score as background.
+    pub fn mask_where(&mut self, masked: impl Fn(usize) -> bool) {
+        for (i, code) in self.codes.iter_mut().enumerate() {
+            if masked(i) {
+                *code = AMBIGUOUS;
+            }
+        }
+    }
+
+    /// Writes the reverse complement of `codes` into `out`, reusing its buffer.
+    pub fn reverse_complement_into(codes: &[u8], out: &mut DigitizedSeq) {
+        out.codes.clear();
+        out.codes.extend(codes.iter().rev().map(|&c| complement(c)));
+    }
+}
+
+impl Deref for DigitizedSeq {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.codes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digitize() {
+        let seq = DigitizedSeq::from_text(b"ACGUTacgutNRy-");
+        assert_eq!(&seq[..], &[0, 1, 2, 3, 3, 0, 1, 2, 3, 3, 4, 4, 4, 4]);
+
+        let mut rc = DigitizedSeq::default();
+        DigitizedSeq::reverse_complement_into(&DigitizedSeq::from_text(b"AACGUN"), &mut rc);
+        assert_eq!(rc, DigitizedSeq::from_text(b"NACGUU"));
+
+        let text = b"ACgutA";
+        let mut masked = DigitizedSeq::from_text(text);
+        masked.mask_where(|i| text[i].is_ascii_lowercase());
+        assert_eq!(masked, DigitizedSeq::from_text(b"ACNNNA"));
+
+        // DNA targets digitize to the same codes as RNA
+        assert_eq!(DigitizedSeq::from_text(b"GATtaca"), DigitizedSeq::from_text(b"GAUuaca"));
+        assert_eq!(gc_fraction(b"GCauN-"), 0.5);
+        assert_eq!(gc_fraction(b"NN"), 0.0);
+    }
+
+    #[test]
+    fn test_reverse_complement_text() {
+        assert_eq!(reverse_complement_text("AACGUu"), "aACGUU");
+        assert_eq!(reverse_complement_text("AACGTt"), "aACGTT");
+        assert_eq!(reverse_complement_text("GGCC"), "GGCC");
+        assert_eq!(reverse_complement_text("ARYNa"), "uNRYU");
+        assert!(is_rna(b"ACG"));
+        assert!(!is_rna(b"ACGT"));
+    }
+
+    #[test]
+    fn test_ambiguous_runs() {
+        let text = b"NNNNACGUNNACnnnnnGUNNNN";
+        assert_eq!(ambiguous_runs(text, 4), vec![0..4, 12..17, 19..23]);
+        assert_eq!(ambiguous_runs(text, 5), vec![12..17]);
+        assert_eq!(ambiguous_runs(text, 1).len(), 4);
+        assert!(ambiguous_runs(b"", 1).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs;
+use std::path::Path;
+use crate::error::FileError;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+const NPY_ALIGN: usize = 64;
+
+// Darkest to brightest
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+/// DP scores (bits) per state x position, with rows grouped as the model's
+/// M_1..M_K, then I_1..I_K, then D_1..D_K states.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DpMatrix {
+    pub model_length: usize,
+    pub columns: usize,
+    data: Vec<f32>, // Row-major
+}
+
+impl DpMatrix {
+    pub fn new(model_length: usize, columns: usize) -> Self {
+        Self {
+            model_length,
+            columns,
+            data: vec![f32::NEG_INFINITY; 3 * model_length * columns],
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        3 * self.model_length
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> f32 {
+        self.data[row * self.columns + col]
+    }
+
+    /// Store column `col` from M, I and D cells in nats, k = 1..=K.
+    pub fn set_column(&mut self, col: usize, m: &[f64], i: &[f64], d: &[f64]) {
+        let k_len = self.model_length;
+        for (block, cells) in [m, i, d].into_iter().enumerate() {
+            for (k, &score) in cells.iter().take(k_len).enumerate() {
+                let row = block * k_len + k;
+                self.data[row * self.columns + col] = (score / std::f64::consts::LN_2) as f32;
+            }
+        }
+    }
+
+    /// Write as a NumPy .npy file (little-endian float32, shape rows x columns).
+    pub fn write_npy(&self, path: &Path) -> Result<(), FileError> {
+        let dict = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.rows(), self.columns
+        );
+        // Magic, version and header length take 10 bytes; pad the header so
+        // the data starts on an aligned offset
+        let unpadded = 10 + dict.len() + 1;
+        let padding = (NPY_ALIGN - unpadded % NPY_ALIGN) % NPY_ALIGN;
+        let header = format!("{}{}\n", dict, " ".repeat(padding));
+
+        let mut bytes = Vec::with_capacity(10 + header.len() + 4 * self.data.len());
+        bytes.extend_from_slice(NPY_MAGIC);
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for value in &self.data {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fs::write(path, bytes).map_err(FileError::io(|| format!("Failed to write DP matrix {}", path.display())))
+    }
+
+    /// Read a matrix written by `write_npy`.
+    pub fn read_npy(path: &Path) -> Result<Self, FileError> {
+        let bytes = fs::read(path).map_err(FileError::io(|| format!("Failed to read DP matrix {}", path.display())))?;
+        let invalid = |message: String| FileError::invalid(format!("{}: {}", path.display(), message));
+        if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+            return Err(invalid("not a .npy file".to_string()));
+        }
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let header = bytes.get(10..10 + header_len)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .ok_or_else(|| invalid("truncated .npy header".to_string()))?;
+        if !header.contains("'<f4'") || !header.contains("'fortran_order': False") {
+            return Err(invalid("only C-order little-endian float32 matrices are supported".to_string()));
+        }
+
+        let shape = header.split("'shape': (")
+            .nth(1)
+            .and_then(|rest| rest.split(')').next())
+            .ok_or_else(|| invalid("missing shape in .npy header".to_string()))?;
+        let dims = shape.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid(format!("invalid shape '{}'", shape)))?;
+        let [rows, columns] = dims[..] else {
+            return Err(invalid(format!("expected a 2-D matrix, got shape ({})", shape)));
+        };
+        if rows % 3 != 0 {
+            return Err(invalid(format!("row count {} is not a multiple of 3 (M/I/D states)", rows)));
+        }
+
+        let data = bytes[10 + header_len..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        if data.len() != rows * columns {
+            return Err(invalid(format!("expected {} values, found {}", rows * columns, data.len())));
+        }
+
+        Ok(Self { model_length: rows / 3, columns, data })
+    }
+
+    /// ASCII heatmap, downsampled to at most `width` x `height` characters per
+    /// state block by taking the maximum of each cell block. Unreachable
+    /// cells render blank.
+    pub fn render_ascii(&self, width: usize, height: usize) -> String {
+        let finite = self.data.iter().copied().filter(|v| v.is_finite());
+        let (lo, hi) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+
+        let k_len = self.model_length;
+        let width = width.clamp(1, self.columns.max(1));
+        let height = height.clamp(1, k_len.max(1));
+        let mut out = format!("positions 0..{} x states 1..{} (bits {:.1} to {:.1})\n",
+                              self.columns.saturating_sub(1), k_len, lo, hi);
+
+        for (block, name) in ["M", "I", "D"].iter().enumerate() {
+            out.push_str(&format!("{}:\n", name));
+            for y in 0..height {
+                let rows = bucket(y, height, k_len);
+                out.push_str(&format!("{:>5} |", rows.start + 1));
+                for x in 0..width {
+                    let cols = bucket(x, width, self.columns);
+                    let best = rows.clone()
+                        .flat_map(|r| cols.clone().map(move |c| (r, c)))
+                        .map(|(r, c)| self.get(block * k_len + r, c))
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    out.push(shade(best, lo, hi));
+                }
+                out.push_str("|\n");
+            }
+        }
+
+        out
+    }
+}
+
+// Range of the `n` source cells covered by output cell `i` of `buckets`
+fn bucket(i: usize, buckets: usize, n: usize) -> std::ops::Range<usize> {
+    let start = i * n / buckets;
+    start..((i + 1) * n / buckets).max(start + 1)
+}
+
+fn shade(value: f32, lo: f32, hi: f32) -> char {
+    if !value.is_finite() {
+        return ' ';
+    }
+    let t = if hi > lo { (value - lo) / (hi - lo) } else { 1.0 };
+    let idx = 1 + (t * (RAMP.len() - 2) as f32).round() as usize;
+    RAMP[idx.min(RAMP.len() - 1)] as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npy_roundtrip() {
+        let mut matrix = DpMatrix::new(2, 3);
+        matrix.set_column(1, &[-1.0, -2.0], &[f64::NEG_INFINITY, -3.0], &[-4.0, -5.0]);
+
+        let path = std::env::temp_dir().join(format!("dpdump-test-{}.npy", std::process::id()));
+        matrix.write_npy(&path).unwrap();
+        let read = DpMatrix::read_npy(&path).unwrap();
+        let header_len = fs::read(&path).unwrap().len() - 4 * 18;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(header_len % NPY_ALIGN, 0);
+        assert_eq!(read, matrix);
+        assert!((read.get(0, 1) - (-1.0 / std::f64::consts::LN_2) as f32).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_render_ascii_blanks_unreachable_cells() {
+        let mut matrix = DpMatrix::new(1, 2);
+        matrix.set_column(1, &[0.0], &[-1.0], &[-2.0]);
+
+        let text = matrix.render_ascii(10, 10);
+        assert!(text.contains("M:\n    1 | @|"));
+        assert!(text.contains("I:\n    1 | +|"));
+        assert!(text.contains("D:\n    1 | .|"));
+    }
+}
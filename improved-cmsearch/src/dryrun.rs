@@ -0,0 +1,138 @@
+use std::io::{self, Write};
+use std::path::Path;
+use crate::align::AlignMemory;
+use crate::cm::Cm;
+use crate::config::{Config, StageKind, TruncMode, STDIN_PATH};
+use crate::error::{Error, OutputError, PipelineError, SeqFormatError};
+use crate::output;
+use crate::seqfile;
+use crate::seqindex::SeqIndex;
+use crate::seqselect::TargetFilter;
+use crate::stage;
+use crate::utils;
+
+/// `search --dry-run`: the pipeline a search with `config` would run, its
+/// expected memory, and where it would write, without searching. The
+/// targets are read only to measure them, when neither -Z nor an index
+/// gives the database size.
+pub fn describe(out: &mut dyn Write, config: &Config) -> Result<(), Error> {
+    config.validate().map_err(PipelineError::Config)?;
+    let cms = Cm::all_from_file(Path::new(&config.cmfile))?;
+    for cm in &cms {
+        cm.validate()?;
+    }
+    let (residues, source) = database_size(config)?;
+    write_plan(out, config, &cms, residues, source)
+        .map_err(|source| OutputError::Write { target: "the dry run".to_string(), source })?;
+    Ok(())
+}
+
+// The description of `describe`, for targets of `residues` residues counted
+// as `source` says
+fn write_plan(out: &mut dyn Write, config: &Config, cms: &[Cm], residues: u64, source: &str) -> io::Result<()> {
+    let db_residues = config.effective_db_residues(config.searched_residues(residues));
+    writeln!(out, "# improved-cmsearch dry run: nothing is searched or written")?;
+    writeln!(out, "# query CM file:           {} ({} models)", config.cmfile, cms.len())?;
+    writeln!(out, "# target database:         {} ({} residues, {})", config.seqdb, residues, source)?;
+    writeln!(out, "# database size (Z):       {} Mb, {}{}", utils::format_g(db_residues as f64 / 1e6, 6),
+             config.strands.label(), if config.z.is_some() { " (-Z)" } else { "" })?;
+    writeln!(out, "# threads:                 {}", utils::resolve_threads(config.threads))?;
+
+    // Filter stages, then the final scoring stage
+    let thresholds = config.filter_thresholds(db_residues);
+    let stages = config.active_stages();
+    writeln!(out, "#")?;
+    writeln!(out, "# pipeline ({:?} preset):", config.preset)?;
+    for kind in stages.iter().copied().chain(std::iter::once(StageKind::Score)) {
+        let built = stage::build_stage(kind, config, &thresholds);
+        let threshold = match kind {
+            StageKind::Score => format!("E-value <= {}{}", config.evalue,
+                                         config.score.map_or(String::new(), |score| format!(" or score >= {}", score))),
+            StageKind::GlocalForward => format!("P <= {} ({} after bias correction)", built.threshold(), thresholds.f4b),
+            _ => format!("P <= {}", built.threshold()),
+        };
+        writeln!(out, "#   {:<8} {}", built.name(), threshold)?;
+    }
+    let inclusion = config.inc_score.map_or(format!("E-value <= {}", config.inc_evalue), |score| format!("score >= {}", score));
+    writeln!(out, "# inclusion threshold:     {} ('!'; other reported hits are marginal, '?')", inclusion)?;
+    let trunc = match config.trunc {
+        _ if !config.truncated_passes() => "off".to_string(),
+        TruncMode::Anywhere => "anywhere (--anytrunc)".to_string(),
+        _ => "at sequence ends".to_string(),
+    };
+    writeln!(out, "# truncated passes:        {}", trunc)?;
+    if config.truncated_passes() {
+        let local: Vec<_> = stages.iter()
+            .filter(|kind| matches!(kind, StageKind::Ssv | StageKind::Forward | StageKind::Consensus))
+            .map(|kind| kind.name())
+            .collect();
+        writeln!(out, "#   running {} and score", local.join(", "))?;
+        writeln!(out, "#   placing model fragments ungapped: a heuristic, not Infernal's TrCYK")?;
+    }
+    writeln!(out, "# consensus stages score HMM envelopes of up to W residues ungapped against the CM consensus")?;
+
+    // Per model: window, memory, and E-value parameters
+    for cm in cms {
+        let w = cm.max_hit_length();
+        writeln!(out, "#")?;
+        writeln!(out, "# model {}: {} consensus positions, W = {}{}", cm.name, cm.length, w,
+                 if cm.window_length.is_some() { "" } else { " (from the model length)" })?;
+        writeln!(out, "#   chunks of {} residues, windows of {}", 3 * w, 2 * w)?;
+
+        // A chunk's text and codes, the filter rows, and one hit alignment
+        let max_bytes = (config.max_mx_size * 1024.0 * 1024.0) as usize;
+        let alignment = AlignMemory::new(cm.length, w);
+        let alignment_bytes = if !config.needs_alignments() {
+            0
+        } else if alignment.full <= max_bytes {
+            alignment.full
+        } else {
+            alignment.minimum
+        };
+        let per_thread = 2 * 3 * w + 6 * (cm.length + 1) * std::mem::size_of::<f64>() + alignment_bytes;
+        writeln!(out, "#   expected memory per thread: {} ({} of it alignment DP{})",
+                 utils::format_bytes(per_thread as u64), utils::format_bytes(alignment_bytes as u64),
+                 if alignment.full > max_bytes { ", divide and conquer under --mx-size" } else { "" })?;
+
+        let calibration = &cm.calibration;
+        match (&calibration.local_inside, &calibration.glocal_inside) {
+            (Some(params), _) | (None, Some(params)) => {
+                let mode = if calibration.local_inside.is_some() { "local" } else { "glocal" };
+                writeln!(out, "#   E-values: the model's {} Inside calibration applied to the consensus score, lambda {:.5}, mu {:.5}, {:.0} hits in {} Mb",
+                         mode, params.lambda, params.mu_extrap, params.nhits, utils::format_g(params.dbsize / 1e6, 6))?;
+                writeln!(out, "#   expected random hits in Z: {}", utils::format_g(params.expected_hits(db_residues as f64), 4))?;
+            }
+            (None, None) => writeln!(out, "#   E-values: uncalibrated, bit score P-values over one trial per {} residues", cm.length)?,
+        }
+    }
+
+    writeln!(out, "#")?;
+    writeln!(out, "# outputs:")?;
+    let destinations = output::destinations(config);
+    if config.output.is_none() {
+        writeln!(out, "#   {:<12} stdout", "report")?;
+    }
+    for (option, path) in destinations {
+        writeln!(out, "#   {:<12} {}", option, path)?;
+    }
+    Ok(())
+}
+
+// Residues of the targets to search, one strand, and where the count came
+// from
+fn database_size(config: &Config) -> Result<(u64, &'static str), Error> {
+    let filter = TargetFilter::from_config(config)?;
+    if let Some(mb) = config.z {
+        return Ok(((mb * 1e6 / config.strands.count() as f64).round() as u64, "from -Z"));
+    }
+    if config.seqdb == STDIN_PATH {
+        return Err(PipelineError::Config("--dry-run cannot measure a database read from stdin; give -Z".to_string()).into());
+    }
+    if let Some(index) = SeqIndex::load_current(Path::new(&config.seqdb))? {
+        let residues = index.entries().iter().filter(|e| filter.keeps(&e.name)).map(|e| e.length as u64).sum();
+        return Ok((residues, "from the index"));
+    }
+    let records = filter.apply(seqfile::open(Path::new(&config.seqdb), config)?);
+    let residues = records.map(|record| record.map(|r| r.length as u64)).sum::<Result<u64, SeqFormatError>>()?;
+    Ok((residues, "measured"))
+}
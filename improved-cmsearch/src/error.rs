@@ -0,0 +1,230 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+/// Any failure of the library's search API.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    CmParse(#[from] CmParseError),
+    #[error(transparent)]
+    SeqFormat(#[from] SeqFormatError),
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+    #[error(transparent)]
+    Output(#[from] OutputError),
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+/// Failure to read a CM file, or a model that cannot be searched.
+#[derive(Debug)]
+pub struct CmParseError {
+    pub input: String, // The CM file, or the model's name for Cm::validate
+    pub line: Option<usize>, // 1-based line of the file, for an invalid record
+    pub kind: CmParseErrorKind,
+}
+
+/// What was wrong with a CM file or model.
+#[derive(Debug, Error)]
+pub enum CmParseErrorKind {
+    #[error("failed to open the CM file")]
+    Open(#[source] io::Error),
+    #[error("failed reading the CM file at byte offset {offset}")]
+    Read { offset: u64, #[source] source: io::Error },
+    #[error("invalid calibration line '{0}'")]
+    Calibration(String),
+    #[error("the model has no nodes")]
+    NoNodes,
+    #[error("the model has no consensus sequence")]
+    NoConsensus,
+    #[error("node {node} has invalid {link} {target}")]
+    InvalidLink { node: usize, link: &'static str, target: usize },
+}
+
+impl CmParseError {
+    pub fn new(input: impl Into<String>, line: Option<usize>, kind: CmParseErrorKind) -> Self {
+        Self { input: input.into(), line, kind }
+    }
+}
+
+// The error is its kind at its place, with the kind's cause as its own
+impl fmt::Display for CmParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} line {}: {}", self.input, line, self.kind),
+            None => write!(f, "{}: {}", self.input, self.kind),
+        }
+    }
+}
+
+impl StdError for CmParseError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.kind.source()
+    }
+}
+
+/// Failure to read a sequence file, or a target sequence rejected by the
+/// checks of --strict-seqs and --dup-names.
+#[derive(Debug, Error)]
+pub enum SeqFormatError {
+    #[error("Failed to open sequence database {input}")]
+    Open { input: String, #[source] source: io::Error },
+    /// `position` says how far reading got, if it had started.
+    #[error("Failed reading {input}{position}")]
+    Read { input: String, position: String, #[source] source: io::Error },
+    #[error("{input}: unrecognised sequence format (expected FASTA, FASTQ, Stockholm, EMBL, or GenBank)")]
+    UnknownFormat { input: String },
+    /// A record that does not parse; `message` gives the line if it can.
+    #[error("{input}: {message}")]
+    Malformed { input: String, line: usize, message: String },
+    /// A residue that is not an IUPAC nucleotide code, at a 1-based position.
+    #[error("{name}: {message}")]
+    InvalidResidue { name: String, position: usize, message: String },
+    #[error("duplicate sequence name '{name}'{hint}")]
+    DuplicateName { name: String, hint: &'static str },
+    #[error("Index of {input} does not match the file; rebuild it with `index`")]
+    StaleIndex { input: String },
+    /// No targets to search: none in the file, or with `filtered` none left
+    /// after --seqlist and --seqexclude.
+    #[error("{input}: {}", if *.filtered { "no target sequences left after --seqlist/--seqexclude" }
+                           else { "no sequences found in the target file" })]
+    Empty { input: String, filtered: bool },
+    #[error("{input}: only uncompressed FASTA files can be indexed")]
+    NotIndexable { input: String },
+    #[error("Sequence '{name}' is not in the index of {input}")]
+    NotIndexed { input: String, name: String },
+    /// The --resume record is not in the file.
+    #[error("--resume: sequence '{name}' is not in {input}")]
+    ResumeNotFound { input: String, name: String },
+}
+
+/// Failure of a search pipeline.
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    /// Options the search cannot run with.
+    #[error("{0}")]
+    Config(String),
+    /// A target sequence could not be read.
+    #[error(transparent)]
+    Sequence(#[from] SeqFormatError),
+    /// The search stopped at --max-rss before searching every target.
+    #[error("Search stopped at the --max-rss limit of {limit} MB; the results cover only the targets searched{}",
+            if *.checkpoint { "; run again with the same --checkpoint to finish it" } else { "" })]
+    ResourceLimit { limit: f64, checkpoint: bool },
+    /// Writing --stream or --checkpoint, or serving --coordinator workers.
+    #[error(transparent)]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl PipelineError {
+    // An error of the pipeline's internals: writing its files, or talking to
+    // remote workers
+    pub(crate) fn other(err: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        PipelineError::Other(err.into())
+    }
+}
+
+/// Failure to write a search's report or one of its output files.
+#[derive(Debug, Error)]
+pub enum OutputError {
+    #[error("{first} and {second} both write to {path}")]
+    SamePath { first: &'static str, second: &'static str, path: String },
+    #[error("Failed to create {option} file {path}")]
+    Create { option: &'static str, path: String, #[source] source: io::Error },
+    /// `target` names what was being written: the report, or an option's file.
+    #[error("Failed to write {target}")]
+    Write { target: String, #[source] source: io::Error },
+    #[error("{option} is not written by scan")]
+    NotScanned { option: &'static str },
+}
+
+/// Failure of a connection between a --coordinator and a worker.
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    /// `action` names the peer and what was being done.
+    #[error("{action}")]
+    Io { action: String, #[source] source: io::Error },
+    #[error("Malformed message from {peer}")]
+    Malformed { peer: String, #[source] source: serde_json::Error },
+    /// A message out of turn.
+    #[error("{0}")]
+    Protocol(String),
+    /// The search the worker was sent cannot be run.
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+}
+
+/// Failure of a batch (`batch --manifest`).
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+    /// A job that failed, by its line of the manifest and output prefix.
+    #[error("Job on manifest line {line} ({prefix})")]
+    Job { line: usize, prefix: String, #[source] source: Error },
+}
+
+/// Failure to read or write one of the other files of a run: a clan file,
+/// target name list, sequence index, cache entry, checkpoint, batch
+/// manifest, results to check, or DP dump.
+#[derive(Debug, Error)]
+pub enum FileError {
+    /// `action` names the file and what was being done to it.
+    #[error("{action}")]
+    Io { action: String, #[source] source: io::Error },
+    /// The file was read, but what it holds is not valid.
+    #[error("{message}")]
+    Invalid { message: String },
+}
+
+impl FileError {
+    // For map_err on I/O, with what it was doing; like anyhow's
+    // with_context, `action` is only formatted on error
+    pub(crate) fn io(action: impl FnOnce() -> String) -> impl FnOnce(io::Error) -> Self {
+        move |source| FileError::Io { action: action(), source }
+    }
+
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        FileError::Invalid { message: message.into() }
+    }
+}
+
+// `err` and its causes, outermost first
+pub(crate) fn chain<'a>(err: &'a (dyn StdError + 'static)) -> impl Iterator<Item = &'a (dyn StdError + 'static)> {
+    std::iter::successors(Some(err), |&err| err.source())
+}
+
+/// `err` with its causes, outermost first, each after a colon (the format
+/// of anyhow's `{:#}`), for logging an error the run goes on after.
+pub(crate) fn report(err: &(dyn StdError + 'static)) -> String {
+    let mut chain = chain(err).map(ToString::to_string);
+    let mut report = chain.next().unwrap_or_default();
+    for cause in chain {
+        report += ": ";
+        report += &cause;
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let err = CmParseError::new("tRNA.cm", Some(12), CmParseErrorKind::Calibration("ECMLI 0.7".to_string()));
+        assert_eq!(err.to_string(), "tRNA.cm line 12: invalid calibration line 'ECMLI 0.7'");
+        let open = io::Error::new(io::ErrorKind::NotFound, "not found");
+        let err = CmParseError::new("tRNA.cm", None, CmParseErrorKind::Open(open));
+        assert_eq!(report(&err), "tRNA.cm: failed to open the CM file: not found");
+
+        let write = io::Error::other("no space left");
+        let err = Error::from(OutputError::Write { target: "--tblout file hits.tbl".to_string(), source: write });
+        assert_eq!(err.to_string(), "Failed to write --tblout file hits.tbl");
+        assert_eq!(err.source().map(ToString::to_string).as_deref(), Some("no space left"));
+        let err = PipelineError::other(FileError::invalid("Checkpoint run.ckpt is of another search"));
+        assert_eq!(err.to_string(), "Checkpoint run.ckpt is of another search");
+    }
+}
@@ -0,0 +1,173 @@
+use serde::Serialize;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, Write};
+use crate::error::{chain, BatchError, CmParseError, Error, PipelineError, RemoteError, SeqFormatError};
+
+/// Exit status of a run that succeeded, with hits if it searched.
+pub const SUCCESS: i32 = 0;
+
+/// Exit status of a search that ran to the end but reported no hits.
+pub const NO_HITS: i32 = 1;
+
+/// What a run failed on, which sets its exit status so that workflow
+/// engines can branch on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Usage,         // Invalid command line or options
+    Other,         // Anything not classified below
+    CmFile,        // Unreadable or invalid CM file
+    SequenceFile,  // Unreadable or invalid sequence file
+    ResourceLimit, // A memory limit (--max-rss) stopped the run
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Usage => 2,
+            ErrorKind::Other => 3,
+            ErrorKind::CmFile => 4,
+            ErrorKind::SequenceFile => 5,
+            ErrorKind::ResourceLimit => 6,
+        }
+    }
+
+    /// `err` marked as a failure of this kind; its messages are unchanged.
+    pub fn tag(self, err: impl Into<Box<dyn StdError + Send + Sync>>) -> Tagged {
+        Tagged { kind: self, error: err.into() }
+    }
+
+    /// Kind of `err`: that of the outermost tag or library error type in
+    /// its chain that has one, else Other.
+    pub fn of(err: &(dyn StdError + 'static)) -> Self {
+        chain(err).find_map(Self::of_cause).unwrap_or(ErrorKind::Other)
+    }
+
+    // The library's wrapping variants are transparent, so they are matched
+    // here rather than met again as sources
+    fn of_cause(cause: &(dyn StdError + 'static)) -> Option<Self> {
+        if let Some(tagged) = cause.downcast_ref::<Tagged>() {
+            return Some(tagged.kind);
+        }
+        if let Some(err) = cause.downcast_ref::<Error>() {
+            return match err {
+                Error::CmParse(_) => Some(ErrorKind::CmFile),
+                Error::SeqFormat(_) => Some(ErrorKind::SequenceFile),
+                Error::Pipeline(err) => Self::of_cause(err),
+                Error::Output(_) | Error::File(_) => None,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<BatchError>() {
+            return match err {
+                BatchError::Pipeline(err) => Self::of_cause(err),
+                BatchError::Job { .. } => None,
+            };
+        }
+        if let Some(RemoteError::Pipeline(err)) = cause.downcast_ref::<RemoteError>() {
+            return Self::of_cause(err);
+        }
+        if cause.is::<CmParseError>() {
+            return Some(ErrorKind::CmFile);
+        }
+        if cause.is::<SeqFormatError>() {
+            return Some(ErrorKind::SequenceFile);
+        }
+        match cause.downcast_ref::<PipelineError>()? {
+            PipelineError::Config(_) => Some(ErrorKind::Usage),
+            PipelineError::Sequence(_) => Some(ErrorKind::SequenceFile),
+            PipelineError::ResourceLimit { .. } => Some(ErrorKind::ResourceLimit),
+            PipelineError::Other(err) => Self::of_cause(err.as_ref()),
+        }
+    }
+}
+
+/// Marks the error of a result as a failure of a kind.
+pub trait Failure<T> {
+    fn failure(self, kind: ErrorKind) -> Result<T, Tagged>;
+}
+
+impl<T, E: Into<Box<dyn StdError + Send + Sync>>> Failure<T> for Result<T, E> {
+    fn failure(self, kind: ErrorKind) -> Result<T, Tagged> {
+        self.map_err(|err| kind.tag(err))
+    }
+}
+
+/// An error with its kind; it displays as the error, with the error's
+/// causes as its own.
+#[derive(Debug)]
+pub struct Tagged {
+    kind: ErrorKind,
+    error: Box<dyn StdError + Send + Sync>,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl StdError for Tagged {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.error.source()
+    }
+}
+
+// Error object of --errors-json
+#[derive(Serialize)]
+struct JsonError {
+    error: ErrorKind,
+    exit_code: i32,
+    message: String,
+    causes: Vec<String>,
+}
+
+/// Writes `err` for --errors-json: one JSON object with its kind, the exit
+/// status, its message, and the messages of its causes, outermost first.
+pub fn write_json(out: &mut impl Write, err: &(dyn StdError + 'static)) -> io::Result<()> {
+    let kind = ErrorKind::of(err);
+    let mut chain = chain(err).map(|cause| cause.to_string());
+    let object = JsonError {
+        error: kind,
+        exit_code: kind.exit_code(),
+        message: chain.next().unwrap_or_default(),
+        causes: chain.collect(),
+    };
+    serde_json::to_writer(&mut *out, &object)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_tagged_errors() {
+        let err = Err::<(), _>(anyhow!("line 3: bad residue"))
+            .failure(ErrorKind::SequenceFile)
+            .context("Failed to search seqs.fa")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&*err), ErrorKind::SequenceFile);
+        assert_eq!(format!("{:#}", err), "Failed to search seqs.fa: line 3: bad residue");
+        assert_eq!(ErrorKind::of(&*anyhow!("other")), ErrorKind::Other);
+        let limit = anyhow::Error::new(PipelineError::ResourceLimit { limit: 100.0, checkpoint: false });
+        assert_eq!(ErrorKind::of(&*limit.context("Job on manifest line 2")), ErrorKind::ResourceLimit);
+        let options = PipelineError::Config("E-value must be positive".to_string());
+        assert_eq!(ErrorKind::of(&options).exit_code(), 2);
+
+        // The library's errors wrap their causes transparently
+        let limit = Error::from(PipelineError::ResourceLimit { limit: 100.0, checkpoint: false });
+        let job = BatchError::Job { line: 2, prefix: "out/chr2".to_string(), source: limit };
+        assert_eq!(ErrorKind::of(&job), ErrorKind::ResourceLimit);
+        let empty = Error::from(SeqFormatError::Empty { input: "chr1.fa".to_string(), filtered: false });
+        assert_eq!(ErrorKind::of(&empty), ErrorKind::SequenceFile);
+
+        let mut out = Vec::new();
+        write_json(&mut out, &*err).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["error"], "sequence_file");
+        assert_eq!(json["exit_code"], 5);
+        assert_eq!(json["causes"][0], "line 3: bad residue");
+    }
+}
@@ -0,0 +1,91 @@
+//! Search covariance models (CMs) of RNA families against sequence
+//! databases, as Infernal's cmsearch does, with a filter pipeline of profile
+//! HMM and CM stages.
+//!
+//! The `improved-cmsearch` binary is a command line wrapper around this
+//! library. To embed a search, read the models with [`cm::Cm`], describe the
+//! search with a [`config::Config`], and run it with [`search::CmSearch`],
+//! which writes its results through [`output::OutputWriter`]; or drive a
+//! [`pipeline::Pipeline`] directly to get the hits of one model back as
+//! [`search::Hit`]s:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use std::sync::Arc;
+//! use improved_cmsearch::cm::Cm;
+//! use improved_cmsearch::config::Config;
+//! use improved_cmsearch::pipeline::{Pipeline, SearchModel};
+//! use improved_cmsearch::seqfile;
+//!
+//! # fn main() -> Result<(), improved_cmsearch::error::Error> {
+//! let config = Config { evalue: 1e-5, ..Config::new() };
+//! let cm = Cm::from_file(Path::new("tRNA.cm"))?;
+//! let model = Arc::new(SearchModel::new(cm, &config));
+//! let targets = seqfile::open(Path::new("genome.fa"), &config)?;
+//! let pipeline = Pipeline::new(model, Arc::new(config), 2 * 3_000_000)?;
+//! for hit in pipeline.search(targets)? {
+//!     println!("{} {}-{} {:.1} bits", hit.sequence_name, hit.start + 1, hit.end, hit.score);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+/// Version of this crate, as the headers of its outputs give it.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Covariance models: reading Infernal CM files, and the model structure.
+pub mod cm;
+/// Search options, as the command line sets them.
+pub mod config;
+/// Command line options of a search, as the binary parses them.
+pub mod args;
+/// The filter pipeline searching the targets for the hits of one model.
+pub mod pipeline;
+/// Stages of the pipeline, and the model scores and statistics they share.
+pub mod stage;
+/// Searches of models and targets in memory, set up option by option.
+pub mod builder;
+/// Whole searches of every model of a CM file, and the hit records.
+pub mod search;
+/// Reports and output files of a search's results.
+pub mod output;
+/// Optimal alignments of hits to their model.
+pub mod align;
+/// Overlapping hits, and how they are resolved.
+pub mod overlap;
+/// Typed errors of reading models and targets, and of searches.
+pub mod error;
+/// Sequence file readers: FASTA, FASTQ, Stockholm, EMBL, and GenBank.
+pub mod seqfile;
+
+// Used by the binary; not part of the documented API
+#[doc(hidden)]
+pub mod batch;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod cmalign;
+#[doc(hidden)]
+pub mod dpdump;
+#[doc(hidden)]
+pub mod dryrun;
+#[doc(hidden)]
+pub mod exitcode;
+#[doc(hidden)]
+pub mod order;
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod seqindex;
+#[doc(hidden)]
+pub mod utils;
+
+mod cache;
+mod checkpoint;
+mod clan;
+mod digital;
+mod hmm;
+mod memory;
+mod pool;
+mod retry;
+mod seqselect;
@@ -1,47 +1,99 @@
-use clap::{Parser, Subcommand};
-use log::{info, error, warn};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{info, LevelFilter};
 use anyhow::{Result, Context};
-use rayon::ThreadPoolBuilder;
+use std::sync::Arc;
 
-mod cm;
-mod pipeline;
-mod search;
-mod utils;
-mod config;
-mod worker;
-mod output;
+mod configfile;
 
-use crate::config::Config;
-use crate::search::CmSearch;
+use improved_cmsearch::args::{one_of, SearchArgs, PRESETS};
+use improved_cmsearch::config::{Config, MsaFormat, Preset, SortOrder};
+use improved_cmsearch::exitcode::{ErrorKind, Failure};
+use improved_cmsearch::search::CmSearch;
+use improved_cmsearch::{batch, bench, cm, cmalign, dpdump, dryrun, exitcode, order, pipeline, remote, seqindex, utils};
+
+// The pipeline makes many small allocations; the `mimalloc` and `jemalloc`
+// features swap in a faster global allocator than the system's
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc"), not(target_env = "msvc")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// Global allocator of this build, for the startup log
+const ALLOCATOR: &str = if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else if cfg!(all(feature = "jemalloc", not(target_env = "msvc"))) {
+    "jemalloc"
+} else {
+    "system"
+};
 
 #[derive(Parser)]
 #[command(name = "improved-cmsearch")]
 #[command(about = "Improved cmsearch implementation in Rust")]
-#[command(version = "0.1.0")]
+#[command(version = improved_cmsearch::VERSION)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
     
-    /// Enable verbose logging
-    #[arg(short, long)]
+    /// Enable verbose logging (--log-level debug)
+    #[arg(short, long, global = true, conflicts_with = "log_level")]
     verbose: bool,
     
-    /// Number of threads to use
+    /// Log errors only (--log-level error)
+    #[arg(short, long, global = true, conflicts_with_all = ["verbose", "log_level"])]
+    quiet: bool,
+    
+    /// Least severe messages logged
+    #[arg(long, global = true, value_name = "LEVEL", default_value = "info", ignore_case = true,
+          value_parser = PossibleValuesParser::new(LOG_LEVELS).map(|level| level.parse::<LevelFilter>().expect("a log level")))]
+    log_level: LevelFilter,
+    
+    /// Append the log to this file instead of writing it to stderr
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<String>,
+    
+    /// On failure, write a JSON object with the error's kind, exit status, and messages to
+    /// stderr instead of the message. Exit status: 0 success, 1 no hits, 2 invalid command
+    /// line, 3 other error, 4 bad CM file, 5 bad sequence file, 6 resource limit exceeded
+    #[arg(long, global = true)]
+    errors_json: bool,
+    
+    /// Number of threads to use (0: every available CPU, within cgroup CPU limits)
     #[arg(short, long, default_value = "1")]
     threads: usize,
+    
+    /// TOML file of option defaults (default: $IMPROVED_CMSEARCH_CONFIG): keys are long
+    /// option names, at the top level or in [search], [scan], ... tables; flags given on
+    /// the command line override it
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<String>,
+    
+    /// Also apply the [presets.<NAME>] table of the config file
+    #[arg(long, global = true, value_name = "NAME")]
+    config_preset: Option<String>,
 }
 
+// Parsed once per run, so the size of the search options does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Search CM(s) against a sequence database
-    Search {
-        /// CM file path
+    Search(SearchArgs),
+    
+    /// Search each sequence of a file against every model of a CM database, as
+    /// cmscan: hits are reported per sequence, with those of all models merged
+    Scan {
+        /// CM database: a CM file of one or more models
         #[arg(required = true)]
-        cmfile: String,
+        cmdb: String,
         
-        /// Sequence database file path
+        /// Query sequence file path ('-' reads stdin)
         #[arg(required = true)]
-        seqdb: String,
+        seqfile: String,
         
         /// Output file (default: stdout)
         #[arg(short, long)]
@@ -55,29 +107,88 @@ enum Commands {
         #[arg(short = 'T', long)]
         score: Option<f64>,
         
-        /// Include alignments in output
-        #[arg(short = 'A', long)]
-        alignments: bool,
+        /// E-value threshold for including a hit ('!'); reported hits beyond it are marginal ('?')
+        #[arg(long = "incE", value_name = "X", default_value = "0.01")]
+        inc_evalue: f64,
         
-        /// Tabular output format
-        #[arg(short = 't', long)]
-        tabular: bool,
+        /// Score threshold for including a hit, in place of --incE
+        #[arg(long = "incT", value_name = "X")]
+        inc_score: Option<f64>,
         
-        /// Use HMM filter
+        /// Compute E-values as if the query sequences were <Mb> megabases (both strands)
+        #[arg(short = 'Z', value_name = "Mb")]
+        z: Option<f64>,
+        
+        /// Omit hit alignments from the report, keeping the hit tables
         #[arg(long)]
-        hmm_filter: bool,
+        noali: bool,
         
-        /// Maximum matrix size in MB
-        #[arg(long, default_value = "1024")]
-        max_mx_size: f64,
+        /// Also write hits to <FILE> as an Infernal tabular hit table, models as targets
+        #[arg(long, value_name = "FILE")]
+        tblout: Option<String>,
+        
+        /// --tblout format: 1, or 2 adding the overlap annotation between hits of
+        /// different models, clan, and model and sequence length columns
+        #[arg(long, default_value = "1", requires = "tblout", value_parser = clap::value_parser!(u8).range(1..=2))]
+        fmt: u8,
+        
+        /// Filter preset (max, nohmm, mid, default, rfam)
+        #[arg(long, value_name = "PRESET", default_value = "default", ignore_case = true,
+              value_parser = one_of::<Preset>(&PRESETS, &[]))]
+        preset: Preset,
+        
+        /// Rfam clan membership file (Rfam.clanin): of overlapping hits from models of
+        /// one clan, only the best is reported; also fills the --fmt 2 clan column
+        #[arg(long, value_name = "FILE")]
+        clanin: Option<String>,
+        
+        /// With --clanin, keep hits overlapping a better hit of the same clan, marked in
+        /// the --fmt 2 olp column, instead of removing them
+        #[arg(long, requires = "clanin")]
+        keep_clan_overlaps: bool,
+    },
+    
+    /// Align sequences to a model, as cmalign, writing one multiple alignment with the
+    /// consensus structure (SS_cons) and sequence (RF). The alignment is that of the
+    /// model's profile HMM, not a CM alignment: base pairs are not scored
+    Align {
+        /// CM file path
+        #[arg(required = true)]
+        cmfile: String,
+        
+        /// Unaligned sequence file path ('-' reads stdin)
+        #[arg(required = true)]
+        seqfile: String,
+        
+        /// Output file (default: stdout)
+        #[arg(short, long)]
+        output: Option<String>,
         
-        /// Enable truncated alignments
+        /// Align for optimal accuracy (summed posteriors) instead of the best score
         #[arg(long)]
-        trunc: bool,
+        acc: bool,
         
-        /// Number of passes
-        #[arg(long, default_value = "3")]
-        passes: usize,
+        /// Alignment format: stockholm, a2m, or afa (aligned FASTA)
+        #[arg(long, value_name = "FORMAT", default_value = "stockholm", ignore_case = true,
+              value_parser = one_of::<MsaFormat>(&["stockholm", "a2m", "afa"], &["pfam"]))]
+        outformat: MsaFormat,
+        
+        /// Maximum DP matrix memory per alignment in MB; larger alignments use divide and conquer
+        #[arg(long, visible_alias = "mx-size", default_value = "1024")]
+        max_mx_size: f64,
+    },
+    
+    /// Run many searches in one process, sharing a thread pool and the models of each CM
+    /// file: one per line of a manifest
+    Batch {
+        /// Tab-separated jobs, one per line: CM file, sequence file, search options
+        /// (space-separated, or '-'), and output prefix, to which .out and .tbl are added
+        #[arg(long, value_name = "FILE")]
+        manifest: String,
+        
+        /// Log a failed job and run the rest, instead of stopping the batch
+        #[arg(long)]
+        keep_going: bool,
     },
     
     /// Validate CM file
@@ -93,58 +204,272 @@ enum Commands {
         #[arg(required = true)]
         cmfile: String,
     },
+    
+    /// Build an index of a FASTA sequence database (<seqdb>.ssi) for random access
+    Index {
+        /// Sequence database file path
+        #[arg(required = true)]
+        seqdb: String,
+    },
+    
+    /// Print sequences by name from an indexed sequence database, as FASTA
+    Fetch {
+        /// Sequence database file path (indexed with `index`)
+        #[arg(required = true)]
+        seqdb: String,
+        
+        /// Names of the sequences to fetch
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    
+    /// Verify that a result file follows the canonical hit ordering
+    CheckOrder {
+        /// Standard report or --tblout file
+        #[arg(required = true)]
+        file: String,
+        
+        /// Order the file was written in (search --sort)
+        #[arg(long, value_name = "ORDER", default_value = "evalue", ignore_case = true,
+              value_parser = one_of::<SortOrder>(&["evalue", "score", "position", "target"], &[]))]
+        sort: SortOrder,
+    },
+    
+    /// Render a DP matrix dumped with --dump-dp as an ASCII heatmap
+    DpView {
+        /// .npy matrix file
+        #[arg(required = true)]
+        file: String,
+        
+        /// Maximum heatmap width in characters
+        #[arg(long, default_value = "100")]
+        width: usize,
+        
+        /// Maximum heatmap height per state type (M, I, D) in lines
+        #[arg(long, default_value = "20")]
+        height: usize,
+    },
+    
+    /// Time the search on a synthetic workload: random targets with sequences
+    /// emitted by the model planted in them
+    Bench {
+        /// CM file path
+        #[arg(required = true)]
+        cmfile: String,
+        
+        /// Residues of the workload (one strand)
+        #[arg(long, value_name = "N", default_value = "1000000")]
+        residues: usize,
+        
+        /// Targets to split the residues into
+        #[arg(long, value_name = "N", default_value = "10")]
+        targets: usize,
+        
+        /// Model sequences to plant in the targets
+        #[arg(long, value_name = "N", default_value = "20")]
+        planted: usize,
+        
+        /// Fraction of the positions of planted sequences drawn from the model's
+        /// emissions instead of copying the consensus
+        #[arg(long, value_name = "F", default_value = "0.2")]
+        divergence: f64,
+        
+        /// Thread counts to run with, comma-separated
+        #[arg(long, value_name = "LIST", value_delimiter = ',', default_value = "1,2,4")]
+        thread_counts: Vec<usize>,
+        
+        /// Filter presets to run (max, nohmm, mid, default, rfam), comma-separated
+        #[arg(long, value_name = "LIST", value_delimiter = ',', default_value = "default,rfam", ignore_case = true,
+              value_parser = one_of::<Preset>(&PRESETS, &[]))]
+        presets: Vec<Preset>,
+        
+        /// Seed of the workload
+        #[arg(long, default_value = "1")]
+        seed: u64,
+        
+        /// Thread-scaling self-test: run the default preset at 1, 2, 4, ...
+        /// threads up to --threads (or every CPU) and report the scaling
+        /// efficiency, instead of --thread-counts and --presets
+        #[arg(long)]
+        speedtest: bool,
+    },
+    
+    /// Print a shell completion script for <SHELL> (bash, zsh, fish, elvish, powershell)
+    Completions {
+        #[arg(required = true)]
+        shell: clap_complete::Shell,
+    },
+    
+    /// Search chunks for a `search --coordinator`, with --threads connections
+    Worker {
+        /// Coordinator address (host:port)
+        #[arg(required = true)]
+        coordinator: String,
+    },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    
-    // Configure rayon thread pool
-    ThreadPoolBuilder::new()
-        .num_threads(cli.threads)
-        .build_global()
-        .expect("Failed to configure thread pool");
-    
-    // Initialize logging
-    if cli.verbose {
-        std::env::set_var("RUST_LOG", "debug");
+// Levels of --log-level, as the log crate names them
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+// The command line, over the defaults of the configuration file if there is one.
+// Errors in either are usage errors; with `errors_json` clap's own are
+// returned to be reported as JSON, rather than written by clap
+fn parse_args(args: Vec<String>, errors_json: bool) -> Result<Cli> {
+    let preset = configfile::preset_from_args(&args);
+    let command = match configfile::ConfigFile::from_args(&args).failure(ErrorKind::Usage)? {
+        Some(file) => file.apply(Cli::command(), preset.as_deref()).failure(ErrorKind::Usage)?,
+        None if preset.is_some() => {
+            let err = anyhow::anyhow!("--config-preset needs a config file, from --config or {}", configfile::CONFIG_ENV);
+            return Err(ErrorKind::Usage.tag(err).into());
+        }
+        None => Cli::command(),
+    };
+    match command.try_get_matches_from(args).and_then(|matches| Cli::from_arg_matches(&matches)) {
+        Ok(cli) => Ok(cli),
+        Err(e) if errors_json && e.use_stderr() => Err(ErrorKind::Usage.tag(clap_error(&e)).into()),
+        Err(e) => e.exit(),
+    }
+}
+
+// The one-line message of a command line error
+fn clap_error(e: &clap::Error) -> anyhow::Error {
+    let message = e.render().to_string();
+    anyhow::anyhow!("{}", message.lines().next().unwrap_or_default().trim_start_matches("error: "))
+}
+
+// Logging as the flags set it, not the environment: to stderr, keeping
+// stdout for the results, or appended to --log-file
+fn init_logging(cli: &Cli) -> Result<()> {
+    let level = if cli.quiet {
+        LevelFilter::Error
+    } else if cli.verbose {
+        LevelFilter::Debug
     } else {
-        std::env::set_var("RUST_LOG", "info");
+        cli.log_level
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).target(env_logger::Target::Stderr);
+    if let Some(path) = &cli.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file))).write_style(env_logger::WriteStyle::Never);
     }
-    env_logger::init();
+    builder.init();
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let errors_json = args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--errors-json");
+    let result = parse_args(args, errors_json).and_then(run);
+    let status = match result {
+        Ok(status) => status,
+        Err(err) => {
+            if errors_json {
+                let _ = exitcode::write_json(&mut std::io::stderr(), &*err);
+            } else {
+                eprintln!("Error: {:?}", err);
+            }
+            ErrorKind::of(&*err).exit_code()
+        }
+    };
+    std::process::exit(status);
+}
+
+// Runs the command; returns the exit status
+fn run(cli: Cli) -> Result<i32> {
+    init_logging(&cli)?;
+    let mut status = exitcode::SUCCESS;
     
-    info!("Starting improved-cmsearch v0.1.0");
+    info!("Starting improved-cmsearch v{} ({} allocator)", improved_cmsearch::VERSION, ALLOCATOR);
     
     match cli.command {
-        Commands::Search { 
-            cmfile, 
-            seqdb, 
-            output, 
-            evalue, 
-            score, 
-            alignments, 
-            tabular, 
-            hmm_filter, 
-            max_mx_size, 
-            trunc, 
-            passes 
-        } => {
+        Commands::Search(args) => {
+            let dry_run = args.dry_run;
+            let config = Config::from_args(args, cli.threads);
+            
+            if dry_run {
+                dryrun::describe(&mut std::io::stdout(), &config)?;
+            } else {
+                let mut searcher = CmSearch::new(config)?;
+                if searcher.run()? == 0 {
+                    status = exitcode::NO_HITS;
+                }
+            }
+        }
+        
+        Commands::Scan { cmdb, seqfile, output, evalue, score, inc_evalue, inc_score, z, noali, tblout, fmt, preset, clanin,
+                         keep_clan_overlaps } => {
             let config = Config {
-                cmfile,
-                seqdb,
+                cmfile: cmdb,
+                seqdb: seqfile,
                 output,
                 evalue,
                 score,
-                alignments,
-                tabular,
-                hmm_filter,
+                inc_evalue,
+                inc_score,
+                z,
+                alignments: !noali,
+                tblout,
+                fmt,
+                preset,
+                threads: cli.threads,
+                scan: true,
+                clanin,
+                keep_clan_overlaps,
+                ..Config::new()
+            };
+            
+            let mut scanner = CmSearch::new(config)?;
+            if scanner.run()? == 0 {
+                status = exitcode::NO_HITS;
+            }
+        }
+        
+        Commands::Align { cmfile, seqfile, output, acc, outformat, max_mx_size } => {
+            let config = Config {
+                cmfile,
+                seqdb: seqfile,
+                output,
+                acc,
+                msa_format: outformat,
                 max_mx_size,
-                trunc,
-                passes,
                 threads: cli.threads,
+                ..Config::new()
             };
             
-            let mut searcher = CmSearch::new(config)?;
-            searcher.run()?;
+            let mut aligner = cmalign::CmAlign::new(config)?;
+            aligner.run()?;
+        }
+        
+        Commands::Batch { manifest, keep_going } => {
+            // Each job's options are parsed as those of a search command line
+            let jobs = batch::read_manifest(&manifest).failure(ErrorKind::Usage)?
+                .into_iter()
+                .map(|job| {
+                    let args = std::iter::once("improved-cmsearch".to_string()).chain(job.search_args());
+                    let cli = Cli::try_parse_from(args)
+                        .map_err(|e| clap_error(&e))
+                        .with_context(|| format!("{}: invalid options on line {}", manifest, job.line))
+                        .failure(ErrorKind::Usage)?;
+                    let Commands::Search(args) = cli.command else {
+                        unreachable!("a job is a search command line");
+                    };
+                    let config = Config::from_args(args, cli.threads);
+                    Ok((job, config))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let summary = batch::run(jobs, cli.threads, keep_going)?;
+            info!("Batch done: {} jobs, {} failed, {} hits", summary.jobs, summary.failed, summary.hits);
+            if summary.failed > 0 {
+                anyhow::bail!("{} of {} jobs of {} failed", summary.failed, summary.jobs, manifest);
+            }
+            if summary.hits == 0 {
+                status = exitcode::NO_HITS;
+            }
         }
         
         Commands::Validate { cmfile } => {
@@ -166,8 +491,82 @@ fn main() -> Result<()> {
             println!("  Nodes: {}", cm.nodes.len());
             println!("  States: {}", cm.states.len());
         }
+        
+        Commands::Index { seqdb } => {
+            let seqdb = std::path::Path::new(&seqdb);
+            info!("Indexing sequence database: {}", seqdb.display());
+            let index = seqindex::SeqIndex::build(seqdb)?;
+            let path = seqindex::SeqIndex::path_for(seqdb);
+            index.write(&path)?;
+            println!("{}: {} sequences, {} residues", path.display(), index.entries().len(), index.total_residues());
+        }
+        
+        Commands::Fetch { seqdb, names } => {
+            let seqdb = std::path::Path::new(&seqdb);
+            let Some(index) = seqindex::SeqIndex::load_current(seqdb)? else {
+                anyhow::bail!("{} has no up to date index; build one with `index`", seqdb.display());
+            };
+            for name in names {
+                let sequence = index.fetch(seqdb, &name)?;
+                match sequence.description.as_str() {
+                    "" => println!(">{}", sequence.name),
+                    description => println!(">{} {}", sequence.name, description),
+                }
+                for line in sequence.sequence.as_bytes().chunks(60) {
+                    println!("{}", String::from_utf8_lossy(line));
+                }
+            }
+        }
+        
+        Commands::CheckOrder { file, sort } => {
+            info!("Checking hit order: {}", file);
+            let count = order::check_order_file(std::path::Path::new(&file), sort)?;
+            let order = match sort {
+                SortOrder::Evalue => "canonical".to_string(),
+                sort => format!("--sort {:?}", sort).to_lowercase(),
+            };
+            println!("{}: {} hits in {} order", file, count, order);
+        }
+        
+        Commands::DpView { file, width, height } => {
+            let matrix = dpdump::DpMatrix::read_npy(std::path::Path::new(&file))?;
+            print!("{}", matrix.render_ascii(width, height));
+        }
+        
+        Commands::Bench { cmfile, residues, targets, planted, divergence, thread_counts, presets, seed, speedtest } => {
+            let cm = cm::Cm::from_file(std::path::Path::new(&cmfile))?;
+            let config = Config { cmfile, seqdb: "(bench workload)".to_string(), ..Config::new() };
+            let model = Arc::new(pipeline::SearchModel::new(cm, &config));
+            let workload = bench::Workload::generate(&model.scores, residues, targets, planted, divergence, seed)?;
+            let (thread_counts, presets) = if speedtest {
+                let max = if cli.threads > 1 { cli.threads } else { utils::available_cpus() };
+                (bench::speedtest_threads(max), vec![Preset::Default])
+            } else {
+                (thread_counts, presets)
+            };
+            let mut runs = Vec::new();
+            for &preset in &presets {
+                for &threads in &thread_counts {
+                    runs.push(bench::run(&model, &config, &workload, preset, threads.max(1))?);
+                }
+            }
+            let out = &mut std::io::stdout().lock();
+            if speedtest {
+                bench::write_scaling(out, &workload, &runs)?;
+            } else {
+                bench::write_report(out, &model.cm, &workload, &runs)?;
+            }
+        }
+        
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "improved-cmsearch", &mut std::io::stdout());
+        }
+        
+        Commands::Worker { coordinator } => {
+            remote::run_worker(&coordinator, utils::resolve_threads(cli.threads))?;
+        }
     }
     
     info!("Completed successfully");
-    Ok(())
+    Ok(status)
 } 
\ No newline at end of file
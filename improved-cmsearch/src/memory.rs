@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use crate::utils;
+
+thread_local! {
+    // The search whose chunk this thread is searching, which its DP
+    // buffers count against
+    static CURRENT: RefCell<Option<Arc<SearchMemory>>> = const { RefCell::new(None) };
+}
+
+/// Memory accounting of one search: estimated bytes of its live DP matrices
+/// and sequence buffers and their peak, and the --max-rss budget it stops
+/// at. Each pipeline has its own, so one search stopping at its budget does
+/// not stop the next.
+#[derive(Debug)]
+pub struct SearchMemory {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    budget: usize,         // --max-rss in bytes
+    exceeded: AtomicBool,  // Whether the search stopped at the budget
+    pool_limit: usize,     // Bytes of idle DP buffers each thread may keep (--mx-size)
+}
+
+impl SearchMemory {
+    pub fn new(budget: Option<usize>, pool_limit: usize) -> Self {
+        Self {
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            budget: budget.unwrap_or(usize::MAX),
+            exceeded: AtomicBool::new(false),
+            pool_limit,
+        }
+    }
+
+    /// Counts `bytes` of a buffer as live while the returned handle is held.
+    pub fn track(self: &Arc<Self>, bytes: usize) -> Tracked {
+        self.add(bytes);
+        Tracked { bytes, memory: Some(Arc::clone(self)) }
+    }
+
+    fn add(&self, bytes: usize) {
+        let live = self.live.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+    }
+
+    /// Peak of the estimated memory of DP matrices and sequence buffers.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Fails, and stops the search, once the resident memory of the process
+    /// (or the estimate, where it is higher) is over the --max-rss budget or
+    /// would be with `more` bytes; the error says by how much.
+    pub fn check(&self, more: usize) -> Result<(), String> {
+        if self.budget == usize::MAX {
+            return Ok(());
+        }
+        let resident = utils::resident_memory().map_or(0, |bytes| bytes as usize);
+        let used = resident.max(self.live.load(Ordering::Relaxed));
+        if used.saturating_add(more) > self.budget {
+            self.exceeded.store(true, Ordering::Relaxed);
+            return Err(format!("Memory use {} would exceed the --max-rss budget of {}",
+                               utils::format_bytes((used + more) as u64), utils::format_bytes(self.budget as u64)));
+        }
+        Ok(())
+    }
+
+    /// Whether the search stopped at the --max-rss budget.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Takes on the peak and stop of `other`, which did the accounting for
+    /// a search this one shared.
+    pub fn record(&self, other: &SearchMemory) {
+        self.peak.fetch_max(other.peak(), Ordering::Relaxed);
+        if other.exceeded() {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts the DP buffers this thread takes against this search until
+    /// the returned guard is dropped.
+    pub fn enter(self: &Arc<Self>) -> Entered {
+        let previous = CURRENT.with(|current| current.replace(Some(Arc::clone(self))));
+        Entered { previous }
+    }
+}
+
+/// The search a thread's DP buffers count against, restored to the one
+/// before when dropped.
+pub struct Entered {
+    previous: Option<Arc<SearchMemory>>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        let _ = CURRENT.try_with(|current| current.replace(previous));
+    }
+}
+
+/// Bytes of a buffer, counted as live memory of its search while this is
+/// held.
+pub struct Tracked {
+    bytes: usize,
+    memory: Option<Arc<SearchMemory>>, // None outside a search
+}
+
+impl Tracked {
+    /// `bytes` of a DP buffer of the search this thread is in, if any.
+    pub fn current(bytes: usize) -> Self {
+        match CURRENT.try_with(|current| current.borrow().clone()).ok().flatten() {
+            Some(memory) => memory.track(bytes),
+            None => Self { bytes, memory: None },
+        }
+    }
+
+    /// Count `bytes` instead, as the buffer grew or shrank.
+    pub fn resize(&mut self, bytes: usize) {
+        match &self.memory {
+            Some(memory) if bytes > self.bytes => memory.add(bytes - self.bytes),
+            Some(memory) => {
+                memory.live.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+            }
+            None => {}
+        }
+        self.bytes = bytes;
+    }
+
+    /// Bytes of idle DP buffers a thread may keep once this one is free.
+    pub fn pool_limit(&self) -> usize {
+        self.memory.as_ref().map_or(usize::MAX, |memory| memory.pool_limit)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(memory) = &self.memory {
+            memory.live.fetch_sub(self.bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracked_peak() {
+        let memory = Arc::new(SearchMemory::new(None, usize::MAX));
+        let mut buffer = memory.track(1 << 40);
+        buffer.resize(1 << 41);
+        assert_eq!(memory.peak(), 1 << 41);
+        drop(buffer);
+        assert_eq!(memory.live.load(Ordering::Relaxed), 0);
+
+        // DP buffers count against the search their thread has entered
+        let entered = memory.enter();
+        let buffer = Tracked::current(100);
+        assert_eq!(memory.live.load(Ordering::Relaxed), 100);
+        drop((buffer, entered));
+        assert!(Tracked::current(100).memory.is_none());
+    }
+
+    #[test]
+    fn test_budgets_are_per_search() {
+        let first = SearchMemory::new(Some(1), usize::MAX);
+        assert!(first.check(2).is_err());
+        assert!(first.exceeded());
+        let second = SearchMemory::new(None, usize::MAX);
+        assert!(second.check(1 << 20).is_ok());
+        assert!(!second.exceeded());
+    }
+}
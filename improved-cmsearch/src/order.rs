@@ -0,0 +1,442 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::config::SortOrder;
+use crate::error::FileError;
+use crate::search::Hit;
+
+/// Canonical hit ordering shared by every output format:
+///
+/// 1. E-value, most significant first;
+/// 2. score, best first;
+/// 3. target sequence name, ascending;
+/// 4. start, then end coordinate, ascending;
+/// 5. strand, plus first.
+///
+/// This is a total order over the hits of a search (overlap resolution
+/// leaves one hit per locus and strand), so output does not depend on the
+/// order in which threads find hits. Each hit appears once, and ranks in
+/// the standard report run 1, 2, 3, ... without gaps or repeats. --sort
+/// orders hits by another key first, with ties broken the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderKey<'a> {
+    pub score: f64,
+    pub evalue: f64,
+    pub target: &'a str,
+    pub start: usize,
+    pub end: usize,
+    pub strand: char,
+}
+
+impl<'a> OrderKey<'a> {
+    pub fn from_hit(hit: &'a Hit) -> Self {
+        Self {
+            score: hit.score,
+            evalue: hit.evalue,
+            target: &hit.sequence_name,
+            start: hit.start,
+            end: hit.end,
+            strand: hit.strand.symbol(),
+        }
+    }
+
+    /// Order under `sort`; SortOrder::Evalue is the canonical ordering.
+    /// Scores and E-values are printed rounded, so with `rounded` hits tied
+    /// on them are not ordered further.
+    pub fn cmp_by(&self, other: &Self, sort: SortOrder, rounded: bool) -> Ordering {
+        let score = other.score.total_cmp(&self.score);
+        let evalue = self.evalue.total_cmp(&other.evalue);
+        let canonical = evalue.then(score);
+        let target = self.target.cmp(other.target);
+        let position = target
+            .then(self.start.cmp(&other.start))
+            .then(self.end.cmp(&other.end))
+            .then(self.strand.cmp(&other.strand));
+        let significance = match sort {
+            SortOrder::Score if rounded => score,
+            SortOrder::Score => score.then(evalue),
+            _ if rounded => evalue,
+            _ => canonical,
+        };
+        let ranked = if rounded { significance } else { significance.then(position) };
+        match sort {
+            SortOrder::Evalue | SortOrder::Score => ranked,
+            SortOrder::Position => position.then(if rounded { Ordering::Equal } else { canonical }),
+            SortOrder::Target => target.then(ranked),
+        }
+    }
+}
+
+/// Order of hits under --sort.
+pub fn compare_hits_by(sort: SortOrder, a: &Hit, b: &Hit) -> Ordering {
+    OrderKey::from_hit(a).cmp_by(&OrderKey::from_hit(b), sort, false)
+}
+
+/// Merge shards of hits, each already in --sort order, into one list in
+/// that order.
+pub fn merge_sorted(shards: Vec<Vec<Hit>>, sort: SortOrder) -> Vec<Hit> {
+    let mut merged = Vec::with_capacity(shards.iter().map(Vec::len).sum());
+    let mut shards: Vec<_> = shards.into_iter().map(Vec::into_iter).collect();
+    let mut heads: BinaryHeap<_> = shards
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(shard, hits)| hits.next().map(|hit| Head { hit, shard, sort }))
+        .collect();
+    while let Some(Head { hit, shard, sort }) = heads.pop() {
+        merged.push(hit);
+        if let Some(hit) = shards[shard].next() {
+            heads.push(Head { hit, shard, sort });
+        }
+    }
+    merged
+}
+
+// The next hit of a shard; the heap pops the hit first in --sort order
+struct Head {
+    hit: Hit,
+    shard: usize,
+    sort: SortOrder,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_hits_by(self.sort, &other.hit, &self.hit).then(other.shard.cmp(&self.shard))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+#[derive(Debug)]
+struct ResultRecord {
+    line: usize,
+    query: String, // The query model's name
+    rank: Option<usize>,
+    score: f64,
+    evalue: f64,
+    target: String,
+    start: usize,
+    end: usize,
+    strand: char,
+}
+
+impl ResultRecord {
+    fn key(&self) -> OrderKey<'_> {
+        OrderKey {
+            score: self.score,
+            evalue: self.evalue,
+            target: &self.target,
+            start: self.start,
+            end: self.end,
+            strand: self.strand,
+        }
+    }
+}
+
+/// Verify that a standard report or --tblout file obeys the canonical
+/// ordering, or that of `sort`. With several query models, the hits of each
+/// query are checked on their own. Returns the number of hits checked.
+pub fn check_order_file(path: &Path, sort: SortOrder) -> Result<usize, FileError> {
+    let file = File::open(path).map_err(FileError::io(|| format!("Failed to open {}", path.display())))?;
+    let invalid = |message: String| FileError::invalid(format!("{}: {}", path.display(), message));
+    let reader = BufReader::new(file);
+
+    let mut records = Vec::new();
+    let mut tblout = None; // --tblout format, from the column header after the run metadata
+    let mut in_table = false; // Within the report's "Hit scores" table
+    let mut query = String::new(); // Of the report's current "Query:" section
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(FileError::io(|| format!("Failed to read {}", path.display())))?;
+        if tblout.is_none() && records.is_empty() {
+            if line.starts_with("#target name") {
+                tblout = Some(1);
+            } else if line.starts_with("#idx target name") {
+                tblout = Some(2);
+            }
+        }
+        let record = match tblout {
+            Some(fmt) => parse_tblout_line(&line, i + 1, fmt).map_err(invalid)?,
+            None => {
+                if let Some(name) = line.strip_prefix("Query:") {
+                    query = name.split_whitespace().next().unwrap_or_default().to_string();
+                } else if line == "Hit scores:" {
+                    in_table = true;
+                } else if line.is_empty() {
+                    in_table = false;
+                }
+                if in_table { parse_record(&line, i + 1, &query).map_err(invalid)? } else { None }
+            }
+        };
+        if let Some(record) = record {
+            records.push(record);
+        }
+    }
+
+    let rounded = tblout.is_some() || records.iter().any(|r| r.rank.is_some());
+    for records in records.chunk_by(|a, b| a.query == b.query) {
+        check_query_order(records, sort, rounded).map_err(invalid)?;
+    }
+
+    Ok(records.len())
+}
+
+// Ranks, duplicates, and order of the hits of one query
+fn check_query_order(records: &[ResultRecord], sort: SortOrder, rounded: bool) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for (i, record) in records.iter().enumerate() {
+        if let Some(rank) = record.rank {
+            if rank != i + 1 {
+                return Err(format!("line {}: rank {} found where rank {} was expected (interleaved or duplicated ranks)",
+                                   record.line, rank, i + 1));
+            }
+        }
+
+        if !seen.insert((record.target.as_str(), record.start, record.end, record.strand)) {
+            return Err(format!("line {}: duplicate hit {}:{}-{} ({})", record.line, record.target, record.start, record.end, record.strand));
+        }
+
+        if i > 0 {
+            let prev = &records[i - 1];
+            if prev.key().cmp_by(&record.key(), sort, rounded) == Ordering::Greater {
+                return Err(format!("line {}: hit {}:{} (score {}) is out of order after line {}",
+                                   record.line, record.target, record.start, record.score, prev.line));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_record(line: &str, line_no: usize, query: &str) -> Result<Option<ResultRecord>, String> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('(') {
+        parse_report_line(trimmed, line_no, query).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+// "(  1) ! <evalue> <pvalue> <score> <bias>  <name:35> <start> <end> ..."
+fn parse_report_line(line: &str, line_no: usize, query: &str) -> Result<ResultRecord, String> {
+    let malformed = || format!("line {}: malformed hit line", line_no);
+
+    // (rank) inc E-value score bias name from to strand ...; from > to on
+    // the minus strand
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 9 {
+        return Err(malformed());
+    }
+    let rank = fields[0].strip_prefix('(')
+        .and_then(|f| f.strip_suffix(')'))
+        .and_then(|r| r.parse().ok())
+        .ok_or_else(malformed)?;
+    let evalue = fields[2].parse().map_err(|_| malformed())?;
+    let score = fields[3].parse().map_err(|_| malformed())?;
+    let target = fields[5].to_string();
+    let from: usize = fields[6].parse().map_err(|_| malformed())?;
+    let to: usize = fields[7].parse().map_err(|_| malformed())?;
+    let strand = fields[8].chars().next().ok_or_else(malformed)?;
+
+    Ok(ResultRecord { line: line_no, query: query.to_string(), rank: Some(rank), score, evalue, target, start: from.min(to), end: from.max(to), strand })
+}
+
+// "<target> <acc> <query> <acc> <mdl> <mdl from> <mdl to> <seq from> <seq to> ..."
+// with seq from > seq to on the minus strand; format 2 adds an index before
+// and a clan after these. `#` lines are comments
+fn parse_tblout_line(line: &str, line_no: usize, fmt: u8) -> Result<Option<ResultRecord>, String> {
+    if line.starts_with('#') || line.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut fields: Vec<&str> = line.split_whitespace().collect();
+    let columns = if fmt == 2 { 29 } else { 18 };
+    if fields.len() < columns {
+        return Err(format!("line {}: expected at least {} columns, found {}", line_no, columns, fields.len()));
+    }
+    if fmt == 2 {
+        fields.remove(5);
+        fields.remove(0);
+    }
+
+    let parse = |i: usize| -> Result<f64, String> {
+        fields[i].parse().map_err(|_| format!("line {}: invalid number '{}'", line_no, fields[i]))
+    };
+    let (seq_from, seq_to) = (parse(7)? as usize, parse(8)? as usize);
+
+    Ok(Some(ResultRecord {
+        line: line_no,
+        query: fields[2].to_string(),
+        rank: None,
+        score: parse(14)?,
+        evalue: parse(15)?,
+        target: fields[0].to_string(),
+        start: seq_from.min(seq_to),
+        end: seq_from.max(seq_to),
+        strand: fields[9].chars().next().unwrap_or('+'),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlap::Overlap;
+    use crate::search::{Strand, Truncation};
+
+    fn hit(target: &str, start: usize, evalue: f64) -> Hit {
+        Hit {
+            id: format!("{}:{}", target, start),
+            sequence_name: target.to_string(),
+            sequence_description: String::new(),
+            sequence_length: 1000,
+            start,
+            end: start + 50,
+            strand: Strand::Plus,
+            trunc: Truncation::None,
+            overlap: Overlap::None,
+            score: -evalue.log10(),
+            bias: 0.0,
+            gc: 0.5,
+            pvalue: evalue,
+            evalue,
+            alignment: None,
+            residues: None,
+        }
+    }
+
+    #[test]
+    fn test_merge_sorted_shards() {
+        let hits = vec![
+            hit("a", 1, 1e-9), hit("b", 1, 1e-3), hit("a", 100, 1e-5),
+            hit("c", 1, 1e-5), hit("b", 100, 1e-12), hit("c", 100, 0.1),
+        ];
+        for sort in [SortOrder::Evalue, SortOrder::Position, SortOrder::Target] {
+            let mut expected = hits.clone();
+            expected.sort_by(|a, b| compare_hits_by(sort, a, b));
+            let mut shards = vec![Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+            for (i, hit) in hits.iter().enumerate() {
+                shards[i % 3].push(hit.clone());
+            }
+            for shard in &mut shards {
+                shard.sort_by(|a, b| compare_hits_by(sort, a, b));
+            }
+            let merged = merge_sorted(shards, sort);
+            let ids = |hits: &[Hit]| hits.iter().map(|hit| hit.id.clone()).collect::<Vec<_>>();
+            assert_eq!(ids(&merged), ids(&expected));
+        }
+    }
+
+    const FMT1: &str = "chr1                 -         tRNA                 -          cm        1       46      241      286      +    no    1 0.57   0.0   40.0   2.1e-11 !   -";
+    const FMT2: &str = "2    chr1                 -         tRNA                 -         CL00001    cm        1       40      280      241      -    no    1 0.55   0.0   36.2   3.4e-10 !     =      1  1.000  0.870      \"      \"      \"      40     526 -";
+
+    // A report of one query, with a hit table of `rows`
+    fn report(rows: &[&str]) -> String {
+        let mut report = "Query:       tRNA  [CLEN=46]\nHit scores:\n".to_string();
+        report += " rank     E-value  score  bias  sequence  start    end   mdl trunc   gc  description\n";
+        report += " ----   --------- ------ -----  -------- ------ ------   --- ----- ----  -----------\n";
+        for row in rows {
+            report += &format!("{}\n", row);
+        }
+        report + "\n//\n[ok]\n"
+    }
+
+    fn check(text: &str) -> Result<usize, FileError> {
+        let path = std::env::temp_dir().join(format!("order-test-{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let checked = check_order_file(&path, SortOrder::Evalue);
+        std::fs::remove_file(&path).unwrap();
+        checked
+    }
+
+    #[test]
+    fn test_check_order_file() {
+        let first = "  (1) !   3.1e-11   40.0   0.0  chr1        241    286 +  cm    no 0.57  -";
+        let second = "  (2) !   3.4e-10   36.2   0.0  chr2        280    241 -  cm    no 0.55  -";
+        assert_eq!(check(&report(&[first, second])).unwrap(), 2);
+
+        let third = "  (3) ?     0.012   18.5   0.1  chr3         10     60 +  cm    no 0.40  -";
+        let err = check(&report(&[first, &third.replace("(3)", "(2)"), &second.replace("(2)", "(3)")])).unwrap_err();
+        assert!(err.to_string().contains("out of order"), "{}", err);
+        let err = check(&report(&[first, third, second])).unwrap_err();
+        assert!(err.to_string().contains("rank 3 found where rank 2 was expected"), "{}", err);
+        let err = check(&report(&[first, &first.replace("(1)", "(2)")])).unwrap_err();
+        assert!(err.to_string().contains("duplicate hit chr1:241-286"), "{}", err);
+
+        // The hits of each query of a --tblout are ordered on their own
+        let header = "#target name         accession query name           accession mdl mdl from   mdl to seq from   seq to strand trunc pass   gc  bias  score   E-value inc description of target\n";
+        let other = FMT1.replace("tRNA ", "rRNA ").replace("2.1e-11", "1.0e-20");
+        assert_eq!(check(&format!("{}{}\n{}\n", header, FMT1, other)).unwrap(), 2);
+        let worse = FMT1.replace("chr1", "chr2").replace("2.1e-11", "1.0e-05");
+        assert!(check(&format!("{}{}\n{}\n", header, worse, FMT1)).is_err());
+        // Hits on both strands at the same coordinates are not duplicates
+        let minus = FMT1.replace("241      286      +", "286      241      -").replace("2.1e-11", "3.0e-10");
+        assert_eq!(check(&format!("{}{}\n{}\n", header, FMT1, minus)).unwrap(), 2);
+        let err = check(&format!("{}{}\n{}\n", header, FMT1, FMT1.replace("2.1e-11", "3.0e-10"))).unwrap_err();
+        assert!(err.to_string().contains("duplicate hit chr1:241-286 (+)"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_query_order() {
+        let record = |line: usize, target: &str, evalue: f64, rank: Option<usize>| ResultRecord {
+            line,
+            query: "tRNA".to_string(),
+            rank,
+            score: -evalue.log10(),
+            evalue,
+            target: target.to_string(),
+            start: 1,
+            end: 50,
+            strand: '+',
+        };
+        let records = [record(1, "a", 1e-9, Some(1)), record(2, "b", 1e-5, Some(2))];
+        assert!(check_query_order(&records, SortOrder::Evalue, true).is_ok());
+        assert!(check_query_order(&records, SortOrder::Target, true).is_ok());
+        let records = [record(1, "b", 1e-9, None), record(2, "a", 1e-5, None)];
+        assert!(check_query_order(&records, SortOrder::Evalue, false).is_ok());
+        assert!(check_query_order(&records, SortOrder::Target, false).is_err());
+        let records = [record(1, "a", 1e-9, Some(2)), record(2, "b", 1e-5, Some(1))];
+        assert!(check_query_order(&records, SortOrder::Evalue, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_report_line() {
+        let line = "(2) !   3.4e-10   36.2   0.0  chr2        280    241 -  cm    no 0.55  -";
+        let record = parse_report_line(line, 7, "tRNA").unwrap();
+        assert_eq!((record.line, record.query.as_str(), record.rank), (7, "tRNA", Some(2)));
+        assert_eq!((record.score, record.evalue), (36.2, 3.4e-10));
+        assert_eq!((record.target.as_str(), record.start, record.end, record.strand), ("chr2", 241, 280, '-'));
+
+        let err = parse_report_line("(2) !   3.4e-10   36.2   0.0  chr2", 7, "tRNA").unwrap_err();
+        assert_eq!(err.to_string(), "line 7: malformed hit line");
+        assert!(parse_report_line(&line.replace("(2)", "(two)"), 7, "tRNA").is_err());
+    }
+
+    #[test]
+    fn test_parse_tblout_line() {
+        let record = parse_tblout_line(FMT1, 3, 1).unwrap().unwrap();
+        assert_eq!((record.query.as_str(), record.rank, record.score, record.evalue), ("tRNA", None, 40.0, 2.1e-11));
+        assert_eq!((record.target.as_str(), record.start, record.end, record.strand), ("chr1", 241, 286, '+'));
+
+        // Format 2 has an index and clan column, and the minus strand's
+        // coordinates are reversed
+        let record = parse_tblout_line(FMT2, 4, 2).unwrap().unwrap();
+        assert_eq!((record.query.as_str(), record.score, record.evalue), ("tRNA", 36.2, 3.4e-10));
+        assert_eq!((record.target.as_str(), record.start, record.end, record.strand), ("chr1", 241, 280, '-'));
+
+        assert!(parse_tblout_line("# Program: cmsearch", 1, 1).unwrap().is_none());
+        let err = parse_tblout_line(FMT1, 3, 2).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: expected at least 29 columns, found 18");
+        let err = parse_tblout_line(&FMT1.replace("40.0", "forty"), 3, 1).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: invalid number 'forty'");
+    }
+}
@@ -1,106 +1,1480 @@
-use anyhow::Result;
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, BufWriter, Write};
 use std::fs::File;
-use std::path::Path;
-use log::{debug, info};
-use crate::config::Config;
-use crate::search::Hit;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use log::warn;
+use serde::Serialize;
+use crate::config::{Config, MsaFormat, SeqAlphabet, SortOrder, StageKind, TruncMode};
+use crate::align::Alignment;
+use crate::cm::{Cm, Consensus};
+use crate::digital;
+use crate::error::OutputError;
+use crate::order;
+use crate::overlap::{self, OverlapWith};
+use crate::pipeline::{PipelineSummary, StageSummary};
+use crate::search::{Hit, Strand, Truncation};
+use crate::stage::{HistogramBin, ScoreHistogram};
+use crate::utils;
 
+// In place of an empty hit table or alignment section, as Infernal writes it
+const NO_HITS: &str = "   [No hits detected that satisfy reporting thresholds]";
+
+// Stated in the output headers: the layouts are Infernal's, the scores are not
+const SCORES_NOTE: &str = "Infernal-format output; bit scores are ungapped consensus scores, not CM scores";
+
+const PREAMBLE_RULE: &str = "# - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -";
+
+/// Formats of the optional output files, each written once the search is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileFormat {
+    Tblout,
+    Json,
+    Sam,
+    Msa,
+    HitFasta,
+    Gff,
+    StatsJson,
+    Histogram,
+}
+
+impl FileFormat {
+    // Option naming the file
+    fn option(self) -> &'static str {
+        match self {
+            FileFormat::Tblout => "--tblout",
+            FileFormat::Json => "--json",
+            FileFormat::Sam => "--sam",
+            FileFormat::Msa => "-A",
+            FileFormat::HitFasta => "--hitfasta",
+            FileFormat::Gff => "--gff",
+            FileFormat::StatsJson => "--stats-json",
+            FileFormat::Histogram => "--histogram",
+        }
+    }
+}
+
+struct OutputFile {
+    format: FileFormat,
+    path: String,
+    out: BufWriter<File>,
+}
+
+impl OutputFile {
+    fn error(&self, source: io::Error) -> OutputError {
+        OutputError::Write { target: format!("{} file {}", self.format.option(), self.path), source }
+    }
+}
+
+fn report_error(source: io::Error) -> OutputError {
+    OutputError::Write { target: "the report".to_string(), source }
+}
+
+/// The results of one query model: the model as the outputs describe it,
+/// its hits, and its pipeline's summary.
+pub struct QueryResult {
+    pub name: String,
+    pub accession: String, // "-" if the model has none
+    pub description: Option<String>,
+    pub length: usize,
+    pub consensus: Consensus,
+    pub clan: Option<String>, // From --clanin
+    pub hits: Vec<Hit>,
+    pub summary: PipelineSummary,
+}
+
+impl QueryResult {
+    pub fn new(cm: &Cm, hits: Vec<Hit>, summary: PipelineSummary) -> Self {
+        Self {
+            name: cm.name.clone(),
+            accession: cm.accession.clone().unwrap_or_else(|| "-".to_string()),
+            description: cm.description.clone(),
+            length: cm.length,
+            consensus: cm.consensus.clone(),
+            clan: None,
+            hits,
+            summary,
+        }
+    }
+}
+
+/// A target sequence of a scan, which cmscan's report calls the query.
+pub struct ScanSequence {
+    pub name: String,
+    pub description: String, // Empty if there is none
+    pub length: usize,
+}
+
+// Hits of a --tblout table whose overlaps are annotated together, each
+// with its query model: those of one model in a search, or those of every
+// model on one sequence in a scan
+type HitGroup<'a> = Vec<(&'a QueryResult, &'a Hit)>;
+
+/// Writes the results of a search: the report to -o (default stdout), and
+/// each requested output file.
 pub struct OutputWriter {
     config: Config,
     output: Box<dyn Write>,
+    files: Vec<OutputFile>,
+    started: Instant,
+    date: SystemTime, // When the run started
 }
 
 impl OutputWriter {
-    pub fn new(config: &Config) -> Result<Self> {
+    pub fn new(config: &Config) -> Result<Self, OutputError> {
+        let requested = requested_files(config);
+        let mut paths: Vec<(&'static str, &str)> = Vec::new();
+        for (option, path) in destinations(config) {
+            if let Some(&(first, _)) = paths.iter().find(|(_, p)| *p == path) {
+                return Err(OutputError::SamePath { first, second: option, path: path.to_string() });
+            }
+            paths.push((option, path));
+        }
+        
         let output: Box<dyn Write> = match &config.output {
             Some(path) => {
-                let file = File::create(path)?;
+                let file = File::create(path)
+                    .map_err(|source| OutputError::Create { option: "-o", path: path.clone(), source })?;
                 Box::new(file)
             }
             None => Box::new(io::stdout()),
         };
+        let files = requested
+            .into_iter()
+            .filter_map(|(format, path)| Some((format, path.clone()?)))
+            .map(|(format, path)| {
+                let file = File::create(&path)
+                    .map_err(|source| OutputError::Create { option: format.option(), path: path.clone(), source })?;
+                Ok(OutputFile { format, path, out: BufWriter::new(file) })
+            })
+            .collect::<Result<Vec<_>, OutputError>>()?;
         
         Ok(Self {
             config: config.clone(),
             output,
+            files,
+            started: Instant::now(),
+            date: SystemTime::now(),
         })
     }
     
-    pub fn write_hits(&mut self, hits: &[Hit]) -> Result<()> {
-        if self.config.tabular {
-            self.write_tabular(hits)?;
+    /// Write the results of each query model, in order, from a search whose
+    /// E-values assume `db_residues` residues (on the strands searched).
+    pub fn write_hits(&mut self, queries: &[QueryResult], db_residues: u64) -> Result<(), OutputError> {
+        self.write_standard(queries, db_residues)
+            .and_then(|()| self.output.flush())
+            .map_err(report_error)?;
+        
+        for mut file in std::mem::take(&mut self.files) {
+            self.write_file(&mut file, queries, db_residues).map_err(|source| file.error(source))?;
+        }
+        Ok(())
+    }
+
+    // One output file of a search, flushed
+    fn write_file(&self, file: &mut OutputFile, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        let out = &mut file.out;
+        match file.format {
+            FileFormat::Tblout => {
+                let groups: Vec<HitGroup> = queries.iter()
+                    .map(|query| query.hits.iter().map(|hit| (query, hit)).collect())
+                    .collect();
+                self.write_tblout(out, queries, &groups, db_residues)?
+            }
+            FileFormat::Json => {
+                for query in queries {
+                    self.write_json(out, query, db_residues)?;
+                }
+            }
+            FileFormat::Sam => self.write_sam(out, queries, db_residues)?,
+            FileFormat::Msa => {
+                let metadata = self.run_metadata(queries, db_residues);
+                for query in queries {
+                    self.write_msa(out, query, &metadata, false)?;
+                }
+            }
+            FileFormat::HitFasta => {
+                for query in queries {
+                    write_hitfasta(out, &query.hits)?;
+                }
+            }
+            FileFormat::Gff => self.write_gff(out, queries, db_residues)?,
+            FileFormat::StatsJson => self.write_stats_json(out, queries, db_residues)?,
+            FileFormat::Histogram => self.write_histogram(out, queries, db_residues)?,
+        }
+        out.flush()
+    }
+    
+    /// Write the results of a scan: the hits of every model on each target
+    /// sequence, in the order the sequences were read, to the report and
+    /// --tblout.
+    pub fn write_scan(&mut self, sequences: &[ScanSequence], models: &[QueryResult], db_residues: u64) -> Result<(), OutputError> {
+        let groups = scan_groups(sequences, models, self.config.sort);
+        self.write_scan_report(sequences, &groups, models, db_residues)
+            .and_then(|()| self.output.flush())
+            .map_err(report_error)?;
+        
+        for mut file in std::mem::take(&mut self.files) {
+            match file.format {
+                FileFormat::Tblout => self.write_tblout(&mut file.out, models, &groups, db_residues)
+                    .and_then(|()| file.out.flush())
+                    .map_err(|source| file.error(source))?,
+                format => return Err(OutputError::NotScanned { option: format.option() }),
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write the alignment of each sequence to the model as one multiple
+    /// alignment in the --outformat format, to the report: the output of
+    /// `align`. Columns beyond the ends of fragments are '~'.
+    pub fn write_aligned(&mut self, query: &QueryResult) -> Result<(), OutputError> {
+        let path = if self.config.acc { "optimal accuracy" } else { "Viterbi" };
+        let method = format!("{} path of the profile HMM built from the CM consensus; base pairs are not scored", path);
+        let mut metadata: Vec<_> = self.run_metadata(std::slice::from_ref(query), 0)
+            .into_iter()
+            .filter(|(label, _)| *label != "Z")
+            .collect();
+        metadata.push(("Alignment", method));
+        let mut msa = Vec::new();
+        self.write_msa(&mut msa, query, &metadata, true)
+            .and_then(|()| self.output.write_all(&msa))
+            .and_then(|()| self.output.flush())
+            .map_err(|source| OutputError::Write { target: "the alignment".to_string(), source })
+    }
+    
+    // Provenance of the run, written at the top of each output format that
+    // has comments: (label, value)
+    fn run_metadata(&self, queries: &[QueryResult], db_residues: u64) -> Vec<(&'static str, String)> {
+        let query = model_names(queries);
+        let [query_input, target_input] = if self.config.scan {
+            [("Query file", self.config.seqdb.clone()), ("Target CM", format!("{} from {}", query, self.config.cmfile))]
+        } else {
+            [("Query CM", format!("{} from {}", query, self.config.cmfile)), ("Target file", self.config.seqdb.clone())]
+        };
+        vec![
+            ("Program", format!("improved-cmsearch {}", crate::VERSION)),
+            ("Command line", env::args().collect::<Vec<_>>().join(" ")),
+            query_input,
+            target_input,
+            ("Date", utils::format_ctime(self.date)),
+            ("Z", format!("{} residues, {}", db_residues, self.config.strands.label())),
+        ]
+    }
+    
+    // Infernal's report: a preamble of the inputs and options, then for each
+    // query its hit table, alignments unless --noali, and the pipeline
+    // statistics, ending in "//"
+    fn write_standard(&mut self, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        // The targets were read once for all the queries
+        self.write_preamble(db_residues, queries.first().map_or(0, |query| query.summary.residues))?;
+        for query in queries {
+            let hits = &query.hits;
+            writeln!(self.output, "Query:       {}  [CLEN={}]", query.name, query.length)?;
+            if query.accession != "-" {
+                writeln!(self.output, "Accession:   {}", query.accession)?;
+            }
+            if let Some(description) = &query.description {
+                writeln!(self.output, "Description: {}", description)?;
+            }
+            let rows: Vec<_> = hits.iter().map(|hit| (hit.sequence_name.as_str(), description_or_dash(hit), hit)).collect();
+            self.write_hit_table("sequence", &rows)?;
+            
+            if self.config.alignments {
+                writeln!(self.output)?;
+                writeln!(self.output)?;
+                writeln!(self.output, "Hit alignments:")?;
+                if hits.is_empty() {
+                    writeln!(self.output)?;
+                    writeln!(self.output, "{}", NO_HITS)?;
+                }
+                for (i, hit) in hits.iter().enumerate() {
+                    writeln!(self.output, ">> {}  {}", hit.sequence_name, description_or_dash(hit))?;
+                    self.write_alignment(query, i + 1, hit)?;
+                }
+            }
+            
+            writeln!(self.output)?;
+            writeln!(self.output)?;
+            self.write_statistics(query)?;
+            writeln!(self.output, "//")?;
+        }
+        writeln!(self.output, "[ok]")?;
+        Ok(())
+    }
+    
+    // cmscan's report: after the preamble, each target sequence as a query,
+    // with the hits of every model on it and their alignments, ending in
+    // "//"
+    fn write_scan_report(&mut self, sequences: &[ScanSequence], groups: &[HitGroup], models: &[QueryResult],
+                         db_residues: u64) -> io::Result<()> {
+        let searched = sequences.iter().map(|sequence| sequence.length as u64).sum();
+        self.write_preamble(db_residues, searched)?;
+        for (sequence, group) in sequences.iter().zip(groups) {
+            writeln!(self.output, "Query:       {}  [L={}]", sequence.name, sequence.length)?;
+            if !sequence.description.is_empty() {
+                writeln!(self.output, "Description: {}", sequence.description)?;
+            }
+            let rows: Vec<_> = group.iter()
+                .map(|&(model, hit)| (model.name.as_str(), model.description.as_deref().unwrap_or("-"), hit))
+                .collect();
+            self.write_hit_table("modelname", &rows)?;
+            
+            if self.config.alignments {
+                writeln!(self.output)?;
+                writeln!(self.output)?;
+                writeln!(self.output, "Hit alignments:")?;
+                if group.is_empty() {
+                    writeln!(self.output)?;
+                    writeln!(self.output, "{}", NO_HITS)?;
+                }
+                for (i, &(model, hit)) in group.iter().enumerate() {
+                    writeln!(self.output, ">> {}  {}", model.name, model.description.as_deref().unwrap_or("-"))?;
+                    self.write_alignment(model, i + 1, hit)?;
+                }
+            }
+            writeln!(self.output)?;
+            writeln!(self.output, "//")?;
+        }
+        let hits = groups.iter().map(Vec::len).sum::<usize>();
+        writeln!(self.output, "# {} models, {} sequences, {} hits reported", models.len(), sequences.len(), hits)?;
+        writeln!(self.output, "[ok]")?;
+        Ok(())
+    }
+    
+    // `searched` is the target residues read, one strand
+    fn write_preamble(&mut self, db_residues: u64, searched: u64) -> io::Result<()> {
+        let mut options = if self.config.scan {
+            vec![
+                ("query sequence file:", self.config.seqdb.clone()),
+                ("target CM database:", self.config.cmfile.clone()),
+            ]
         } else {
-            self.write_standard(hits)?;
+            vec![
+                ("query CM file:", self.config.cmfile.clone()),
+                ("target sequence database:", self.config.seqdb.clone()),
+            ]
+        };
+        if let Some(path) = &self.config.output {
+            options.push(("output directed to file:", path.clone()));
+        }
+        if let Some(path) = &self.config.tblout {
+            options.push(("tabular output of hits:", path.clone()));
+        }
+        if self.config.evalue != Config::new().evalue {
+            options.push(("sequence reporting threshold:", format!("E-value <= {}", self.config.evalue)));
+        }
+        if let Some(score) = self.config.score {
+            options.push(("sequence reporting threshold:", format!("score >= {}", score)));
+        }
+        if self.config.inc_evalue != Config::new().inc_evalue || self.config.inc_score.is_some() {
+            options.push(("sequence inclusion threshold:", self.inclusion()));
         }
+        // With -Z, or records skipped by --resume, E-values assume a database
+        // size other than the residues searched
+        let z_mb = utils::format_g(db_residues as f64 / 1e6, 6);
+        if db_residues != self.config.searched_residues(searched) {
+            options.push(("database size is set to:", format!("{} Mb", z_mb)));
+        } else {
+            options.push(("database size (Z):", format!("{} Mb, {}", z_mb, self.config.strands.label())));
+        }
+        if self.config.threads > 1 {
+            options.push(("number of worker threads:", self.config.threads.to_string()));
+        }
+        options.push(("command line:", env::args().collect::<Vec<_>>().join(" ")));
+        options.push(("date:", utils::format_ctime(self.date)));
+        
+        if self.config.scan {
+            writeln!(self.output, "# cmscan :: search sequence(s) against a CM database")?;
+        } else {
+            writeln!(self.output, "# cmsearch :: search CM(s) against a sequence database")?;
+        }
+        writeln!(self.output, "# improved-cmsearch {} ({})", crate::VERSION, SCORES_NOTE)?;
+        writeln!(self.output, "{}", PREAMBLE_RULE)?;
+        for (label, value) in options {
+            writeln!(self.output, "# {:<39}{}", label, value)?;
+        }
+        writeln!(self.output, "{}", PREAMBLE_RULE)?;
+        writeln!(self.output)?;
+        Ok(())
+    }
+    
+    // The inclusion threshold, as the report states it
+    fn inclusion(&self) -> String {
+        match self.config.inc_score {
+            Some(score) => format!("score >= {}", score),
+            None => format!("E-value <= {}", utils::format_g(self.config.inc_evalue, 6)),
+        }
+    }
+    
+    // "Hit scores", one row per (name, description, hit), the name column
+    // titled `title`: '!' marks hits within the inclusion threshold, '?'
+    // those reported but not included; when hits are sorted by
+    // significance, a line separates the two
+    fn write_hit_table(&mut self, title: &str, rows: &[(&str, &str, &Hit)]) -> io::Result<()> {
+        let name_width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0).max(title.len());
+        let pos_width = rows.iter().map(|(_, _, h)| h.end.to_string().len()).max().unwrap_or(0).max(6);
+        // Descriptions are cut to keep rows within the text width
+        let description_width = self.config.textw
+            .map_or(usize::MAX, |textw| textw.saturating_sub(55 + name_width + 2 * pos_width).max(11));
         
+        writeln!(self.output, "Hit scores:")?;
+        writeln!(self.output, " {:>4} {:1} {:>9} {:>6} {:>5}  {:<nw$} {:>pw$} {:>pw$} {:1} {:>3} {:>5} {:>4}  description",
+            "rank", "", "E-value", "score", "bias", title, "start", "end", "", "mdl", "trunc", "gc",
+            nw = name_width, pw = pos_width)?;
+        writeln!(self.output, " {:>4} {:1} {:>9} {:>6} {:>5}  {:<nw$} {:>pw$} {:>pw$} {:1} {:>3} {:>5} {:>4}  -----------",
+            "----", "", "---------", "------", "-----", "-".repeat(name_width), "-".repeat(pos_width),
+            "-".repeat(pos_width), "", "---", "-----", "----", nw = name_width, pw = pos_width)?;
+        if rows.is_empty() {
+            writeln!(self.output)?;
+            writeln!(self.output, "{}", NO_HITS)?;
+        }
+        
+        let mut below_inclusion = false;
+        for (i, &(name, description, hit)) in rows.iter().enumerate() {
+            let included = self.config.includes(hit);
+            if !included && !below_inclusion && self.config.sort.by_significance() {
+                writeln!(self.output, "  ------ inclusion threshold ------")?;
+                below_inclusion = true;
+            }
+            let (seq_from, seq_to) = seq_coords(hit);
+            writeln!(self.output, " {:>4} {} {:>9} {:>6.1} {:>5.1}  {:<nw$} {:>pw$} {:>pw$} {} {:>3} {:>5} {:>4.2}  {}",
+                format!("({})", i + 1), if included { '!' } else { '?' }, utils::format_g(hit.evalue, 2),
+                hit.score, hit.bias, name, seq_from, seq_to, hit.strand.symbol(), "cm",
+                hit.trunc.label(), hit.gc, truncate(description, description_width),
+                nw = name_width, pw = pos_width)?;
+        }
         Ok(())
     }
     
-    fn write_standard(&mut self, hits: &[Hit]) -> Result<()> {
-        writeln!(self.output, "Infernal 1.1.5 (Rust implementation)")?;
-        writeln!(self.output, "Query:       {}", self.config.cmfile)?;
-        writeln!(self.output, "Target:      {}", self.config.seqdb)?;
-        writeln!(self.output, "Hits:        {}", hits.len())?;
+    // One hit of "Hit alignments", after its `>>` line: its scores and
+    // coordinates, with `[`/`]` where the alignment reaches an end of the
+    // model or target (`.` otherwise), then the alignment in blocks wrapped
+    // to the text width
+    fn write_alignment(&mut self, query: &QueryResult, rank: usize, hit: &Hit) -> io::Result<()> {
+        let Some(alignment) = &hit.alignment else {
+            writeln!(self.output, "   [alignment not computed: over --mx-size]")?;
+            writeln!(self.output)?;
+            return Ok(());
+        };
+        
+        let (seq_from, seq_to) = seq_coords(hit);
+        let bracket = |at_start: bool, at_end: bool| format!("{}{}", if at_start { '[' } else { '.' }, if at_end { ']' } else { '.' });
+        let model_ends = bracket(alignment.model_from == 1, alignment.model_to == query.length);
+        let seq_ends = match hit.strand {
+            Strand::Plus => bracket(hit.start == 0, hit.end == hit.sequence_length),
+            Strand::Minus => bracket(hit.end == hit.sequence_length, hit.start == 0),
+        };
+        let acc = alignment.accuracy.map_or("-".to_string(), |acc| format!("{:.2}", acc));
+        let inc = if self.config.includes(hit) { '!' } else { '?' };
+        
+        writeln!(self.output, " {:>4} {:1} {:>9} {:>6} {:>5} {:>3} {:>8} {:>8} {:>2} {:>11} {:>11} {:1} {:>2} {:>5} {:>5} {:>4}",
+            "rank", "", "E-value", "score", "bias", "mdl", "mdl from", "mdl to", "", "seq from", "seq to", "", "", "acc", "trunc", "gc")?;
+        writeln!(self.output, " {:>4} {:1} {:>9} {:>6} {:>5} {:>3} {:>8} {:>8} {:>2} {:>11} {:>11} {:1} {:>2} {:>5} {:>5} {:>4}",
+            "----", "", "---------", "------", "-----", "---", "--------", "--------", "", "-----------", "-----------", "", "", "-----", "-----", "----")?;
+        writeln!(self.output, " {:>4} {} {:>9} {:>6.1} {:>5.1} {:>3} {:>8} {:>8} {} {:>11} {:>11} {} {} {:>5} {:>5} {:>4.2}",
+            format!("({})", rank), inc, utils::format_g(hit.evalue, 2), hit.score, hit.bias, "cm",
+            alignment.model_from, alignment.model_to, model_ends, seq_from, seq_to, hit.strand.symbol(), seq_ends,
+            acc, hit.trunc.label(), hit.gc)?;
         writeln!(self.output)?;
         
-        if !hits.is_empty() {
-            writeln!(self.output, "Hit scores:")?;
-            writeln!(self.output, "  rank     E-value  score  bias  sequence                               start    end   mdl trunc   gc  description")?;
-            writeln!(self.output, " -----   --------- ------ -----  ------------------------------------- ------ ------   --- ----- ----  -----------")?;
+        let name_width = query.name.len().max(hit.sequence_name.len());
+        let coord_width = [alignment.model_to, seq_from, seq_to].iter().map(|c| c.to_string().len()).max().unwrap_or(1);
+        let pad = " ".repeat(2 + name_width + 1 + coord_width + 1);
+        let columns = alignment.target.len();
+        let block_width = match self.config.textw {
+            Some(textw) => textw.saturating_sub(pad.len() + 1 + coord_width).max(10),
+            None => columns.max(1),
+        };
+        let nc = alignment.noncanonical();
+        
+        // Next model and target positions at the start of each block
+        let (mut model_pos, mut seq_pos) = (alignment.model_from, seq_from);
+        for start in (0..columns).step_by(block_width) {
+            let end = (start + block_width).min(columns);
+            let model = &alignment.model[start..end];
+            let target = &alignment.target[start..end];
+            let model_residues = model.bytes().filter(|&c| c != b'.').count();
+            let seq_residues = target.bytes().filter(|&c| c != b'-').count();
+            let model_end = (model_pos + model_residues).saturating_sub(1);
+            let seq_end = match hit.strand {
+                Strand::Plus => (seq_pos + seq_residues).saturating_sub(1),
+                Strand::Minus => seq_pos + 1 - seq_residues.min(seq_pos + 1),
+            };
             
-            for (i, hit) in hits.iter().enumerate() {
-                let rank = i + 1;
-                let evalue_str = if hit.evalue < 1e-10 { "0".to_string() } else { format!("{:.1e}", hit.evalue) };
-                let score_str = format!("{:.1}", hit.score * 1000.0); // Scale score to match cmsearch format
-                let bias = "0.0";
-                let sequence_name = if hit.sequence_name.len() > 35 {
-                    format!("{}...", &hit.sequence_name[..32])
-                } else {
-                    format!("{:<35}", hit.sequence_name)
-                };
-                let start = hit.start + 1;
-                let end = hit.end;
-                let mdl = "cm";
-                let trunc = "no";
-                let gc = "0.55"; // Default GC content
-                let description = "-";
+            if let Some(nc) = &nc {
+                writeln!(self.output, "{}{} NC", pad, &nc[start..end])?;
+            }
+            writeln!(self.output, "{}{} CS", pad, &alignment.structure[start..end])?;
+            writeln!(self.output, "  {:>nw$} {:>cw$} {} {}", query.name, model_pos, model, model_end,
+                nw = name_width, cw = coord_width)?;
+            writeln!(self.output, "{}{}", pad, &alignment.matches[start..end])?;
+            writeln!(self.output, "  {:>nw$} {:>cw$} {} {}", hit.sequence_name, seq_pos, target, seq_end,
+                nw = name_width, cw = coord_width)?;
+            if let Some(pp) = &alignment.posteriors {
+                writeln!(self.output, "{}{} PP", pad, &pp[start..end])?;
+            }
+            writeln!(self.output)?;
+            
+            model_pos += model_residues;
+            seq_pos = match hit.strand {
+                Strand::Plus => seq_pos + seq_residues,
+                Strand::Minus => seq_pos - seq_residues,
+            };
+        }
+        Ok(())
+    }
+    
+    // "Internal CM pipeline statistics summary": the sequences searched, and
+    // the windows passing each filter stage, with the fraction of residues
+    // (on the strands searched) they cover against the stage's P-value
+    // threshold. Infernal prints that threshold as the expected pass rate;
+    // here every stage thresholds the 2^-s bound on its score's P-value, not
+    // a calibrated one, so it is labelled as the threshold it is
+    fn write_statistics(&mut self, query: &QueryResult) -> io::Result<()> {
+        let (hits, summary) = (&query.hits, &query.summary);
+        let searched = self.config.searched_residues(summary.residues);
+        let fraction = |residues: u64| if searched == 0 { 0.0 } else { residues as f64 / searched as f64 };
+        
+        writeln!(self.output, "Internal CM pipeline statistics summary:")?;
+        writeln!(self.output, "----------------------------------------")?;
+        writeln!(self.output, "{:<50} {:>15}  ({} consensus positions)", "Query model(s):", 1, query.length)?;
+        writeln!(self.output, "{:<50} {:>15}  ({} residues searched)", "Target sequences:", summary.targets, searched)?;
+        if let Some(first) = summary.trunc_stages.first() {
+            writeln!(self.output, "{:<50} {:>15}  ({} residues re-searched)",
+                "Windows re-searched for truncated hits:", first.entered, first.residues)?;
+        }
+        for kind in StageKind::FILTERS {
+            let label = stage_label(kind);
+            match summary.stages.iter().find(|stage| stage.kind == kind) {
+                Some(stage) => writeln!(self.output, "{:<50} {:>15}  ({}); threshold ({})", label, stage.passed,
+                    utils::format_g(fraction(stage.passed_residues), 4), utils::format_g(stage.threshold, 4))?,
+                None => writeln!(self.output, "{:<50} {:>15}  (off)", label, "")?,
+            }
+        }
+        let hit_residues = hits.iter().map(|hit| (hit.end - hit.start) as u64).sum();
+        let truncated = hits.iter().filter(|hit| hit.trunc != Truncation::None).count();
+        writeln!(self.output, "{:<50} {:>15}  ({}); includes {} truncated hit(s)", "Total CM hits reported:",
+            hits.len(), utils::format_g(fraction(hit_residues), 4), truncated)?;
+        writeln!(self.output)?;
+        let elapsed = format_elapsed(self.started.elapsed());
+        match utils::cpu_times() {
+            Some((user, system)) => writeln!(self.output, "# CPU time: {:.2}u {:.2}s {} Elapsed: {}",
+                user.as_secs_f64(), system.as_secs_f64(), format_elapsed(user + system), elapsed)?,
+            None => writeln!(self.output, "# Elapsed: {}", elapsed)?,
+        }
+        if let Some(peak) = utils::peak_memory() {
+            writeln!(self.output, "# Peak memory: {} resident; {} estimated in DP matrices and sequence buffers",
+                utils::format_bytes(peak), utils::format_bytes(summary.memory_peak))?;
+        }
+        Ok(())
+    }
+    
+    // The statistics summary as one JSON object (--stats-json), or an array
+    // of one per query when several models were searched
+    fn write_stats_json(&self, out: &mut impl Write, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        let cpu = utils::cpu_times();
+        let stats: Vec<JsonStats> = queries.iter().map(|query| {
+            let (hits, summary) = (&query.hits, &query.summary);
+            JsonStats {
+                program: "improved-cmsearch",
+                version: crate::VERSION,
+                command: env::args().collect(),
+                date: utils::format_rfc3339(self.date),
+                cm_file: &self.config.cmfile,
+                target_file: &self.config.seqdb,
+                db_residues,
+                query: JsonQuery::new(query),
+                targets: summary.targets,
+                residues_searched: self.config.searched_residues(summary.residues),
+                stages: &summary.stages,
+                trunc_stages: &summary.trunc_stages,
+                hits_reported: hits.len(),
+                hits_included: hits.iter().filter(|hit| self.config.includes(hit)).count(),
+                truncated_hits: hits.iter().filter(|hit| hit.trunc != Truncation::None).count(),
+                cpu_user: cpu.map(|(user, _)| user.as_secs_f64()),
+                cpu_system: cpu.map(|(_, system)| system.as_secs_f64()),
+                elapsed: self.started.elapsed().as_secs_f64(),
+            }
+        }).collect();
+        match stats.as_slice() {
+            [stats] => serde_json::to_writer_pretty(&mut *out, stats).map_err(io::Error::from)?,
+            stats => serde_json::to_writer_pretty(&mut *out, stats).map_err(io::Error::from)?,
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+    
+    // Infernal's tabular hit table (--tblout): space-aligned columns under
+    // two `#` header lines, then a `#` trailer recording the run. Format 2
+    // adds a leading index, the clan, overlap annotation, and lengths. The
+    // groups follow each other in one table; overlaps are annotated between
+    // hits of the same group. In a scan the targets are the models and the
+    // queries the sequences, as cmscan has them
+    fn write_tblout(&self, out: &mut impl Write, queries: &[QueryResult], groups: &[HitGroup], db_residues: u64) -> io::Result<()> {
+        let fmt2 = self.config.fmt == 2;
+        let scan = self.config.scan;
+        let hits = || groups.iter().flatten();
+        let width = |values: &mut dyn Iterator<Item = usize>, min: usize| values.max().unwrap_or(0).max(min);
+        let model_width = width(&mut queries.iter().map(|q| q.name.len()), 20);
+        let acc_width = width(&mut queries.iter().map(|q| q.accession.len()), 9);
+        let clan_width = width(&mut queries.iter().map(|q| q.clan.as_ref().map_or(0, String::len)), 9);
+        let sequence_width = width(&mut hits().map(|(_, h)| h.sequence_name.len()), 20);
+        let pos_width = width(&mut hits().map(|(_, h)| h.end.to_string().len()), 8);
+        let idx_width = hits().count().to_string().len().max(4);
+        let len_width = width(&mut hits().map(|(_, h)| h.sequence_length.to_string().len()), 7);
+        
+        // (title, width, left-aligned); the last column is not padded
+        let mut columns = Vec::new();
+        if fmt2 {
+            columns.push(("idx", idx_width, true));
+        }
+        if scan {
+            columns.extend([("target name", model_width, true), ("accession", acc_width, true), ("query name", sequence_width, true), ("accession", 9, true)]);
+        } else {
+            columns.extend([("target name", sequence_width, true), ("accession", 9, true), ("query name", model_width, true), ("accession", acc_width, true)]);
+        }
+        if fmt2 {
+            columns.push(("clan name", clan_width, true));
+        }
+        columns.extend([
+            ("mdl", 3, false), ("mdl from", 8, false), ("mdl to", 8, false), ("seq from", pos_width, false),
+            ("seq to", pos_width, false), ("strand", 6, false), ("trunc", 5, false), ("pass", 4, false),
+            ("gc", 4, false), ("bias", 5, false), ("score", 6, false), ("E-value", 9, false), ("inc", 3, true),
+        ]);
+        if fmt2 {
+            columns.extend([
+                ("olp", 3, false), ("anyidx", idx_width.max(6), false), ("afrct1", 6, false), ("afrct2", 6, false),
+                ("winidx", idx_width.max(6), false), ("wfrct1", 6, false), ("wfrct2", 6, false),
+                ("mdl len", 7, false), ("seq len", len_width, false),
+            ]);
+        }
+        columns.push(("description of target", 0, true));
+        
+        let titles: Vec<String> = columns.iter().map(|c| c.0.to_string()).collect();
+        let rules: Vec<String> = columns.iter().enumerate()
+            .map(|(i, c)| "-".repeat(if i == 0 { c.1 - 1 } else { c.1.max(c.0.len()) }))
+            .collect();
+        for header in [titles, rules] {
+            writeln!(out, "#{}", tblout_row(&columns, &header, 1))?;
+        }
+        
+        // Rows before the current group's, for the table-wide index
+        let mut first = 0;
+        for group in groups {
+            let overlaps = if fmt2 {
+                overlap::annotate_table(&group.iter().map(|&(_, hit)| hit).collect::<Vec<_>>())
+            } else {
+                Vec::new()
+            };
+            for (i, &(query, hit)) in group.iter().enumerate() {
+                // Without an alignment (over --mx-size), report the whole model
+                let (model_from, model_to) = hit.alignment.as_ref()
+                    .map_or((1, query.length), |a| (a.model_from, a.model_to));
+                let (seq_from, seq_to) = seq_coords(hit);
+                let inc = if self.config.includes(hit) { "!" } else { "?" };
                 
-                writeln!(self.output, "  ({:3}) ! {:>9} {:>6} {:>5}  {} {:>6} {:>6}   {}   {} {}  {}", 
-                    rank, evalue_str, score_str, bias, sequence_name, start, end, mdl, trunc, gc, description)?;
+                let mut values = Vec::with_capacity(columns.len());
+                if fmt2 {
+                    values.push((first + i + 1).to_string());
+                }
+                if scan {
+                    values.extend([query.name.clone(), query.accession.clone(), hit.sequence_name.clone(), "-".to_string()]);
+                } else {
+                    values.extend([hit.sequence_name.clone(), "-".to_string(), query.name.clone(), query.accession.clone()]);
+                }
+                if fmt2 {
+                    values.push(query.clan.clone().unwrap_or_else(|| "-".to_string()));
+                }
+                values.extend([
+                    "cm".to_string(), model_from.to_string(), model_to.to_string(), seq_from.to_string(),
+                    seq_to.to_string(), hit.strand.symbol().to_string(), hit.trunc.label().to_string(),
+                    self.pass_index(hit.trunc).to_string(), format!("{:.2}", hit.gc), format!("{:.1}", hit.bias),
+                    format!("{:.1}", hit.score), utils::format_g(hit.evalue, 2), inc.to_string(),
+                ]);
+                if fmt2 {
+                    let overlap = overlaps[i];
+                    values.push(overlap.olp.to_string());
+                    values.extend(overlap_columns(overlap.any, first));
+                    // The winning hit is often the best overlapping one; Infernal
+                    // writes `"` (ditto) then
+                    match overlap.win {
+                        Some(win) if Some(win) == overlap.any => values.extend(["\"".to_string(), "\"".to_string(), "\"".to_string()]),
+                        win => values.extend(overlap_columns(win, first)),
+                    }
+                    values.extend([query.length.to_string(), hit.sequence_length.to_string()]);
+                }
+                let description = if scan { query.description.as_deref().unwrap_or("-") } else { description_or_dash(hit) };
+                values.push(description.to_string());
+                writeln!(out, "{}", tblout_row(&columns, &values, 0))?;
             }
+            first += group.len();
+        }
+        
+        let command: Vec<String> = env::args().collect();
+        let cwd = env::current_dir().map_or("[unknown]".to_string(), |d| d.display().to_string());
+        writeln!(out, "#")?;
+        let (program, mode, query_file, target_file, models) = if scan {
+            ("cmscan", "SCAN", &self.config.seqdb, &self.config.cmfile, "Target name")
+        } else {
+            ("cmsearch", "SEARCH", &self.config.cmfile, &self.config.seqdb, "Query name")
+        };
+        writeln!(out, "# Program:         {}", program)?;
+        writeln!(out, "# Version:         improved-cmsearch {} ({})", crate::VERSION, SCORES_NOTE)?;
+        writeln!(out, "# Pipeline mode:   {}", mode)?;
+        writeln!(out, "# Query file:      {}", query_file)?;
+        writeln!(out, "# Target file:     {}", target_file)?;
+        writeln!(out, "# {:<16} {}", format!("{}:", models), model_names(queries))?;
+        writeln!(out, "# Z:               {} residues, {}", db_residues, self.config.strands.label())?;
+        writeln!(out, "# Option settings: {}", command.join(" "))?;
+        writeln!(out, "# Current dir:     {}", cwd)?;
+        writeln!(out, "# Date:            {}", utils::format_ctime(self.date))?;
+        writeln!(out, "# [ok]")?;
+        
+        Ok(())
+    }
+    
+    // JSON Lines: a `run` object describing the search of one query, then
+    // one `hit` object per hit in report order
+    fn write_json(&self, out: &mut impl Write, query: &QueryResult, db_residues: u64) -> io::Result<()> {
+        let hits = &query.hits;
+        let run = JsonRun {
+            kind: "run",
+            program: "improved-cmsearch",
+            version: crate::VERSION,
+            command: env::args().collect(),
+            date: utils::format_rfc3339(self.date),
+            cm_file: &self.config.cmfile,
+            query: JsonQuery::new(query),
+            target_file: &self.config.seqdb,
+            db_residues,
+            hits: hits.len(),
+            config: &self.config,
+        };
+        serde_json::to_writer(&mut *out, &run).map_err(io::Error::from)?;
+        writeln!(out)?;
+        
+        for (i, hit) in hits.iter().enumerate() {
+            // Hits are also aligned for other outputs; --noali leaves them out
+            let alignment = hit.alignment.as_ref().filter(|_| self.config.alignments);
+            serde_json::to_writer(&mut *out, &JsonHit::new(hit, Some(i + 1), self.config.includes(hit), alignment))
+                .map_err(io::Error::from)?;
+            writeln!(out)?;
         }
         
         Ok(())
     }
     
-    fn write_tabular(&mut self, hits: &[Hit]) -> Result<()> {
-        // Write tabular header
-        writeln!(self.output, "#target_name\tquery_name\taccession\ttarget_accession\thmm_from\thmm_to\tali_from\tali_to\tenv_from\tenv_to\tsq_len\tstrand\tevalue\tscore\tbias\tdescription_of_target")?;
-        
-        for hit in hits {
-            writeln!(
-                self.output,
-                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                hit.sequence_name,
-                "test_cm", // query name
-                "-", // accession
-                "-", // target accession
-                hit.start + 1, // hmm_from
-                hit.end, // hmm_to
-                hit.start + 1, // ali_from
-                hit.end, // ali_to
-                hit.start + 1, // env_from
-                hit.end, // env_to
-                hit.end - hit.start, // sq_len
-                "+", // strand
-                hit.evalue, // evalue
-                hit.score, // score
-                0.0, // bias
-                "test sequence" // description
-            )?;
+    // SAM: one record per hit, placed on its target, with the aligned model
+    // consensus as the read (see `sam_alignment`); targets with hits get
+    // @SQ lines. Hits not aligned within --mx-size have no CIGAR or SEQ
+    fn write_sam(&self, out: &mut impl Write, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        let hits = || queries.iter().flat_map(|query| &query.hits);
+        writeln!(out, "@HD\tVN:1.6\tSO:unsorted")?;
+        let mut targets = HashSet::new();
+        for hit in hits() {
+            if targets.insert(hit.sequence_name.as_str()) {
+                writeln!(out, "@SQ\tSN:{}\tLN:{}", hit.sequence_name, hit.sequence_length)?;
+            }
+        }
+        let command: Vec<String> = env::args().collect();
+        writeln!(out, "@PG\tID:improved-cmsearch\tPN:improved-cmsearch\tVN:{}\tCL:{}", crate::VERSION, command.join(" "))?;
+        for (label, value) in self.run_metadata(queries, db_residues) {
+            writeln!(out, "@CO\t{}: {}", label, value)?;
+        }
+        
+        for hit in hits() {
+            let flag = match hit.strand {
+                Strand::Plus => 0,
+                Strand::Minus => 16,
+            };
+            let (cigar, read) = match &hit.alignment {
+                Some(alignment) => sam_alignment(alignment, hit.strand),
+                None => ("*".to_string(), "*".to_string()),
+            };
+            // AS: bit score rounded; ZS, ZE: bit score and E-value
+            writeln!(out, "{}\t{}\t{}\t{}\t255\t{}\t*\t0\t0\t{}\t*\tAS:i:{}\tZS:f:{:.2}\tZE:f:{}",
+                hit.id, flag, hit.sequence_name, hit.start + 1, cigar, read,
+                hit.score.round() as i64, hit.score, utils::format_g(hit.evalue, 3))?;
         }
         
         Ok(())
     }
-} 
\ No newline at end of file
+    
+    // GFF3 features of the included hits: a nucleotide_match per hit, scored in
+    // bits, with the model span as its Target
+    fn write_gff(&self, out: &mut impl Write, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        let hits = || queries.iter()
+            .flat_map(|query| query.hits.iter().map(move |hit| (query, hit)))
+            .filter(|(_, hit)| self.config.includes(hit));
+        writeln!(out, "##gff-version 3")?;
+        for (label, value) in self.run_metadata(queries, db_residues) {
+            writeln!(out, "# {}: {}", label, value)?;
+        }
+        let mut targets = HashSet::new();
+        for (_, hit) in hits() {
+            if targets.insert(hit.sequence_name.as_str()) {
+                writeln!(out, "##sequence-region {} 1 {}", gff_escape(&hit.sequence_name), hit.sequence_length)?;
+            }
+        }
+        
+        for (query, hit) in hits() {
+            let mut attributes = vec![
+                format!("ID={}", hit.id),
+                format!("Name={}", gff_escape(&query.name)),
+            ];
+            if let Some(alignment) = &hit.alignment {
+                attributes.push(format!("Target={} {} {}", gff_escape(&query.name).replace(' ', "%20"),
+                    alignment.model_from, alignment.model_to));
+            }
+            attributes.push(format!("evalue={}", utils::format_g(hit.evalue, 3)));
+            attributes.push(format!("trunc={}", hit.trunc.label()));
+            attributes.push(format!("gc={:.2}", hit.gc));
+            writeln!(out, "{}\timproved-cmsearch\tnucleotide_match\t{}\t{}\t{:.1}\t{}\t.\t{}",
+                gff_escape(&hit.sequence_name), hit.start + 1, hit.end, hit.score, hit.strand.symbol(),
+                attributes.join(";"))?;
+        }
+        Ok(())
+    }
+    
+    // Score histograms (--histogram): the scores of every candidate each
+    // stage scored, passing or not, then those of the reported hits, with
+    // the included ones counted as passing. With several queries, each
+    // query's rows follow a `# Query:` line
+    fn write_histogram(&self, out: &mut impl Write, queries: &[QueryResult], db_residues: u64) -> io::Result<()> {
+        for (label, value) in self.run_metadata(queries, db_residues) {
+            writeln!(out, "# {:<16} {}", format!("{}:", label), value)?;
+        }
+        writeln!(out, "#")?;
+        writeln!(out, "# Bit scores in bins of 1 bit; the end bins are open. Filter stage scores")?;
+        writeln!(out, "# are those of the stage's own model, thresholded by P-value; score is the")?;
+        writeln!(out, "# final score. For hits, passed counts the included hits ({}).", self.inclusion())?;
+        writeln!(out, "{:<10} {:<6} {:>8} {:>8} {:>10} {:>10}", "#pass", "stage", "low", "high", "scored", "passed")?;
+        
+        let write_bins = |out: &mut dyn Write, pass: &str, stage: &str, bins: &[HistogramBin]| -> io::Result<()> {
+            for bin in bins {
+                writeln!(out, "{:<10} {:<6} {:>8.1} {:>8.1} {:>10} {:>10}", pass, stage, bin.low, bin.high, bin.scored, bin.passed)?;
+            }
+            Ok(())
+        };
+        for query in queries {
+            if queries.len() > 1 {
+                writeln!(out, "# Query: {}", query.name)?;
+            }
+            let summary = &query.summary;
+            for (pass, stages) in [("standard", &summary.stages), ("truncated", &summary.trunc_stages)] {
+                for stage in stages {
+                    write_bins(out, pass, stage.kind.name(), &stage.histogram)?;
+                }
+            }
+            let scores = ScoreHistogram::default();
+            for hit in &query.hits {
+                scores.record(hit.score, self.config.includes(hit));
+            }
+            write_bins(out, "reported", "hits", &scores.bins())?;
+        }
+        Ok(())
+    }
+    
+    // Alignment of the hits (-A), one row per hit named target/from-to,
+    // with the residues of each consensus position in one column and
+    // inserts padded with '.' to the longest insert at each position. In
+    // Stockholm, SS_cons and RF come from the model; A2M and aligned FASTA
+    // start with the consensus as a reference row, and A2M leaves inserts
+    // unpadded. Each query's included hits are a separate alignment.
+    // With `sequences`, each hit is a whole sequence aligned by `align`:
+    // its row is named by the sequence alone, and the columns of the model
+    // a fragment does not reach are '~' rather than '-'
+    fn write_msa(&self, out: &mut impl Write, query: &QueryResult, metadata: &[(&str, String)], sequences: bool) -> io::Result<()> {
+        let clen = query.length;
+        let mut rows = Vec::new();
+        for hit in query.hits.iter().filter(|hit| sequences || self.config.includes(hit)) {
+            let Some(alignment) = &hit.alignment else {
+                warn!("Leaving {} out of the -A alignment: it was not aligned (over --mx-size)", hit.id);
+                continue;
+            };
+            let (from, to) = seq_coords(hit);
+            let name = if sequences { hit.sequence_name.clone() } else { format!("{}/{}-{}", hit.sequence_name, from, to) };
+            let gap = if sequences && hit.trunc != Truncation::None { b'~' } else { b'-' };
+            let residues = split_by_position(alignment, &alignment.target, gap, clen);
+            let pp = alignment.posteriors.as_ref().map(|pp| split_by_position(alignment, pp, b'.', clen));
+            rows.push((name, residues, pp));
+        }
+        
+        let mut insert_widths = vec![0; clen + 1];
+        for (_, (_, inserts), _) in &rows {
+            for (width, insert) in insert_widths.iter_mut().zip(inserts) {
+                *width = (*width).max(insert.len());
+            }
+        }
+        // Columns of a row: each position's inserts, padded, then the next position
+        let layout = |columns: &[u8], inserts: &[Vec<u8>], pad: u8| {
+            let mut line = Vec::with_capacity(clen + insert_widths.iter().sum::<usize>());
+            for (k, insert) in inserts.iter().enumerate() {
+                if k > 0 {
+                    line.push(columns[k - 1]);
+                }
+                line.extend_from_slice(insert);
+                line.resize(line.len() + insert_widths[k] - insert.len(), pad);
+            }
+            String::from_utf8_lossy(&line).into_owned()
+        };
+        let no_inserts = vec![Vec::new(); clen + 1];
+        
+        if self.config.msa_format != MsaFormat::Stockholm {
+            let a2m = self.config.msa_format == MsaFormat::A2m;
+            let reference = query.consensus.sequence.to_ascii_uppercase();
+            let reference = if a2m { reference } else { layout(reference.as_bytes(), &no_inserts, b'.') };
+            write_fasta(out, &format!("{} reference consensus", query.name), &reference)?;
+            for (name, (columns, inserts), _) in &rows {
+                let columns = columns.to_ascii_uppercase();
+                let row = if a2m {
+                    let mut row = inserts[0].clone();
+                    for (&column, insert) in columns.iter().zip(&inserts[1..]) {
+                        row.push(column);
+                        row.extend_from_slice(insert);
+                    }
+                    String::from_utf8_lossy(&row).into_owned()
+                } else {
+                    layout(&columns, inserts, b'.')
+                };
+                write_fasta(out, name, &row)?;
+            }
+            return Ok(());
+        }
+        
+        let name_width = rows.iter().map(|(name, _, pp)| name.len() + if pp.is_some() { 8 } else { 0 }).max().unwrap_or(0).max(12);
+        writeln!(out, "# STOCKHOLM 1.0")?;
+        writeln!(out, "#=GF AU improved-cmsearch {}", crate::VERSION)?;
+        for (label, value) in metadata {
+            writeln!(out, "#=GF CC {}: {}", label, value)?;
+        }
+        writeln!(out)?;
+        for (name, (columns, inserts), pp) in &rows {
+            writeln!(out, "{:<w$} {}", name, layout(columns, inserts, b'.'), w = name_width)?;
+            if let Some((columns, inserts)) = pp {
+                writeln!(out, "{:<w$} {}", format!("#=GR {} PP", name), layout(columns, inserts, b'.'), w = name_width)?;
+            }
+        }
+        writeln!(out, "{:<w$} {}", "#=GC SS_cons", layout(query.consensus.structure.as_bytes(), &no_inserts, b'.'), w = name_width)?;
+        writeln!(out, "{:<w$} {}", "#=GC RF", layout(query.consensus.sequence.as_bytes(), &no_inserts, b'.'), w = name_width)?;
+        writeln!(out, "//")?;
+        Ok(())
+    }
+    
+    // Infernal's `pass` column: 1 standard, 2 5'-truncated, 3 3'-truncated,
+    // 4 5'&3'-truncated, 5 truncated anywhere (--anytrunc)
+    fn pass_index(&self, trunc: Truncation) -> usize {
+        match trunc {
+            Truncation::None => 1,
+            _ if self.config.trunc == TruncMode::Anywhere => 5,
+            Truncation::FivePrime => 2,
+            Truncation::ThreePrime => 3,
+            Truncation::Both => 4,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRun<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    program: &'static str,
+    version: &'static str,
+    command: Vec<String>,
+    date: String, // RFC 3339, UTC
+    cm_file: &'a str,
+    query: JsonQuery<'a>,
+    target_file: &'a str,
+    db_residues: u64, // On the strands searched, as E-values assume
+    hits: usize,
+    config: &'a Config,
+}
+
+#[derive(Serialize)]
+struct JsonStats<'a> {
+    program: &'static str,
+    version: &'static str,
+    command: Vec<String>,
+    date: String, // RFC 3339, UTC
+    cm_file: &'a str,
+    target_file: &'a str,
+    db_residues: u64, // Z, on the strands searched
+    query: JsonQuery<'a>,
+    targets: usize,
+    residues_searched: u64, // On the strands searched
+    stages: &'a [StageSummary],
+    trunc_stages: &'a [StageSummary], // Truncated passes; empty if off
+    hits_reported: usize,
+    hits_included: usize,
+    truncated_hits: usize,
+    cpu_user: Option<f64>, // Seconds; None where not available
+    cpu_system: Option<f64>,
+    elapsed: f64,
+}
+
+#[derive(Serialize)]
+struct JsonQuery<'a> {
+    name: &'a str,
+    accession: Option<&'a str>,
+    length: usize,
+}
+
+impl<'a> JsonQuery<'a> {
+    fn new(query: &'a QueryResult) -> Self {
+        Self {
+            name: &query.name,
+            accession: Some(query.accession.as_str()).filter(|&a| a != "-"),
+            length: query.length,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonHit<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank: Option<usize>, // None in --stream
+    id: &'a str,
+    target: &'a str,
+    description: Option<&'a str>,
+    target_length: usize,
+    start: usize, // 1-based, inclusive, start <= end on either strand
+    end: usize,
+    strand: char,
+    trunc: &'static str,
+    olp: char,
+    score: f64,
+    bias: f64,
+    pvalue: f64,
+    evalue: f64,
+    included: bool, // Within the inclusion threshold (--incE or --incT)
+    gc: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alignment: Option<&'a Alignment>,
+}
+
+impl<'a> JsonHit<'a> {
+    fn new(hit: &'a Hit, rank: Option<usize>, included: bool, alignment: Option<&'a Alignment>) -> Self {
+        Self {
+            kind: "hit",
+            rank,
+            id: &hit.id,
+            target: &hit.sequence_name,
+            description: Some(hit.sequence_description.as_str()).filter(|d| !d.is_empty()),
+            target_length: hit.sequence_length,
+            start: hit.start + 1,
+            end: hit.end,
+            strand: hit.strand.symbol(),
+            trunc: hit.trunc.label(),
+            olp: hit.overlap.symbol(),
+            score: hit.score,
+            bias: hit.bias,
+            pvalue: hit.pvalue,
+            evalue: hit.evalue,
+            included,
+            gc: hit.gc,
+            alignment,
+        }
+    }
+}
+
+/// Hits written as they are found (--stream): JSON Lines records like those
+/// of --json, without ranks or alignments, flushed as each chunk of a
+/// target is searched so that a run that stops early keeps what it found.
+/// Streamed hits are provisional: in the order found, and before overlap
+/// resolution, so a locus may appear more than once. The other outputs are
+/// written at the end, resolved and sorted.
+pub struct HitStream {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl HitStream {
+    pub fn create(path: &str) -> Result<Self, OutputError> {
+        let file = File::create(path)
+            .map_err(|source| OutputError::Create { option: "--stream", path: path.to_string(), source })?;
+        Ok(Self { out: Mutex::new(BufWriter::new(file)) })
+    }
+    
+    pub fn write<'a>(&self, hits: impl IntoIterator<Item = &'a Hit>, config: &Config) -> Result<(), OutputError> {
+        let mut out = self.out.lock().unwrap_or_else(|err| err.into_inner());
+        let write = || -> io::Result<()> {
+            for hit in hits {
+                serde_json::to_writer(&mut *out, &JsonHit::new(hit, None, config.includes(hit), None)).map_err(io::Error::from)?;
+                writeln!(out)?;
+            }
+            out.flush()
+        };
+        write().map_err(|source| OutputError::Write { target: "--stream file".to_string(), source })
+    }
+}
+
+// `text` with the characters GFF3 reserves in columns and attribute values
+// percent-encoded
+fn gff_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            ';' | '=' | '&' | ',' | '%' | '\t' | '\n' | '\r' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// The residues of each hit as FASTA, named target/from-to after Easel's
+// convention (from > to on the minus strand), with the target's description
+fn write_hitfasta(out: &mut impl Write, hits: &[Hit]) -> io::Result<()> {
+    for hit in hits {
+        let (from, to) = seq_coords(hit);
+        let mut header = format!("{}/{}-{}", hit.sequence_name, from, to);
+        if !hit.sequence_description.is_empty() {
+            header = format!("{} {}", header, hit.sequence_description);
+        }
+        write_fasta(out, &header, hit.residues.as_deref().unwrap_or_default())?;
+    }
+    Ok(())
+}
+
+// One FASTA record, wrapped at 60 columns
+fn write_fasta(out: &mut impl Write, header: &str, residues: &str) -> io::Result<()> {
+    writeln!(out, ">{}", header)?;
+    for line in residues.as_bytes().chunks(60) {
+        writeln!(out, "{}", String::from_utf8_lossy(line))?;
+    }
+    Ok(())
+}
+
+/// Each file the results are written to, by the option naming it: -o (the
+/// report, which is otherwise on stdout), --stream, then the other outputs.
+pub fn destinations(config: &Config) -> Vec<(&'static str, &str)> {
+    let files = requested_files(config).map(|(format, path)| (format.option(), path));
+    [("-o", &config.output), ("--stream", &config.stream)]
+        .into_iter()
+        .chain(files)
+        .filter_map(|(option, path)| Some((option, path.as_deref()?)))
+        .collect()
+}
+
+// The output file options of `config`, set or not
+fn requested_files(config: &Config) -> [(FileFormat, &Option<String>); 8] {
+    [
+        (FileFormat::Tblout, &config.tblout),
+        (FileFormat::Json, &config.json),
+        (FileFormat::Sam, &config.sam),
+        (FileFormat::Msa, &config.msa),
+        (FileFormat::HitFasta, &config.hitfasta),
+        (FileFormat::Gff, &config.gff),
+        (FileFormat::StatsJson, &config.stats_json),
+        (FileFormat::Histogram, &config.histogram),
+    ]
+}
+
+// One line of a hit alignment (its target residues or PP) split by
+// consensus position: the character in each of the model's `clen` positions,
+// `gap` outside the aligned range, and the characters inserted after each
+// position 0..=clen
+fn split_by_position(alignment: &Alignment, line: &str, gap: u8, clen: usize) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let mut columns = vec![gap; clen];
+    let mut inserts = vec![Vec::new(); clen + 1];
+    let mut k = alignment.model_from.saturating_sub(1); // Positions passed
+    for (m, c) in alignment.model.bytes().zip(line.bytes()) {
+        if m == b'.' {
+            inserts[k].push(c);
+        } else {
+            columns[k] = c;
+            k += 1;
+        }
+    }
+    (columns, inserts)
+}
+
+// CIGAR and SEQ of a hit alignment in SAM, with the target as reference and
+// the model consensus as the read: consensus positions are M (aligned) or I
+// (deleted in the target), target residues in insert states are D. Minus
+// strand alignments are reverse complemented onto the forward strand
+fn sam_alignment(alignment: &Alignment, strand: Strand) -> (String, String) {
+    let mut ops: Vec<(char, usize)> = Vec::new();
+    let mut read = String::with_capacity(alignment.model.len());
+    for (model, target) in alignment.model.chars().zip(alignment.target.chars()) {
+        let op = match (model, target) {
+            ('.', _) => 'D',
+            (_, '-') => 'I',
+            _ => 'M',
+        };
+        if op != 'D' {
+            read.push(model.to_ascii_uppercase());
+        }
+        match ops.last_mut() {
+            Some((last, count)) if *last == op => *count += 1,
+            _ => ops.push((op, 1)),
+        }
+    }
+    if strand == Strand::Minus {
+        ops.reverse();
+        read = digital::reverse_complement_text(&read);
+    }
+    let cigar = ops.iter().map(|(op, count)| format!("{}{}", count, op)).collect();
+    (cigar, utils::convert_alphabet(&read, SeqAlphabet::Dna))
+}
+
+// The hits of every model on each scanned sequence, in --sort order
+fn scan_groups<'a>(sequences: &[ScanSequence], models: &'a [QueryResult], sort: SortOrder) -> Vec<HitGroup<'a>> {
+    let mut by_sequence: HashMap<&str, HitGroup> = HashMap::new();
+    for model in models {
+        for hit in &model.hits {
+            by_sequence.entry(hit.sequence_name.as_str()).or_default().push((model, hit));
+        }
+    }
+    sequences.iter()
+        .map(|sequence| {
+            let mut group = by_sequence.remove(sequence.name.as_str()).unwrap_or_default();
+            group.sort_by(|a, b| order::compare_hits_by(sort, a.1, b.1));
+            group
+        })
+        .collect()
+}
+
+// One line of a --tblout table: `values` padded to the column widths, the
+// first `indent` characters narrower to make room for a leading '#'
+fn tblout_row(columns: &[(&str, usize, bool)], values: &[String], indent: usize) -> String {
+    let last = columns.len() - 1;
+    let cells: Vec<String> = columns.iter().zip(values).enumerate().map(|(i, (&(_, width, left), value))| {
+        let width = if i == 0 { width - indent } else { width };
+        match (i == last, left) {
+            (true, _) => value.clone(),
+            (false, true) => format!("{:<w$}", value, w = width),
+            (false, false) => format!("{:>w$}", value, w = width),
+        }
+    }).collect();
+    cells.join(" ")
+}
+
+// anyidx/afrct1/afrct2 or winidx/wfrct1/wfrct2: the other hit's index in
+// the table, whose query's hits start after `first` rows, and the
+// overlapping fractions of both hits, or dashes
+fn overlap_columns(with: Option<OverlapWith>, first: usize) -> [String; 3] {
+    match with {
+        Some(w) => [(first + w.index + 1).to_string(), format!("{:.3}", w.fraction), format!("{:.3}", w.other_fraction)],
+        None => ["-".to_string(), "-".to_string(), "-".to_string()],
+    }
+}
+
+// Target coordinates of a hit as reported: 1-based, and from > to on the
+// minus strand
+fn seq_coords(hit: &Hit) -> (usize, usize) {
+    match hit.strand {
+        Strand::Plus => (hit.start + 1, hit.end),
+        Strand::Minus => (hit.end, hit.start + 1),
+    }
+}
+
+// Label of a filter stage in the statistics summary, as Infernal words it
+fn stage_label(kind: StageKind) -> &'static str {
+    match kind {
+        StageKind::Ssv => "Windows   passing  local HMM SSV           filter:",
+        StageKind::Viterbi => "Windows   passing glocal HMM Viterbi       filter:",
+        StageKind::Forward => "Windows   passing  local HMM Forward       filter:",
+        StageKind::GlocalForward => "Windows   passing glocal HMM Forward       filter:",
+        StageKind::Envelope => "Envelopes passing glocal HMM envelope defn filter:",
+        StageKind::Consensus => "Envelopes passing  CM consensus score      filter:",
+        StageKind::Score => "Envelopes scored against the CM consensus:",
+    }
+}
+
+// hh:mm:ss.cc
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!("{:02}:{:02}:{:02}.{:02}", secs / 3600, secs / 60 % 60, secs % 60, elapsed.subsec_millis() / 10)
+}
+
+// `text` cut to at most `width` characters, ending in "..." when cut
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(width.saturating_sub(3)).collect();
+    cut.push_str("...");
+    cut
+}
+
+// Target description, or "-" when the header had none
+fn description_or_dash(hit: &Hit) -> &str {
+    if hit.sequence_description.is_empty() { "-" } else { &hit.sequence_description }
+} 
+
+// The searched model's name and accession, or a count of several
+fn model_names(queries: &[QueryResult]) -> String {
+    match queries {
+        [query] if query.accession == "-" => query.name.clone(),
+        [query] => format!("{} ({})", query.name, query.accession),
+        _ => format!("{} models", queries.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sam_alignment() {
+        let alignment = Alignment {
+            model_from: 1,
+            model_to: 6,
+            structure: "<<..-->>".to_string(),
+            model: "GCa..UGC".to_string(),
+            matches: "||    ||".to_string(),
+            target: "GC-aaUGC".to_string(),
+            posteriors: None,
+            score: 10.0,
+            accuracy: None,
+        };
+        assert_eq!(sam_alignment(&alignment, Strand::Plus), ("2M1I2D3M".to_string(), "GCATGC".to_string()));
+        assert_eq!(sam_alignment(&alignment, Strand::Minus), ("3M2D1I2M".to_string(), "GCATGC".to_string()));
+    }
+
+    #[test]
+    fn test_split_by_position() {
+        let alignment = Alignment {
+            model_from: 2,
+            model_to: 5,
+            structure: "<..-->".to_string(),
+            model: "Ca..UG".to_string(),
+            matches: "|   ||".to_string(),
+            target: "C-aaUG".to_string(),
+            posteriors: Some("9.**89".to_string()),
+            score: 10.0,
+            accuracy: None,
+        };
+        let (columns, inserts) = split_by_position(&alignment, &alignment.target, b'-', 6);
+        assert_eq!(columns, b"-C-UG-");
+        assert_eq!(inserts, vec![vec![], vec![], vec![], b"aa".to_vec(), vec![], vec![], vec![]]);
+        let (columns, _) = split_by_position(&alignment, alignment.posteriors.as_ref().unwrap(), b'.', 6);
+        assert_eq!(columns, b".9.89.");
+    }
+
+    fn test_hit(sequence: &str, start: usize, end: usize, strand: Strand, trunc: Truncation) -> Hit {
+        Hit {
+            id: Hit::stable_id("tRNA", sequence, start, end, strand),
+            sequence_name: sequence.to_string(),
+            sequence_description: "test chromosome".to_string(),
+            sequence_length: 526,
+            start,
+            end,
+            strand,
+            trunc,
+            overlap: overlap::Overlap::None,
+            score: 40.0,
+            bias: 0.0,
+            gc: 0.57,
+            pvalue: 1e-14,
+            evalue: 3.1e-11,
+            alignment: None,
+            residues: None,
+        }
+    }
+
+    // The report and --tblout of a search of a 46-position model finding
+    // `hits`
+    fn write_outputs(hits: Vec<Hit>) -> (String, String) {
+        let path = |name: &str| std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let (report, tblout) = (path("report-test"), path("tblout-test"));
+        let config = Config {
+            cmfile: "tRNA.cm".to_string(),
+            seqdb: "genome.fa".to_string(),
+            output: Some(report.to_string_lossy().into_owned()),
+            tblout: Some(tblout.to_string_lossy().into_owned()),
+            alignments: false,
+            threads: 1,
+            ..Config::new()
+        };
+        let consensus = "GCGGAUUUAGCUCAGUUGGGAGAGCGCCAGACUGAAGAUCUGGAGG";
+        let query = QueryResult {
+            name: "tRNA".to_string(),
+            accession: "-".to_string(),
+            description: None,
+            length: consensus.len(),
+            consensus: Consensus { sequence: consensus.to_string(), structure: ":".repeat(consensus.len()), length: consensus.len() },
+            clan: None,
+            hits,
+            summary: PipelineSummary { targets: 2, residues: 526, ..PipelineSummary::default() },
+        };
+        OutputWriter::new(&config).unwrap().write_hits(&[query], 1052).unwrap();
+        let read = |path| {
+            let text = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            text
+        };
+        (read(report), read(tblout))
+    }
+
+    #[test]
+    fn test_report_hit_table() {
+        let (report, tblout) = write_outputs(vec![test_hit("chr1", 240, 286, Strand::Plus, Truncation::None)]);
+        assert!(!report.contains("INFERNAL") && !tblout.contains("INFERNAL"));
+        assert!(tblout.contains(&format!("# Version:         improved-cmsearch {} (Infernal-format output;", crate::VERSION)), "{}", tblout);
+        assert_eq!(tblout.lines().filter(|line| line.starts_with("# Program:")).collect::<Vec<_>>(), ["# Program:         cmsearch"]);
+        for field in ["Version", "Pipeline mode", "Query file", "Target file", "Query name", "Z", "Option settings", "Current dir", "Date"] {
+            let prefix = format!("# {}:", field);
+            assert_eq!(tblout.lines().filter(|line| line.starts_with(&prefix)).count(), 1, "{}\n{}", field, tblout);
+        }
+        assert!(tblout.contains("# Query name:      tRNA\n# Z:               1052 residues, "), "{}", tblout);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "# cmsearch :: search CM(s) against a sequence database");
+        assert_eq!(lines[1], format!("# improved-cmsearch {} (Infernal-format output; bit scores are ungapped consensus scores, not CM scores)",
+                                     crate::VERSION));
+        assert_eq!(lines[3], "# query CM file:                         tRNA.cm");
+        assert_eq!(lines[4], "# target sequence database:              genome.fa");
+        let table = lines.iter().position(|line| *line == "Hit scores:").unwrap();
+        assert_eq!(lines[table - 1], "Query:       tRNA  [CLEN=46]");
+        assert_eq!(&lines[table + 1..table + 4], [
+            " rank     E-value  score  bias  sequence  start    end   mdl trunc   gc  description",
+            " ----   --------- ------ -----  -------- ------ ------   --- ----- ----  -----------",
+            "  (1) !   3.1e-11   40.0   0.0  chr1        241    286 +  cm    no 0.57  test chromosome",
+        ]);
+        // The Viterbi filter aligns the HMM glocally, unlike SSV and Forward
+        let stages: Vec<&str> = lines.iter().filter(|line| line.contains(" HMM ")).map(|line| &line[..50]).collect();
+        assert_eq!(&stages[..3], [
+            "Windows   passing  local HMM SSV           filter:",
+            "Windows   passing glocal HMM Viterbi       filter:",
+            "Windows   passing  local HMM Forward       filter:",
+        ]);
+        assert_eq!(lines.last(), Some(&"[ok]"));
+    }
+
+    #[test]
+    fn test_trunc_column() {
+        let (report, tblout) = write_outputs(vec![
+            test_hit("chr1", 0, 26, Strand::Plus, Truncation::FivePrime),
+            test_hit("chr2", 0, 30, Strand::Minus, Truncation::ThreePrime),
+            test_hit("chr3", 0, 526, Strand::Plus, Truncation::Both),
+            test_hit("chr4", 240, 286, Strand::Plus, Truncation::None),
+        ]);
+
+        // The report's trunc column, and the tblout's trunc and pass columns
+        let report: Vec<(&str, &str)> = report.lines()
+            .filter(|line| line.trim_start().starts_with('('))
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                (fields[5], fields[10])
+            })
+            .collect();
+        assert_eq!(report, [("chr1", "5'"), ("chr2", "3'"), ("chr3", "5'&3'"), ("chr4", "no")]);
+        let tblout: Vec<(&str, &str, &str)> = tblout.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                (fields[0], fields[10], fields[11])
+            })
+            .collect();
+        assert_eq!(tblout, [("chr1", "5'", "2"), ("chr2", "3'", "3"), ("chr3", "5'&3'", "4"), ("chr4", "no", "1")]);
+    }
+
+    #[test]
+    fn test_gff_escape() {
+        assert_eq!(gff_escape("chr1"), "chr1");
+        assert_eq!(gff_escape("a;b=c,d%e\tf"), "a%3Bb%3Dc%2Cd%25e%09f");
+    }
+}
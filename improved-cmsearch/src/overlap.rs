@@ -0,0 +1,236 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use crate::search::{Hit, Strand};
+
+/// Overlap status of a hit, shown in the `olp` output column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Overlap {
+    None,       // Overlaps no other hit
+    Best,       // Best of a set of overlapping hits
+    Overlapped, // Overlaps a better hit; reported only with --keep-overlaps
+}
+
+impl Overlap {
+    /// Mark of the status, as in the `olp` column of a `--fmt 2` table.
+    pub fn symbol(&self) -> char {
+        match self {
+            Overlap::None => '*',
+            Overlap::Best => '^',
+            Overlap::Overlapped => '=',
+        }
+    }
+}
+
+/// Resolve overlapping hits on the same target and strand: greedily by
+/// E-value, a hit is kept unless it overlaps an already kept hit. Overlapped
+/// hits are dropped, or kept and marked when `keep_overlapped` is set;
+/// repeated detections of identical coordinates are always merged.
+pub fn resolve_overlaps(hits: Vec<Hit>, keep_overlapped: bool) -> Vec<Hit> {
+    let mut groups: HashMap<(String, Strand), Vec<Hit>> = HashMap::new();
+    for hit in hits {
+        groups.entry((hit.sequence_name.clone(), hit.strand)).or_default().push(hit);
+    }
+
+    let mut resolved = Vec::new();
+    for (_, mut group) in groups {
+        group.sort_by(compare_significance);
+        // Overlapping windows and chunks find one locus more than once; the
+        // best detection stands for all of them, kept or overlapped
+        let mut seen = HashSet::new();
+        group.retain(|hit| seen.insert((hit.start, hit.end)));
+
+        let mut kept: Vec<Hit> = Vec::with_capacity(group.len());
+        let mut overlapped = Vec::new();
+        for mut hit in group {
+            match kept.iter_mut().find(|k| overlaps(k, &hit)) {
+                Some(better) => {
+                    better.overlap = Overlap::Best;
+                    hit.overlap = Overlap::Overlapped;
+                    overlapped.push(hit);
+                }
+                None => {
+                    hit.overlap = Overlap::None;
+                    kept.push(hit);
+                }
+            }
+        }
+
+        resolved.extend(kept);
+        if keep_overlapped {
+            resolved.extend(overlapped);
+        }
+    }
+
+    resolved
+}
+
+/// Overlap annotation of one hit in a `--fmt 2` hit table (Infernal's olp,
+/// anyidx, afrct and winidx, wfrct columns), against the other hits in the
+/// table on the same target and strand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TableOverlap {
+    pub olp: char,                 // '*' overlaps nothing, '^' overlaps only worse hits, '=' overlaps a better hit
+    pub any: Option<OverlapWith>, // With '=': the best hit this one overlaps
+    pub win: Option<OverlapWith>, // With '=': the best '^' hit this one overlaps
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverlapWith {
+    pub index: usize,        // Position of the other hit in the table
+    pub fraction: f64,       // Fraction of this hit in the overlap
+    pub other_fraction: f64, // Fraction of the other hit in the overlap
+}
+
+/// Overlap annotation of each hit of a table ordered best first.
+pub fn annotate_table<H: Borrow<Hit>>(hits: &[H]) -> Vec<TableOverlap> {
+    let hits: Vec<&Hit> = hits.iter().map(Borrow::borrow).collect();
+    let mut groups: HashMap<(&str, Strand), Vec<usize>> = HashMap::new();
+    for (i, hit) in hits.iter().enumerate() {
+        groups.entry((hit.sequence_name.as_str(), hit.strand)).or_default().push(i);
+    }
+
+    let mut table = vec![TableOverlap { olp: '*', any: None, win: None }; hits.len()];
+    for group in groups.values() {
+        for (k, &i) in group.iter().enumerate() {
+            let overlapping = |&&j: &&usize| overlaps(hits[i], hits[j]);
+            let with = |j: usize| {
+                let shared = (hits[i].end.min(hits[j].end) - hits[i].start.max(hits[j].start)) as f64;
+                OverlapWith {
+                    index: j,
+                    fraction: shared / (hits[i].end - hits[i].start) as f64,
+                    other_fraction: shared / (hits[j].end - hits[j].start) as f64,
+                }
+            };
+            // Better hits come first, so they are annotated already
+            if let Some(&better) = group[..k].iter().find(overlapping) {
+                let win = group[..k].iter().filter(overlapping).find(|&&j| table[j].olp == '^');
+                table[i] = TableOverlap { olp: '=', any: Some(with(better)), win: win.map(|&j| with(j)) };
+            } else if group[k + 1..].iter().any(|j| overlapping(&j)) {
+                table[i].olp = '^';
+            }
+        }
+    }
+    table
+}
+
+fn overlaps(a: &Hit, b: &Hit) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+// Most significant first: E-value, then score, then position. Hits of one
+// locus from different passes can tie on all of these, so bias and the
+// truncation label settle them, keeping the resolution independent of the
+// order the hits were found in
+fn compare_significance(a: &Hit, b: &Hit) -> Ordering {
+    a.evalue.total_cmp(&b.evalue)
+        .then_with(|| b.score.total_cmp(&a.score))
+        .then_with(|| a.start.cmp(&b.start))
+        .then_with(|| a.end.cmp(&b.end))
+        .then_with(|| a.bias.total_cmp(&b.bias))
+        .then_with(|| a.trunc.label().cmp(b.trunc.label()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::Truncation;
+
+    fn hit(target: &str, start: usize, end: usize, strand: Strand, evalue: f64) -> Hit {
+        Hit {
+            id: format!("{}:{}", target, start),
+            sequence_name: target.to_string(),
+            sequence_description: String::new(),
+            sequence_length: 1000,
+            start,
+            end,
+            strand,
+            trunc: Truncation::None,
+            overlap: Overlap::None,
+            score: -evalue.log10(),
+            bias: 0.0,
+            gc: 0.5,
+            pvalue: evalue,
+            evalue,
+            alignment: None,
+            residues: None,
+        }
+    }
+
+    fn ids(hits: &[Hit]) -> Vec<(String, char)> {
+        let mut ids: Vec<_> = hits.iter().map(|h| (h.id.clone(), h.overlap.symbol())).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_resolve_overlaps_keeps_best() {
+        let hits = vec![
+            hit("a", 0, 100, Strand::Plus, 1e-5),
+            hit("a", 50, 150, Strand::Plus, 1e-9),
+            hit("a", 140, 240, Strand::Plus, 1e-3),
+            hit("a", 60, 160, Strand::Minus, 1e-2),
+            hit("b", 0, 100, Strand::Plus, 1e-1),
+            hit("b", 0, 100, Strand::Plus, 1e-1),
+        ];
+
+        let resolved = resolve_overlaps(hits.clone(), false);
+        assert_eq!(ids(&resolved), vec![
+            ("a:50".to_string(), '^'),
+            ("a:60".to_string(), '*'),
+            ("b:0".to_string(), '*'),
+        ]);
+
+        let annotated = resolve_overlaps(hits, true);
+        assert_eq!(ids(&annotated), vec![
+            ("a:0".to_string(), '='),
+            ("a:140".to_string(), '='),
+            ("a:50".to_string(), '^'),
+            ("a:60".to_string(), '*'),
+            ("b:0".to_string(), '*'),
+        ]);
+    }
+
+    #[test]
+    fn test_resolve_overlaps_merges_duplicates() {
+        // The overlapped hit at 50..150 lies in two overlapping windows, and
+        // the second detection scored a little lower
+        let mut again = hit("a", 50, 150, Strand::Plus, 1e-3);
+        again.evalue = 2e-3;
+        let hits = vec![
+            hit("a", 0, 100, Strand::Plus, 1e-9),
+            hit("a", 50, 150, Strand::Plus, 1e-3),
+            again,
+        ];
+        let annotated = resolve_overlaps(hits, true);
+        assert_eq!(ids(&annotated), vec![
+            ("a:0".to_string(), '^'),
+            ("a:50".to_string(), '='),
+        ]);
+        assert_eq!(annotated.iter().find(|h| h.start == 50).unwrap().evalue, 1e-3);
+    }
+
+    #[test]
+    fn test_annotate_table() {
+        // A overlaps B, B overlaps C, but A and C do not overlap
+        let hits = vec![
+            hit("a", 100, 200, Strand::Plus, 1e-9),
+            hit("a", 150, 250, Strand::Plus, 1e-6),
+            hit("a", 225, 325, Strand::Plus, 1e-3),
+            hit("a", 150, 250, Strand::Minus, 1e-2),
+        ];
+
+        let table = annotate_table(&hits);
+        let olp: String = table.iter().map(|t| t.olp).collect();
+        assert_eq!(olp, "^==*");
+
+        let any = table[1].any.unwrap();
+        assert_eq!((any.index, any.fraction, any.other_fraction), (0, 0.5, 0.5));
+        assert_eq!(table[1].win, table[1].any);
+        let any = table[2].any.unwrap();
+        assert_eq!((any.index, any.fraction, any.other_fraction), (1, 0.25, 0.25));
+        assert_eq!(table[2].win, None);
+        assert_eq!(table[0].any, None);
+    }
+}
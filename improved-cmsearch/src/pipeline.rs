@@ -1,290 +1,1291 @@
-use anyhow::Result;
-use log::info;
+use crate::align::{self, Alignment};
+use crate::checkpoint::CheckpointWriter;
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{info, warn};
 use rayon::prelude::*;
-use crate::cm::Cm;
-use crate::config::Config;
-use crate::search::{Sequence, Hit};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::cm::{CalibrationParams, Cm};
+use crate::config::{Config, StageKind, TruncMode};
+use crate::digital::{self, DigitizedSeq};
+use crate::error::{self, FileError, PipelineError, RemoteError, SeqFormatError};
+use crate::hmm::{self, ProfileHmm};
+use crate::memory::{SearchMemory, Tracked};
+use crate::order;
+use crate::output::HitStream;
+use crate::overlap::{self, Overlap};
+use crate::search::{Sequence, Hit, Strand, Truncation};
+use crate::remote::{Connection, RemoteChunk, Request};
+use crate::stage::{self, Candidate, CmScores, HistogramBin, Stage, StageContext, TruncPass};
+use crate::utils::StableHasher;
+
+// Digitized chunks the reader thread may queue ahead of the workers
+const CHUNK_QUEUE: usize = 256;
+
+// Hits search_iter may hold ahead of the caller
+const HIT_QUEUE: usize = 256;
+
+// How often --coordinator checks for new workers
+const WORKER_POLL: Duration = Duration::from_millis(100);
+
+/// A model with the read-only score tables of its search, built once and
+/// shared by every pipeline and thread searching with it.
+pub struct SearchModel {
+    pub cm: Cm,
+    pub hmm: ProfileHmm,
+    pub scores: CmScores, // CM log-odds tables for the CM stages
+}
+
+impl SearchModel {
+    pub fn new(cm: Cm, config: &Config) -> Self {
+        Self {
+            hmm: ProfileHmm::from_cm(&cm).with_f32_filters(config.f32_filters),
+            scores: CmScores::new(&cm),
+            cm,
+        }
+    }
+
+    /// Optimal alignment of the model's profile HMM (not the CM) to digitized
+    /// `residues` (shown as `text`), truncated at either end of the model with `truncated`, within
+    /// the --mx-size cap: over it, alignments fall back to divide and conquer
+    /// without posteriors, or are skipped if even that does not fit.
+    pub fn hmm_align(&self, config: &Config, id: &str, residues: &[u8], text: &[u8], truncated: bool) -> Option<Alignment> {
+        let max_bytes = (config.max_mx_size * 1024.0 * 1024.0) as usize;
+        let memory = align::AlignMemory::new(self.hmm.length, residues.len());
+        let mb = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        if memory.minimum > max_bytes {
+            warn!("Skipping alignment of {}: needs at least {:.1} MB of DP memory, over --mx-size {} MB",
+                  id, mb(memory.minimum), config.max_mx_size);
+            return None;
+        }
+        if memory.full > max_bytes {
+            let fallback = if config.acc { "Viterbi alignment without --acc" } else { "divide and conquer" };
+            warn!("Alignment of {} needs {:.1} MB of full DP matrices, over --mx-size {} MB; using {} and no PP line",
+                  id, mb(memory.full), config.max_mx_size, fallback);
+        }
+        
+        let path = align::hmm_align(&self.hmm, residues, max_bytes, config.acc, truncated)?;
+        Some(path.alignment(&self.hmm, &self.cm.consensus, text))
+    }
+}
 
 pub struct Pipeline {
-    cm: Cm,
-    config: Config,
+    model: Arc<SearchModel>,
+    config: Arc<Config>,
+    stages: Vec<Box<dyn Stage>>,
+    trunc_stages: Vec<Box<dyn Stage>>, // Run by the truncated passes
+    db_residues: u64,
+    targets: AtomicUsize, // Target sequences read
+    residues: AtomicU64,  // Their residues, one strand
+    stream: Option<HitStream>, // --stream: hits written as chunks are searched
+    checkpoint: Option<CheckpointWriter>, // --checkpoint: progress saved as targets finish
+    memory: Arc<SearchMemory>, // Memory of the search's buffers, within --max-rss
+}
+
+/// Work done by a search, for the statistics summary of the report.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PipelineSummary {
+    pub targets: usize,
+    pub residues: u64, // Target residues read, one strand
+    pub stages: Vec<StageSummary>,
+    pub trunc_stages: Vec<StageSummary>, // Truncated passes; empty if off
+    #[serde(default)]
+    pub memory_peak: u64, // Estimated bytes of DP matrices and sequence buffers at the peak
+}
+
+/// Counts of one pipeline stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageSummary {
+    pub kind: StageKind,
+    pub threshold: f64,
+    pub entered: usize,
+    pub passed: usize,
+    pub residues: u64,        // Residues of the candidates entering
+    pub passed_residues: u64, // Residues of the candidates passing
+    pub seconds: f64,         // Time in the stage, summed over threads
+    #[serde(skip)]
+    pub histogram: Vec<HistogramBin>, // Scores of the candidates entering
 }
 
 impl Pipeline {
-    pub fn new(cm: &Cm, config: &Config) -> Result<Self> {
-        Ok(Self {
-            cm: cm.clone(),
-            config: config.clone(),
-        })
+    /// Pipeline for searching a database of `db_residues` residues (both
+    /// strands), which sets the default filter thresholds.
+    pub fn new(model: Arc<SearchModel>, config: Arc<Config>, db_residues: u64) -> Result<Self, PipelineError> {
+        config.validate_options().map_err(PipelineError::Config)?;
+        Self::open(model, config, db_residues)
     }
     
-    pub fn search(&self, sequences: &[Sequence]) -> Result<Vec<Hit>> {
-        info!("Starting real CM search pipeline with {} sequences", sequences.len());
-        
-        let hits: Vec<Hit> = sequences
-            .par_iter()
-            .flat_map(|seq| self.search_sequence(seq))
-            .collect();
+    // The pipeline of valid options, with the files it writes opened
+    fn open(model: Arc<SearchModel>, config: Arc<Config>, db_residues: u64) -> Result<Self, PipelineError> {
+        let cm = &model.cm;
+        if let Some(dir) = &config.dump_dp {
+            std::fs::create_dir_all(dir)
+                .map_err(FileError::io(|| format!("Failed to create DP dump directory {}", dir)))
+                .map_err(PipelineError::other)?;
+        }
         
-        info!("Found {} hits before filtering", hits.len());
+        if cm.calibration.local_inside.is_none() && cm.calibration.glocal_inside.is_none() {
+            warn!("Model {} has no Inside calibration (ECMLI/ECMGI); E-values are conservative bounds", cm.name);
+        }
         
-        // Sort by score (best first)
-        let mut hits = hits;
-        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        let thresholds = config.filter_thresholds(db_residues);
+        let build = |kinds: Vec<StageKind>| -> Vec<Box<dyn Stage>> {
+            kinds.into_iter()
+                .chain(std::iter::once(StageKind::Score))
+                .map(|kind| stage::build_stage(kind, &config, &thresholds))
+                .collect()
+        };
         
-        // Apply thresholds based on original cmsearch behavior
-        let hits: Vec<Hit> = hits
-            .into_iter()
-            .filter(|hit| {
-                let passes_evalue = hit.evalue <= self.config.evalue;
-                let passes_score = self.config.score.map_or(true, |threshold| hit.score >= threshold);
-                passes_evalue && passes_score
-            })
-            .collect();
+        let stages = build(config.active_stages());
+        // Glocal HMM stages need the whole model, so truncated passes keep
+        // only the local filters
+        let trunc_stages = if config.truncated_passes() {
+            build(config.active_stages()
+                .into_iter()
+                .filter(|kind| matches!(kind, StageKind::Ssv | StageKind::Forward | StageKind::Consensus))
+                .collect())
+        } else {
+            Vec::new()
+        };
         
-        info!("Pipeline found {} hits after filtering", hits.len());
-        Ok(hits)
+        let stream = config.stream.as_deref().map(HitStream::create).transpose().map_err(PipelineError::other)?;
+        let interval = Duration::from_secs(config.checkpoint_interval);
+        let checkpoint = config.checkpoint.as_deref()
+            .map(|path| CheckpointWriter::open(path, interval, &cm.name, &config.seqdb, db_residues, config.options_digest()))
+            .transpose()
+            .map_err(PipelineError::other)?;
+        let pipeline = Self { stream, ..Self::with_stages(Arc::clone(&model), config, stages, trunc_stages, db_residues) };
+        if let Some(checkpoint) = &checkpoint {
+            // Targets searched by earlier runs count as read
+            let (targets, residues) = checkpoint.resumed_counts();
+            pipeline.targets.store(targets, Ordering::Relaxed);
+            pipeline.residues.store(residues, Ordering::Relaxed);
+        }
+        Ok(Self { checkpoint, ..pipeline })
     }
     
-    fn search_sequence(&self, sequence: &Sequence) -> Vec<Hit> {
-        let mut hits = Vec::new();
-        
-        // Only search sequences that are long enough - require at least 80% of CM length
-        if sequence.length < (self.cm.length as f64 * 0.8) as usize {
-            return hits;
-        }
-        
-        // Stage 1: HMM-like filtering to identify promising regions
-        let promising_regions = self.hmm_filter_stage(sequence);
-        
-        // Stage 2: CM-based scoring on promising regions
-        for region in promising_regions {
-            if let Some(hit) = self.cm_search_stage(sequence, region) {
-                hits.push(hit);
-            }
+    /// Pipeline running exactly the given stages, in order; the last stage
+    /// of each list should assign the final bit score. Truncated passes run
+    /// `trunc_stages`, and are skipped if it is empty.
+    pub fn with_stages(
+        model: Arc<SearchModel>,
+        config: Arc<Config>,
+        stages: Vec<Box<dyn Stage>>,
+        trunc_stages: Vec<Box<dyn Stage>>,
+        db_residues: u64,
+    ) -> Self {
+        // DP buffers idle between windows count against the --mx-size cap
+        let budget = config.max_rss.map(|budget| (budget * 1024.0 * 1024.0) as usize);
+        let memory = SearchMemory::new(budget, (config.max_mx_size * 1024.0 * 1024.0) as usize);
+        Self {
+            model,
+            config,
+            stages,
+            trunc_stages,
+            db_residues,
+            targets: AtomicUsize::new(0),
+            residues: AtomicU64::new(0),
+            stream: None,
+            checkpoint: None,
+            memory: Arc::new(memory),
         }
-        
-        // Search reverse complement
-        let rev_comp = self.reverse_complement(&sequence.sequence);
-        let rev_sequence = Sequence {
-            name: format!("{}_rev", sequence.name),
-            sequence: rev_comp,
-            length: sequence.length,
+    }
+    
+    /// Counts of the sequences read and the work of each stage so far.
+    pub fn summary(&self) -> PipelineSummary {
+        let summarize = |stages: &[Box<dyn Stage>]| -> Vec<StageSummary> {
+            stages.iter()
+                .map(|stage| {
+                    let stats = stage.stats();
+                    StageSummary {
+                        kind: stage.kind(),
+                        threshold: stage.threshold(),
+                        entered: stats.entered(),
+                        passed: stats.passed(),
+                        residues: stats.residues(),
+                        passed_residues: stats.passed_residues(),
+                        seconds: stats.time().as_secs_f64(),
+                        histogram: stats.histogram(),
+                    }
+                })
+                .collect()
         };
-        
-        let rev_promising_regions = self.hmm_filter_stage(&rev_sequence);
-        for region in rev_promising_regions {
-            if let Some(hit) = self.cm_search_stage(&rev_sequence, region) {
-                // Adjust coordinates for reverse complement
-                let adjusted_hit = Hit {
-                    sequence_name: sequence.name.clone(),
-                    start: sequence.length - hit.end,
-                    end: sequence.length - hit.start,
-                    score: hit.score,
-                    evalue: hit.evalue,
-                    alignment: hit.alignment,
-                };
-                hits.push(adjusted_hit);
+        PipelineSummary {
+            targets: self.targets.load(Ordering::Relaxed),
+            residues: self.residues.load(Ordering::Relaxed),
+            stages: summarize(&self.stages),
+            trunc_stages: summarize(&self.trunc_stages),
+            memory_peak: self.memory.peak() as u64,
+        }
+    }
+    
+    /// Searches the target sequences as they are read: a reader thread
+    /// parses, splits, and digitizes them into a bounded queue of chunks
+    /// while the worker threads search, so I/O overlaps the search. A search
+    /// that stops at --max-rss fails with [`PipelineError::ResourceLimit`].
+    pub fn search<I>(&self, sequences: I) -> Result<Vec<Hit>, PipelineError>
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send,
+    {
+        let hits = search_batch(std::slice::from_ref(self), sequences)?.pop().unwrap_or_default();
+        self.check_limit()?;
+        Ok(hits)
+    }
+    
+    /// Searches the targets as [`Pipeline::search`] does, but hands each
+    /// reported hit to `on_hit` as soon as its target is searched, rather
+    /// than returning them all at the end. The hits of a target come
+    /// together, in the --sort order; targets finish in no set order.
+    pub fn on_hit<I, F>(&self, sequences: I, mut on_hit: F) -> Result<(), PipelineError>
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send,
+        F: FnMut(Hit) + Send,
+    {
+        self.hits_while(sequences, |hit| {
+            on_hit(hit);
+            true
+        })
+    }
+    
+    /// The hits of [`Pipeline::on_hit`] as an iterator. The search runs on a
+    /// thread of its own, its chunks on rayon's global pool, a bounded
+    /// number of hits ahead of the caller; a failed search ends with its
+    /// error. Dropping the iterator stops the search.
+    pub fn search_iter<I>(self: Arc<Self>, sequences: I) -> impl Iterator<Item = Result<Hit, PipelineError>>
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send + 'static,
+    {
+        let (sender, receiver) = channel::bounded(HIT_QUEUE);
+        thread::spawn(move || {
+            let hits = sender.clone();
+            if let Err(err) = self.hits_while(sequences, |hit| hits.send(Ok(hit)).is_ok()) {
+                let _ = sender.send(Err(err));
             }
+        });
+        receiver.into_iter()
+    }
+    
+    // Hands each reported hit to `on_hit` as its target finishes, stopping
+    // the search once `on_hit` wants no more
+    fn hits_while<I, F>(&self, sequences: I, mut on_hit: F) -> Result<(), PipelineError>
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send,
+        F: FnMut(Hit) -> bool + Send,
+    {
+        if self.config.coordinator.is_some() {
+            let message = "--coordinator hands chunks to remote workers, so hits cannot be passed on as targets finish";
+            return Err(PipelineError::Config(message.to_string()));
         }
-        
+        let (sender, receiver) = channel::unbounded::<Vec<Hit>>();
+        let stopped = AtomicBool::new(false);
+        let targets = TargetSink { sender, stopped: &stopped };
+        thread::scope(|scope| {
+            let deliver = scope.spawn(|| {
+                let resumed = self.checkpoint.as_ref().map(CheckpointWriter::resumed_hits).unwrap_or_default();
+                for hits in std::iter::once(resumed).chain(receiver) {
+                    if !self.report(hits).into_iter().all(&mut on_hit) {
+                        stopped.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            });
+            let searched = search_chunks(std::slice::from_ref(self), sequences, Some(targets));
+            deliver.join().unwrap_or_else(|err| panic::resume_unwind(err));
+            searched
+        })?;
+        if stopped.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.check_limit()
+    }
+    
+    // Hits of one target as reported: overlaps resolved, thresholded, and
+    // in the output order
+    fn report(&self, hits: Vec<Hit>) -> Vec<Hit> {
+        let mut hits = overlap::resolve_overlaps(hits, self.config.keep_overlaps);
+        hits.retain(|hit| self.reportable(hit));
+        hits.sort_by(|a, b| order::compare_hits_by(self.config.sort, a, b));
         hits
     }
     
-    fn hmm_filter_stage(&self, sequence: &Sequence) -> Vec<std::ops::Range<usize>> {
-        let mut regions = Vec::new();
-        let consensus = &self.cm.consensus.sequence;
+    // Hits of the whole search, before reporting: with the hits of targets
+    // searched by earlier runs, overlaps resolved, sorted, and thresholded
+    fn finish(&self, mut shards: HitShards) -> Vec<Hit> {
+        if let Some(checkpoint) = &self.checkpoint {
+            shards.add(checkpoint.resumed_hits());
+        }
+        
+        info!("Found {} hits before filtering", shards.len());
+        log_stage_stats(&self.stages);
+        if !self.trunc_stages.is_empty() {
+            info!("Truncated passes:");
+            log_stage_stats(&self.trunc_stages);
+        }
         
-        // Use sliding window with proper HMM-like scoring
-        let window_size = self.cm.length;
-        let step_size = window_size / 2; // Larger step to reduce overlapping windows
+        // Each shard in parallel: overlapping windows and passes report the
+        // same locus more than once, then thresholds based on original
+        // cmsearch behavior, then the output order
+        let shards: Vec<(usize, Vec<Hit>)> = shards.0
+            .into_par_iter()
+            .map(|hits| {
+                let mut hits = overlap::resolve_overlaps(hits, self.config.keep_overlaps);
+                let resolved = hits.len();
+                hits.retain(|hit| self.reportable(hit));
+                hits.sort_by(|a, b| order::compare_hits_by(self.config.sort, a, b));
+                (resolved, hits)
+            })
+            .collect();
+        info!("{} hits after overlap resolution", shards.iter().map(|(resolved, _)| resolved).sum::<usize>());
+        let hits = order::merge_sorted(shards.into_iter().map(|(_, hits)| hits).collect(), self.config.sort);
         
-        for start in (0..sequence.length).step_by(step_size) {
-            let end = std::cmp::min(start + window_size, sequence.length);
-            if end - start < window_size / 2 {
-                break;
+        info!("Pipeline found {} hits after filtering", hits.len());
+        hits
+    }
+    
+    /// Fails with [`PipelineError::ResourceLimit`] if the search stopped at
+    /// --max-rss before searching every target, once its --checkpoint is
+    /// saved to finish the search from.
+    pub fn check_limit(&self) -> Result<(), PipelineError> {
+        if !self.memory.exceeded() {
+            return Ok(());
+        }
+        self.flush_checkpoint()?;
+        Err(PipelineError::ResourceLimit {
+            limit: self.config.max_rss.unwrap_or_default(),
+            checkpoint: self.config.checkpoint.is_some(),
+        })
+    }
+    
+    /// Remove the --checkpoint file once the results are written.
+    pub fn finish_checkpoint(&self) -> Result<(), PipelineError> {
+        self.checkpoint.as_ref().map_or(Ok(()), CheckpointWriter::finish).map_err(PipelineError::other)
+    }
+    
+    /// Save the --checkpoint of a search that stopped early.
+    pub fn flush_checkpoint(&self) -> Result<(), PipelineError> {
+        self.checkpoint.as_ref().map_or(Ok(()), CheckpointWriter::flush).map_err(PipelineError::other)
+    }
+    
+    /// Searches a chunk sent by a --coordinator (worker mode).
+    pub fn search_remote_chunk(&self, chunk: RemoteChunk) -> Vec<Hit> {
+        let sequence = Arc::new(Sequence {
+            name: chunk.name,
+            description: chunk.description,
+            sequence: String::new(), // Only the chunk's residues are sent
+            length: chunk.length,
+        });
+        let range = chunk.start..chunk.start + chunk.residues.len();
+        let _search = self.memory.enter();
+        self.search_chunk(&self.chunk(sequence, Arc::new(self.memory.track(0)), range, chunk.residues))
+    }
+    
+    // Hits of a searched chunk, also written to --stream and --checkpoint
+    fn chunk_searched(&self, chunk: &Chunk, hits: Vec<Hit>) -> Result<Vec<Hit>, PipelineError> {
+        if let Some(stream) = &self.stream {
+            stream.write(hits.iter().filter(|hit| self.reportable(hit)), &self.config).map_err(PipelineError::other)?;
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.chunk_searched(&chunk.sequence.name, &hits).map_err(PipelineError::other)?;
+        }
+        Ok(hits)
+    }
+    
+    // --coordinator: accepts workers until the local threads run out of
+    // chunks, each connection taking chunks from the same queue
+    fn serve_workers(&self, listener: TcpListener, receiver: Receiver<Chunk>, done: &AtomicBool) -> Result<Vec<Hit>, PipelineError> {
+        thread::scope(|scope| {
+            let mut workers = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let connection = stream.set_nonblocking(false)
+                            .map_err(|source| RemoteError::Io { action: "Failed to set up a worker connection".to_string(), source })
+                            .and_then(|()| Connection::new(stream))
+                            .map_err(PipelineError::other)?;
+                        info!("Worker {} connected", connection.peer());
+                        let receiver = receiver.clone();
+                        workers.push(scope.spawn(move || self.serve_worker(connection, receiver)));
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                        thread::sleep(WORKER_POLL);
+                    }
+                    Err(source) => {
+                        let action = "Failed to accept a worker".to_string();
+                        return Err(PipelineError::other(RemoteError::Io { action, source }));
+                    }
+                }
             }
-            
-            // Calculate HMM-like score for this window
-            let score = self.calculate_hmm_score(&sequence.sequence[start..end], consensus);
-            
-            // Use much stricter HMM filter threshold (based on original cmsearch F1 threshold)
-            if score > 0.7 { // Much stricter F1 threshold - only very good matches
-                regions.push(start..end);
+            let mut hits = Vec::new();
+            for worker in workers {
+                hits.extend(worker.join().unwrap_or_else(|err| panic::resume_unwind(err))?);
             }
+            Ok(hits)
+        })
+    }
+    
+    // Sends one worker chunks until the queue is empty. A chunk in flight
+    // when the worker fails is searched here instead, and the worker dropped
+    fn serve_worker(&self, mut connection: Connection, receiver: Receiver<Chunk>) -> Result<Vec<Hit>, PipelineError> {
+        let setup = Request::Setup {
+            cm: Box::new(self.model.cm.clone()),
+            config: Box::new(self.config.for_worker()),
+            db_residues: self.db_residues,
+        };
+        let (mut hits, mut searched) = (Vec::new(), 0);
+        let mut failed = connection.send(&setup).err();
+        while failed.is_none() {
+            let Ok(chunk) = receiver.recv() else {
+                break;
+            };
+            let chunk_hits = match remote_search(&mut connection, &chunk) {
+                Ok(chunk_hits) => {
+                    searched += 1;
+                    chunk_hits
+                }
+                Err(err) => {
+                    failed = Some(err);
+                    self.search_chunk(&chunk)
+                }
+            };
+            hits.extend(self.chunk_searched(&chunk, chunk_hits)?);
         }
-        
-        regions
+        match failed {
+            Some(err) => warn!("Worker {} failed after {} chunks: {}", connection.peer(), searched, error::report(&err)),
+            None => info!("Worker {} searched {} chunks", connection.peer(), searched),
+        }
+        Ok(hits)
     }
     
-    fn cm_search_stage(&self, sequence: &Sequence, region: std::ops::Range<usize>) -> Option<Hit> {
-        let score = self.calculate_cm_score(sequence, &region);
+    // Whether a hit is within the reporting thresholds (-E, -T)
+    fn reportable(&self, hit: &Hit) -> bool {
+        let passes_evalue = hit.evalue <= self.config.evalue;
+        let passes_score = self.config.score.is_none_or(|threshold| hit.score >= threshold);
+        passes_evalue && passes_score
+    }
+    
+    // Reader thread: queues the chunks of each target for the workers.
+    // Chunks of all targets are searched in parallel, so a single chromosome
+    // still uses every thread. Long runs of N are jumped over; a hit of
+    // length up to W cannot span a run of W or more
+    fn read_chunks<I>(&self, sequences: I, sender: Sender<Chunk>, targets: Option<TargetSink>) -> Result<(), PipelineError>
+    where
+        I: Iterator<Item = Result<Sequence, SeqFormatError>>,
+    {
+        let w = self.model.cm.max_hit_length();
+        let min_run = self.config.min_n_run.max(w);
+        let (mut count, mut chunks, mut skipped) = (0, 0, 0);
+        for sequence in sequences {
+            let sequence = Arc::new(sequence?);
+            if self.checkpoint.as_ref().is_some_and(|c| c.is_resumed(&sequence.name)) {
+                continue;
+            }
+            count += 1;
+            if let Some(budget) = self.config.low_mem {
+                // Records are held whole while their chunks are searched
+                if sequence.length as f64 > budget * 1024.0 * 1024.0 / 2.0 {
+                    warn!("Target {} ({} residues) takes over half the --low-mem budget of {} MB",
+                          sequence.name, sequence.length, budget);
+                }
+            }
+            self.targets.fetch_add(1, Ordering::Relaxed);
+            self.residues.fetch_add(sequence.length as u64, Ordering::Relaxed);
+            let record = Arc::new(self.memory.track(sequence.length));
+            let target = targets.as_ref().map(|targets| Arc::new(TargetHits::new(targets.sender.clone())));
+            let runs = digital::ambiguous_runs(sequence.sequence.as_bytes(), min_run);
+            skipped += runs.iter().map(|run| run.len()).sum::<usize>();
+            for segment in between(sequence.length, &runs) {
+                for chunk in self::chunks(segment.len(), w) {
+                    let range = segment.start + chunk.start..segment.start + chunk.end;
+                    // Over --max-rss no more chunks are queued; the search
+                    // ends with the hits of those already queued
+                    if let Err(err) = self.memory.check(2 * range.len()) {
+                        warn!("{}; stopping the search at {}", err, sequence.name);
+                        return Ok(());
+                    }
+                    // Nor once the hits passed on are no longer wanted
+                    if targets.as_ref().is_some_and(TargetSink::stopped) {
+                        return Ok(());
+                    }
+                    let text = sequence.sequence[range.clone()].to_string();
+                    let chunk = Chunk {
+                        target: target.clone(),
+                        ..self.chunk(Arc::clone(&sequence), Arc::clone(&record), range, text)
+                    };
+                    chunks += 1;
+                    if let Some(checkpoint) = &self.checkpoint {
+                        checkpoint.chunk_queued(&sequence.name);
+                    }
+                    // The workers only hang up if they panic or fail to
+                    // write --stream
+                    if sender.send(chunk).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            if let Some(checkpoint) = &self.checkpoint {
+                checkpoint.target_read(&sequence.name, sequence.length).map_err(PipelineError::other)?;
+            }
+        }
         
-        // Use much stricter CM search threshold (based on original cmsearch F6 threshold)
-        let min_score = 0.8; // Much stricter F6 threshold - only excellent matches
-        if score > min_score {
-            let evalue = self.calculate_evalue(score);
-            
-            Some(Hit {
-                sequence_name: sequence.name.clone(),
-                start: region.start,
-                end: region.end,
-                score,
-                evalue,
-                alignment: None,
-            })
-        } else {
-            None
+        info!("Read {} sequences as {} chunks of up to {} residues", count, chunks, 3 * w);
+        if skipped > 0 {
+            info!("Skipped {} residues in runs of {} or more ambiguous residues", skipped, min_run);
         }
+        Ok(())
     }
     
-    fn calculate_hmm_score(&self, sequence: &str, consensus: &str) -> f64 {
-        // Real HMM-like scoring based on original cmsearch MSV filter
-        let min_len = std::cmp::min(sequence.len(), consensus.len());
-        if min_len < 50 {
-            return 0.0;
+    // Scoring runs on residue codes, digitized once per chunk; masked
+    // residues score as background
+    fn chunk(&self, sequence: Arc<Sequence>, record: Arc<Tracked>, range: Range<usize>, text: String) -> Chunk {
+        let bytes = text.as_bytes();
+        let mut codes = DigitizedSeq::from_text(bytes);
+        if self.config.masks_residues() {
+            codes.mask_where(|i| self.config.is_masked(bytes[i]));
         }
+        let buffers = self.memory.track(text.len() + codes.len());
+        Chunk { sequence, range, text, codes, target: None, _record: record, _buffers: buffers }
+    }
+    
+    // Searches the windows of one chunk; hit coordinates are relative to the
+    // whole sequence
+    fn search_chunk(&self, chunk: &Chunk) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        let truncated_passes = !self.trunc_stages.is_empty();
         
-        // Calculate log-odds score similar to MSV filter
-        let mut log_odds = 0.0;
-        let mut total_positions = 0;
-        let mut exact_matches = 0;
+        let sequence = &*chunk.sequence;
+        let text = chunk.text.as_bytes();
+        let offset = chunk.range.start;
+        let digital = &chunk.codes;
+        let masking = self.config.masks_residues();
         
-        for i in 0..min_len {
-            let seq_char = sequence.chars().nth(i).unwrap_or('N');
-            let cons_char = consensus.chars().nth(i).unwrap_or('N');
-            
-            total_positions += 1;
-            
-            // Count exact matches for strict scoring
-            if seq_char.to_ascii_uppercase() == cons_char.to_ascii_uppercase() {
-                exact_matches += 1;
+        // Both strands of a window are scored in one fused pass: the reverse
+        // complement is built per window into a reused buffer rather than as
+        // a second full-length Sequence
+        let mut rc_window = DigitizedSeq::default();
+        
+        for local in windows(chunk.range.len(), self.model.cm.max_hit_length()) {
+            if masking && text[local.clone()].iter().all(|&c| self.config.is_masked(c)) {
+                continue;
             }
+            let window = &digital[local.clone()];
+            let region = chunk.range.start + local.start..chunk.range.start + local.end;
+            DigitizedSeq::reverse_complement_into(window, &mut rc_window);
             
-            // Calculate emission probability
-            let emission_prob = self.calculate_emission_probability(seq_char, cons_char);
-            let null_prob = 0.25; // Background probability for uniform distribution
+            // Composition is strand-symmetric, so the bias is shared by both strands
+            let bias = hmm::composition_bias(window, self.model.cm.null_model.null2_omega);
             
-            if emission_prob > 0.0 {
-                log_odds += (emission_prob / null_prob).ln();
+            for strand in [Strand::Plus, Strand::Minus].into_iter().filter(|&strand| self.config.strands.includes(strand)) {
+                let data = match strand {
+                    Strand::Plus => window,
+                    Strand::Minus => &rc_window[..],
+                };
+                let mut window_hit = self.search_window(data, bias, &self.stages, None);
+                
+                // Windows at the ends of the strand (or every window, with
+                // --anytrunc) get truncated passes for partial hits
+                if window_hit.is_none() && truncated_passes {
+                    let passes = if self.config.trunc == TruncMode::Anywhere {
+                        vec![TruncPass::Anywhere]
+                    } else {
+                        match strand {
+                            Strand::Plus => truncation_passes(region.start == 0, region.end == sequence.length, self.config.passes),
+                            Strand::Minus => truncation_passes(region.end == sequence.length, region.start == 0, self.config.passes),
+                        }
+                    };
+                    window_hit = passes
+                        .into_iter()
+                        .filter_map(|pass| self.search_window(data, bias, &self.trunc_stages, Some(pass)))
+                        .max_by(|a, b| a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal));
+                }
+                let Some(window_hit) = window_hit else {
+                    continue;
+                };
+                
+                // Map window-relative envelope coordinates back to the target
+                let envelope = window_hit.envelope;
+                let (start, end) = match strand {
+                    Strand::Plus => (region.start + envelope.start, region.start + envelope.end),
+                    Strand::Minus => (region.end - envelope.end, region.end - envelope.start),
+                };
+                
+                // Hits may run into masked residues but not start in them
+                let first = match strand {
+                    Strand::Plus => start,
+                    Strand::Minus => end - 1,
+                };
+                if masking && self.config.is_masked(text[first - offset]) {
+                    continue;
+                }
+                let score = window_hit.score;
+                let pvalue = self.calculate_pvalue(score);
+                let id = Hit::stable_id(&self.model.cm.name, &sequence.name, start, end, strand);
+                
+                let residues = &data[envelope];
+                if let Some(dir) = &self.config.dump_dp {
+                    self.dump_matrix(dir, &id, residues);
+                }
+                // The residues as written in the target, on the hit's strand
+                let residues_text = &chunk.text[start - offset..end - offset];
+                let text = || match strand {
+                    Strand::Plus => residues_text.to_string(),
+                    Strand::Minus => digital::reverse_complement_text(residues_text),
+                };
+                let alignment = if self.config.needs_alignments() {
+                    self.model.hmm_align(&self.config, &id, residues, text().as_bytes(), false)
+                } else {
+                    None
+                };
+                
+                // A standard pass hit aligned to part of the model is
+                // truncated where it runs off an end of the strand
+                let (at_5p, at_3p) = match strand {
+                    Strand::Plus => (start == 0, end == sequence.length),
+                    Strand::Minus => (end == sequence.length, start == 0),
+                };
+                let trunc = match &alignment {
+                    Some(alignment) if window_hit.trunc == Truncation::None && truncated_passes => Truncation::of(
+                        at_5p && alignment.model_from > 1,
+                        at_3p && alignment.model_to < self.model.cm.length,
+                    ),
+                    _ => window_hit.trunc,
+                };
+                
+                hits.push(Hit {
+                    id,
+                    sequence_name: sequence.name.clone(),
+                    sequence_description: sequence.description.clone(),
+                    sequence_length: sequence.length,
+                    start,
+                    end,
+                    strand,
+                    trunc,
+                    overlap: Overlap::None,
+                    score,
+                    bias: window_hit.bias,
+                    gc: digital::gc_fraction(residues_text.as_bytes()),
+                    pvalue,
+                    evalue: self.calculate_evalue(pvalue),
+                    alignment,
+                    residues: self.config.hitfasta.is_some().then(text),
+                });
             }
         }
         
-        // Require at least 70% exact matches for HMM filter to pass
-        let match_ratio = exact_matches as f64 / total_positions as f64;
-        if match_ratio < 0.7 {
-            return 0.0;
-        }
-        
-        // Normalize by sequence length and convert to probability
-        let normalized_score = log_odds / total_positions as f64;
-        let probability = 1.0 / (1.0 + (-normalized_score).exp());
-        
-        probability
+        hits
     }
     
-    fn calculate_cm_score(&self, sequence: &Sequence, range: &std::ops::Range<usize>) -> f64 {
-        let seq_slice = &sequence.sequence[range.clone()];
+    // Runs the given stages on one strand of a window
+    fn search_window(
+        &self,
+        window: &[u8],
+        bias: f64,
+        stages: &[Box<dyn Stage>],
+        trunc_pass: Option<TruncPass>,
+    ) -> Option<WindowHit> {
+        let ctx = StageContext { cm: &self.model.cm, hmm: &self.model.hmm, scores: &self.model.scores };
+        let mut candidate = Candidate { trunc_pass, ..Candidate::new(window, bias) };
         
-        if seq_slice.len() < self.cm.length / 2 {
-            return 0.0;
+        for stage in stages {
+            if !stage.run(&ctx, &mut candidate) {
+                return None;
+            }
         }
         
-        // Real CM-based scoring using Inside algorithm approximation
-        self.calculate_cm_likelihood(seq_slice)
+        Some(WindowHit {
+            envelope: candidate.region,
+            score: candidate.score,
+            bias: candidate.bias,
+            trunc: candidate.trunc,
+        })
     }
     
-    fn calculate_cm_likelihood(&self, sequence: &str) -> f64 {
-        let consensus = &self.cm.consensus.sequence;
-        let min_len = std::cmp::min(sequence.len(), consensus.len());
-        
-        if min_len < 50 {
-            return 0.0;
+    fn dump_matrix(&self, dir: &str, id: &str, envelope: &[u8]) {
+        let Some(matrix) = self.model.hmm.forward_matrix(envelope) else {
+            return;
+        };
+        let path = std::path::Path::new(dir).join(format!("{}.npy", id));
+        // A failed debug dump should not abort the search
+        if let Err(e) = matrix.write_npy(&path) {
+            warn!("{}", error::report(&e));
         }
-        
-        // Calculate Inside algorithm score (simplified version)
-        let mut inside_score = 0.0;
-        let mut total_positions = 0;
-        
-        for i in 0..min_len {
-            let seq_char = sequence.chars().nth(i).unwrap_or('N');
-            let cons_char = consensus.chars().nth(i).unwrap_or('N');
-            
-            total_positions += 1;
-            
-            // Calculate emission probability for this position
-            let emission_prob = self.calculate_emission_probability(seq_char, cons_char);
-            
-            // Add to Inside score (log-space)
-            if emission_prob > 0.0 {
-                inside_score += emission_prob.ln();
-            }
+    }
+    
+    // Final scores are calibrated as Infernal's Inside scores, which they
+    // stand in for; Infernal's default is local mode
+    fn calibration(&self) -> Option<&CalibrationParams> {
+        let calibration = &self.model.cm.calibration;
+        calibration.local_inside.as_ref().or(calibration.glocal_inside.as_ref())
+    }
+    
+    fn calculate_pvalue(&self, score: f64) -> f64 {
+        match self.calibration() {
+            Some(params) => params.pvalue(score),
+            None => hmm::bit_score_pvalue(score),
         }
-        
-        // Normalize and convert to probability
-        let normalized_score = inside_score / total_positions as f64;
-        let probability = 1.0 / (1.0 + (-normalized_score).exp());
-        
-        probability
     }
     
-    fn calculate_emission_probability(&self, seq_char: char, cons_char: char) -> f64 {
-        // Calculate emission probability based on CM model - much stricter
-        match (seq_char.to_ascii_uppercase(), cons_char.to_ascii_uppercase()) {
-            (a, b) if a == b => 0.95, // Exact match - very high
-            ('A', 'U') | ('U', 'A') | ('G', 'C') | ('C', 'G') => 0.85, // Watson-Crick - high
-            ('G', 'U') | ('U', 'G') => 0.7, // Wobble - moderate
-            ('N', _) | (_, 'N') => 0.05, // N matches - very low (background)
-            _ => 0.01, // Mismatch - extremely low
+    fn calculate_evalue(&self, pvalue: f64) -> f64 {
+        let db_residues = self.db_residues as f64;
+        let expected = match self.calibration() {
+            Some(params) => params.expected_hits(db_residues),
+            // Uncalibrated: one trial per model-length window searched
+            None => (db_residues / self.model.cm.length.max(1) as f64).max(1.0),
+        };
+        pvalue * expected
+    }
+}
+
+/// Searches the target sequences with every pipeline in one pass: each
+/// chunk is read and digitized once, then searched by each model in turn.
+/// Chunks are sized for the model with the longest hits, so every model's
+/// hits still lie wholly inside a chunk; the other models scan their own
+/// windows of it, which need not fall where they would in a search with
+/// that model alone. Hits are returned per pipeline, in order.
+pub fn search_batch<I>(pipelines: &[Pipeline], sequences: I) -> Result<Vec<Vec<Hit>>, PipelineError>
+where
+    I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send,
+{
+    let model_hits = search_chunks(pipelines, sequences, None)?;
+    Ok(pipelines.iter().zip(model_hits).map(|(pipeline, hits)| pipeline.finish(hits)).collect())
+}
+
+// Searches the chunks of the targets with every pipeline, and returns the
+// hits found by each; with `targets`, the hits of a single pipeline go there
+// instead, those of each target once all its chunks are searched
+fn search_chunks<I>(pipelines: &[Pipeline], sequences: I, targets: Option<TargetSink>) -> Result<Vec<HitShards>, PipelineError>
+where
+    I: Iterator<Item = Result<Sequence, SeqFormatError>> + Send,
+{
+    let Some(reader) = pipelines.iter().max_by_key(|pipeline| pipeline.model.cm.max_hit_length()) else {
+        return Ok(Vec::new());
+    };
+    info!("Starting real CM search pipeline");
+    if pipelines.len() > 1 {
+        info!("Searching {} models in one pass over the targets", pipelines.len());
+    }
+    
+    let config = &reader.config;
+    let listener = match &config.coordinator {
+        Some(addr) => {
+            let listen = || -> io::Result<TcpListener> {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                info!("Coordinating: workers may connect to {}", listener.local_addr()?);
+                Ok(listener)
+            };
+            let listener = listen()
+                .map_err(|source| RemoteError::Io { action: format!("Failed to listen on {}", addr), source })
+                .map_err(PipelineError::other)?;
+            Some(listener)
         }
+        None => None,
+    };
+    // --low-mem queues just enough chunks to keep the threads busy
+    let queue = if config.low_mem.is_some() { 2 * config.threads } else { CHUNK_QUEUE };
+    let (sender, receiver) = channel::bounded(queue);
+    let done = AtomicBool::new(false); // The local threads found the queue empty
+    // The threads stream each chunk's hits to a collector, which shards
+    // them as they arrive
+    let (hit_sender, hit_receiver) = channel::unbounded::<(usize, Vec<Hit>)>();
+    let shard_count = rayon::current_num_threads();
+    let stopped = targets.as_ref().map(|targets| targets.stopped);
+    let (searched, mut model_hits, remote_hits, read) = thread::scope(|scope| {
+        let read = scope.spawn(move || reader.read_chunks(sequences, sender, targets));
+        let remote = listener.map(|listener| {
+            let (receiver, done) = (receiver.clone(), &done);
+            scope.spawn(move || reader.serve_workers(listener, receiver, done))
+        });
+        let collect = scope.spawn(move || {
+            let mut model_hits: Vec<_> = pipelines.iter().map(|_| HitShards::new(shard_count)).collect();
+            for (model, hits) in hit_receiver {
+                model_hits[model].add(hits);
+            }
+            model_hits
+        });
+        let searched = receiver
+            .into_iter()
+            .par_bridge()
+            .try_for_each_with(hit_sender, |hit_sender, chunk| {
+                // Chunks still queued at --max-rss, or once the hits are no
+                // longer wanted, are left unsearched
+                if reader.memory.exceeded() || stopped.is_some_and(|stopped| stopped.load(Ordering::Relaxed)) {
+                    return Ok(());
+                }
+                let _search = reader.memory.enter();
+                for (model, pipeline) in pipelines.iter().enumerate() {
+                    let hits = pipeline.chunk_searched(&chunk, pipeline.search_chunk(&chunk))?;
+                    match &chunk.target {
+                        Some(target) => target.add(hits),
+                        None => hit_sender.send((model, hits)).expect("the collector outlives the search"),
+                    }
+                }
+                Ok::<_, PipelineError>(())
+            });
+        done.store(true, Ordering::Relaxed);
+        let remote_hits = remote.map_or(Ok(Vec::new()), |remote| {
+            remote.join().unwrap_or_else(|err| panic::resume_unwind(err))
+        });
+        let model_hits = collect.join().unwrap_or_else(|err| panic::resume_unwind(err));
+        (searched, model_hits, remote_hits, read.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+    });
+    read?;
+    searched?;
+    
+    // Workers only join single-model searches
+    model_hits[0].add(remote_hits?);
+    
+    // The targets were read once, for every model, within the memory of
+    // the reader's search
+    let (targets, residues) = (reader.targets.load(Ordering::Relaxed), reader.residues.load(Ordering::Relaxed));
+    for pipeline in pipelines {
+        pipeline.targets.store(targets, Ordering::Relaxed);
+        pipeline.residues.store(residues, Ordering::Relaxed);
+        pipeline.memory.record(&reader.memory);
+    }
+    Ok(model_hits)
+}
+
+// Hits of one model, sharded by target and strand: overlaps are only
+// resolved between hits on the same target and strand, so each shard is
+// resolved and sorted on its own
+struct HitShards(Vec<Vec<Hit>>);
+
+impl HitShards {
+    fn new(count: usize) -> Self {
+        Self(vec![Vec::new(); count.max(1)])
     }
     
-    fn nucleotides_match(&self, seq_char: char, cons_char: char) -> bool {
-        // Handle RNA/DNA ambiguity and base pairing
-        match (seq_char.to_ascii_uppercase(), cons_char.to_ascii_uppercase()) {
-            (a, b) if a == b => true,
-            ('A', 'U') | ('U', 'A') | ('G', 'C') | ('C', 'G') => true, // Watson-Crick
-            ('G', 'U') | ('U', 'G') => true, // Wobble
-            ('N', _) | (_, 'N') => true, // N matches anything
-            _ => false,
+    fn add(&mut self, hits: impl IntoIterator<Item = Hit>) {
+        for hit in hits {
+            let mut hasher = StableHasher::new();
+            hasher.update(hit.sequence_name.as_bytes());
+            hasher.update(&[hit.strand.symbol() as u8]);
+            let shard = (hasher.finish() % self.0.len() as u64) as usize;
+            self.0[shard].push(hit);
         }
     }
     
-    fn reverse_complement(&self, sequence: &str) -> String {
-        sequence.chars()
-            .rev()
-            .map(|c| match c {
-                'A' => 'T',
-                'T' => 'A',
-                'G' => 'C',
-                'C' => 'G',
-                'U' => 'A',
-                _ => c,
-            })
-            .collect()
+    fn len(&self) -> usize {
+        self.0.iter().map(Vec::len).sum()
+    }
+}
+
+// A chunk of a target sequence, digitized by the reader thread
+struct Chunk {
+    sequence: Arc<Sequence>,
+    range: Range<usize>,
+    text: String,        // Residues of `range` as written
+    codes: DigitizedSeq, // Residue codes of `range`
+    target: Option<Arc<TargetHits>>, // Where the hits go, when passed on as targets finish
+    _record: Arc<Tracked>, // The record's residues, held until its last chunk is searched
+    _buffers: Tracked,     // `text` and `codes`
+}
+
+// Where the hits of each target go once it is searched, for as long as they
+// are wanted
+struct TargetSink<'a> {
+    sender: Sender<Vec<Hit>>,
+    stopped: &'a AtomicBool, // Set once no more hits are wanted
+}
+
+impl TargetSink<'_> {
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+// Hits of the chunks of one target, gathered as they are searched; once the
+// reader and the last chunk let go of them they are sent on whole
+struct TargetHits {
+    hits: Mutex<Vec<Hit>>,
+    sender: Sender<Vec<Hit>>,
+}
+
+impl TargetHits {
+    fn new(sender: Sender<Vec<Hit>>) -> Self {
+        Self { hits: Mutex::new(Vec::new()), sender }
     }
     
-    fn calculate_evalue(&self, score: f64) -> f64 {
-        // Much more realistic E-value calculation based on CM score and database size
-        // This is a simplified version - real cmsearch uses calibrated parameters
-        if score > 0.95 {
-            1e-100 // Extremely high confidence
-        } else if score > 0.9 {
-            1e-50  // Very high confidence
-        } else if score > 0.85 {
-            1e-30  // High confidence
-        } else if score > 0.8 {
-            1e-15  // Good confidence
-        } else if score > 0.75 {
-            1e-8   // Moderate confidence
-        } else if score > 0.7 {
-            1e-4   // Low confidence
-        } else if score > 0.65 {
-            1e-2   // Very low confidence
-        } else if score > 0.6 {
-            1e-1   // Extremely low confidence
-        } else {
-            1.0    // Not significant
+    fn add(&self, hits: Vec<Hit>) {
+        self.hits.lock().unwrap_or_else(|err| err.into_inner()).extend(hits);
+    }
+}
+
+impl Drop for TargetHits {
+    fn drop(&mut self) {
+        let hits = std::mem::take(self.hits.get_mut().unwrap_or_else(|err| err.into_inner()));
+        // The receiver only hangs up if the search failed
+        let _ = self.sender.send(hits);
+    }
+}
+
+struct WindowHit {
+    envelope: Range<usize>, // Relative to the scored window strand
+    score: f64,
+    bias: f64, // Null2 + null3 correction in bits
+    trunc: Truncation,
+}
+
+// Searches a chunk on a worker
+fn remote_search(connection: &mut Connection, chunk: &Chunk) -> Result<Vec<Hit>, RemoteError> {
+    connection.send(&Request::Chunk(RemoteChunk {
+        name: chunk.sequence.name.clone(),
+        description: chunk.sequence.description.clone(),
+        length: chunk.sequence.length,
+        start: chunk.range.start,
+        residues: chunk.text.clone(),
+    }))?;
+    connection.receive()?.ok_or_else(|| RemoteError::Protocol("worker hung up".to_string()))
+}
+
+// Chunks of 3W residues overlapping by W, searched independently. Every hit
+// of length up to W lies wholly inside at least one chunk, and the windows
+// of the chunks are exactly the windows of the whole sequence. A W of 0
+// counts as 1
+fn chunks(length: usize, w: usize) -> Vec<Range<usize>> {
+    let w = w.max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let end = std::cmp::min(start + 3 * w, length);
+        chunks.push(start..end);
+        if end == length {
+            break;
         }
+        start += 2 * w;
+    }
+    chunks
+}
+
+// Parts of 0..length outside the ordered, disjoint `runs`
+fn between(length: usize, runs: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut parts = Vec::with_capacity(runs.len() + 1);
+    let mut start = 0;
+    for run in runs.iter().chain(std::iter::once(&(length..length))) {
+        if run.start > start {
+            parts.push(start..run.start);
+        }
+        start = run.end;
+    }
+    parts
+}
+
+// Windows of 2W residues overlapping by W, so that every hit of length up
+// to W lies wholly inside at least one window. A W of 0 counts as 1
+fn windows(length: usize, w: usize) -> Vec<Range<usize>> {
+    let w = w.max(1);
+    let mut windows = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let end = std::cmp::min(start + 2 * w, length);
+        windows.push(start..end);
+        if end == length {
+            break;
+        }
+        start += w;
+    }
+    windows
+}
+
+// Truncated pass types for a window touching the 5' and/or 3' end of the
+// searched strand, out of the first `passes` search passes (pass 1 is the
+// standard pass)
+fn truncation_passes(at_5p: bool, at_3p: bool, passes: usize) -> Vec<TruncPass> {
+    let mut trunc_passes = Vec::new();
+    if at_5p {
+        trunc_passes.push(TruncPass::FivePrime);
+    }
+    if at_3p {
+        trunc_passes.push(TruncPass::ThreePrime);
+    }
+    if at_5p && at_3p && passes >= 3 {
+        trunc_passes.push(TruncPass::Both);
+    }
+    trunc_passes
+}
+
+fn log_stage_stats(stages: &[Box<dyn Stage>]) {
+    for stage in stages {
+        let stats = stage.stats();
+        info!("  {:<9} (threshold {:e}): {} in, {} passed, {} residues",
+              stage.name(), stage.threshold(), stats.entered(), stats.passed(), stats.residues());
     }
-} 
\ No newline at end of file
+}
+
+/// Models and targets shared by the tests of the search modules.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::fs;
+    use std::path::Path;
+
+    /// Consensus of the test models: a tRNA.
+    pub const CONSENSUS: &str = "GCGGAUUUAGCUCAGUUGGGAGAGCGCCAGACUGAAGAUCUGGAGG";
+
+    /// Unrelated sequence to place hits between.
+    pub fn flank() -> String {
+        "ACGUUGCAAGCU".repeat(20)
+    }
+
+    /// `consensus` once, between two flanks.
+    pub fn planted(consensus: &str) -> String {
+        let flank = flank();
+        format!("{}{}{}", flank, consensus, flank)
+    }
+
+    /// Writes a CM file of models emitting their consensus, each given as
+    /// (name, consensus), to `path`.
+    pub fn write_models(path: &Path, models: &[(&str, &str)]) {
+        let mut file = String::new();
+        for (name, consensus) in models {
+            file += &format!("INFERNAL1/a\nNAME  {}\nCLEN  {}\nHMM\n", name, consensus.len());
+            for (k, c) in consensus.chars().enumerate() {
+                let scores: Vec<&str> = "ACGU".chars().map(|b| if b == c { "-0.3" } else { "-2.0" }).collect();
+                file += &format!("{} {} {}\n", k + 1, scores.join(" "), c);
+            }
+            file += "//\n";
+        }
+        fs::write(path, file).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fixtures::{flank, planted, CONSENSUS};
+    use crate::cm::Alphabet;
+    use crate::overlap::Overlap;
+    use crate::search::Truncation;
+
+    fn test_model() -> Arc<SearchModel> {
+        let mut cm = Cm::new("test".to_string(), Alphabet::RNA);
+        cm.consensus.sequence = CONSENSUS.to_string();
+        cm.consensus.structure = ":".repeat(cm.consensus.sequence.len());
+        cm.consensus.length = cm.consensus.sequence.len();
+        cm.length = cm.consensus.length;
+        Arc::new(SearchModel::new(cm, &Config::new()))
+    }
+
+    fn target(name: &str, sequence: String) -> Sequence {
+        Sequence { name: name.to_string(), description: String::new(), length: sequence.len(), sequence }
+    }
+
+    // The model's consensus between stretches of unrelated sequence
+    fn targets() -> impl Iterator<Item = Result<Sequence, SeqFormatError>> + Send {
+        std::iter::once(Ok(target("chr1", planted(CONSENSUS))))
+    }
+
+    // Targets with hits on either strand, more than one, or none
+    fn several_targets() -> Vec<Sequence> {
+        let (flank, consensus) = (flank(), test_model().cm.consensus.sequence.clone());
+        let reverse = digital::reverse_complement_text(&consensus);
+        vec![
+            target("chr1", format!("{}{}{}", flank, consensus, flank)),
+            target("chr2", flank.repeat(2)),
+            target("chr3", format!("{}{}{}", flank, reverse, flank)),
+            target("chr4", format!("{}{}{}{}", consensus, flank, consensus, flank)),
+        ]
+    }
+
+    fn positions(mut hits: Vec<Hit>) -> Vec<(String, usize, usize, Strand)> {
+        hits.sort_by(|a, b| (&a.sequence_name, a.start).cmp(&(&b.sequence_name, b.start)));
+        hits.into_iter().map(|hit| (hit.sequence_name, hit.start, hit.end, hit.strand)).collect()
+    }
+
+    fn hit(score: f64, evalue: f64) -> Hit {
+        Hit {
+            id: String::new(),
+            sequence_name: "chr1".to_string(),
+            sequence_description: String::new(),
+            sequence_length: 1000,
+            start: 100,
+            end: 146,
+            strand: Strand::Plus,
+            trunc: Truncation::None,
+            overlap: Overlap::None,
+            score,
+            bias: 0.0,
+            gc: 0.5,
+            pvalue: evalue,
+            evalue,
+            alignment: None,
+            residues: None,
+        }
+    }
+
+    #[test]
+    fn test_reporting_thresholds() {
+        let config = Config { evalue: 0.01, ..Config::new() };
+        let pipeline = Pipeline::with_stages(test_model(), Arc::new(config), Vec::new(), Vec::new(), 0);
+        assert!(pipeline.reportable(&hit(20.0, 0.01)));
+        assert!(!pipeline.reportable(&hit(20.0, 0.02)));
+
+        // -T reports by score, and the hit must still be within -E
+        let config = Config { score: Some(30.0), evalue: 1.0, ..Config::new() };
+        let pipeline = Pipeline::with_stages(test_model(), Arc::new(config), Vec::new(), Vec::new(), 0);
+        assert!(pipeline.reportable(&hit(30.0, 1e-6)));
+        assert!(!pipeline.reportable(&hit(29.9, 1e-6)));
+        assert!(!pipeline.reportable(&hit(40.0, 2.0)));
+    }
+
+    #[test]
+    fn test_memory_budget_is_per_search() {
+        // The first search stops at its --max-rss budget; the next has none
+        let config = Config { max_rss: Some(1e-3), ..Config::new() };
+        let pipeline = Pipeline::new(test_model(), Arc::new(config), 1_000_000).unwrap();
+        assert!(matches!(pipeline.search(targets()), Err(PipelineError::ResourceLimit { .. })));
+
+        let pipeline = Pipeline::new(test_model(), Arc::new(Config::new()), 1_000_000).unwrap();
+        let hits = pipeline.search(targets()).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].start, hits[0].end), (240, 286));
+    }
+
+    #[test]
+    fn test_streamed_hits_match_search() {
+        let pipeline = Arc::new(Pipeline::new(test_model(), Arc::new(Config::new()), 1_000_000).unwrap());
+        let searched = positions(pipeline.search(several_targets().into_iter().map(Ok)).unwrap());
+        assert_eq!(searched.len(), 4);
+        assert_eq!(searched[1], ("chr3".to_string(), 240, 286, Strand::Minus));
+
+        let streamed = Mutex::new(Vec::new());
+        pipeline.on_hit(several_targets().into_iter().map(Ok), |hit| streamed.lock().unwrap().push(hit)).unwrap();
+        assert_eq!(positions(streamed.into_inner().unwrap()), searched);
+
+        let iterated = pipeline.search_iter(several_targets().into_iter().map(Ok)).collect::<Result<Vec<_>, _>>();
+        assert_eq!(positions(iterated.unwrap()), searched);
+    }
+
+    #[test]
+    fn test_truncated_hits_at_sequence_ends() {
+        // The model's last 26 positions at the start of chr1, and its first
+        // 30 at the end of chr2
+        let (flank, consensus) = (flank(), test_model().cm.consensus.sequence.clone());
+        let targets = vec![
+            target("chr1", format!("{}{}", &consensus[20..], flank)),
+            target("chr2", format!("{}{}", flank, &consensus[..30])),
+        ];
+        let pipeline = Pipeline::new(test_model(), Arc::new(Config::new()), 1_000_000).unwrap();
+        let mut hits = pipeline.search(targets.into_iter().map(Ok)).unwrap();
+        hits.sort_by(|a, b| a.sequence_name.cmp(&b.sequence_name));
+        let ends: Vec<_> = hits.iter()
+            .map(|hit| {
+                let alignment = hit.alignment.as_ref().unwrap();
+                (hit.sequence_name.as_str(), hit.start, hit.end, hit.trunc, alignment.model_from, alignment.model_to)
+            })
+            .collect();
+        assert_eq!(ends, [("chr1", 0, 26, Truncation::FivePrime, 21, 46), ("chr2", 240, 270, Truncation::ThreePrime, 1, 30)]);
+    }
+
+    #[test]
+    fn test_one_long_target_is_searched_in_chunks() {
+        // Copies of the model along one target many chunks long
+        let model = test_model();
+        let (flank, consensus) = (flank(), model.cm.consensus.sequence.clone());
+        let sequence = format!("{}{}", flank, consensus).repeat(12) + &flank;
+        assert!(chunks(sequence.len(), model.cm.max_hit_length()).len() > 16);
+        let copies: Vec<_> = (0..12)
+            .map(|i| ("chr1".to_string(), 240 + i * 286, 286 + i * 286, Strand::Plus))
+            .collect();
+
+        // Its chunks are shared out to the threads, which find each copy
+        // once whichever chunk holds it
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let pipeline = Pipeline::new(Arc::clone(&model), Arc::new(Config::new()), 1_000_000).unwrap();
+            let hits = pool.install(|| pipeline.search(std::iter::once(Ok(target("chr1", sequence.clone()))))).unwrap();
+            assert_eq!(positions(hits), copies, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn test_non_ascii_byte_at_chunk_boundary() {
+        // An invalid byte just before the second chunk's start would put
+        // that start inside its three-byte U+FFFD; the reader rejects it
+        // rather than leaving read_chunks to slice through the character
+        let model = test_model();
+        let w = model.cm.max_hit_length();
+        let mut fasta = b">chr1\n".to_vec();
+        fasta.extend("ACGU".repeat(w).bytes().take(2 * w - 1));
+        fasta.push(0xff);
+        fasta.extend("ACGU".repeat(w).bytes());
+        fasta.push(b'\n');
+        let reader = crate::seqfile::FastaReader::new(std::io::Cursor::new(fasta), "chr1.fa");
+
+        let pipeline = Pipeline::new(model, Arc::new(Config::new()), 1_000_000).unwrap();
+        let err = pipeline.search(reader).unwrap_err();
+        assert!(matches!(err, PipelineError::Sequence(SeqFormatError::Malformed { line: 2, .. })), "{:?}", err);
+    }
+
+    #[test]
+    fn test_search_iter_ends_with_error() {
+        let pipeline = Arc::new(Pipeline::new(test_model(), Arc::new(Config::new()), 1_000_000).unwrap());
+        let unreadable = SeqFormatError::Malformed { input: "targets.fa".to_string(), line: 9, message: "bad record".to_string() };
+        let mut results: Vec<_> = pipeline.search_iter(targets().chain(std::iter::once(Err(unreadable)))).collect();
+        assert!(matches!(results.pop(), Some(Err(PipelineError::Sequence(SeqFormatError::Malformed { line: 9, .. })))));
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn test_windows_contain_every_hit() {
+        let (length, w) = (1000, 120);
+        let windows = windows(length, w);
+        assert_eq!(windows.first(), Some(&(0..240)));
+        assert_eq!(windows.last().map(|r| r.end), Some(length));
+
+        for start in 0..=length - w {
+            assert!(windows.iter().any(|r| r.start <= start && start + w <= r.end),
+                    "hit at {} not contained in any window", start);
+        }
+        assert_eq!(super::windows(50, w), vec![0..50]);
+        assert!(super::windows(0, w).is_empty());
+    }
+
+    #[test]
+    fn test_windows_of_short_sequences() {
+        assert_eq!(windows(100, 60), vec![0..100]);
+        assert_eq!(windows(120, 60), vec![0..120]);
+        assert_eq!(windows(121, 60), vec![0..120, 60..121]);
+        assert_eq!(chunks(180, 60), vec![0..180]);
+        // A model of no length still gets windows, of at most 2 residues
+        assert_eq!(windows(3, 0), vec![0..2, 1..3]);
+        assert_eq!(chunks(4, 0), vec![0..3, 2..4]);
+    }
+
+    #[test]
+    fn test_chunk_windows_match_sequence_windows() {
+        let w = 120;
+        for length in [0, 50, 240, 360, 361, 600, 1000, 1201] {
+            let mut from_chunks: Vec<Range<usize>> = chunks(length, w)
+                .into_iter()
+                .flat_map(|c| windows(c.len(), w).into_iter().map(move |r| c.start + r.start..c.start + r.end))
+                .collect();
+            from_chunks.sort_by_key(|r| (r.start, r.end));
+            assert_eq!(from_chunks, windows(length, w), "length {}", length);
+        }
+        assert_eq!(chunks(1000, w).first(), Some(&(0..360)));
+    }
+
+    #[test]
+    fn test_between_runs() {
+        assert_eq!(between(100, &[]), vec![0..100]);
+        assert_eq!(between(100, &[0..10, 40..50, 90..100]), vec![10..40, 50..90]);
+        assert_eq!(between(100, std::slice::from_ref(&(0..100))), Vec::<Range<usize>>::new());
+        assert!(between(0, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_truncation_passes() {
+        assert!(truncation_passes(false, false, 3).is_empty());
+        assert_eq!(truncation_passes(true, false, 2), vec![TruncPass::FivePrime]);
+        assert_eq!(truncation_passes(true, true, 2), vec![TruncPass::FivePrime, TruncPass::ThreePrime]);
+        assert_eq!(truncation_passes(true, true, 3),
+                   vec![TruncPass::FivePrime, TruncPass::ThreePrime, TruncPass::Both]);
+    }
+}
@@ -0,0 +1,111 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use crate::memory::Tracked;
+
+thread_local! {
+    static POOL: RefCell<MatrixPool> = RefCell::new(MatrixPool::default());
+}
+
+/// Idle DP buffers of one thread, by element type, so the scan and
+/// alignment matrices of each window reuse the allocations of the last.
+#[derive(Default)]
+struct MatrixPool {
+    free: HashMap<TypeId, Vec<Box<dyn Any>>>, // Each a Vec of that element type
+    bytes: usize,                             // Capacity of the idle buffers
+}
+
+impl MatrixPool {
+    fn take<T: 'static>(&mut self) -> Option<Vec<T>> {
+        let buffer = self.free.get_mut(&TypeId::of::<T>())?.pop()?;
+        let buffer = *buffer.downcast::<Vec<T>>().ok()?;
+        self.bytes -= bytes_of(&buffer);
+        Some(buffer)
+    }
+
+    // Keeps `buffer` unless that would hold more than `limit` bytes idle
+    fn give<T: 'static>(&mut self, buffer: Vec<T>, limit: usize) {
+        let bytes = bytes_of(&buffer);
+        if bytes == 0 || self.bytes + bytes > limit {
+            return;
+        }
+        self.bytes += bytes;
+        self.free.entry(TypeId::of::<T>()).or_default().push(Box::new(buffer));
+    }
+}
+
+/// `len` copies of `fill` in a buffer of this thread's pool, returned to
+/// the pool when dropped, within the --mx-size cap on idle buffers of the
+/// search the thread is in.
+pub fn take<T: Copy + 'static>(len: usize, fill: T) -> PooledVec<T> {
+    let mut buffer = POOL.try_with(|pool| pool.borrow_mut().take()).ok().flatten().unwrap_or_default();
+    buffer.clear();
+    buffer.resize(len, fill);
+    let tracked = Tracked::current(bytes_of(&buffer));
+    PooledVec { buffer, tracked }
+}
+
+/// A buffer on loan from the thread's pool.
+pub struct PooledVec<T: 'static> {
+    buffer: Vec<T>,
+    tracked: Tracked, // Counted as live DP memory of the search while on loan
+}
+
+impl<T> Deref for PooledVec<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.buffer
+    }
+}
+
+impl<T> DerefMut for PooledVec<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.buffer
+    }
+}
+
+impl<T> Drop for PooledVec<T> {
+    fn drop(&mut self) {
+        // Count what the buffer grew to while on loan
+        self.tracked.resize(bytes_of(&self.buffer));
+        let buffer = std::mem::take(&mut self.buffer);
+        // The pool is gone once its thread is exiting; the buffer is freed
+        let limit = self.tracked.pool_limit();
+        let _ = POOL.try_with(|pool| pool.borrow_mut().give(buffer, limit));
+    }
+}
+
+fn bytes_of<T>(buffer: &Vec<T>) -> usize {
+    buffer.capacity() * std::mem::size_of::<T>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuses_buffers() {
+        let mut first = take(100, 0.0f64);
+        first[7] = 1.0;
+        let address = first.as_ptr();
+        drop(first);
+
+        // Refilled, in the same allocation
+        let second = take(50, -1.0f64);
+        assert_eq!(second.as_ptr(), address);
+        assert!(second.iter().all(|&x| x == -1.0));
+    }
+
+    #[test]
+    fn test_pool_limit() {
+        let mut pool = MatrixPool::default();
+        pool.give(vec![0u64; 10], 100);
+        pool.give(vec![0u64; 10], 100);
+        assert_eq!(pool.bytes, 80);
+        assert!(pool.take::<u32>().is_none());
+        assert_eq!(pool.take::<u64>().map(|b| b.len()), Some(10));
+        assert_eq!(pool.bytes, 0);
+    }
+}
@@ -0,0 +1,207 @@
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::cm::Cm;
+use crate::config::Config;
+use crate::error::RemoteError;
+use crate::pipeline::{Pipeline, SearchModel};
+use crate::search::Hit;
+
+/// Messages from a `search --coordinator` to its workers, one JSON object
+/// per line. Each worker connection is set up once, then searches one chunk
+/// at a time and answers with its hits (a JSON array).
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// The search every chunk belongs to: the model, the options, and Z.
+    Setup { cm: Box<Cm>, config: Box<Config>, db_residues: u64 },
+    Chunk(RemoteChunk),
+}
+
+/// A chunk of a target, with the target's name and length so hits come back
+/// in target coordinates.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoteChunk {
+    pub name: String,
+    pub description: String,
+    pub length: usize,   // Of the whole target
+    pub start: usize,    // Target position of the first residue
+    pub residues: String,
+}
+
+/// A worker or coordinator end of a connection.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    peer: String,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Result<Self, RemoteError> {
+        let peer = stream.peer_addr().map_or_else(|_| "?".to_string(), |addr| addr.to_string());
+        let reader = stream.set_nodelay(true)
+            .and_then(|()| stream.try_clone())
+            .map_err(|source| RemoteError::Io { action: format!("Failed to set up the connection to {}", peer), source })?;
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(stream),
+            peer,
+        })
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn send<T: Serialize>(&mut self, message: &T) -> Result<(), RemoteError> {
+        let mut send = || -> io::Result<()> {
+            serde_json::to_writer(&mut self.writer, message)?;
+            self.writer.write_all(b"\n")?;
+            self.writer.flush()
+        };
+        send().map_err(|source| RemoteError::Io { action: format!("Failed to send to {}", self.peer), source })
+    }
+
+    /// The next message, or None once the other end hangs up.
+    pub fn receive<T: DeserializeOwned>(&mut self) -> Result<Option<T>, RemoteError> {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line)
+            .map_err(|source| RemoteError::Io { action: format!("Failed to receive from {}", self.peer), source })?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let message = serde_json::from_str(&line)
+            .map_err(|source| RemoteError::Malformed { peer: self.peer.clone(), source })?;
+        Ok(Some(message))
+    }
+}
+
+/// Worker mode: open `threads` connections to the coordinator at `addr`
+/// and search the chunks sent on each until the coordinator is done. The
+/// connections share one pipeline, set up by the first to hear from the
+/// coordinator.
+pub fn run_worker(addr: &str, threads: usize) -> Result<(), RemoteError> {
+    info!("Connecting {} worker threads to coordinator {}", threads, addr);
+    let shared = Mutex::new(None);
+    let searched = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| scope.spawn(|| serve_coordinator(addr, &shared)))
+            .collect();
+        workers.into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+            .sum::<Result<usize, _>>()
+    })?;
+    info!("Coordinator {} is done; searched {} chunks", addr, searched);
+    Ok(())
+}
+
+// One worker connection; returns the number of chunks searched
+fn serve_coordinator(addr: &str, shared: &Mutex<Option<Arc<Pipeline>>>) -> Result<usize, RemoteError> {
+    let stream = TcpStream::connect(addr)
+        .map_err(|source| RemoteError::Io { action: format!("Failed to connect to coordinator {}", addr), source })?;
+    let mut connection = Connection::new(stream)?;
+    let pipeline = match connection.receive()? {
+        Some(Request::Setup { cm, config, db_residues }) => {
+            let mut shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+            match &*shared {
+                Some(pipeline) => Arc::clone(pipeline),
+                None => {
+                    info!("Searching {} for {} (Z = {})", cm.name, connection.peer(), db_residues);
+                    // Whoever connects sends the options, so the files only a
+                    // coordinator writes are dropped rather than created here
+                    let config = config.for_worker();
+                    let model = Arc::new(SearchModel::new(*cm, &config));
+                    let pipeline = Arc::new(Pipeline::new(model, Arc::new(config), db_residues)?);
+                    *shared = Some(Arc::clone(&pipeline));
+                    pipeline
+                }
+            }
+        }
+        Some(Request::Chunk(_)) => {
+            return Err(RemoteError::Protocol(format!("Coordinator {} sent a chunk before the search setup", addr)));
+        }
+        None => return Ok(0),
+    };
+
+    let mut searched = 0;
+    while let Some(request) = connection.receive()? {
+        let Request::Chunk(chunk) = request else {
+            return Err(RemoteError::Protocol(format!("Coordinator {} sent a second search setup", addr)));
+        };
+        let hits: Vec<Hit> = pipeline.search_remote_chunk(chunk);
+        connection.send(&hits)?;
+        searched += 1;
+    }
+    Ok(searched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use crate::pipeline::fixtures::{planted, write_models, CONSENSUS};
+
+    #[test]
+    fn test_connection_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut connection = Connection::new(TcpStream::connect(addr).unwrap()).unwrap();
+            let chunk = RemoteChunk {
+                name: "chr1".to_string(),
+                description: String::new(),
+                length: 1000,
+                start: 250,
+                residues: "ACGU".to_string(),
+            };
+            connection.send(&Request::Chunk(chunk)).unwrap();
+        });
+
+        let mut connection = Connection::new(listener.accept().unwrap().0).unwrap();
+        match connection.receive::<Request>().unwrap() {
+            Some(Request::Chunk(chunk)) => assert_eq!((chunk.start, chunk.residues.as_str()), (250, "ACGU")),
+            other => panic!("unexpected message {:?}", other),
+        }
+        client.join().unwrap();
+        // The worker hung up
+        assert!(connection.receive::<Request>().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_worker_writes_no_coordinator_files() {
+        let dir = std::env::temp_dir().join(format!("remote-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("tRNA.cm"), &[("tRNA", CONSENSUS)]);
+        let cm = Cm::from_file(&dir.join("tRNA.cm")).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let config = Config {
+            stream: Some(path("stream.jsonl")),
+            checkpoint: Some(path("checkpoint.json")),
+            dump_dp: Some(path("dp")),
+            ..Config::new()
+        };
+
+        // A coordinator asking for those files, then sending one chunk
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let worker = thread::spawn(move || serve_coordinator(&addr, &Mutex::new(None)));
+        let mut connection = Connection::new(listener.accept().unwrap().0).unwrap();
+        connection.send(&Request::Setup { cm: Box::new(cm), config: Box::new(config), db_residues: 1_000_000 }).unwrap();
+        let residues = planted(CONSENSUS);
+        let chunk = RemoteChunk { name: "chr1".to_string(), description: String::new(), length: residues.len(), start: 0, residues };
+        connection.send(&Request::Chunk(chunk)).unwrap();
+        let hits: Vec<Hit> = connection.receive().unwrap().unwrap();
+        drop(connection);
+        assert_eq!(worker.join().unwrap().unwrap(), 1);
+
+        assert!(hits.iter().any(|hit| hit.sequence_name == "chr1"));
+        for name in ["stream.jsonl", "checkpoint.json", "checkpoint.json.tmp", "dp"] {
+            assert!(!dir.join(name).exists(), "the worker created {}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
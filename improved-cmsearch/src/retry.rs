@@ -0,0 +1,86 @@
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Errors worth retrying: the kinds network filesystems produce sporadically.
+pub fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => true,
+        // EIO, EAGAIN and ESTALE (stale NFS file handle)
+        _ => matches!(err.raw_os_error(), Some(5) | Some(11) | Some(116)),
+    }
+}
+
+/// Run `op`, retrying transient I/O errors with exponential backoff.
+pub fn with_retry<T>(what: &str, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                warn!("{} failed ({}), retrying in {:?} (attempt {}/{})", what, err, backoff, attempt, MAX_ATTEMPTS);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// File reader that retries transient read failures, reopening the file and
+/// seeking back to the current offset before each retry.
+pub struct RetryingFile {
+    path: PathBuf,
+    file: File,
+    offset: u64,
+}
+
+impl RetryingFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = with_retry(&format!("Opening {}", path.display()), || File::open(path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            offset: 0,
+        })
+    }
+
+    /// Byte offset of the next read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        self.file = file;
+        Ok(())
+    }
+}
+
+impl Read for RetryingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let what = format!("Reading {} at byte offset {}", self.path.display(), self.offset);
+        let mut first = true;
+        let n = with_retry(&what, || {
+            if !first {
+                self.reopen()?;
+            }
+            first = false;
+            self.file.read(buf)
+        })?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
@@ -1,122 +1,560 @@
-use anyhow::Result;
-use log::info;
-use rayon::prelude::*;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use crate::config::Config;
+use log::{info, warn};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use crate::align::Alignment;
+use crate::builder::SearchBuilder;
+use crate::cache::ResultCache;
+use crate::clan::Clans;
+use crate::config::{Config, STDIN_PATH};
+use crate::error::{self, Error, PipelineError, SeqFormatError};
 use crate::cm::Cm;
-use crate::pipeline::Pipeline;
-use crate::output::OutputWriter;
+use crate::pipeline::{self, Pipeline, SearchModel};
+use crate::output::{HitStream, OutputWriter, QueryResult, ScanSequence};
+use crate::overlap::Overlap;
+use crate::seqfile::{self, SequenceReader};
+use crate::seqindex::SeqIndex;
+use crate::seqselect::TargetFilter;
+use crate::utils;
 
 pub struct CmSearch {
-    config: Config,
-    cm: Cm,
-    pipeline: Pipeline,
+    config: Arc<Config>,
+    models: Vec<Arc<SearchModel>>, // Searched together in one pass over the targets
+    clans: Option<Clans>,
     output_writer: OutputWriter,
 }
 
 impl CmSearch {
-    pub fn new(config: Config) -> Result<Self> {
+    /// A search of models and targets in memory, for library use; see
+    /// [`SearchBuilder`].
+    pub fn builder() -> SearchBuilder {
+        SearchBuilder::new()
+    }
+    
+    pub fn new(config: Config) -> Result<Self, Error> {
+        config.validate().map_err(PipelineError::Config)?;
+        let models = Self::load_models(&config)?;
+        Self::with_models(config, models)
+    }
+    
+    /// The search models of every CM in `config.cmfile`.
+    pub fn load_models(config: &Config) -> Result<Vec<Arc<SearchModel>>, Error> {
+        let cms = Cm::all_from_file(std::path::Path::new(&config.cmfile))?;
+        for cm in &cms {
+            cm.validate()?;
+        }
+        Ok(cms.into_iter().map(|cm| Arc::new(SearchModel::new(cm, config))).collect())
+    }
+    
+    /// A search with the models of `config.cmfile` already loaded, as the
+    /// jobs of a batch share them.
+    pub fn with_models(mut config: Config, models: Vec<Arc<SearchModel>>) -> Result<Self, Error> {
+        config.threads = utils::resolve_threads(config.threads);
         info!("Initializing cmsearch with config: {:?}", config);
         
-        // Load CM
-        let cm = Cm::from_file(std::path::Path::new(&config.cmfile))?;
-        cm.validate()?;
+        config.validate().map_err(PipelineError::Config)?;
         
-        // Initialize pipeline
-        let pipeline = Pipeline::new(&cm, &config)?;
+        if models.len() > 1 {
+            info!("Loaded {} models from {}", models.len(), config.cmfile);
+            let single = [("--stream", config.stream.is_some()), ("--checkpoint", config.checkpoint.is_some()),
+                          ("--coordinator", config.coordinator.is_some())];
+            if let Some((option, _)) = single.iter().find(|(_, set)| *set) {
+                let message = format!("{} searches a single model, but {} holds {}", option, config.cmfile, models.len());
+                return Err(PipelineError::Config(message).into());
+            }
+        }
+        
+        let clans = config.clanin.as_deref().map(Clans::from_file).transpose()?;
         
         // Initialize output writer
         let output_writer = OutputWriter::new(&config)?;
         
         Ok(Self {
-            config,
-            cm,
-            pipeline,
+            config: Arc::new(config),
+            models,
+            clans,
             output_writer,
         })
     }
     
-    pub fn run(&mut self) -> Result<()> {
+    /// Runs the search on a pool of `config.threads` threads and writes the
+    /// results; returns the number of hits reported.
+    pub fn run(&mut self) -> Result<usize, Error> {
+        let pool = ThreadPoolBuilder::new().num_threads(self.config.threads).build().map_err(PipelineError::other)?;
+        self.run_on(&pool)
+    }
+    
+    /// Runs the search on `pool` rather than a pool of its own.
+    pub fn run_on(&mut self, pool: &ThreadPool) -> Result<usize, Error> {
         info!("Starting cmsearch");
         
-        // Load sequence database
-        let sequences = self.load_sequences()?;
-        info!("Loaded {} sequences", sequences.len());
+        // A search already in the --cache is not run again; its hits still
+        // go to --stream, no longer as they are found
+        let cache = self.config.cache.as_deref().map(|dir| ResultCache::open(dir, &self.config)).transpose()?;
+        if let Some((queries, db_residues)) = cache.as_ref().map(|cache| cache.load(&self.models)).transpose()?.flatten() {
+            if let Some(path) = &self.config.stream {
+                HitStream::create(path)?.write(queries.iter().flat_map(|query| &query.hits), &self.config)?;
+            }
+            self.output_writer.write_hits(&queries, db_residues)?;
+            info!("cmsearch completed successfully");
+            return Ok(queries.iter().map(|query| query.hits.len()).sum());
+        }
+        
+        // Filter thresholds and E-values scale with the database size, on
+        // the strands searched; records skipped by --resume still count, so
+        // resumed runs report the same E-values as a full run. When the size
+        // is known up front (-Z, or from an index) the targets are searched
+        // as they are read; otherwise they are read in first to measure them,
+        // or with --low-mem read twice, first only to measure them
+        let path = self.config.get_seqdb_path();
+        let index = if self.config.seqdb == STDIN_PATH { None } else { SeqIndex::load_current(&path)? };
+        let filter = TargetFilter::from_config(&self.config)?;
+        let open = || -> Result<(SequenceReader, u64), Error> {
+            let (mut sequences, skipped) = self.open_sequences(index.as_ref(), &filter)?;
+            if self.config.strict_seqs {
+                sequences = seqfile::strict(sequences);
+            }
+            Ok((seqfile::unique_names(sequences, self.config.dup_names), skipped))
+        };
+        let (sequences, skipped) = open()?;
+        let (sequences, searched) = if self.config.z.is_some() || index.is_some() {
+            let indexed = index.as_ref().map_or(0, |index| {
+                index.entries().iter().filter(|e| filter.keeps(&e.name)).map(|e| e.length as u64).sum()
+            });
+            (sequences, self.config.searched_residues(indexed))
+        } else if self.config.low_mem.is_some() {
+            let measured = open()?.0.map(|sequence| sequence.map(|s| s.length as u64)).sum::<Result<u64, SeqFormatError>>()?;
+            info!("Measured {}: {} residues", self.config.seqdb, measured);
+            (sequences, self.config.searched_residues(skipped + measured))
+        } else {
+            let sequences = sequences.collect::<Result<Vec<_>, SeqFormatError>>()?;
+            info!("Loaded {} sequences from {}", sequences.len(), self.config.seqdb);
+            let residues = skipped + sequences.iter().map(|s| s.length as u64).sum::<u64>();
+            (Box::new(sequences.into_iter().map(Ok)) as SequenceReader, self.config.searched_residues(residues))
+        };
+        let db_residues = self.config.effective_db_residues(searched);
+        let pipelines = self.models.iter()
+            .map(|model| Pipeline::new(Arc::clone(model), Arc::clone(&self.config), db_residues))
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        // Run search pipeline, reading the targets once for every model; a
+        // scan reports each target in the order read
+        let scanned = Mutex::new(Vec::new());
+        let scan = self.config.scan;
+        let sequences = sequences.inspect(|sequence| {
+            if let (true, Ok(sequence)) = (scan, sequence) {
+                scanned.lock().unwrap_or_else(|err| err.into_inner()).push(ScanSequence {
+                    name: sequence.name.clone(),
+                    description: sequence.description.clone(),
+                    length: sequence.length,
+                });
+            }
+        });
+        let hits = pool.install(|| pipeline::search_batch(&pipelines, sequences))?;
+        let mut queries: Vec<QueryResult> = self.models.iter()
+            .zip(&pipelines)
+            .zip(hits)
+            .map(|((model, pipeline), hits)| QueryResult::new(&model.cm, hits, pipeline.summary()))
+            .collect();
+        if let Some(clans) = &self.clans {
+            for query in &mut queries {
+                query.clan = clans.clan_of(&query.name).map(str::to_string);
+            }
+            if !self.config.keep_clan_overlaps {
+                // Not in the log call, which skips its arguments when info
+                // logging is off
+                let removed = clans.compete(&mut queries);
+                info!("Clan competition removed {} overlapping hits", removed);
+            }
+        }
+        
+        // Nothing searched is an error rather than a search without hits,
+        // unless --resume skipped every record
+        if queries.first().map_or(0, |query| query.summary.targets) == 0 && skipped == 0 {
+            return Err(SeqFormatError::Empty { input: self.config.seqdb.clone(), filtered: !filter.is_empty() }.into());
+        }
+        let reported = queries.iter().map(|query| query.hits.len()).sum();
+        info!("Found {} hits", reported);
         
-        // Run search pipeline
-        let hits = self.pipeline.search(&sequences)?;
-        info!("Found {} hits", hits.len());
+        // Write results; a search stopped at --max-rss keeps its checkpoint
+        // and is not cached
+        if scan {
+            let sequences = scanned.into_inner().unwrap_or_else(|err| err.into_inner());
+            self.output_writer.write_scan(&sequences, &queries, db_residues)?;
+        } else {
+            self.output_writer.write_hits(&queries, db_residues)?;
+        }
+        pipelines.iter().map(Pipeline::check_limit).fold(Ok(()), Result::and)?;
+        for pipeline in &pipelines {
+            pipeline.finish_checkpoint()?;
+        }
+        if let Some(cache) = &cache {
+            // The results are written either way
+            if let Err(err) = cache.store(&queries, db_residues) {
+                warn!("{}", error::report(&err));
+            }
+        }
         
-        // Write results
-        self.output_writer.write_hits(&hits)?;
+        if let Some(budget) = self.config.low_mem {
+            let budget = (budget * 1024.0 * 1024.0) as u64;
+            match utils::peak_memory() {
+                Some(peak) if peak > budget => warn!("Peak memory {} was over the --low-mem budget of {}",
+                                                     utils::format_bytes(peak), utils::format_bytes(budget)),
+                Some(peak) => info!("Peak memory {} (--low-mem budget {})", utils::format_bytes(peak), utils::format_bytes(budget)),
+                None => {}
+            }
+        }
         
         info!("cmsearch completed successfully");
-        Ok(())
+        Ok(reported)
     }
     
-    fn load_sequences(&self) -> Result<Vec<Sequence>> {
-        let file = File::open(&self.config.seqdb)?;
-        let reader = BufReader::new(file);
-        let mut sequences = Vec::new();
-        let mut current_name = String::new();
-        let mut current_sequence = String::new();
-        
-        for line in reader.lines() {
-            let line = line?;
-            let line = line.trim();
-            
-            if line.is_empty() {
-                continue;
+    // Records to search, and the residues of records skipped by --resume;
+    // both leave out the targets excluded by --seqlist and --seqexclude
+    fn open_sequences(&self, index: Option<&SeqIndex>, filter: &TargetFilter) -> Result<(SequenceReader, u64), Error> {
+        let path = self.config.get_seqdb_path();
+        
+        // With an index, seek straight past the resume point and to the
+        // selected records
+        if let Some(index) = index.filter(|_| self.config.resume.is_some() || !filter.is_empty()) {
+            let first = match &self.config.resume {
+                Some(resume) => match index.position(resume) {
+                    Some(position) => {
+                        info!("Resuming after '{}' (record {} of {}, from the index)", resume, position + 1, index.entries().len());
+                        position + 1
+                    }
+                    None => return Err(SeqFormatError::ResumeNotFound { input: path.display().to_string(), name: resume.clone() }.into()),
+                },
+                None => 0,
+            };
+            let (skipped, rest) = index.entries().split_at(first);
+            let skipped = skipped.iter().filter(|e| filter.keeps(&e.name)).map(|e| e.length as u64).sum();
+            if filter.is_empty() {
+                return Ok((index.read_from(&path, first)?, skipped));
             }
-            
-            if line.starts_with('>') {
-                // Save previous sequence if we have one
-                if !current_name.is_empty() {
-                    sequences.push(Sequence {
-                        name: current_name.clone(),
-                        sequence: current_sequence.clone(),
-                        length: current_sequence.len(),
-                    });
-                }
-                
-                // Start new sequence
-                current_name = line[1..].to_string();
-                current_sequence.clear();
+            let positions: Vec<usize> = (first..index.entries().len())
+                .filter(|&i| filter.keeps(&index.entries()[i].name))
+                .collect();
+            info!("Selected {} of {} targets from the index", positions.len(), rest.len());
+            return Ok((index.read_at(&path, &positions)?, skipped));
+        }
+        
+        let records = seqfile::open(&path, &self.config)?;
+        let Some(resume) = &self.config.resume else {
+            return Ok((filter.clone().apply(records), 0));
+        };
+        
+        let mut sequences = Vec::new();
+        let mut skipped = 0;
+        let mut found = false;
+        for record in records {
+            let record = record?;
+            if !filter.keeps(&record.name) {
+                found |= record.name == *resume;
+            } else if found {
+                sequences.push(record);
             } else {
-                // Add to current sequence
-                current_sequence.push_str(line);
+                skipped += record.length as u64;
+                found = record.name == *resume;
             }
         }
-        
-        // Don't forget the last sequence
-        if !current_name.is_empty() {
-            let sequence_length = current_sequence.len();
-            sequences.push(Sequence {
-                name: current_name,
-                sequence: current_sequence,
-                length: sequence_length,
-            });
+        if !found {
+            return Err(SeqFormatError::ResumeNotFound { input: path.display().to_string(), name: resume.clone() }.into());
         }
+        info!("Resuming after '{}'; index {} to skip scanning", resume, self.config.seqdb);
         
-        info!("Loaded {} sequences from {}", sequences.len(), self.config.seqdb);
-        Ok(sequences)
+        Ok((Box::new(sequences.into_iter().map(Ok)), skipped))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Sequence {
     pub name: String,
+    pub description: String, // Rest of the header; empty if there is none
     pub sequence: String,
     pub length: usize,
 }
 
-#[derive(Debug, Clone)]
+impl Sequence {
+    pub fn new(name: String, description: String, sequence: String) -> Self {
+        Self {
+            name,
+            description,
+            length: sequence.len(),
+            sequence,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Strand {
+    Plus,
+    Minus,
+}
+
+impl Strand {
+    pub fn symbol(&self) -> char {
+        match self {
+            Strand::Plus => '+',
+            Strand::Minus => '-',
+        }
+    }
+}
+
+/// Which ends of the model a hit is missing because it runs off the end of
+/// the target sequence, as the `trunc` column reports it. Truncated hits are
+/// found by the ungapped fragment placement of the truncated passes (see
+/// [`crate::stage::truncated_placement`]), not by Infernal's TrCYK, which
+/// this crate does not implement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Truncation {
+    None,
+    FivePrime,
+    ThreePrime,
+    Both,
+}
+
+impl Truncation {
+    /// The truncation of a hit missing the model's 5' and/or 3' end.
+    pub fn of(five_prime: bool, three_prime: bool) -> Self {
+        match (five_prime, three_prime) {
+            (false, false) => Truncation::None,
+            (true, false) => Truncation::FivePrime,
+            (false, true) => Truncation::ThreePrime,
+            (true, true) => Truncation::Both,
+        }
+    }
+
+    /// Label for the `trunc` output column.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Truncation::None => "no",
+            Truncation::FivePrime => "5'",
+            Truncation::ThreePrime => "3'",
+            Truncation::Both => "5'&3'",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hit {
+    pub id: String,
     pub sequence_name: String,
-    pub start: usize,
-    pub end: usize,
+    pub sequence_description: String,
+    pub sequence_length: usize,
+    pub start: usize, // 0-based, on the forward strand whatever the hit's strand
+    pub end: usize, // Exclusive, so start < end on either strand
+    pub strand: Strand, // The strand the hit is on; outputs flip from/to for Minus
+    pub trunc: Truncation,
+    pub overlap: Overlap,
     pub score: f64,
+    pub bias: f64,
+    pub gc: f64, // GC fraction of the hit's residues
+    pub pvalue: f64,
     pub evalue: f64,
-    pub alignment: Option<String>,
-} 
\ No newline at end of file
+    pub alignment: Option<Alignment>,
+    pub residues: Option<String>, // The hit's residues on its strand, kept for --hitfasta
+}
+
+impl Hit {
+    /// Deterministic identifier from model, target, coordinates, and strand,
+    /// used as the joining key across output formats.
+    pub fn stable_id(model: &str, target: &str, start: usize, end: usize, strand: Strand) -> String {
+        let key = format!("{}\0{}\0{}\0{}\0{}", model, target, start, end, strand.symbol());
+        format!("hit-{:016x}", utils::stable_hash(key.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exitcode;
+    use crate::pipeline::fixtures::{flank, planted, write_models, CONSENSUS};
+
+    use std::fs;
+    use std::path::Path;
+
+    fn hit_lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path).unwrap().lines().filter(|line| !line.starts_with('#')).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_scan_clan_competition() {
+        // Two models of one clan, the second a 5' part of the first, both
+        // hitting the same stretch of chr1
+        let dir = std::env::temp_dir().join(format!("scan-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("two.cm"), &[("tRNA-a", CONSENSUS), ("tRNA-b", &CONSENSUS[..40])]);
+        fs::write(dir.join("chr1.fa"), format!(">chr1\n{}\n", planted(CONSENSUS))).unwrap();
+        fs::write(dir.join("clans.txt"), "CL00001\ttRNA-a\ttRNA-b\n").unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let config = Config {
+            cmfile: path("two.cm"),
+            seqdb: path("chr1.fa"),
+            scan: true,
+            clanin: Some(path("clans.txt")),
+            output: Some(path("out.txt")),
+            tblout: Some(path("hits.tbl")),
+            fmt: 2,
+            ..Config::new()
+        };
+
+        // Of a clan's overlapping hits only the best is kept
+        assert_eq!(CmSearch::new(config.clone()).unwrap().run().unwrap(), 1);
+        let lines = hit_lines(&dir.join("hits.tbl"));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("tRNA-a") && lines[0].contains("CL00001"));
+
+        // --keep-clan-overlaps keeps both, marking the worse as overlapping
+        // the better
+        let config = Config { keep_clan_overlaps: true, ..config };
+        assert_eq!(CmSearch::new(config).unwrap().run().unwrap(), 2);
+        let lines = hit_lines(&dir.join("hits.tbl"));
+        assert_eq!(lines.len(), 2);
+        let olp: Vec<&str> = lines.iter().map(|line| line.split_whitespace().nth(19).unwrap()).collect();
+        assert_eq!(olp, ["^", "="]);
+        assert!(lines.iter().all(|line| line.contains("CL00001")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_is_independent_of_threads() {
+        // Hits on either strand, in several targets, some tied on score
+        let dir = std::env::temp_dir().join(format!("threads-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("tRNA.cm"), &[("tRNA", CONSENSUS)]);
+        let flank = flank();
+        let reverse = utils::reverse_complement(CONSENSUS);
+        let mut targets = String::new();
+        for i in 1..=8 {
+            let mut sequence = flank.clone();
+            for copy in [CONSENSUS, &reverse, &CONSENSUS[3..]].iter().take(i % 4) {
+                sequence += copy;
+                sequence += &flank;
+            }
+            targets += &format!(">chr{}\n{}\n", i, sequence);
+        }
+        fs::write(dir.join("targets.fa"), targets).unwrap();
+
+        let tblout = |threads: usize| {
+            let path = dir.join(format!("hits-{}.tbl", threads));
+            let config = Config {
+                cmfile: dir.join("tRNA.cm").to_string_lossy().into_owned(),
+                seqdb: dir.join("targets.fa").to_string_lossy().into_owned(),
+                output: Some(dir.join(format!("out-{}.txt", threads)).to_string_lossy().into_owned()),
+                tblout: Some(path.to_string_lossy().into_owned()),
+                threads,
+                ..Config::new()
+            };
+            assert!(CmSearch::new(config).unwrap().run().unwrap() > 8);
+            // All but the date the run started
+            let text = fs::read_to_string(path).unwrap();
+            text.lines().filter(|line| !line.starts_with("# Date:")).collect::<Vec<_>>().join("\n")
+        };
+        assert_eq!(tblout(1), tblout(4));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cached_run_writes_stream() {
+        let dir = std::env::temp_dir().join(format!("cache-stream-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("tRNA.cm"), &[("tRNA", CONSENSUS)]);
+        fs::write(dir.join("chr1.fa"), format!(">chr1\n{}\n", planted(CONSENSUS))).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let config = Config {
+            cmfile: path("tRNA.cm"),
+            seqdb: path("chr1.fa"),
+            output: Some(path("out.txt")),
+            stream: Some(path("hits.jsonl")),
+            cache: Some(path("cache")),
+            ..Config::new()
+        };
+
+        let hits = CmSearch::new(config.clone()).unwrap().run().unwrap();
+        assert!(hits > 0);
+        assert_eq!(fs::read_dir(dir.join("cache")).unwrap().count(), 1);
+        fs::remove_file(dir.join("hits.jsonl")).unwrap();
+
+        // The second run is answered from the cache, and still streams its hits
+        assert_eq!(CmSearch::new(config).unwrap().run().unwrap(), hits);
+        let streamed = fs::read_to_string(dir.join("hits.jsonl")).unwrap();
+        assert_eq!(streamed.lines().count(), hits);
+        assert!(streamed.lines().all(|line| line.contains(r#""target":"chr1""#)), "{}", streamed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_target_file() {
+        let dir = std::env::temp_dir().join(format!("empty-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("tRNA.cm"), &[("tRNA", CONSENSUS)]);
+        fs::write(dir.join("empty.fa"), "\n").unwrap();
+        let seqdb = dir.join("empty.fa").to_string_lossy().into_owned();
+        let config = Config {
+            cmfile: dir.join("tRNA.cm").to_string_lossy().into_owned(),
+            seqdb: seqdb.clone(),
+            output: Some(dir.join("out.txt").to_string_lossy().into_owned()),
+            ..Config::new()
+        };
+
+        // A bad sequence file, not a search without hits
+        let err = CmSearch::new(config).unwrap().run().unwrap_err();
+        assert!(matches!(err, Error::SeqFormat(SeqFormatError::Empty { filtered: false, .. })), "{:?}", err);
+        assert_eq!(err.to_string(), format!("{}: no sequences found in the target file", seqdb));
+        assert_eq!(exitcode::ErrorKind::of(&err).exit_code(), 5);
+        let mut json = Vec::new();
+        exitcode::write_json(&mut json, &err).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with(r#"{"error":"sequence_file","exit_code":5,"#));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes_of_unsearchable_runs() {
+        let dir = std::env::temp_dir().join(format!("exit-code-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_models(&dir.join("tRNA.cm"), &[("tRNA", CONSENSUS)]);
+        fs::write(dir.join("chr.fa"), format!(">chr1\n{}\n>chr2\n{}\n", CONSENSUS, CONSENSUS)).unwrap();
+        fs::write(dir.join("names.txt"), "chrX\n").unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let config = Config {
+            cmfile: path("tRNA.cm"),
+            seqdb: path("chr.fa"),
+            output: Some(path("out.txt")),
+            ..Config::new()
+        };
+        let failure = |config: Config| {
+            let err = CmSearch::new(config).and_then(|mut search| search.run()).unwrap_err();
+            (exitcode::ErrorKind::of(&err).exit_code(), err.to_string())
+        };
+
+        // Targets that leave nothing to search are a bad sequence file
+        let filtered = Config { seqlist: Some(path("names.txt")), ..config.clone() };
+        assert_eq!(failure(filtered), (5, format!("{}: no target sequences left after --seqlist/--seqexclude", path("chr.fa"))));
+        let resumed = Config { resume: Some("chr3".to_string()), ..config.clone() };
+        assert_eq!(failure(resumed), (5, format!("--resume: sequence 'chr3' is not in {}", path("chr.fa"))));
+
+        // Options the search cannot run with are a usage error
+        let invalid = Config { evalue: 0.0, ..config.clone() };
+        assert_eq!(failure(invalid), (2, "E-value must be positive".to_string()));
+        let nan = Config { evalue: f64::NAN, ..config.clone() };
+        assert_eq!(failure(nan), (2, "E-value must be positive".to_string()));
+        let nan = Config { z: Some(f64::NAN), ..config.clone() };
+        assert_eq!(failure(nan), (2, "Database size (-Z) must be positive".to_string()));
+        let nan = Config { max_rss: Some(f64::NAN), ..config.clone() };
+        assert_eq!(failure(nan), (2, "Memory limit (--max-rss) must be positive".to_string()));
+        let nan = Config { low_mem: Some(f64::NAN), ..config.clone() };
+        assert_eq!(failure(nan), (2, "Memory budget (--low-mem) must be positive".to_string()));
+        let nan = Config { inc_evalue: f64::NAN, ..config.clone() };
+        assert_eq!(failure(nan), (2, "Inclusion E-value (--incE) must be positive".to_string()));
+        let nan = Config { max_mx_size: f64::NAN, ..config.clone() };
+        assert_eq!(failure(nan), (2, "Maximum matrix size must be positive".to_string()));
+        let nan = Config { f3: Some(f64::NAN), ..config.clone() };
+        assert_eq!(failure(nan), (2, "--F3 P-value threshold must be in (0, 1]".to_string()));
+        let infinite = Config { inc_score: Some(f64::INFINITY), ..config.clone() };
+        assert_eq!(failure(infinite), (2, "Inclusion score (--incT) must be a finite number".to_string()));
+        let stdin = Config { seqdb: STDIN_PATH.to_string(), low_mem: Some(100.0), ..config };
+        assert_eq!(failure(stdin).0, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,889 @@
+use flate2::read::MultiGzDecoder;
+use memmap2::Mmap;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use crate::config::{Config, DupNames, STDIN_PATH};
+use crate::digital;
+use crate::error::SeqFormatError;
+use crate::utils;
+use crate::retry::RetryingFile;
+use crate::search::Sequence;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+type Result<T, E = SeqFormatError> = std::result::Result<T, E>;
+
+/// Records of a sequence file, whatever its format.
+pub type SequenceReader = Box<dyn Iterator<Item = Result<Sequence>> + Send>;
+
+/// Sequence file formats, recognised from the first bytes of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqFormat {
+    Fasta, // Plain or aligned
+    Fastq,
+    Stockholm,
+    Embl,
+    GenBank,
+}
+
+impl SeqFormat {
+    /// Format of input starting with `head`, judged by its first non-blank
+    /// byte; empty input reads as FASTA with no records.
+    pub fn sniff(head: &[u8]) -> Option<SeqFormat> {
+        let start = head.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(head.len());
+        match head.get(start) {
+            Some(b'>') | Some(b';') | None => Some(SeqFormat::Fasta),
+            Some(b'@') => Some(SeqFormat::Fastq),
+            Some(b'#') if head[start..].starts_with(b"# STOCKHOLM") => Some(SeqFormat::Stockholm),
+            Some(b'I') if head[start..].starts_with(b"ID ") => Some(SeqFormat::Embl),
+            Some(b'L') if head[start..].starts_with(b"LOCUS ") => Some(SeqFormat::GenBank),
+            _ => None,
+        }
+    }
+}
+
+/// Opens a sequence file, plain or gzip-compressed, with the parser for its
+/// format: read through a buffer, or memory-mapped with `config.mmap`.
+/// `-` streams standard input. FASTQ residues with Phred quality below
+/// `config.mask_quality` are replaced by N.
+pub fn open(path: &Path, config: &Config) -> Result<SequenceReader> {
+    let source = path.display().to_string();
+    let open_error = |source| SeqFormatError::Open { input: path.display().to_string(), source };
+    if path == Path::new(STDIN_PATH) {
+        from_input(BufReader::new(io::stdin()), "stdin", config.mask_quality)
+    } else if config.mmap {
+        let file = MappedFile::open(path).map_err(open_error)?;
+        from_input(file, &source, config.mask_quality)
+    } else {
+        let file = RetryingFile::open(path).map_err(open_error)?;
+        from_input(BufReader::new(file), &source, config.mask_quality)
+    }
+}
+
+// Error of reading the start of `input`
+fn read_error(input: &str) -> impl FnOnce(io::Error) -> SeqFormatError + '_ {
+    move |source| SeqFormatError::Read { input: input.to_string(), position: String::new(), source }
+}
+
+// Decompresses gzip input, recognised by its magic bytes, and sniffs the format
+fn from_input<R: BufRead + Send + 'static>(mut reader: R, source: &str, mask_quality: Option<u8>) -> Result<SequenceReader> {
+    let compressed = reader.fill_buf()
+        .map_err(read_error(source))?
+        .starts_with(&GZIP_MAGIC);
+    if compressed {
+        return from_reader(BufReader::new(MultiGzDecoder::new(reader)), source, mask_quality);
+    }
+    from_reader(reader, source, mask_quality)
+}
+
+/// Parser for the uncompressed sequence data in `reader`, chosen by sniffing.
+pub fn from_reader<R: BufRead + Send + 'static>(mut reader: R, source: &str, mask_quality: Option<u8>) -> Result<SequenceReader> {
+    let head = reader.fill_buf().map_err(read_error(source))?;
+    match SeqFormat::sniff(head) {
+        Some(SeqFormat::Fasta) => Ok(Box::new(FastaReader::new(reader, source))),
+        Some(SeqFormat::Fastq) => Ok(Box::new(FastqReader::new(reader, source).with_mask_quality(mask_quality))),
+        Some(SeqFormat::Stockholm) => Ok(Box::new(StockholmReader::new(reader, source))),
+        Some(format @ (SeqFormat::Embl | SeqFormat::GenBank)) => Ok(Box::new(FlatFileReader::new(reader, source, format))),
+        None => Err(SeqFormatError::UnknownFormat { input: source.to_string() }),
+    }
+}
+
+/// Records of `reader`, failing at the first one with a residue that is not
+/// an IUPAC nucleotide code (--strict-seqs).
+pub fn strict(reader: SequenceReader) -> SequenceReader {
+    Box::new(reader.map(|record| record.and_then(|sequence| check_residues(&sequence).map(|()| sequence))))
+}
+
+/// Records of `reader` with no two sharing a name: a duplicate is an error,
+/// renamed with the next free `.1`, `.2`, ... suffix, or dropped if its
+/// residues are identical to the first copy's, as `mode` says.
+pub fn unique_names(reader: SequenceReader, mode: DupNames) -> SequenceReader {
+    // Name -> hash of the residues of its first record, and copies seen
+    let mut seen: HashMap<String, (u64, usize)> = HashMap::new();
+    Box::new(reader.filter_map(move |record| {
+        let mut sequence = match record {
+            Ok(sequence) => sequence,
+            Err(err) => return Some(Err(err)),
+        };
+        let hash = utils::stable_hash(sequence.sequence.as_bytes());
+        let Some(&(first_hash, copies)) = seen.get(&sequence.name) else {
+            seen.insert(sequence.name.clone(), (hash, 1));
+            return Some(Ok(sequence));
+        };
+        match mode {
+            DupNames::Error => Some(Err(SeqFormatError::DuplicateName {
+                name: sequence.name,
+                hint: "; use --dup-names suffix or dedup to accept it",
+            })),
+            DupNames::Dedup if first_hash == hash => None,
+            DupNames::Dedup => Some(Err(SeqFormatError::DuplicateName {
+                name: sequence.name,
+                hint: " with different residues; --dup-names dedup drops identical copies only",
+            })),
+            DupNames::Suffix => {
+                let (name, copies) = (copies..)
+                    .map(|n| (format!("{}.{}", sequence.name, n), n + 1))
+                    .find(|(name, _)| !seen.contains_key(name))
+                    .expect("unbounded suffixes");
+                seen.insert(sequence.name.clone(), (first_hash, copies));
+                seen.insert(name.clone(), (hash, 1));
+                sequence.name = name;
+                Some(Ok(sequence))
+            }
+        }
+    }))
+}
+
+/// Checks that every residue of `sequence` is an IUPAC nucleotide code.
+/// Ambiguity codes are accepted; the error for anything else gives the
+/// sequence name and 1-based position, and tells input that is not
+/// nucleotide text at all (binary data, protein) from a stray character.
+pub fn check_residues(sequence: &Sequence) -> Result<()> {
+    let residues = sequence.sequence.as_bytes();
+    let Some(i) = residues.iter().position(|&c| !digital::is_iupac(c)) else {
+        return Ok(());
+    };
+    let c = residues[i];
+    let bases = residues.iter().filter(|&&c| b"ACGTUN".contains(&c.to_ascii_uppercase())).count();
+    let message = if !c.is_ascii_graphic() {
+        format!("unprintable byte 0x{:02x} at position {}; the input looks like binary data, not sequence text", c, i + 1)
+    } else if bases * 2 < residues.len() {
+        format!("'{}' at position {}; the sequence looks like protein, not nucleotides", c as char, i + 1)
+    } else {
+        format!("'{}' at position {} is not an IUPAC nucleotide code", c as char, i + 1)
+    };
+    Err(SeqFormatError::InvalidResidue { name: sequence.name.clone(), position: i + 1, message })
+}
+
+/// Read-only memory map of a file, read in place: each line is taken from
+/// the mapped pages without going through a read buffer, and repeated
+/// searches of the same database reuse the OS page cache. Records still
+/// own their text, so every line is copied once into its record.
+pub struct MappedFile {
+    map: Mmap,
+    pos: usize,
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the map is only read. Truncating the file while it is
+        // mapped can fault the process, as for any mmap reader
+        let map = unsafe { Mmap::map(&file)? };
+        #[cfg(unix)]
+        map.advise(memmap2::Advice::Sequential)?;
+        Ok(Self { map, pos: 0 })
+    }
+}
+
+impl Read for MappedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.map[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl BufRead for MappedFile {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.map[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.map.len());
+    }
+}
+
+// Line reader tracking the position for error messages
+struct Lines<R> {
+    reader: R,
+    source: String,
+    buf: Vec<u8>,
+    offset: u64,     // Bytes consumed so far
+    line_start: u64, // Byte offset of the last line read
+    line_number: usize,
+}
+
+impl<R: BufRead> Lines<R> {
+    fn new(reader: R, source: &str) -> Self {
+        Self {
+            reader,
+            source: source.to_string(),
+            buf: Vec::new(),
+            offset: 0,
+            line_start: 0,
+            line_number: 0,
+        }
+    }
+
+    // Next line with surrounding whitespace and CR removed, or None at end
+    // of input; `record` and `records` describe the position on error
+    fn next(&mut self, record: Option<&str>, records: usize) -> Result<Option<String>> {
+        let read_error = |source| SeqFormatError::Read {
+            input: self.source.clone(),
+            position: format!(" at byte offset {} (record {} '{}')", self.offset, records + 1, record.unwrap_or("-")),
+            source,
+        };
+        // A line whole in the reader's buffer (every line of a mapped file)
+        // is taken from it in place; others are gathered with read_until
+        let available = self.reader.fill_buf().map_err(&read_error)?;
+        let (line, n) = match available.iter().position(|&c| c == b'\n') {
+            Some(end) => {
+                let line = String::from_utf8_lossy(&available[..end]).trim().to_string();
+                self.reader.consume(end + 1);
+                (line, end + 1)
+            }
+            None => {
+                self.buf.clear();
+                let n = self.reader.read_until(b'\n', &mut self.buf).map_err(&read_error)?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                (String::from_utf8_lossy(&self.buf).trim().to_string(), n)
+            }
+        };
+        self.line_start = self.offset;
+        self.offset += n as u64;
+        self.line_number += 1;
+        Ok(Some(line))
+    }
+
+    // A record that does not parse, at the last line read
+    fn malformed(&self, message: String) -> SeqFormatError {
+        SeqFormatError::Malformed { input: self.source.clone(), line: self.line_number, message }
+    }
+
+    // `line` if it holds only ASCII: sequences are sliced by byte offset
+    // into chunks, and a multi-byte character (or the U+FFFD standing in
+    // for invalid UTF-8) would split between two of them
+    fn residues<'a>(&self, residues: &'a str) -> Result<&'a str> {
+        match residues.chars().find(|c| !c.is_ascii()) {
+            None => Ok(residues),
+            Some(c) => Err(self.malformed(format!("non-ASCII character {:?} among the residues at line {}", c, self.line_number))),
+        }
+    }
+}
+
+// Alignment gap characters, removed from aligned input
+fn is_gap(c: char) -> bool {
+    matches!(c, '-' | '.' | '_' | '~')
+}
+
+// Text with runs of whitespace (tabs included) collapsed to one space
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Name (the first word) and description (the rest) of a header line
+fn split_header(header: &str) -> (String, String) {
+    let header = header.trim_start();
+    let (name, description) = header.split_once(char::is_whitespace).unwrap_or((header, ""));
+    (name.to_string(), collapse_whitespace(description))
+}
+
+/// Streaming FASTA reader yielding one `Sequence` per record. The first word
+/// of the header is the name, and the rest the description.
+///
+/// Tolerates wrapped sequence lines, CRLF line endings, leading and trailing
+/// whitespace, blank lines, ';' comment lines, whitespace inside headers
+/// (tabs are normalised to single spaces), records with no residues, and
+/// '*' end-of-sequence terminators. Aligned FASTA is degapped, so hit
+/// coordinates refer to the ungapped sequence.
+pub struct FastaReader<R> {
+    lines: Lines<R>,
+    header: Option<(String, String)>, // Name and description of the record being read
+    header_offset: u64,     // Byte offset of its header line
+    record_offset: u64,     // Byte offset of the header of the last record returned
+    records: usize,         // Records returned so far
+    done: bool,
+}
+
+impl<R: BufRead> FastaReader<R> {
+    /// Reader over `reader`; `source` names the input in error messages.
+    pub fn new(reader: R, source: &str) -> Self {
+        Self {
+            lines: Lines::new(reader, source),
+            header: None,
+            header_offset: 0,
+            record_offset: 0,
+            records: 0,
+            done: false,
+        }
+    }
+
+    /// Byte offset, from the start of the input, of the header line of the
+    /// last record returned.
+    pub fn record_offset(&self) -> u64 {
+        self.record_offset
+    }
+
+    fn read_record(&mut self) -> Result<Option<Sequence>> {
+        let mut residues = String::new();
+
+        loop {
+            let Some(line) = self.lines.next(self.header.as_ref().map(|(name, _)| name.as_str()), self.records)? else {
+                // End of input completes the last record
+                self.record_offset = self.header_offset;
+                return Ok(self.header.take().map(|header| self.finish(header, residues)));
+            };
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('>') {
+                let header = split_header(header);
+                if header.0.is_empty() {
+                    return Err(self.lines.malformed(format!("empty FASTA header at line {}", self.lines.line_number)));
+                }
+                let previous = self.header.replace(header);
+                let offset = std::mem::replace(&mut self.header_offset, self.lines.line_start);
+                match previous {
+                    Some(previous) => {
+                        self.record_offset = offset;
+                        return Ok(Some(self.finish(previous, residues)));
+                    }
+                    None => continue,
+                }
+            }
+
+            if self.header.is_none() {
+                return Err(self.lines.malformed(format!("sequence data before the first '>' header at line {}", self.lines.line_number)));
+            }
+            residues.extend(self.lines.residues(&line)?.chars().filter(|&c| !c.is_whitespace() && c != '*' && !is_gap(c)));
+        }
+    }
+
+    fn finish(&mut self, (name, description): (String, String), sequence: String) -> Sequence {
+        self.records += 1;
+        Sequence::new(name, description, sequence)
+    }
+}
+
+impl<R: BufRead> Iterator for FastaReader<R> {
+    type Item = Result<Sequence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record();
+        if !matches!(record, Ok(Some(_))) {
+            self.done = true;
+        }
+        record.transpose()
+    }
+}
+
+/// Streaming FASTQ reader yielding one `Sequence` per read.
+///
+/// Sequence and quality strings may be wrapped over several lines; the
+/// quality string is read by length, so quality lines starting with '@' or
+/// '+' are not mistaken for record boundaries. Qualities are Phred+33 and
+/// are otherwise ignored unless a masking threshold is set.
+pub struct FastqReader<R> {
+    lines: Lines<R>,
+    mask_quality: Option<u8>,
+    records: usize, // Records returned so far
+    done: bool,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    /// Reader over `reader`; `source` names the input in error messages.
+    pub fn new(reader: R, source: &str) -> Self {
+        Self {
+            lines: Lines::new(reader, source),
+            mask_quality: None,
+            records: 0,
+            done: false,
+        }
+    }
+
+    /// Replace residues with Phred quality below `threshold` by N.
+    pub fn with_mask_quality(mut self, threshold: Option<u8>) -> Self {
+        self.mask_quality = threshold;
+        self
+    }
+
+    fn read_record(&mut self) -> Result<Option<Sequence>> {
+        let header = loop {
+            match self.lines.next(None, self.records)? {
+                None => return Ok(None),
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+        let Some(header) = header.strip_prefix('@') else {
+            return Err(self.lines.malformed(format!("expected a FASTQ '@' header at line {}", self.lines.line_number)));
+        };
+        let (name, description) = split_header(header);
+        if name.is_empty() {
+            return Err(self.lines.malformed(format!("empty FASTQ header at line {}", self.lines.line_number)));
+        }
+
+        let mut sequence = String::new();
+        loop {
+            match self.lines.next(Some(&name), self.records)? {
+                None => return Err(self.lines.malformed(format!("read '{}' ends before its '+' separator line", name))),
+                Some(line) if line.starts_with('+') => break,
+                Some(line) => sequence.extend(self.lines.residues(&line)?.chars().filter(|c| !c.is_whitespace())),
+            }
+        }
+
+        let mut quality = Vec::with_capacity(sequence.len());
+        while quality.len() < sequence.len() {
+            let Some(line) = self.lines.next(Some(&name), self.records)? else {
+                break;
+            };
+            quality.extend(line.bytes().filter(|b| !b.is_ascii_whitespace()));
+        }
+        if quality.len() != sequence.len() {
+            return Err(self.lines.malformed(format!("read '{}' has {} quality values for {} residues (line {})",
+                                                    name, quality.len(), sequence.len(), self.lines.line_number)));
+        }
+
+        if let Some(threshold) = self.mask_quality {
+            sequence = sequence.chars()
+                .zip(&quality)
+                .map(|(c, &q)| if q.saturating_sub(b'!') < threshold { 'N' } else { c })
+                .collect();
+        }
+
+        self.records += 1;
+        Ok(Some(Sequence::new(name, description, sequence)))
+    }
+}
+
+impl<R: BufRead> Iterator for FastqReader<R> {
+    type Item = Result<Sequence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record();
+        if !matches!(record, Ok(Some(_))) {
+            self.done = true;
+        }
+        record.transpose()
+    }
+}
+
+/// Reader for Stockholm alignments yielding each aligned sequence, degapped
+/// so that hit coordinates refer to the ungapped sequence.
+///
+/// Interleaved blocks are joined by sequence name, and `#=GS <name> DE`
+/// lines give descriptions; other markup lines (`#=GF`, `#=GS`, `#=GR`,
+/// `#=GC`) are ignored. A file may hold several alignments, each ending
+/// with `//`.
+pub struct StockholmReader<R> {
+    lines: Lines<R>,
+    pending: VecDeque<Sequence>, // Sequences of the last alignment read
+    records: usize,              // Records returned so far
+    done: bool,
+}
+
+impl<R: BufRead> StockholmReader<R> {
+    /// Reader over `reader`; `source` names the input in error messages.
+    pub fn new(reader: R, source: &str) -> Self {
+        Self {
+            lines: Lines::new(reader, source),
+            pending: VecDeque::new(),
+            records: 0,
+            done: false,
+        }
+    }
+
+    // Reads the next alignment into `pending`; false at end of input
+    fn read_alignment(&mut self) -> Result<bool> {
+        let mut sequences: Vec<(String, String)> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        let mut descriptions: HashMap<String, String> = HashMap::new();
+        let mut in_alignment = false;
+
+        loop {
+            let Some(line) = self.lines.next(None, self.records)? else {
+                if in_alignment {
+                    return Err(self.lines.malformed("alignment ends without a '//' line".to_string()));
+                }
+                return Ok(false);
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("# STOCKHOLM") {
+                in_alignment = true;
+                continue;
+            }
+            if !in_alignment {
+                return Err(self.lines.malformed(format!("expected a '# STOCKHOLM 1.0' header at line {}", self.lines.line_number)));
+            }
+            if line == "//" {
+                self.pending.extend(sequences.into_iter().map(|(name, sequence)| {
+                    let description = descriptions.remove(&name).unwrap_or_default();
+                    Sequence::new(name, description, sequence)
+                }));
+                return Ok(true);
+            }
+            if let Some(markup) = line.strip_prefix("#=GS") {
+                let mut fields = markup.trim_start().splitn(3, char::is_whitespace);
+                if let (Some(name), Some("DE"), Some(text)) = (fields.next(), fields.next(), fields.next()) {
+                    descriptions.insert(name.to_string(), collapse_whitespace(text));
+                }
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let name = fields.next().unwrap_or_default();
+            self.lines.residues(&line[name.len()..])?;
+            let i = *index.entry(name.to_string()).or_insert_with(|| {
+                sequences.push((name.to_string(), String::new()));
+                sequences.len() - 1
+            });
+            sequences[i].1.extend(fields.flat_map(str::chars).filter(|&c| !is_gap(c)));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for StockholmReader<R> {
+    type Item = Result<Sequence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(sequence) = self.pending.pop_front() {
+                self.records += 1;
+                return Some(Ok(sequence));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_alignment() {
+                Ok(true) => continue,
+                Ok(false) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Reader for EMBL or GenBank flat files yielding the sequence of each
+/// record, named by its ID/LOCUS name followed by its DE/DEFINITION
+/// description. Other annotation, and the position numbers and spaces of
+/// the sequence lines, are skipped.
+pub struct FlatFileReader<R> {
+    lines: Lines<R>,
+    format: SeqFormat, // Embl or GenBank
+    records: usize,    // Records returned so far
+    done: bool,
+}
+
+impl<R: BufRead> FlatFileReader<R> {
+    /// Reader over `reader`; `source` names the input in error messages.
+    pub fn new(reader: R, source: &str, format: SeqFormat) -> Self {
+        Self {
+            lines: Lines::new(reader, source),
+            format,
+            records: 0,
+            done: false,
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<Sequence>> {
+        let (id_tag, description_tag, sequence_tag) = match self.format {
+            SeqFormat::GenBank => ("LOCUS", "DEFINITION", "ORIGIN"),
+            _ => ("ID", "DE", "SQ"),
+        };
+
+        let header = loop {
+            match self.lines.next(None, self.records)? {
+                None => return Ok(None),
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+        let name = tag_value(&header, id_tag)
+            .and_then(|id| id.split(|c: char| c.is_whitespace() || c == ';').next())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+        let Some(name) = name else {
+            return Err(self.lines.malformed(format!("expected a '{}' line at line {}", id_tag, self.lines.line_number)));
+        };
+
+        let mut description = None;
+        let mut sequence = String::new();
+        let mut in_sequence = false;
+        loop {
+            let Some(line) = self.lines.next(Some(&name), self.records)? else {
+                return Err(self.lines.malformed(format!("record '{}' ends without a '//' line", name)));
+            };
+            if line.starts_with("//") {
+                break;
+            }
+            if in_sequence {
+                sequence.extend(line.chars().filter(|c| c.is_ascii_alphabetic()));
+            } else if tag_value(&line, sequence_tag).is_some() {
+                in_sequence = true;
+            } else if description.is_none() {
+                description = tag_value(&line, description_tag).map(collapse_whitespace);
+            }
+        }
+
+        self.records += 1;
+        Ok(Some(Sequence::new(name, description.unwrap_or_default(), sequence)))
+    }
+}
+
+impl<R: BufRead> Iterator for FlatFileReader<R> {
+    type Item = Result<Sequence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record();
+        if !matches!(record, Ok(Some(_))) {
+            self.done = true;
+        }
+        record.transpose()
+    }
+}
+
+// Value of a flat-file line starting with the keyword `tag`
+fn tag_value<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(tag)?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(fasta: &str) -> Result<Vec<(String, String, String)>> {
+        FastaReader::new(Cursor::new(fasta.as_bytes()), "test.fa")
+            .map(|record| record.map(|s| (s.name, s.description, s.sequence)))
+            .collect()
+    }
+
+    fn record(name: &str, description: &str, sequence: &str) -> (String, String, String) {
+        (name.to_string(), description.to_string(), sequence.to_string())
+    }
+
+    #[test]
+    fn test_wrapped_and_crlf_records() {
+        let records = read(">seq1 first\r\nACGU\r\nACGU\r\n\r\n>seq2\r\nGGCC\r\n").unwrap();
+        assert_eq!(records, vec![record("seq1", "first", "ACGUACGU"), record("seq2", "", "GGCC")]);
+    }
+
+    #[test]
+    fn test_whitespace_tabs_and_terminators() {
+        let fasta = "\n  >seq1\tdescription  with\ttabs \nACG U  \n  UUA*\n; comment\n>seq2\nAC GU\n*\n";
+        let records = read(fasta).unwrap();
+        assert_eq!(records, vec![
+            record("seq1", "description with tabs", "ACGUUUA"),
+            record("seq2", "", "ACGU"),
+        ]);
+    }
+
+    #[test]
+    fn test_empty_records_and_last_line_without_newline() {
+        let records = read(">empty1\n>seq\nACGU\n>empty2\n>last\nGG").unwrap();
+        assert_eq!(records, vec![
+            record("empty1", "", ""),
+            record("seq", "", "ACGU"),
+            record("empty2", "", ""),
+            record("last", "", "GG"),
+        ]);
+        assert!(read("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_many_records() {
+        let fasta: String = (0..500).map(|i| format!(">s{}\nACGU\nACGU\n", i)).collect();
+        let records = read(&fasta).unwrap();
+        assert_eq!(records.len(), 500);
+        assert_eq!(records[499], record("s499", "", "ACGUACGU"));
+
+        // Lines split across the reader's buffer read the same
+        let split: Vec<_> = FastaReader::new(BufReader::with_capacity(7, Cursor::new(fasta.into_bytes())), "test.fa")
+            .map(|record| record.map(|s| (s.name, s.description, s.sequence)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(split, records);
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let err = read("ACGU\n>seq\nACGU\n").unwrap_err();
+        assert!(err.to_string().contains("before the first '>' header at line 1"), "{}", err);
+        let err = read(">seq\nACGU\n> \t\nACGU\n").unwrap_err();
+        assert!(err.to_string().contains("empty FASTA header at line 3"), "{}", err);
+
+        // Records after an error are not returned
+        let mut reader = FastaReader::new(Cursor::new(&b">a\nAC\n>b\nUU\n>\nGG\n>c\nAC\n"[..]), "test.fa");
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    fn read_sniffed(input: &str, mask_quality: Option<u8>) -> Result<Vec<(String, String, String)>> {
+        from_reader(Cursor::new(input.as_bytes().to_vec()), "test", mask_quality)?
+            .map(|record| record.map(|s| (s.name, s.description, s.sequence)))
+            .collect()
+    }
+
+    #[test]
+    fn test_fastq_records() {
+        // Wrapped second read, and quality lines starting with '@' and '+'
+        let fastq = "@read1 lane\tone\r\nACGU\r\n+\r\nIIII\r\n\n@read2\nACG\nUA\n+read2\n@+I\nII\n@empty\n\n+\n\n";
+        let records = read_sniffed(fastq, None).unwrap();
+        assert_eq!(records, vec![
+            record("read1", "lane one", "ACGU"),
+            record("read2", "", "ACGUA"),
+            record("empty", "", ""),
+        ]);
+
+        // '#' is Phred 2, '5' Phred 20, 'I' Phred 40
+        let records = read_sniffed("@r\nACGUA\n+\nI#5I#\n", Some(20)).unwrap();
+        assert_eq!(records, vec![record("r", "", "ANGUN")]);
+    }
+
+    #[test]
+    fn test_malformed_fastq() {
+        let err = read_sniffed("@r\nACGU\n+\nIII\n", None).unwrap_err();
+        assert!(err.to_string().contains("3 quality values for 4 residues"), "{}", err);
+        let err = read_sniffed("@r\nACGU\n", None).unwrap_err();
+        assert!(err.to_string().contains("before its '+' separator"), "{}", err);
+        let err = read_sniffed("@r\nAC\n+\nII\nr2\nAC\n", None).unwrap_err();
+        assert!(err.to_string().contains("'@' header at line 5"), "{}", err);
+    }
+
+    #[test]
+    fn test_non_ascii_residues() {
+        // Invalid UTF-8 reads as U+FFFD, which is three bytes long
+        let fasta = FastaReader::new(Cursor::new(&b">seq\nACGU\nAC\xffGU\n"[..]), "test.fa").collect::<Result<Vec<_>>>();
+        let err = fasta.unwrap_err();
+        assert!(matches!(err, SeqFormatError::Malformed { line: 3, .. }), "{:?}", err);
+        assert!(err.to_string().contains("non-ASCII character '\u{fffd}' among the residues at line 3"), "{}", err);
+
+        let err = read_sniffed("@r\nACGé\n+\nIIII\n", None).unwrap_err();
+        assert!(err.to_string().contains("non-ASCII character 'é' among the residues at line 2"), "{}", err);
+        let err = read_sniffed("# STOCKHOLM 1.0\nséq1 ACGU\nseq2 AC–GU\n//\n", None).unwrap_err();
+        assert!(err.to_string().contains("non-ASCII character '–' among the residues at line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_aligned_input_is_degapped() {
+        let records = read(">seq1\nAC--GU..\nA~C_G\n>seq2\n---A\n").unwrap();
+        assert_eq!(records, vec![record("seq1", "", "ACGUACG"), record("seq2", "", "A")]);
+
+        let stockholm = "# STOCKHOLM 1.0\n#=GF ID test\n\n\
+                         #=GS seq2 DE  second\tsequence\n#=GS seq1 AC X1\n\
+                         seq1  AC--GU\nseq2  A.CGG-\n#=GC SS_cons <<..>>\n\n\
+                         seq1  ..AA\nseq2  UU-A\n//\n\
+                         # STOCKHOLM 1.0\nseq3 ACGU\n//\n";
+        let records = read_sniffed(stockholm, None).unwrap();
+        assert_eq!(records, vec![
+            record("seq1", "", "ACGUAA"),
+            record("seq2", "second sequence", "ACGGUUA"),
+            record("seq3", "", "ACGU"),
+        ]);
+
+        let err = read_sniffed("# STOCKHOLM 1.0\nseq1 ACGU\n", None).unwrap_err();
+        assert!(err.to_string().contains("without a '//' line"), "{}", err);
+    }
+
+    #[test]
+    fn test_embl_and_genbank_records() {
+        let genbank = "LOCUS       AB000001     12 bp    RNA     linear   PLN 01-JAN-2000\n\
+                       DEFINITION  Test RNA,\n            partial sequence.\n\
+                       ACCESSION   AB000001\nFEATURES             Location/Qualifiers\n\
+                       ORIGIN\n        1 acguacguac gu\n//\n\
+                       LOCUS       AB000002     4 bp    DNA\nORIGIN      \n        1 ggtt\n//\n";
+        let records = read_sniffed(genbank, None).unwrap();
+        assert_eq!(records, vec![
+            record("AB000001", "Test RNA,", "acguacguacgu"),
+            record("AB000002", "", "ggtt"),
+        ]);
+
+        let embl = "ID   X56734; SV 1; linear; mRNA; STD; PLN; 8 BP.\r\nXX\r\n\
+                    DE   Test\tmRNA\r\nXX\r\nSQ   Sequence 8 BP; 2 A; 2 C; 2 G; 2 T; 0 other;\r\n\
+                    \x20    aaccggtt                                                            8\r\n//\r\n";
+        let records = read_sniffed(embl, None).unwrap();
+        assert_eq!(records, vec![record("X56734", "Test mRNA", "aaccggtt")]);
+
+        let err = read_sniffed("ID   X1;\nSQ   Sequence\n     acgu\n", None).unwrap_err();
+        assert!(err.to_string().contains("without a '//' line"), "{}", err);
+        let err = read_sniffed("LOCUS       A1\nORIGIN\n 1 ac\n//\nACCESSION x\n", None).unwrap_err();
+        assert!(err.to_string().contains("expected a 'LOCUS' line at line 5"), "{}", err);
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        assert_eq!(SeqFormat::sniff(b"\n\n>seq\nACGU"), Some(SeqFormat::Fasta));
+        assert_eq!(SeqFormat::sniff(b"; comment\n>seq"), Some(SeqFormat::Fasta));
+        assert_eq!(SeqFormat::sniff(b""), Some(SeqFormat::Fasta));
+        assert_eq!(SeqFormat::sniff(b"@read\nACGU\n+\nIIII\n"), Some(SeqFormat::Fastq));
+        assert_eq!(SeqFormat::sniff(b"# STOCKHOLM 1.0\n"), Some(SeqFormat::Stockholm));
+        assert_eq!(SeqFormat::sniff(b"# comment\n"), None);
+        assert_eq!(SeqFormat::sniff(b"ID   X56734; SV 1;\n"), Some(SeqFormat::Embl));
+        assert_eq!(SeqFormat::sniff(b"LOCUS       AB000001\n"), Some(SeqFormat::GenBank));
+        assert_eq!(SeqFormat::sniff(b"ACGU\n"), None);
+        assert!(from_reader(Cursor::new(b"LOC x\n".to_vec()), "x", None).is_err());
+    }
+
+    #[test]
+    fn test_check_residues() {
+        let sequence = |residues: &str| Sequence::new("s".to_string(), String::new(), residues.to_string());
+        assert!(check_residues(&sequence("ACGTUacgtuNRYKMSWBDHVn")).is_ok());
+        assert!(check_residues(&sequence("")).is_ok());
+
+        let message = |residues: &str| check_residues(&sequence(residues)).unwrap_err().to_string();
+        assert_eq!(message("ACGUJACGU"), "s: 'J' at position 5 is not an IUPAC nucleotide code");
+        assert!(message("ACG\0UU").contains("byte 0x00 at position 4"));
+        assert!(message("MKVLEFPQIL").contains("looks like protein"));
+
+        let records: SequenceReader = Box::new(vec![Ok(sequence("ACGU")), Ok(sequence("AC1"))].into_iter());
+        let checked: Vec<_> = strict(records).collect();
+        assert!(checked[0].is_ok() && checked[1].is_err());
+    }
+
+    #[test]
+    fn test_unique_names() {
+        let records = || -> SequenceReader {
+            let fasta = ">a\nACGU\n>b\nGG\n>a\nACGU\n>a.1\nCC\n>a\nACGU\n";
+            Box::new(FastaReader::new(Cursor::new(fasta.as_bytes().to_vec()), "test"))
+        };
+        let names = |mode| unique_names(records(), mode).map(|s| s.map(|s| s.name)).collect::<Result<Vec<_>>>();
+
+        let err = names(DupNames::Error).unwrap_err();
+        assert_eq!(err.to_string(), "duplicate sequence name 'a'; use --dup-names suffix or dedup to accept it");
+        assert_eq!(names(DupNames::Suffix).unwrap(), vec!["a", "b", "a.1", "a.1.1", "a.2"]);
+        assert_eq!(names(DupNames::Dedup).unwrap(), vec!["a", "b", "a.1"]);
+
+        let differing: SequenceReader = Box::new(FastaReader::new(Cursor::new(b">a\nAC\n>a\nGG\n".to_vec()), "test"));
+        assert!(unique_names(differing, DupNames::Dedup).any(|s| s.is_err()));
+    }
+
+    #[test]
+    fn test_mmap_matches_buffered_read() {
+        let path = std::env::temp_dir().join(format!("seqfile-test-{}.fa", std::process::id()));
+        std::fs::write(&path, ">seq1\nACGU\nAC\n>seq2\r\nGG*\n").unwrap();
+
+        let mut config = Config::new();
+        let buffered: Vec<_> = open(&path, &config).unwrap().map(|s| s.unwrap().sequence).collect();
+        config.mmap = true;
+        let mapped: Vec<_> = open(&path, &config).unwrap().map(|s| s.unwrap().sequence).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(buffered, vec!["ACGUAC", "GG"]);
+        assert_eq!(mapped, buffered);
+    }
+}
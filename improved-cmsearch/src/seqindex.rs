@@ -0,0 +1,241 @@
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::error::{FileError, SeqFormatError};
+use crate::retry::RetryingFile;
+use crate::search::Sequence;
+use crate::seqfile::{self, FastaReader, SeqFormat, SequenceReader};
+
+const MAGIC: &str = "#SSI";
+const VERSION: &str = "v2";
+
+/// Location of one record of an indexed FASTA file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub offset: u64,   // Byte offset of the header line
+    pub length: usize, // Residues
+}
+
+/// Name -> location index of a FASTA sequence database, stored beside it as
+/// `<seqdb>.ssi` (cf. Infernal's SSI indexes). Records can be fetched by
+/// name, and a search can start at any record, without scanning the file.
+///
+/// The index records the size and modification time of the file it was
+/// built from, and is not used once the file changes.
+#[derive(Debug, Clone)]
+pub struct SeqIndex {
+    entries: Vec<IndexEntry>, // In file order
+    by_name: HashMap<String, usize>,
+    file_size: u64,
+    file_mtime: u64, // Seconds since the epoch
+}
+
+impl SeqIndex {
+    /// Path of the index of `seqdb`.
+    pub fn path_for(seqdb: &Path) -> PathBuf {
+        let mut path = seqdb.as_os_str().to_owned();
+        path.push(".ssi");
+        PathBuf::from(path)
+    }
+
+    /// Index of an uncompressed FASTA file, built by reading it once.
+    pub fn build(seqdb: &Path) -> Result<Self, SeqFormatError> {
+        let input = seqdb.display().to_string();
+        let file = RetryingFile::open(seqdb).map_err(|source| SeqFormatError::Open { input: input.clone(), source })?;
+        let mut reader = BufReader::new(file);
+        let read_error = |source| SeqFormatError::Read { input: input.clone(), position: String::new(), source };
+        let head = reader.fill_buf().map_err(read_error)?;
+        if SeqFormat::sniff(head) != Some(SeqFormat::Fasta) {
+            return Err(SeqFormatError::NotIndexable { input });
+        }
+
+        let (file_size, file_mtime) = file_stamp(seqdb).map_err(read_error)?;
+        let mut records = FastaReader::new(reader, &input);
+        let mut entries = Vec::new();
+        while let Some(record) = records.next().transpose()? {
+            entries.push(IndexEntry {
+                name: record.name,
+                offset: records.record_offset(),
+                length: record.length,
+            });
+        }
+        Ok(Self::from_entries(entries, file_size, file_mtime))
+    }
+
+    fn from_entries(entries: Vec<IndexEntry>, file_size: u64, file_mtime: u64) -> Self {
+        let mut by_name = HashMap::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            by_name.entry(entry.name.clone()).or_insert(i);
+        }
+        Self { entries, by_name, file_size, file_mtime }
+    }
+
+    /// Writes the index as text: a header line, then one tab-separated
+    /// `name offset length` line per record.
+    pub fn write(&self, path: &Path) -> Result<(), FileError> {
+        let file = File::create(path).map_err(FileError::io(|| format!("Failed to create index {}", path.display())))?;
+        let mut out = BufWriter::new(file);
+        let mut write = || -> io::Result<()> {
+            writeln!(out, "{}\t{}\t{}\t{}", MAGIC, VERSION, self.file_size, self.file_mtime)?;
+            for entry in &self.entries {
+                writeln!(out, "{}\t{}\t{}", entry.name, entry.offset, entry.length)?;
+            }
+            out.flush()
+        };
+        write().map_err(FileError::io(|| format!("Failed to write index {}", path.display())))
+    }
+
+    pub fn read(path: &Path) -> Result<Self, FileError> {
+        let text = fs::read_to_string(path).map_err(FileError::io(|| format!("Failed to read index {}", path.display())))?;
+        let mut lines = text.lines();
+        let header: Vec<&str> = lines.next().unwrap_or_default().split('\t').collect();
+        let stamp = match header[..] {
+            [MAGIC, VERSION, size, mtime] => size.parse().ok().zip(mtime.parse().ok()),
+            _ => None,
+        };
+        let Some((file_size, file_mtime)) = stamp else {
+            return Err(FileError::invalid(format!("{}: not a sequence index of this version; rebuild it with `index`",
+                                                  path.display())));
+        };
+
+        let entries = lines
+            .enumerate()
+            .map(|(i, line)| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                let entry = match fields[..] {
+                    [name, offset, length] => offset.parse().ok().zip(length.parse().ok()).map(|(offset, length)| {
+                        IndexEntry { name: name.to_string(), offset, length }
+                    }),
+                    _ => None,
+                };
+                entry.ok_or_else(|| FileError::invalid(format!("{}: malformed index line {}", path.display(), i + 2)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_entries(entries, file_size, file_mtime))
+    }
+
+    /// The index of `seqdb`, if one exists and is up to date.
+    pub fn load_current(seqdb: &Path) -> Result<Option<Self>, FileError> {
+        let path = Self::path_for(seqdb);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let index = Self::read(&path)?;
+        let stamp = file_stamp(seqdb).map_err(FileError::io(|| format!("Failed to stat {}", seqdb.display())))?;
+        if stamp != (index.file_size, index.file_mtime) {
+            warn!("Ignoring out of date index {}; rebuild it with `index`", path.display());
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Position in file order of the first record named `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn total_residues(&self) -> u64 {
+        self.entries.iter().map(|e| e.length as u64).sum()
+    }
+
+    /// Reads the record named `name` from `seqdb`.
+    pub fn fetch(&self, seqdb: &Path, name: &str) -> Result<Sequence, SeqFormatError> {
+        let input = seqdb.display().to_string();
+        let Some(position) = self.position(name) else {
+            return Err(SeqFormatError::NotIndexed { input, name: name.to_string() });
+        };
+        let record = self.read_from(seqdb, position)?.next().transpose()?;
+        match record {
+            Some(sequence) if sequence.name == name => Ok(sequence),
+            _ => Err(SeqFormatError::StaleIndex { input }),
+        }
+    }
+
+    /// Records of `seqdb` from the one at `position` in file order to the end.
+    pub fn read_from(&self, seqdb: &Path, position: usize) -> Result<SequenceReader, SeqFormatError> {
+        let input = seqdb.display().to_string();
+        let offset = self.entries.get(position).map_or(self.file_size, |e| e.offset);
+        let mut file = File::open(seqdb).map_err(|source| SeqFormatError::Open { input: input.clone(), source })?;
+        file.seek(SeekFrom::Start(offset)).map_err(|source| SeqFormatError::Read {
+            input: input.clone(),
+            position: format!(" at byte offset {}", offset),
+            source,
+        })?;
+        seqfile::from_reader(BufReader::new(file), &input, None)
+    }
+
+    /// Records of `seqdb` at the given positions, each read by seeking
+    /// straight to it.
+    pub fn read_at(&self, seqdb: &Path, positions: &[usize]) -> Result<SequenceReader, SeqFormatError> {
+        let source = seqdb.display().to_string();
+        let mut file = File::open(seqdb).map_err(|err| SeqFormatError::Open { input: source.clone(), source: err })?;
+        let entries: Vec<IndexEntry> = positions.iter().map(|&p| self.entries[p].clone()).collect();
+        Ok(Box::new(entries.into_iter().map(move |entry| {
+            file.seek(SeekFrom::Start(entry.offset)).map_err(|err| SeqFormatError::Read {
+                input: source.clone(),
+                position: format!(" at byte offset {} (record '{}')", entry.offset, entry.name),
+                source: err,
+            })?;
+            match FastaReader::new(BufReader::new(&mut file), &source).next().transpose()? {
+                Some(sequence) if sequence.name == entry.name => Ok(sequence),
+                _ => Err(SeqFormatError::StaleIndex { input: source.clone() }),
+            }
+        })))
+    }
+}
+
+// Size and modification time (seconds) identifying a version of a file
+fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Ok((metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_roundtrip_and_fetch() {
+        let dir = std::env::temp_dir();
+        let seqdb = dir.join(format!("seqindex-test-{}.fa", std::process::id()));
+        fs::write(&seqdb, ">s1 first\nACGU\nAC\n\n>s2\r\nGG\n>s3\nUUUA\n").unwrap();
+
+        let index = SeqIndex::build(&seqdb).unwrap();
+        let path = SeqIndex::path_for(&seqdb);
+        index.write(&path).unwrap();
+        let read = SeqIndex::load_current(&seqdb).unwrap().unwrap();
+
+        assert_eq!(read.entries(), index.entries());
+        assert_eq!(read.entries().iter().map(|e| e.offset).collect::<Vec<_>>(), vec![0, 19, 27]);
+        assert_eq!(read.total_residues(), 12);
+        assert_eq!(read.fetch(&seqdb, "s2").unwrap().sequence, "GG");
+        let s1 = read.fetch(&seqdb, "s1").unwrap();
+        assert_eq!((s1.description.as_str(), s1.sequence.as_str()), ("first", "ACGUAC"));
+        assert!(read.fetch(&seqdb, "s4").is_err());
+
+        let rest: Vec<String> = read.read_from(&seqdb, 1).unwrap().map(|s| s.unwrap().name).collect();
+        assert_eq!(rest, vec!["s2", "s3"]);
+        assert_eq!(read.read_from(&seqdb, 3).unwrap().count(), 0);
+        let picked: Vec<String> = read.read_at(&seqdb, &[2, 0]).unwrap().map(|s| s.unwrap().name).collect();
+        assert_eq!(picked, vec!["s3", "s1"]);
+
+        // A changed file makes the index stale
+        fs::write(&seqdb, ">s1 first\nACGU\n").unwrap();
+        assert!(SeqIndex::load_current(&seqdb).unwrap().is_none());
+
+        fs::remove_file(&seqdb).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use crate::config::Config;
+use crate::error::FileError;
+use crate::seqfile::SequenceReader;
+
+// Prefix of a --seqlist/--seqexclude argument that is a regex, not a file
+const REGEX_PREFIX: &str = "re:";
+
+/// Target names given to --seqlist or --seqexclude: a file of names, one per
+/// line, or `re:<REGEX>`.
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    Names(HashSet<String>),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    pub fn parse(arg: &str) -> Result<Self, FileError> {
+        if let Some(pattern) = arg.strip_prefix(REGEX_PREFIX) {
+            let regex = Regex::new(pattern)
+                .map_err(|err| FileError::invalid(format!("Invalid target name regex '{}': {}", pattern, err)))?;
+            return Ok(NamePattern::Regex(regex));
+        }
+        let text = fs::read_to_string(arg).map_err(FileError::io(|| format!("Failed to read target names from {}", arg)))?;
+        Ok(Self::from_names(&text))
+    }
+
+    /// Names listed one per line; blank lines and `#` comments are ignored.
+    pub fn from_names(text: &str) -> Self {
+        let names = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        NamePattern::Names(names)
+    }
+
+    /// Whether a target name is listed, or matched anywhere by the regex.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Names(names) => names.contains(name),
+            NamePattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Which targets are searched: those matching --seqlist, if given, and not
+/// matching --seqexclude.
+#[derive(Debug, Clone, Default)]
+pub struct TargetFilter {
+    include: Option<NamePattern>,
+    exclude: Option<NamePattern>,
+}
+
+impl TargetFilter {
+    pub fn new(include: Option<NamePattern>, exclude: Option<NamePattern>) -> Self {
+        Self { include, exclude }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, FileError> {
+        let include = config.seqlist.as_deref().map(NamePattern::parse).transpose()?;
+        let exclude = config.seqexclude.as_deref().map(NamePattern::parse).transpose()?;
+        Ok(Self::new(include, exclude))
+    }
+
+    /// Whether every target is searched.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    pub fn keeps(&self, name: &str) -> bool {
+        self.include.as_ref().is_none_or(|p| p.matches(name))
+            && !self.exclude.as_ref().is_some_and(|p| p.matches(name))
+    }
+
+    /// Records of `reader` that the filter keeps; read errors pass through.
+    pub fn apply(self, reader: SequenceReader) -> SequenceReader {
+        if self.is_empty() {
+            return reader;
+        }
+        Box::new(reader.filter(move |record| record.as_ref().map_or(true, |s| self.keeps(&s.name))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::Sequence;
+
+    #[test]
+    fn test_target_filter() {
+        let list = NamePattern::from_names("# wanted\nchr1\n\n  chr2 \nchrX\n");
+        let filter = TargetFilter::new(Some(list), Some(NamePattern::parse("re:^chr2").unwrap()));
+        assert!(filter.keeps("chr1"));
+        assert!(filter.keeps("chrX"));
+        assert!(!filter.keeps("chrX1"));
+        assert!(!filter.keeps("chr2"));
+        assert!(!filter.keeps("chr3"));
+        assert!(TargetFilter::default().keeps("chr3"));
+        assert!(NamePattern::parse("re:(").is_err());
+
+        let records: SequenceReader = Box::new(["chr1", "chr2", "chr3"].into_iter().map(|name| {
+            Ok(Sequence::new(name.to_string(), String::new(), "ACGU".to_string()))
+        }));
+        let kept: Vec<String> = filter.apply(records).map(|s| s.unwrap().name).collect();
+        assert_eq!(kept, vec!["chr1"]);
+    }
+}
@@ -0,0 +1,879 @@
+use log::debug;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::cm::Cm;
+use crate::config::{Config, FilterThresholds, StageKind};
+use crate::digital::{self, AMBIGUOUS};
+use crate::hmm::{self, ProfileHmm};
+use crate::search::Truncation;
+
+// Score histogram bins: HISTOGRAM_BIN bits wide from HISTOGRAM_MIN, with
+// scores out of range counted in the end bins
+const HISTOGRAM_MIN: f64 = -40.0;
+const HISTOGRAM_BIN: f64 = 1.0;
+const HISTOGRAM_BINS: usize = 200;
+
+/// Read-only model data shared by every stage.
+pub struct StageContext<'a> {
+    pub cm: &'a Cm,
+    pub hmm: &'a ProfileHmm,
+    pub scores: &'a CmScores,
+}
+
+/// Log-odds lookup tables of the CM consensus, built once per model so the
+/// CM stages score residues by table lookup; shared read-only by the worker
+/// threads.
+#[derive(Debug, Clone)]
+pub struct CmScores {
+    emissions: Vec<[f64; 5]>,     // Nats of each residue code at each consensus position; ambiguous 0
+    distributions: Vec<[f64; 4]>, // Sum of the emission distributions of positions 0..k, for null2
+}
+
+impl CmScores {
+    pub fn new(cm: &Cm) -> Self {
+        let mut distributions = vec![[0.0; 4]];
+        let emissions = cm.consensus.sequence
+            .chars()
+            .map(|cons| {
+                let dist = emission_distribution(cons);
+                let mut sum = *distributions.last().unwrap();
+                for (total, p) in sum.iter_mut().zip(dist) {
+                    *total += p;
+                }
+                distributions.push(sum);
+                let [a, c, g, u] = dist.map(|p| (p / 0.25).ln());
+                [a, c, g, u, 0.0]
+            })
+            .collect();
+        Self { emissions, distributions }
+    }
+
+    /// Consensus positions.
+    pub(crate) fn len(&self) -> usize {
+        self.emissions.len()
+    }
+
+    /// A sequence emitted by the consensus, with `uniform` (samples in
+    /// [0, 1)): each position keeps its consensus residue, or at a rate of
+    /// `divergence` draws one from its emission distribution.
+    pub fn emit(&self, divergence: f64, mut uniform: impl FnMut() -> f64) -> String {
+        const RESIDUES: &[u8; 4] = b"ACGU";
+        self.emissions
+            .iter()
+            .map(|scores| {
+                let consensus = (0..RESIDUES.len()).max_by(|&a, &b| scores[a].total_cmp(&scores[b])).unwrap_or(0);
+                if uniform() >= divergence {
+                    return RESIDUES[consensus] as char;
+                }
+                let mut u = uniform();
+                let residue = (0..RESIDUES.len())
+                    .find(|&r| {
+                        u -= 0.25 * scores[r].exp();
+                        u < 0.0
+                    })
+                    .unwrap_or(consensus);
+                RESIDUES[residue] as char
+            })
+            .collect()
+    }
+
+    // Log-odds (nats) of residue `code` at consensus position `k`
+    fn emission(&self, k: usize, code: u8) -> f64 {
+        self.emissions[k][code as usize]
+    }
+}
+
+/// One strand of a window moving through the pipeline. Stages may narrow
+/// `region` (envelope definition) and set the score of the survivor.
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub window: &'a [u8], // Digitized residues
+    pub region: Range<usize>, // Region of interest within `window`
+    pub window_bias: f64,     // Composition bias of the whole window in bits
+    pub score: f64,           // Score assigned by the last scoring stage
+    pub bias: f64,            // Null2 + null3 correction in bits
+    pub trunc_pass: Option<TruncPass>, // Truncated pass type; None for the standard pass
+    pub trunc: Truncation,    // Truncation of the aligned model fragment
+}
+
+impl<'a> Candidate<'a> {
+    pub fn new(window: &'a [u8], window_bias: f64) -> Self {
+        Self {
+            window,
+            region: 0..window.len(),
+            window_bias,
+            score: 0.0,
+            bias: 0.0,
+            trunc_pass: None,
+            trunc: Truncation::None,
+        }
+    }
+
+    pub fn residues(&self) -> &'a [u8] {
+        &self.window[self.region.clone()]
+    }
+}
+
+/// Per-stage counters, updated concurrently by the worker threads.
+#[derive(Debug, Default)]
+pub struct StageStats {
+    entered: AtomicUsize,
+    passed: AtomicUsize,
+    residues: AtomicU64,
+    passed_residues: AtomicU64,
+    nanos: AtomicU64, // Time in the stage, summed over threads
+    histogram: ScoreHistogram,
+}
+
+impl StageStats {
+    /// Counts a candidate of `residues` residues and its `score` (NaN if
+    /// the stage could not score it), taking `elapsed`; one that passes may
+    /// have been narrowed to `passed_residues`.
+    pub fn record(&self, residues: usize, score: f64, passed: bool, passed_residues: usize, elapsed: Duration) {
+        self.entered.fetch_add(1, Ordering::Relaxed);
+        self.nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.residues.fetch_add(residues as u64, Ordering::Relaxed);
+        if passed {
+            self.passed.fetch_add(1, Ordering::Relaxed);
+            self.passed_residues.fetch_add(passed_residues as u64, Ordering::Relaxed);
+        }
+        self.histogram.record(score, passed);
+    }
+
+    pub fn entered(&self) -> usize {
+        self.entered.load(Ordering::Relaxed)
+    }
+
+    pub fn passed(&self) -> usize {
+        self.passed.load(Ordering::Relaxed)
+    }
+
+    pub fn residues(&self) -> u64 {
+        self.residues.load(Ordering::Relaxed)
+    }
+
+    pub fn passed_residues(&self) -> u64 {
+        self.passed_residues.load(Ordering::Relaxed)
+    }
+
+    /// Time spent in the stage by all threads together.
+    pub fn time(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn histogram(&self) -> Vec<HistogramBin> {
+        self.histogram.bins()
+    }
+}
+
+/// Distribution of the scores of the candidates a stage scored, passing or
+/// not, updated concurrently by the worker threads.
+#[derive(Debug)]
+pub struct ScoreHistogram {
+    scored: Vec<AtomicUsize>,
+    passed: Vec<AtomicUsize>,
+}
+
+/// One non-empty bin of a score histogram: scores in `low..high` bits. The
+/// end bins are open, with infinite bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistogramBin {
+    pub low: f64,
+    pub high: f64,
+    pub scored: usize,
+    pub passed: usize,
+}
+
+impl Default for ScoreHistogram {
+    fn default() -> Self {
+        Self {
+            scored: (0..HISTOGRAM_BINS).map(|_| AtomicUsize::new(0)).collect(),
+            passed: (0..HISTOGRAM_BINS).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+impl ScoreHistogram {
+    /// Counts `score`; NaN scores are left out.
+    pub fn record(&self, score: f64, passed: bool) {
+        if score.is_nan() {
+            return;
+        }
+        let bin = ((score - HISTOGRAM_MIN) / HISTOGRAM_BIN).floor().clamp(0.0, (HISTOGRAM_BINS - 1) as f64) as usize;
+        self.scored[bin].fetch_add(1, Ordering::Relaxed);
+        if passed {
+            self.passed[bin].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The non-empty bins, lowest scores first.
+    pub fn bins(&self) -> Vec<HistogramBin> {
+        (0..HISTOGRAM_BINS)
+            .filter_map(|i| {
+                let scored = self.scored[i].load(Ordering::Relaxed);
+                let low = if i == 0 { f64::NEG_INFINITY } else { HISTOGRAM_MIN + i as f64 * HISTOGRAM_BIN };
+                let high = if i == HISTOGRAM_BINS - 1 { f64::INFINITY } else { HISTOGRAM_MIN + (i + 1) as f64 * HISTOGRAM_BIN };
+                (scored > 0).then(|| HistogramBin { low, high, scored, passed: self.passed[i].load(Ordering::Relaxed) })
+            })
+            .collect()
+    }
+}
+
+/// One stage of the pipeline: scores the candidate windows that reach it,
+/// passing on those over its threshold.
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn kind(&self) -> StageKind;
+
+    /// Survival threshold of the stage.
+    fn threshold(&self) -> f64;
+
+    fn stats(&self) -> &StageStats;
+
+    /// Score `candidate`, updating it in place; returns whether it survives.
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool;
+
+    /// `apply` with statistics bookkeeping.
+    fn run(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let residues = candidate.region.len();
+        let start = Instant::now();
+        // Stages that find no envelope or alignment reject the candidate
+        // unscored, leaving it out of the histogram
+        candidate.score = f64::NAN;
+        let passed = self.apply(ctx, candidate);
+        self.stats().record(residues, candidate.score, passed, candidate.region.len(), start.elapsed());
+        passed
+    }
+}
+
+/// Build the stage of the given kind with the given filter thresholds.
+pub fn build_stage(kind: StageKind, config: &Config, thresholds: &FilterThresholds) -> Box<dyn Stage> {
+    match kind {
+        StageKind::Ssv => Box::new(SsvStage::new(thresholds.f1)),
+        StageKind::Viterbi => Box::new(ViterbiStage::new(thresholds.f2)),
+        StageKind::Forward => Box::new(ForwardStage::new(thresholds.f3)),
+        StageKind::GlocalForward => Box::new(GlocalForwardStage::new(thresholds.f4, thresholds.f4b)),
+        StageKind::Envelope => Box::new(EnvelopeStage::new(thresholds.f5)),
+        StageKind::Consensus => Box::new(ConsensusFilterStage::new(thresholds.f6)),
+        StageKind::Score => Box::new(ConsensusScoreStage::new(0.0, !config.nonull3)),
+    }
+}
+
+/// SSV: best ungapped diagonal of the filter HMM, thresholded by P-value.
+pub struct SsvStage {
+    threshold: f64,
+    stats: StageStats,
+}
+
+impl SsvStage {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for SsvStage {
+    fn name(&self) -> &'static str { "SSV" }
+    fn kind(&self) -> StageKind { StageKind::Ssv }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let score = ctx.hmm.ssv(candidate.residues());
+        candidate.score = score;
+        hmm::bit_score_pvalue(score) <= self.threshold
+    }
+}
+
+/// Vit: glocal Viterbi score of the filter HMM, thresholded by P-value.
+pub struct ViterbiStage {
+    threshold: f64,
+    stats: StageStats,
+}
+
+impl ViterbiStage {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for ViterbiStage {
+    fn name(&self) -> &'static str { "Vit" }
+    fn kind(&self) -> StageKind { StageKind::Viterbi }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let score = ctx.hmm.glocal_viterbi(candidate.residues());
+        candidate.score = score;
+        hmm::bit_score_pvalue(score) <= self.threshold
+    }
+}
+
+/// Fwd: local Forward score of the filter HMM, thresholded by P-value.
+pub struct ForwardStage {
+    threshold: f64,
+    stats: StageStats,
+}
+
+impl ForwardStage {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for ForwardStage {
+    fn name(&self) -> &'static str { "Fwd" }
+    fn kind(&self) -> StageKind { StageKind::Forward }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let score = ctx.hmm.local_forward(candidate.residues());
+        candidate.score = score;
+        hmm::bit_score_pvalue(score) <= self.threshold
+    }
+}
+
+/// gFwd: glocal Forward score (F4), then the same score after window
+/// composition bias correction (F4b).
+pub struct GlocalForwardStage {
+    threshold: f64,
+    bias_threshold: f64,
+    stats: StageStats,
+}
+
+impl GlocalForwardStage {
+    pub fn new(threshold: f64, bias_threshold: f64) -> Self {
+        Self { threshold, bias_threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for GlocalForwardStage {
+    fn name(&self) -> &'static str { "gFwd" }
+    fn kind(&self) -> StageKind { StageKind::GlocalForward }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let score = ctx.hmm.glocal_forward(candidate.residues());
+        candidate.score = score;
+        hmm::bit_score_pvalue(score) <= self.threshold
+            && hmm::bit_score_pvalue(score - candidate.window_bias) <= self.bias_threshold
+    }
+}
+
+/// Envelope definition from begin/end posterior maxima; the envelope is
+/// re-scored with glocal Forward (F5) and replaces the candidate region.
+pub struct EnvelopeStage {
+    threshold: f64,
+    stats: StageStats,
+}
+
+impl EnvelopeStage {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for EnvelopeStage {
+    fn name(&self) -> &'static str { "envelope" }
+    fn kind(&self) -> StageKind { StageKind::Envelope }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let Some(env) = ctx.hmm.envelope(candidate.residues()) else {
+            return false;
+        };
+        let offset = candidate.region.start;
+        candidate.region = offset + env.start..offset + env.end;
+
+        let score = ctx.hmm.glocal_forward(candidate.residues());
+        candidate.score = score;
+
+        debug!("Envelope {}..{} (env {:.1} bits, pp {:.2}/{:.2})",
+               candidate.region.start, candidate.region.end, score, env.begin_pp, env.end_pp);
+        hmm::bit_score_pvalue(score) <= self.threshold
+    }
+}
+
+/// Consensus filter: log-odds score of the candidate region against the CM
+/// consensus emissions, without composition corrections, thresholded by
+/// P-value. It runs where Infernal runs CYK, but the residues are placed
+/// on the consensus ungapped, one per position from the region's start,
+/// and base pairs are not scored together.
+pub struct ConsensusFilterStage {
+    threshold: f64,
+    stats: StageStats,
+}
+
+impl ConsensusFilterStage {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, stats: StageStats::default() }
+    }
+}
+
+impl Stage for ConsensusFilterStage {
+    fn name(&self) -> &'static str { "Cons" }
+    fn kind(&self) -> StageKind { StageKind::Consensus }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        let score = if candidate.trunc_pass.is_none() {
+            cm_bit_score(ctx.scores, candidate.residues())
+        } else {
+            match place_truncated(ctx.scores, candidate) {
+                Some(placement) => placement.score,
+                None => return false,
+            }
+        };
+        candidate.score = score;
+        hmm::bit_score_pvalue(score) <= self.threshold
+    }
+}
+
+/// Final score: the consensus log-odds score of the consensus filter,
+/// length-normalized by the model path's transitions and the null model's
+/// length distribution, with the null2 and (optionally) null3 corrections
+/// subtracted; hits need a positive corrected score. It stands in for
+/// Infernal's Inside score, and is E-valued with its calibration, but sums
+/// a single ungapped placement rather than every alignment.
+pub struct ConsensusScoreStage {
+    threshold: f64,
+    null3: bool,
+    stats: StageStats,
+}
+
+impl ConsensusScoreStage {
+    pub fn new(threshold: f64, null3: bool) -> Self {
+        Self { threshold, null3, stats: StageStats::default() }
+    }
+}
+
+impl Stage for ConsensusScoreStage {
+    fn name(&self) -> &'static str { "Score" }
+    fn kind(&self) -> StageKind { StageKind::Score }
+    fn threshold(&self) -> f64 { self.threshold }
+    fn stats(&self) -> &StageStats { &self.stats }
+
+    fn apply(&self, ctx: &StageContext, candidate: &mut Candidate) -> bool {
+        // Truncated passes score only the model fragment placed on the hit
+        let (emissions, aligned) = if candidate.trunc_pass.is_none() {
+            let residues = candidate.residues();
+            (cm_bit_score(ctx.scores, residues), residues.len().min(ctx.scores.len()))
+        } else {
+            match place_truncated(ctx.scores, candidate) {
+                Some(placement) => (placement.score, placement.model.len()),
+                None => return false,
+            }
+        };
+        let residues = candidate.residues();
+        let raw = emissions + length_correction(ctx.hmm, aligned, residues.len());
+        let null2 = null2_correction(ctx.cm, ctx.scores, residues);
+
+        // Null3: penalty for the low-complexity composition of the hit itself
+        let null3 = if self.null3 {
+            hmm::composition_bias(residues, ctx.cm.null_model.null3_omega)
+        } else {
+            0.0
+        };
+
+        candidate.bias = null2 + null3;
+        candidate.score = raw - candidate.bias;
+        candidate.score > self.threshold
+    }
+}
+
+// Log-odds score in bits of the consensus-aligned (digitized) residues
+// against the uniform null
+fn cm_bit_score(scores: &CmScores, sequence: &[u8]) -> f64 {
+    let nats: f64 = sequence.iter()
+        .take(scores.len())
+        .enumerate()
+        .map(|(k, &code)| scores.emission(k, code))
+        .sum();
+    nats / std::f64::consts::LN_2
+}
+
+// Transition scores (bits) missing from the emission-only CM score: the
+// model path through `aligned` consensus positions, less the null model's
+// length distribution for `residues` residues (p1 = n/(n+1), as in HMMER's
+// null1). Without it every extra residue adds only its emission log-odds,
+// so scores grow with hit length.
+fn length_correction(hmm: &ProfileHmm, aligned: usize, residues: usize) -> f64 {
+    let t_mm = hmm.transitions()[0][0];
+    let model = aligned.saturating_sub(1) as f64 * t_mm;
+    let n = residues as f64;
+    let null = n * (n / (n + 1.0)).ln() + (1.0 / (n + 1.0)).ln();
+    (model - null) / std::f64::consts::LN_2
+}
+
+/// Model placements a truncated pass considers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncPass {
+    FivePrime,  // Model suffix at the sequence start
+    ThreePrime, // Model prefix at the sequence end
+    Both,       // Internal model fragment spanning the whole sequence
+    Anywhere,   // Any model fragment at any position (--anytrunc)
+}
+
+/// Ungapped placement of a model fragment on a sequence, as found by a
+/// truncated pass: consensus position `model.start + j` is placed on
+/// residue `sequence.start + j`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruncatedPlacement {
+    pub model: Range<usize>,    // Consensus positions aligned
+    pub sequence: Range<usize>, // Residues aligned to them
+    pub score: f64,             // Bits, including the truncation penalty
+    pub trunc: Truncation,      // Model ends missing from the fragment
+}
+
+/// Best placement of a model fragment on the digitized `sequence` for a
+/// truncated pass, scored by the consensus log-odds of its residues. This
+/// is not Infernal's TrCYK: fragments are placed on a diagonal without
+/// gaps, and their base pairs are not scored together. The score is
+/// penalised by the number of truncation points considered.
+pub fn truncated_placement(scores: &CmScores, sequence: &[u8], pass: TruncPass) -> Option<TruncatedPlacement> {
+    let k = scores.len();
+    let l = sequence.len();
+    if k < 2 || l == 0 {
+        return None;
+    }
+
+    let score_at = |k: usize, i: usize| scores.emission(k, sequence[i]);
+
+    // (model start, sequence start, aligned length) for each placement
+    let placements: Vec<(usize, usize, usize)> = match pass {
+        TruncPass::FivePrime => (k.saturating_sub(l).max(1)..k)
+            .map(|k0| (k0, 0, k - k0))
+            .collect(),
+        TruncPass::ThreePrime => (1..k.min(l + 1))
+            .map(|m| (0, l - m, m))
+            .collect(),
+        TruncPass::Both => (1..k.saturating_sub(l))
+            .map(|k0| (k0, 0, l))
+            .collect(),
+        // Best ungapped segment on each diagonal
+        TruncPass::Anywhere => (0..k + l - 1)
+            .filter_map(|d| {
+                let (k0, i0) = if d < k { (k - 1 - d, 0) } else { (0, d - k + 1) };
+                let mut best = None;
+                let (mut start, mut sum, mut best_sum) = (0, 0.0, 0.0);
+                for j in 0..(k - k0).min(l - i0) {
+                    if sum <= 0.0 {
+                        start = j;
+                        sum = 0.0;
+                    }
+                    sum += score_at(k0 + j, i0 + j);
+                    if sum > best_sum {
+                        best_sum = sum;
+                        best = Some((k0 + start, i0 + start, j + 1 - start));
+                    }
+                }
+                best
+            })
+            .collect(),
+    };
+    if placements.is_empty() {
+        return None;
+    }
+
+    let penalty = match pass {
+        TruncPass::Anywhere => ((k * (k + 1) / 2) as f64).log2(),
+        _ => (placements.len() as f64).log2(),
+    };
+    placements.into_iter()
+        .map(|(k0, i0, len)| {
+            let nats: f64 = (0..len).map(|j| score_at(k0 + j, i0 + j)).sum();
+            TruncatedPlacement {
+                model: k0..k0 + len,
+                sequence: i0..i0 + len,
+                score: nats / std::f64::consts::LN_2 - penalty,
+                trunc: Truncation::of(k0 > 0, k0 + len < k),
+            }
+        })
+        .max_by(|a, b| a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+// Truncated placement on the candidate's residues; narrows the region to
+// the placed residues and records the truncation
+fn place_truncated(scores: &CmScores, candidate: &mut Candidate) -> Option<TruncatedPlacement> {
+    let aln = truncated_placement(scores, candidate.residues(), candidate.trunc_pass?)?;
+    let offset = candidate.region.start;
+    candidate.region = offset + aln.sequence.start..offset + aln.sequence.end;
+    candidate.trunc = aln.trunc;
+    Some(aln)
+}
+
+fn calculate_emission_probability(residue: u8, cons: u8) -> f64 {
+    // Compared as codes, so T in the consensus of a DNA model is U
+    match (residue, cons) {
+        (_, AMBIGUOUS) => 0.05, // N consensus - background
+        (a, b) if a == b => 0.95, // Exact match - very high
+        (a, b) if a + b == 3 => 0.85, // Watson-Crick (A-U, C-G) - high
+        (2, 3) | (3, 2) => 0.7, // G-U wobble - moderate
+        _ => 0.01, // Mismatch - extremely low
+    }
+}
+
+// Null2 correction (bits) for biased composition: the hit is re-scored
+// against a null whose composition is the average emission distribution
+// of the consensus positions its residues are placed on. Infernal weights
+// each position by the posterior probability of the alignment instead;
+// here the placement is the ungapped one of the consensus score
+fn null2_correction(cm: &Cm, scores: &CmScores, sequence: &[u8]) -> f64 {
+    let aligned = std::cmp::min(sequence.len(), scores.len());
+    if aligned == 0 {
+        return 0.0;
+    }
+
+    let null2 = scores.distributions[aligned].map(|total| total / aligned as f64);
+
+    // Log-odds of the hit residues under null2 versus the uniform null
+    let null2_score: f64 = sequence.iter()
+        .filter(|&&code| code < AMBIGUOUS)
+        .map(|&code| (null2[code as usize] / 0.25).ln())
+        .sum();
+
+    // ln(1 + omega * e^null2_score), computed without overflow
+    let x = cm.null_model.null2_omega.ln() + null2_score;
+    let nats = if x > 0.0 {
+        x + (-x).exp().ln_1p()
+    } else {
+        x.exp().ln_1p()
+    };
+    nats / std::f64::consts::LN_2
+}
+
+fn emission_distribution(cons_char: char) -> [f64; 4] {
+    let mut dist = [0.0; 4];
+    for (r, p) in dist.iter_mut().enumerate() {
+        *p = calculate_emission_probability(r as u8, digital::code(cons_char as u8));
+    }
+    let total: f64 = dist.iter().sum();
+    dist.map(|p| p / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cm::Alphabet;
+    use crate::digital::DigitizedSeq;
+
+    fn dsq(sequence: &str) -> DigitizedSeq {
+        DigitizedSeq::from_text(sequence.as_bytes())
+    }
+
+    // Deterministic pseudo-random RNA from a linear congruential generator
+    fn random_rna(len: usize, seed: u64) -> String {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                b"ACGU"[(state >> 33) as usize % 4] as char
+            })
+            .collect()
+    }
+
+    fn test_model() -> (Cm, ProfileHmm) {
+        let mut cm = Cm::new("test".to_string(), Alphabet::RNA);
+        cm.consensus.sequence = random_rna(60, 1);
+        cm.consensus.length = 60;
+        cm.length = 60;
+        let hmm = ProfileHmm::from_cm(&cm);
+        (cm, hmm)
+    }
+
+    fn passes(stage: &dyn Stage, cm: &Cm, hmm: &ProfileHmm, window: &str) -> bool {
+        let scores = CmScores::new(cm);
+        let ctx = StageContext { cm, hmm, scores: &scores };
+        stage.run(&ctx, &mut Candidate::new(&dsq(window), 0.0))
+    }
+
+    #[test]
+    fn test_ssv_stage() {
+        let (cm, hmm) = test_model();
+        let stage = SsvStage::new(0.02);
+        assert!(passes(&stage, &cm, &hmm, &cm.consensus.sequence));
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 2)));
+        assert_eq!(stage.stats().entered(), 2);
+        assert_eq!(stage.stats().passed(), 1);
+        assert_eq!(stage.stats().residues(), 120);
+    }
+
+    #[test]
+    fn test_viterbi_stage() {
+        let (cm, hmm) = test_model();
+        let stage = ViterbiStage::new(0.001);
+        assert!(passes(&stage, &cm, &hmm, &cm.consensus.sequence));
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 3)));
+    }
+
+    #[test]
+    fn test_forward_stage() {
+        let (cm, hmm) = test_model();
+        let stage = ForwardStage::new(1e-5);
+        assert!(passes(&stage, &cm, &hmm, &cm.consensus.sequence));
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 4)));
+    }
+
+    #[test]
+    fn test_glocal_forward_stage() {
+        let (cm, hmm) = test_model();
+        let stage = GlocalForwardStage::new(0.002, 0.002);
+        assert!(passes(&stage, &cm, &hmm, &cm.consensus.sequence));
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 5)));
+
+        // A strongly biased window fails the bias-corrected threshold only
+        let scores = CmScores::new(&cm);
+        let ctx = StageContext { cm: &cm, hmm: &hmm, scores: &scores };
+        assert!(!stage.run(&ctx, &mut Candidate::new(&dsq(&cm.consensus.sequence), 1000.0)));
+    }
+
+    #[test]
+    fn test_envelope_stage_narrows_region() {
+        let (cm, hmm) = test_model();
+        let window = dsq(&format!("{}{}{}", random_rna(30, 6), cm.consensus.sequence, random_rna(30, 7)));
+        let scores = CmScores::new(&cm);
+        let ctx = StageContext { cm: &cm, hmm: &hmm, scores: &scores };
+        let mut candidate = Candidate::new(&window, 0.0);
+
+        assert!(EnvelopeStage::new(0.0002).run(&ctx, &mut candidate));
+        assert_eq!(candidate.region, 30..90);
+        assert_eq!(candidate.residues(), &dsq(&cm.consensus.sequence)[..]);
+    }
+
+    #[test]
+    fn test_consensus_filter_stage() {
+        let (cm, hmm) = test_model();
+        let stage = ConsensusFilterStage::new(1e-4);
+        assert!(passes(&stage, &cm, &hmm, &cm.consensus.sequence));
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 8)));
+        assert_eq!(stage.stats().passed(), 1);
+    }
+
+    #[test]
+    fn test_truncated_placement() {
+        let (cm, _) = test_model();
+        let scores = CmScores::new(&cm);
+        let cons = &cm.consensus.sequence;
+
+        let five = format!("{}{}", &cons[20..], random_rna(20, 9));
+        let aln = truncated_placement(&scores, &dsq(&five), TruncPass::FivePrime).unwrap();
+        assert_eq!((aln.model, aln.sequence), (20..60, 0..40));
+        assert_eq!(aln.trunc, Truncation::FivePrime);
+        assert!(aln.score > 0.0);
+
+        let three = format!("{}{}", random_rna(20, 10), &cons[..40]);
+        let aln = truncated_placement(&scores, &dsq(&three), TruncPass::ThreePrime).unwrap();
+        assert_eq!((aln.model, aln.sequence), (0..40, 20..60));
+        assert_eq!(aln.trunc, Truncation::ThreePrime);
+
+        let aln = truncated_placement(&scores, &dsq(&cons[10..40]), TruncPass::Both).unwrap();
+        assert_eq!((aln.model, aln.sequence), (10..40, 0..30));
+
+        assert_eq!(aln.trunc, Truncation::Both);
+
+        let internal = format!("{}{}{}", random_rna(20, 11), &cons[15..45], random_rna(20, 12));
+        let aln = truncated_placement(&scores, &dsq(&internal), TruncPass::Anywhere).unwrap();
+        // On the planted diagonal; may extend into chance matches in the flanks
+        assert_eq!(aln.sequence.start, aln.model.start + 5);
+        assert!(aln.model.start <= 15 && aln.model.end >= 45);
+        assert_eq!(aln.trunc, Truncation::Both);
+    }
+
+    #[test]
+    fn test_consensus_score_stage_bit_scores() {
+        let (cm, hmm) = test_model();
+        let stage = ConsensusScoreStage::new(0.0, true);
+        let scores = CmScores::new(&cm);
+        let ctx = StageContext { cm: &cm, hmm: &hmm, scores: &scores };
+
+        let consensus = dsq(&cm.consensus.sequence);
+        let mut hit = Candidate::new(&consensus, 0.0);
+        assert!(stage.run(&ctx, &mut hit));
+        assert!(hit.score > 20.0);
+        assert!(!passes(&stage, &cm, &hmm, &random_rna(60, 13)));
+    }
+
+    #[test]
+    fn test_length_correction_does_not_grow_with_length() {
+        let (cm, hmm) = test_model();
+        let corrections: Vec<f64> = [30, 60, 120, 240].iter()
+            .map(|&n| length_correction(&hmm, n, n))
+            .collect();
+        assert!(corrections.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", corrections);
+
+        // Final scores include it
+        let scores = CmScores::new(&cm);
+        let ctx = StageContext { cm: &cm, hmm: &hmm, scores: &scores };
+        let consensus = dsq(&cm.consensus.sequence);
+        let mut hit = Candidate::new(&consensus, 0.0);
+        ConsensusScoreStage::new(f64::NEG_INFINITY, false).run(&ctx, &mut hit);
+        let expected = cm_bit_score(&scores, &consensus) + length_correction(&hmm, 60, 60) - hit.bias;
+        assert!((hit.score - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_consensus_score_stage_reports_bias() {
+        let (cm, hmm) = test_model();
+        let scores = CmScores::new(&cm);
+        let ctx = StageContext { cm: &cm, hmm: &hmm, scores: &scores };
+        let poly_a = dsq(&"A".repeat(60));
+
+        let mut with_null3 = Candidate::new(&poly_a, 0.0);
+        ConsensusScoreStage::new(0.0, true).run(&ctx, &mut with_null3);
+        let mut without_null3 = Candidate::new(&poly_a, 0.0);
+        ConsensusScoreStage::new(0.0, false).run(&ctx, &mut without_null3);
+
+        assert!(with_null3.bias > without_null3.bias);
+        assert!(with_null3.score < without_null3.score);
+    }
+
+    #[test]
+    fn test_bias_of_a_low_complexity_target() {
+        let (_, hmm) = test_model();
+        let poly_a = dsq(&"A".repeat(60));
+        let bias = |cm: &Cm, null3: bool| {
+            let scores = CmScores::new(cm);
+            let ctx = StageContext { cm, hmm: &hmm, scores: &scores };
+            let mut candidate = Candidate::new(&poly_a, 0.0);
+            ConsensusScoreStage::new(f64::NEG_INFINITY, null3).run(&ctx, &mut candidate);
+            candidate.bias
+        };
+        // 2 bits per residue of a one-residue composition, less the 16 bits
+        // of the biased null's prior (omega = 2^-16)
+        let null3 = 60.0 * 2.0 - 16.0;
+
+        // Against a mixed consensus null2 is near uniform and corrects
+        // almost nothing
+        let (cm, _) = test_model();
+        assert!(bias(&cm, false) < 1e-3);
+        assert!((bias(&cm, true) - null3).abs() < 1e-3);
+
+        // Against a poly-A consensus null2 takes on the consensus
+        // composition: A at 0.95 / 1.82 per position
+        let mut poly_a_cm = cm.clone();
+        poly_a_cm.consensus.sequence = "A".repeat(60);
+        let null2 = 60.0 * (0.95 / 1.82 / 0.25f64).log2() - 16.0;
+        assert!((bias(&poly_a_cm, false) - null2).abs() < 1e-3);
+        assert!((bias(&poly_a_cm, true) - (null2 + null3)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_score_histogram() {
+        let histogram = ScoreHistogram::default();
+        for (score, passed) in [(-1000.0, false), (2.5, false), (2.0, true), (1e9, true), (f64::NAN, false)] {
+            histogram.record(score, passed);
+        }
+
+        let bins = histogram.bins();
+        assert_eq!(bins.len(), 3);
+        assert_eq!((bins[0].low, bins[0].high, bins[0].scored), (f64::NEG_INFINITY, HISTOGRAM_MIN + HISTOGRAM_BIN, 1));
+        assert_eq!((bins[1].low, bins[1].high, bins[1].scored, bins[1].passed), (2.0, 3.0, 2, 1));
+        assert_eq!((bins[2].high, bins[2].passed), (f64::INFINITY, 1));
+    }
+}
@@ -1,6 +1,7 @@
-use anyhow::Result;
-use log::{debug, info, warn};
-use std::time::{Duration, Instant};
+use log::info;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::config::SeqAlphabet;
+use crate::digital;
 
 pub struct Timer {
     start: Instant,
@@ -36,11 +37,14 @@ pub fn format_bytes(bytes: u64) -> String {
     const MB: u64 = KB * 1024;
     const GB: u64 = MB * 1024;
     
-    match bytes {
-        0..=KB => format!("{} B", bytes),
-        KB..=MB => format!("{:.1} KB", bytes as f64 / KB as f64),
-        MB..=GB => format!("{:.1} MB", bytes as f64 / MB as f64),
-        _ => format!("{:.1} GB", bytes as f64 / GB as f64),
+    if bytes <= KB {
+        format!("{} B", bytes)
+    } else if bytes < MB {
+        format!("{:.1} KB", bytes as f64 / KB as f64)
+    } else if bytes < GB {
+        format!("{:.1} MB", bytes as f64 / MB as f64)
+    } else {
+        format!("{:.1} GB", bytes as f64 / GB as f64)
     }
 }
 
@@ -55,20 +59,142 @@ pub fn format_time(duration: Duration) -> String {
     }
 }
 
+/// `value` as C's `printf("%.<precision>g")` formats it: `precision`
+/// significant digits, in exponent form for very small or large values,
+/// without trailing zeros.
+pub fn format_g(value: f64, precision: usize) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return format!("{}", value);
+    }
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, value)).to_string()
+    }
+}
+
+// Drops trailing zeros after a decimal point, and then the point itself
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') { number.trim_end_matches('0').trim_end_matches('.') } else { number }
+}
+
+/// User and system CPU time of this process so far; None where
+/// /proc/self/stat is unavailable (outside Linux).
+pub fn cpu_times() -> Option<(Duration, Duration)> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields from the third on follow the command name, which may hold spaces
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime and stime (fields 14 and 15) count USER_HZ ticks, 100 per second on Linux
+    let ticks = |i: usize| fields.get(i)?.parse::<u64>().ok().map(|t| Duration::from_millis(t * 10));
+    Some((ticks(11)?, ticks(12)?))
+}
+
+/// Peak resident memory of this process so far; None where
+/// /proc/self/status is unavailable (outside Linux).
+pub fn peak_memory() -> Option<u64> {
+    status_bytes("VmHWM:")
+}
+
+/// Resident memory of this process now; None outside Linux.
+pub fn resident_memory() -> Option<u64> {
+    status_bytes("VmRSS:")
+}
+
+// A size in kB from /proc/self/status, in bytes
+fn status_bytes(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// CPUs this process may use: those it is scheduled on, within the CPU
+/// quota of its cgroup (as containers and batch schedulers set).
+pub fn available_cpus() -> usize {
+    let scheduled = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let read = |path: &str| std::fs::read_to_string(path).ok();
+    let quota = read("/sys/fs/cgroup/cpu.max")
+        .or_else(|| Some(format!("{} {}", read("/sys/fs/cgroup/cpu/cpu.cfs_quota_us")?.trim(),
+                                 read("/sys/fs/cgroup/cpu/cpu.cfs_period_us")?.trim())))
+        .and_then(|text| cpu_quota(&text));
+    quota.map_or(scheduled, |cpus| scheduled.min((cpus.ceil() as usize).max(1)))
+}
+
+/// Threads for --threads `requested`: 0 means every available CPU.
+pub fn resolve_threads(requested: usize) -> usize {
+    if requested == 0 { available_cpus() } else { requested }
+}
+
+// CPUs of a cgroup CPU quota, "<quota> <period>" in microseconds as in v2's
+// `cpu.max`; None without a limit ("max", or v1's -1)
+fn cpu_quota(text: &str) -> Option<f64> {
+    let mut fields = text.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next().map_or(Some(100_000.0), |period| period.parse().ok())?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// `time` in UTC as C's `ctime()` writes it, without the newline:
+/// `Thu Jan  1 00:00:00 1970`.
+pub fn format_ctime(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    
+    let (days, year, month, day, secs_of_day) = civil_time(time);
+    format!("{} {} {:2} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[(days % 7) as usize], MONTHS[(month - 1) as usize], day,
+        secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60, year)
+}
+
+/// `time` in UTC as RFC 3339: `1970-01-01T00:00:00Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let (_, year, month, day, secs_of_day) = civil_time(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+// Days since the epoch, civil year, month, day, and seconds into the day of
+// `time` in UTC
+fn civil_time(time: SystemTime) -> (u64, u64, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    
+    // Civil date from days since the epoch (Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (days, year, month, day, secs_of_day)
+}
+
 pub fn calculate_gc_content(sequence: &str) -> f64 {
     let gc_count = sequence.chars().filter(|&c| c == 'G' || c == 'C').count();
     gc_count as f64 / sequence.len() as f64
 }
 
 pub fn reverse_complement(sequence: &str) -> String {
+    digital::reverse_complement_text(sequence)
+}
+
+/// Rewrite T/U residues into the requested alphabet, preserving case.
+pub fn convert_alphabet(sequence: &str, alphabet: SeqAlphabet) -> String {
     sequence.chars()
-        .rev()
-        .map(|c| match c {
-            'A' => 'T',
-            'T' => 'A',
-            'G' => 'C',
-            'C' => 'G',
-            'U' => 'A',
+        .map(|c| match (alphabet, c) {
+            (SeqAlphabet::Dna, 'U') => 'T',
+            (SeqAlphabet::Dna, 'u') => 't',
+            (SeqAlphabet::Rna, 'T') => 'U',
+            (SeqAlphabet::Rna, 't') => 'u',
             _ => c,
         })
         .collect()
@@ -94,11 +220,11 @@ pub fn levenshtein_distance(s1: &str, s2: &str) -> usize {
     
     let mut matrix = vec![vec![0; len2 + 1]; len1 + 1];
     
-    for i in 0..=len1 {
-        matrix[i][0] = i;
+    for (i, row) in matrix.iter_mut().enumerate() {
+        row[0] = i;
     }
-    for j in 0..=len2 {
-        matrix[0][j] = j;
+    for (j, cell) in matrix[0].iter_mut().enumerate() {
+        *cell = j;
     }
     
     for (i, c1) in s1.chars().enumerate() {
@@ -113,10 +239,51 @@ pub fn levenshtein_distance(s1: &str, s2: &str) -> usize {
     matrix[len1][len2]
 }
 
+/// 64-bit FNV-1a hash; stable across platforms, runs, and compiler versions.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// `stable_hash` of bytes given in pieces, such as a file read in blocks.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+    
+    pub fn update(&mut self, bytes: &[u8]) {
+        const PRIME: u64 = 0x100000001b3;
+        self.0 = bytes.iter().fold(self.0, |hash, &b| (hash ^ b as u64).wrapping_mul(PRIME));
+    }
+    
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     
+    #[test]
+    fn test_cpu_quota() {
+        assert_eq!(cpu_quota("max 100000\n"), None);
+        assert_eq!(cpu_quota("250000 100000\n"), Some(2.5));
+        assert_eq!(cpu_quota("50000"), Some(0.5));
+        assert_eq!(cpu_quota("-1 100000"), None);
+        assert!(resolve_threads(0) >= 1);
+        assert_eq!(resolve_threads(3), 3);
+    }
+    
     #[test]
     fn test_format_bytes() {
         assert_eq!(format_bytes(1023), "1023 B");
@@ -124,6 +291,26 @@ mod tests {
         assert_eq!(format_bytes(1048576), "1.0 MB");
     }
     
+    #[test]
+    fn test_format_g() {
+        assert_eq!(format_g(2.4e-19, 2), "2.4e-19");
+        assert_eq!(format_g(1.0e-19, 2), "1e-19");
+        assert_eq!(format_g(0.0012345, 2), "0.0012");
+        assert_eq!(format_g(0.5, 2), "0.5");
+        assert_eq!(format_g(12.0, 2), "12");
+        assert_eq!(format_g(99.7, 2), "1e+02");
+        assert_eq!(format_g(1234.0, 2), "1.2e+03");
+        assert_eq!(format_g(0.0, 2), "0");
+    }
+    
+    #[test]
+    fn test_format_ctime() {
+        assert_eq!(format_ctime(UNIX_EPOCH), "Thu Jan  1 00:00:00 1970");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_723);
+        assert_eq!(format_ctime(leap_day), "Tue Feb 29 01:02:03 2000");
+        assert_eq!(format_rfc3339(leap_day), "2000-02-29T01:02:03Z");
+    }
+    
     #[test]
     fn test_calculate_gc_content() {
         assert_eq!(calculate_gc_content("ATGC"), 0.5);
@@ -134,7 +321,15 @@ mod tests {
     #[test]
     fn test_reverse_complement() {
         assert_eq!(reverse_complement("ATGC"), "GCAT");
-        assert_eq!(reverse_complement("AAAA"), "TTTT");
+        assert_eq!(reverse_complement("AAAA"), "UUUU");
+        assert_eq!(reverse_complement("AUGC"), "GCAU");
+    }
+    
+    #[test]
+    fn test_convert_alphabet() {
+        assert_eq!(convert_alphabet("ACGUacgu", SeqAlphabet::Dna), "ACGTacgt");
+        assert_eq!(convert_alphabet("ACGTacgt", SeqAlphabet::Rna), "ACGUacgu");
+        assert_eq!(convert_alphabet("ACGN", SeqAlphabet::Rna), "ACGN");
     }
     
     #[test]
@@ -144,6 +339,12 @@ mod tests {
         assert_eq!(hamming_distance("ATGC", "CCCC"), 3);
     }
     
+    #[test]
+    fn test_stable_hash() {
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+    }
+    
     #[test]
     fn test_levenshtein_distance() {
         assert_eq!(levenshtein_distance("kitten", "sitting"), 3);
@@ -1,132 +0,0 @@
-use anyhow::Result;
-use log::{debug, info};
-use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
-use crate::cm::Cm;
-use crate::search::{Sequence, Hit};
-
-pub struct WorkerPool {
-    workers: Vec<Worker>,
-    cm: Arc<Cm>,
-}
-
-pub struct Worker {
-    id: usize,
-    cm: Arc<Cm>,
-}
-
-impl WorkerPool {
-    pub fn new(cm: Cm, num_workers: usize) -> Self {
-        let cm = Arc::new(cm);
-        let workers: Vec<Worker> = (0..num_workers)
-            .map(|id| Worker {
-                id,
-                cm: Arc::clone(&cm),
-            })
-            .collect();
-        
-        Self { workers, cm }
-    }
-    
-    pub fn process_sequences(&self, sequences: &[Sequence]) -> Result<Vec<Hit>> {
-        info!("Processing {} sequences with {} workers", sequences.len(), self.workers.len());
-        
-        let hits: Vec<Hit> = sequences
-            .par_iter()
-            .flat_map(|seq| self.process_sequence(seq))
-            .collect();
-        
-        Ok(hits)
-    }
-    
-    fn process_sequence(&self, sequence: &Sequence) -> Vec<Hit> {
-        // Simplified processing - in real implementation this would use worker threads
-        let mut hits = Vec::new();
-        
-        // Simple scoring for demonstration
-        let score = self.calculate_sequence_score(sequence);
-        
-        if score > 0.5 {
-            hits.push(Hit {
-                sequence_name: sequence.name.clone(),
-                start: 0,
-                end: sequence.length,
-                score,
-                evalue: 1.0 / (score + 1.0),
-                alignment: None,
-            });
-        }
-        
-        hits
-    }
-    
-    fn calculate_sequence_score(&self, sequence: &Sequence) -> f64 {
-        // Simplified scoring algorithm
-        let consensus = &self.cm.consensus.sequence;
-        let mut matches = 0;
-        let min_len = std::cmp::min(sequence.sequence.len(), consensus.len());
-        
-        for i in 0..min_len {
-            if sequence.sequence.chars().nth(i) == consensus.chars().nth(i) {
-                matches += 1;
-            }
-        }
-        
-        matches as f64 / min_len as f64
-    }
-}
-
-impl Worker {
-    pub fn new(id: usize, cm: Arc<Cm>) -> Self {
-        Self { id, cm }
-    }
-    
-    pub fn process(&self, sequence: &Sequence) -> Result<Vec<Hit>> {
-        debug!("Worker {} processing sequence {}", self.id, sequence.name);
-        
-        // In real implementation, this would do the actual CM search
-        let hits = self.search_sequence(sequence)?;
-        
-        Ok(hits)
-    }
-    
-    fn search_sequence(&self, sequence: &Sequence) -> Result<Vec<Hit>> {
-        let mut hits = Vec::new();
-        
-        // Simplified search - just check for basic similarity
-        let score = self.calculate_score(sequence);
-        
-        if score > 0.6 {
-            hits.push(Hit {
-                sequence_name: sequence.name.clone(),
-                start: 0,
-                end: sequence.length,
-                score,
-                evalue: self.calculate_evalue(score),
-                alignment: None,
-            });
-        }
-        
-        Ok(hits)
-    }
-    
-    fn calculate_score(&self, sequence: &Sequence) -> f64 {
-        // Simplified scoring
-        let consensus = &self.cm.consensus.sequence;
-        let mut score = 0.0;
-        let min_len = std::cmp::min(sequence.sequence.len(), consensus.len());
-        
-        for i in 0..min_len {
-            if sequence.sequence.chars().nth(i) == consensus.chars().nth(i) {
-                score += 1.0;
-            }
-        }
-        
-        score / min_len as f64
-    }
-    
-    fn calculate_evalue(&self, score: f64) -> f64 {
-        // Simplified E-value calculation
-        1.0 / (score + 1.0)
-    }
-} 
\ No newline at end of file